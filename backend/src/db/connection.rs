@@ -1,4 +1,23 @@
+use crate::ops::{
+    endpoints::{backfill_request_services, load_templater, retemplate_requests},
+    headers::backfill_request_headers,
+    issues::backfill_issues,
+    redaction::{seed_default_redaction_rules, CARD_NUMBER_PATTERN},
+    search::rebuild_search_index,
+};
 use rusqlite::Connection;
+use std::time::Duration;
+
+/// How long background connections wait on a database locked by a write
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Opens a connection to a database `connect_db` already created, for background work that
+/// shouldn't run the migrations again
+pub fn open_db(db_path: &str) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(db_path)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    Ok(conn)
+}
 
 pub fn connect_db(db_path: &str) -> Connection {
     //Open a new connection to database. If a database does not exist at the path, one is created.
@@ -11,6 +30,14 @@ pub fn connect_db(db_path: &str) -> Connection {
     conn.pragma_update(None, "locking_mode", "NORMAL")
         .expect("Failed to run in WAL mode");
 
+    let has_redaction_rules = table_exists(&conn, "redaction_rules");
+    let has_request_headers = table_exists(&conn, "request_headers");
+    let has_search_index = table_exists(&conn, "requests_fts");
+    let has_endpoint_templates = column_exists(&conn, "requests", "endpoint_template");
+    let has_services = column_exists(&conn, "requests", "service");
+    let has_issues = table_exists(&conn, "issues");
+    let has_backfills = table_exists(&conn, "backfills");
+
     //Requests TABLE
     match conn.execute_batch(
         "
@@ -26,8 +53,15 @@ pub fn connect_db(db_path: &str) -> Connection {
             status   TEXT,
             req_body TEXT,
             res_body TEXT,
-            res_time INTEGER
+            res_time INTEGER,
+            endpoint_template TEXT,
+            service  TEXT,
+            trace_id TEXT,
+            span_id  TEXT,
+            parent_span_id TEXT,
+            issue_id INTEGER
         );
+        CREATE INDEX IF NOT EXISTS requests_date ON requests(date);
         CREATE TABLE IF NOT EXISTS uptime_settings(
             url	TEXT PRIMARY KEY NOT NULL,
             interval INT DEFAULT 60,
@@ -40,6 +74,224 @@ pub fn connect_db(db_path: &str) -> Connection {
             status      TEXT,
             url         TEXT
         );
+        CREATE TABLE IF NOT EXISTS audit_log(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            date      DATETIME NOT NULL,
+            actor     TEXT NOT NULL,
+            action    TEXT NOT NULL,
+            target    TEXT,
+            before    TEXT,
+            after     TEXT,
+            source_ip TEXT
+        );
+        CREATE INDEX IF NOT EXISTS audit_log_date ON audit_log(date);
+        -- Entries can only be removed by retention, never rewritten
+        CREATE TRIGGER IF NOT EXISTS audit_log_append_only
+        BEFORE UPDATE ON audit_log
+        BEGIN
+            SELECT RAISE(ABORT, 'audit_log is append-only');
+        END;
+        CREATE TABLE IF NOT EXISTS redaction_rules(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            kind        TEXT NOT NULL,
+            pattern     TEXT NOT NULL,
+            action      TEXT NOT NULL DEFAULT 'mask',
+            enabled     INTEGER NOT NULL DEFAULT 1,
+            description TEXT,
+            validation  TEXT,
+            UNIQUE(kind, pattern)
+        );
+        CREATE TABLE IF NOT EXISTS request_headers(
+            request_id INTEGER NOT NULL,
+            direction  TEXT NOT NULL,
+            name       TEXT NOT NULL,
+            value      TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS request_headers_request ON request_headers(request_id);
+        CREATE INDEX IF NOT EXISTS request_headers_name_value ON request_headers(direction, name, value);
+        -- Full-text index over requests, kept in sync by the triggers below
+        CREATE VIRTUAL TABLE IF NOT EXISTS requests_fts USING fts5(
+            endpoint, params, req_headers, res_headers, req_body, res_body,
+            content = 'requests',
+            content_rowid = 'id',
+            prefix = '2 3'
+        );
+        CREATE TRIGGER IF NOT EXISTS requests_fts_insert
+        AFTER INSERT ON requests
+        BEGIN
+            INSERT INTO requests_fts(rowid, endpoint, params, req_headers, res_headers, req_body, res_body)
+            VALUES (new.id, new.endpoint, new.params, new.req_headers, new.res_headers, new.req_body, new.res_body);
+        END;
+        CREATE TRIGGER IF NOT EXISTS requests_fts_delete
+        AFTER DELETE ON requests
+        BEGIN
+            INSERT INTO requests_fts(requests_fts, rowid, endpoint, params, req_headers, res_headers, req_body, res_body)
+            VALUES ('delete', old.id, old.endpoint, old.params, old.req_headers, old.res_headers, old.req_body, old.res_body);
+        END;
+        CREATE TRIGGER IF NOT EXISTS requests_fts_update
+        AFTER UPDATE OF endpoint, params, req_headers, res_headers, req_body, res_body ON requests
+        BEGIN
+            INSERT INTO requests_fts(requests_fts, rowid, endpoint, params, req_headers, res_headers, req_body, res_body)
+            VALUES ('delete', old.id, old.endpoint, old.params, old.req_headers, old.res_headers, old.req_body, old.res_body);
+            INSERT INTO requests_fts(rowid, endpoint, params, req_headers, res_headers, req_body, res_body)
+            VALUES (new.id, new.endpoint, new.params, new.req_headers, new.res_headers, new.req_body, new.res_body);
+        END;
+        CREATE TABLE IF NOT EXISTS endpoint_patterns(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            pattern     TEXT NOT NULL UNIQUE,
+            description TEXT,
+            created_at  DATETIME NOT NULL
+        );
+        -- Seasonal baseline of a metric for each endpoint, method and hour of the week
+        CREATE TABLE IF NOT EXISTS anomaly_baselines(
+            endpoint     TEXT NOT NULL,
+            method       TEXT NOT NULL,
+            metric       TEXT NOT NULL,
+            hour_of_week INTEGER NOT NULL,
+            mean         REAL NOT NULL,
+            variance     REAL NOT NULL,
+            samples      INTEGER NOT NULL,
+            PRIMARY KEY(endpoint, method, metric, hour_of_week)
+        );
+        -- Hourly buckets already checked for anomalies
+        CREATE TABLE IF NOT EXISTS anomaly_runs(
+            bucket       DATETIME PRIMARY KEY,
+            evaluated_at DATETIME NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS anomalies(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            endpoint TEXT NOT NULL,
+            method   TEXT NOT NULL,
+            metric   TEXT NOT NULL,
+            bucket   DATETIME NOT NULL,
+            value    REAL NOT NULL,
+            expected REAL NOT NULL,
+            stddev   REAL NOT NULL,
+            score    REAL NOT NULL,
+            UNIQUE(endpoint, method, metric, bucket)
+        );
+        CREATE INDEX IF NOT EXISTS anomalies_bucket ON anomalies(bucket);
+        -- Custom application metrics, one row per name and label set
+        CREATE TABLE IF NOT EXISTS metric_series(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name   TEXT NOT NULL,
+            kind   TEXT NOT NULL,
+            labels TEXT NOT NULL,
+            UNIQUE(name, labels)
+        );
+        -- Histogram samples keep their sum in value, with count and cumulative buckets
+        CREATE TABLE IF NOT EXISTS metric_samples(
+            series_id INTEGER NOT NULL,
+            date      DATETIME NOT NULL,
+            value     REAL NOT NULL,
+            count     REAL,
+            buckets   TEXT,
+            PRIMARY KEY(series_id, date)
+        );
+        CREATE INDEX IF NOT EXISTS metric_samples_date ON metric_samples(date);
+        -- Failed requests grouped by fingerprint, see ops::issues
+        CREATE TABLE IF NOT EXISTS issues(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            fingerprint TEXT NOT NULL UNIQUE,
+            method      TEXT NOT NULL,
+            endpoint_template TEXT NOT NULL,
+            status      INTEGER NOT NULL,
+            message     TEXT,
+            state       TEXT NOT NULL DEFAULT 'open',
+            regressed   INTEGER NOT NULL DEFAULT 0,
+            first_seen  DATETIME NOT NULL,
+            last_seen   DATETIME NOT NULL,
+            resolved_at DATETIME,
+            occurrences INTEGER NOT NULL DEFAULT 0,
+            last_request_id INTEGER
+        );
+        CREATE INDEX IF NOT EXISTS issues_last_seen ON issues(last_seen);
+        -- One-off data migrations that already ran
+        CREATE TABLE IF NOT EXISTS backfills(
+            name TEXT PRIMARY KEY,
+            completed_at DATETIME NOT NULL
+        );
+        -- Hourly occurrence counts of each issue
+        CREATE TABLE IF NOT EXISTS issue_occurrences(
+            issue_id INTEGER NOT NULL,
+            bucket   DATETIME NOT NULL,
+            count    INTEGER NOT NULL,
+            PRIMARY KEY(issue_id, bucket)
+        );
+        -- Scripted multi-step checks, see ops::synthetic
+        CREATE TABLE IF NOT EXISTS synthetic_monitors(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name       TEXT NOT NULL UNIQUE,
+            interval   INTEGER NOT NULL DEFAULT 300,
+            enabled    INTEGER NOT NULL DEFAULT 1,
+            variables  TEXT NOT NULL,
+            steps      TEXT NOT NULL,
+            created_at DATETIME NOT NULL,
+            updated_at DATETIME NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS synthetic_runs(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            monitor_id  INTEGER NOT NULL,
+            date        DATETIME NOT NULL,
+            status      TEXT NOT NULL,
+            duration    INTEGER NOT NULL,
+            failed_step TEXT,
+            error       TEXT,
+            steps       TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS synthetic_runs_monitor_date ON synthetic_runs(monitor_id, date);
+        -- Consecutive failing runs of a monitor, resolved_at is set by the next passing run
+        CREATE TABLE IF NOT EXISTS synthetic_incidents(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            monitor_id   INTEGER NOT NULL,
+            started_at   DATETIME NOT NULL,
+            resolved_at  DATETIME,
+            last_failure DATETIME NOT NULL,
+            failures     INTEGER NOT NULL,
+            failed_step  TEXT,
+            error        TEXT
+        );
+        CREATE INDEX IF NOT EXISTS synthetic_incidents_monitor ON synthetic_incidents(monitor_id, started_at);
+        -- Application log lines, severity orders the levels from trace (0) to fatal (5)
+        CREATE TABLE IF NOT EXISTS logs(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            date       DATETIME NOT NULL,
+            level      TEXT NOT NULL,
+            severity   INTEGER NOT NULL,
+            message    TEXT NOT NULL,
+            attributes TEXT NOT NULL,
+            service    TEXT,
+            trace_id   TEXT,
+            span_id    TEXT,
+            correlation_id TEXT
+        );
+        CREATE INDEX IF NOT EXISTS logs_date ON logs(date);
+        CREATE INDEX IF NOT EXISTS logs_trace_id ON logs(trace_id);
+        CREATE INDEX IF NOT EXISTS logs_correlation_id ON logs(correlation_id);
+        CREATE TABLE IF NOT EXISTS log_attributes(
+            log_id INTEGER NOT NULL,
+            name   TEXT NOT NULL,
+            value  TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS log_attributes_log ON log_attributes(log_id);
+        CREATE INDEX IF NOT EXISTS log_attributes_name_value ON log_attributes(name, value);
+        CREATE VIRTUAL TABLE IF NOT EXISTS logs_fts USING fts5(
+            message,
+            content = 'logs',
+            content_rowid = 'id'
+        );
+        CREATE TRIGGER IF NOT EXISTS logs_insert
+        AFTER INSERT ON logs
+        BEGIN
+            INSERT INTO logs_fts(rowid, message) VALUES (new.id, new.message);
+        END;
+        -- Retention deletes lines along with their attributes and index entries
+        CREATE TRIGGER IF NOT EXISTS logs_delete
+        AFTER DELETE ON logs
+        BEGIN
+            INSERT INTO logs_fts(logs_fts, rowid, message) VALUES ('delete', old.id, old.message);
+            DELETE FROM log_attributes WHERE log_id = old.id;
+        END;
         COMMIT;
         ",
     ) {
@@ -51,5 +303,170 @@ pub fn connect_db(db_path: &str) -> Connection {
         }
     }
 
+    // Card numbers were redacted without checking them, masking timestamps and ids too
+    if !column_exists(&conn, "redaction_rules", "validation") {
+        let migrated = conn
+            .execute("ALTER TABLE redaction_rules ADD COLUMN validation TEXT", [])
+            .and_then(|_| {
+                conn.execute(
+                    "UPDATE redaction_rules SET validation = 'luhn' WHERE kind = 'regex' AND pattern = ?1",
+                    [CARD_NUMBER_PATTERN],
+                )
+            });
+        if let Err(err) = migrated {
+            if cfg!(debug_assertions) {
+                println!("{:#?}", err)
+            }
+        }
+    }
+
+    // Seed the default rules only once so that deleting them sticks
+    if !has_redaction_rules {
+        if let Err(err) = seed_default_redaction_rules(&conn) {
+            if cfg!(debug_assertions) {
+                println!("{:#?}", err)
+            }
+        }
+    }
+
+    if !has_request_headers {
+        if let Err(err) = backfill_request_headers(&conn) {
+            if cfg!(debug_assertions) {
+                println!("{:#?}", err)
+            }
+        }
+    }
+
+    // Requests logged before templates existed get the column and their templates
+    if !column_exists(&conn, "requests", "endpoint_template") {
+        if let Err(err) = conn.execute("ALTER TABLE requests ADD COLUMN endpoint_template TEXT", []) {
+            if cfg!(debug_assertions) {
+                println!("{:#?}", err)
+            }
+        }
+    }
+    if let Err(err) = conn.execute(
+        "CREATE INDEX IF NOT EXISTS requests_endpoint_template ON requests(endpoint_template)",
+        [],
+    ) {
+        if cfg!(debug_assertions) {
+            println!("{:#?}", err)
+        }
+    }
+    if !has_endpoint_templates {
+        if let Err(err) = load_templater(&conn).and_then(|t| retemplate_requests(&conn, &t)) {
+            if cfg!(debug_assertions) {
+                println!("{:#?}", err)
+            }
+        }
+    }
+
+    // Requests logged before services existed get the host of their endpoint
+    if !column_exists(&conn, "requests", "service") {
+        if let Err(err) = conn.execute("ALTER TABLE requests ADD COLUMN service TEXT", []) {
+            if cfg!(debug_assertions) {
+                println!("{:#?}", err)
+            }
+        }
+    }
+    if let Err(err) = conn.execute(
+        "CREATE INDEX IF NOT EXISTS requests_service ON requests(service)",
+        [],
+    ) {
+        if cfg!(debug_assertions) {
+            println!("{:#?}", err)
+        }
+    }
+    if !has_services {
+        if let Err(err) = backfill_request_services(&conn) {
+            if cfg!(debug_assertions) {
+                println!("{:#?}", err)
+            }
+        }
+    }
+
+    // Requests logged before trace correlation existed have no trace
+    for column in ["trace_id", "span_id", "parent_span_id"] {
+        if !column_exists(&conn, "requests", column) {
+            if let Err(err) =
+                conn.execute(&format!("ALTER TABLE requests ADD COLUMN {} TEXT", column), [])
+            {
+                if cfg!(debug_assertions) {
+                    println!("{:#?}", err)
+                }
+            }
+        }
+    }
+    if let Err(err) = conn.execute(
+        "CREATE INDEX IF NOT EXISTS requests_trace_id ON requests(trace_id)",
+        [],
+    ) {
+        if cfg!(debug_assertions) {
+            println!("{:#?}", err)
+        }
+    }
+
+    if !column_exists(&conn, "requests", "issue_id") {
+        if let Err(err) = conn.execute("ALTER TABLE requests ADD COLUMN issue_id INTEGER", []) {
+            if cfg!(debug_assertions) {
+                println!("{:#?}", err)
+            }
+        }
+    }
+    if let Err(err) = conn.execute(
+        "CREATE INDEX IF NOT EXISTS requests_issue_id ON requests(issue_id)",
+        [],
+    ) {
+        if cfg!(debug_assertions) {
+            println!("{:#?}", err)
+        }
+    }
+
+    // Issues created before backfills were recorded were grouped then
+    if has_issues && !has_backfills {
+        if let Err(err) = conn.execute(
+            "INSERT OR IGNORE INTO backfills (name, completed_at) VALUES ('issues', strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))",
+            [],
+        ) {
+            if cfg!(debug_assertions) {
+                println!("{:#?}", err)
+            }
+        }
+    }
+
+    // Group the failed requests logged before issues existed
+    if let Err(err) = backfill_issues(&conn) {
+        if cfg!(debug_assertions) {
+            println!("{:#?}", err)
+        }
+    }
+
+    // Index requests logged before full-text search existed
+    if !has_search_index {
+        if let Err(err) = rebuild_search_index(&conn) {
+            if cfg!(debug_assertions) {
+                println!("{:#?}", err)
+            }
+        }
+    }
+
     conn
 }
+
+fn column_exists(conn: &Connection, table: &str, column: &str) -> bool {
+    conn.query_row(
+        "SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2",
+        [table, column],
+        |_| Ok(()),
+    )
+    .is_ok()
+}
+
+fn table_exists(conn: &Connection, table: &str) -> bool {
+    conn.query_row(
+        "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        [table],
+        |_| Ok(()),
+    )
+    .is_ok()
+}
@@ -4,35 +4,78 @@ use actix_web::{
     web::{self},
     App, HttpResponse, HttpServer,
 };
+use chrono::{DateTime, Utc};
 use db::connection::connect_db;
 use dotenv::dotenv;
 use ops::{
+    anomalies::start_anomaly_detection,
+    audit::DEFAULT_AUDIT_LOG_RETENTION_DAYS,
     auth::{generate_api_key, verify_encrypted_key},
+    custom_metrics::DEFAULT_METRIC_RETENTION_DAYS,
+    endpoints::{load_templater, EndpointTemplater},
+    export::{export_requests, ExportFormat},
+    filters::RequestFilter,
+    live::RequestHub,
+    logs::DEFAULT_LOG_RETENTION_DAYS,
+    metrics::{RequestMetrics, DEFAULT_METRICS_MAX_ENDPOINTS},
+    query::Query,
+    redaction::{load_redactor, Redactor},
+    search::rebuild_search_index,
+    synthetic::start_synthetic_monitors,
     uptime::{restart_uptime_service, UptimeActor},
 };
 use routes::{
+    anomalies::{get_active_anomalies, get_anomalies},
+    audit::audit_log,
     auth::authenticate,
+    endpoints::{
+        add_endpoint_pattern, get_endpoint_patterns, get_endpoint_stats, remove_endpoint_pattern,
+    },
+    issues::{get_issue, get_issue_list, update_issue},
+    logs::{append_logs, get_logs, request_logs},
+    metrics::{get_metrics, ingest_metrics, query_metrics, write_metrics},
+    redaction::{add_redaction_rule, get_redaction_rules, remove_redaction_rule},
+    replay::replay_request,
     requests::{
-        append_request, get_requests_by_status, get_requests_search, get_requests_search_suggestions,
-        get_requests_socket, get_service_level_indicators, get_status_stats, index, requests,
-        requests_from,
+        append_request, compare, export, get_requests_by_status, get_requests_search,
+        get_requests_search_suggestions, get_requests_socket, get_service_level_indicators,
+        get_status_stats, index, request_detail, requests, requests_from, series, stream_requests,
+    },
+    synthetic::{
+        add_synthetic_monitor, get_synthetic_incidents, get_synthetic_monitor, get_synthetic_monitors,
+        get_synthetic_runs, remove_synthetic_monitor, run_synthetic_monitor, update_synthetic_monitor,
     },
+    traces::get_trace,
     uptime::{delete_uptime_setting, get_uptime_percentage, get_uptime_settings, setup_uptime_ping},
 };
 use rusqlite::Connection;
-use std::{env, process};
+use std::{
+    env, process,
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+};
 
 mod db;
 mod middleware;
 mod ops;
 mod routes;
 
+/// Largest remote-write body accepted
+const MAX_WRITE_REQUEST_BYTES: usize = 16 * 1024 * 1024;
+
 #[derive(Debug)]
 struct AppState {
     db_client: Connection,
     db_path: String,
     require_auth: bool,
     uptime_actor: actix::Addr<UptimeActor>,
+    audit_retention_days: i64,
+    log_retention_days: i64,
+    metric_retention_days: i64,
+    redactor: Arc<RwLock<Redactor>>,
+    templater: Arc<RwLock<EndpointTemplater>>,
+    hub: RequestHub,
+    metrics: Arc<Mutex<RequestMetrics>>,
 }
 
 #[actix_web::main]
@@ -46,6 +89,9 @@ async fn main() -> std::io::Result<()> {
     let api_ip = env::var("API_IP").expect("API_IP missing");
     let api_port = env::var("API_PORT").expect("API_PORT missing");
     let db_path = env::var("DB_PATH").expect("DB_PATH missing");
+    let audit_retention_days = audit_retention_days();
+    let log_retention_days = log_retention_days();
+    let metric_retention_days = metric_retention_days();
 
     let uptime_actor = UptimeActor {
         is_running: false,
@@ -53,6 +99,24 @@ async fn main() -> std::io::Result<()> {
     }
     .start();
 
+    // Creates and migrates the schema once before the workers open their own connections
+    let setup_conn = connect_db(&db_path);
+
+    // Shared by all workers so rule changes apply everywhere at once
+    let redactor = Arc::new(RwLock::new(
+        load_redactor(&setup_conn).expect("Failed to load redaction rules"),
+    ));
+    let templater = Arc::new(RwLock::new(
+        load_templater(&setup_conn).expect("Failed to load endpoint patterns"),
+    ));
+
+    // Feeds the live tails of every worker
+    let hub = RequestHub::default();
+    let metrics = Arc::new(Mutex::new(RequestMetrics::new(metrics_max_endpoints())));
+
+    start_anomaly_detection(db_path.clone());
+    start_synthetic_monitors(db_path.clone());
+
     HttpServer::new(move || {
         let db_client = connect_db(&db_path);
         // Set the update hook
@@ -60,33 +124,77 @@ async fn main() -> std::io::Result<()> {
 
         let cors = Cors::default()
             .allowed_origin_fn(|origin, _req_head| origin.as_bytes().starts_with(b"http://localhost"))
-            .allowed_methods(vec!["GET", "POST", "OPTIONS", "DELETE"])
+            .allowed_methods(vec!["GET", "POST", "PUT", "PATCH", "OPTIONS", "DELETE"])
             .allow_any_header()
             .max_age(3600);
         App::new()
             .wrap(cors)
             //PUBLIC ROUTES
+            // Remote-write batches are larger than the default limit of raw bodies
+            .app_data(web::PayloadConfig::new(MAX_WRITE_REQUEST_BYTES))
             .app_data(web::Data::new(AppState {
                 db_client,
                 db_path: db_path.clone(),
                 require_auth,
                 uptime_actor: uptime_actor.clone(),
+                audit_retention_days,
+                log_retention_days,
+                metric_retention_days,
+                redactor: redactor.clone(),
+                templater: templater.clone(),
+                hub: hub.clone(),
+                metrics: metrics.clone(),
             }))
             .service(index)
             .service(authenticate)
             .service(append_request)
             .service(requests)
             .service(requests_from)
+            .service(request_detail)
+            .service(request_logs)
+            .service(replay_request)
+            .service(append_logs)
+            .service(get_logs)
+            .service(export)
+            .service(stream_requests)
+            .service(get_trace)
+            .service(get_issue_list)
+            .service(get_issue)
+            .service(update_issue)
             .service(get_requests_socket)
+            .service(get_metrics)
+            .service(ingest_metrics)
+            .service(write_metrics)
+            .service(query_metrics)
             .service(get_status_stats)
             .service(get_requests_by_status)
             .service(setup_uptime_ping)
             .service(get_uptime_percentage)
             .service(get_uptime_settings)
             .service(delete_uptime_setting)
+            .service(get_synthetic_monitors)
+            .service(add_synthetic_monitor)
+            .service(get_synthetic_monitor)
+            .service(update_synthetic_monitor)
+            .service(remove_synthetic_monitor)
+            .service(get_synthetic_runs)
+            .service(get_synthetic_incidents)
+            .service(run_synthetic_monitor)
             .service(get_requests_search_suggestions)
             .service(get_requests_search)
             .service(get_service_level_indicators)
+            .service(compare)
+            .service(series)
+            .service(audit_log)
+            .service(get_redaction_rules)
+            .service(add_redaction_rule)
+            .service(remove_redaction_rule)
+            .service(get_endpoint_patterns)
+            .service(add_endpoint_pattern)
+            .service(remove_endpoint_pattern)
+            .service(get_endpoint_stats)
+            .service(get_anomalies)
+            .service(get_active_anomalies)
             .default_service(web::to(HttpResponse::NotFound))
     })
     .bind((
@@ -97,13 +205,45 @@ async fn main() -> std::io::Result<()> {
     .await
 }
 
+/// Days to keep audit log entries for, set with AUDIT_LOG_RETENTION_DAYS. 0 keeps entries forever.
+fn audit_retention_days() -> i64 {
+    env::var("AUDIT_LOG_RETENTION_DAYS")
+        .ok()
+        .and_then(|days| days.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_AUDIT_LOG_RETENTION_DAYS)
+}
+
+/// Days to keep application log lines for, set with LOG_RETENTION_DAYS. 0 keeps them forever.
+fn log_retention_days() -> i64 {
+    env::var("LOG_RETENTION_DAYS")
+        .ok()
+        .and_then(|days| days.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_LOG_RETENTION_DAYS)
+}
+
+/// Days to keep custom metric samples for, set with METRIC_RETENTION_DAYS. 0 keeps them forever.
+fn metric_retention_days() -> i64 {
+    env::var("METRIC_RETENTION_DAYS")
+        .ok()
+        .and_then(|days| days.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_METRIC_RETENTION_DAYS)
+}
+
+/// Endpoint templates getting their own Prometheus series, the rest being counted as `other`
+fn metrics_max_endpoints() -> usize {
+    env::var("METRICS_MAX_ENDPOINTS")
+        .ok()
+        .and_then(|max| max.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_METRICS_MAX_ENDPOINTS)
+}
+
 fn handle_cli_args(args: Vec<String>) {
     if args.len() < 2 {
         return;
     }
     match args[1].as_str() {
         "--keygen" => {
-            generate_api_key(args[2].clone(), args[3].clone());
+            generate_api_key(args[2].clone(), args[3].clone(), audit_retention_days());
             process::exit(0);
         }
         "--verify" => {
@@ -113,6 +253,49 @@ fn handle_cli_args(args: Vec<String>) {
             println!("Key is valid: {}", is_valid);
             process::exit(0);
         }
+        "--rebuild-fts" => {
+            dotenv().ok();
+            let db_path = env::var("DB_PATH").expect("DB_PATH missing");
+            match rebuild_search_index(&connect_db(&db_path)) {
+                Ok(_) => println!("Rebuilt the search index"),
+                Err(e) => {
+                    eprintln!("Failed to rebuild the search index: {}", e);
+                    process::exit(1);
+                }
+            }
+            process::exit(0);
+        }
+        "--export" => {
+            // --export <csv|ndjson|parquet> <file> [from] [to] [query]
+            dotenv().ok();
+            let db_path = env::var("DB_PATH").expect("DB_PATH missing");
+            let format =
+                ExportFormat::from_str(&args[2]).expect("Format should be csv, ndjson or parquet");
+            let date = |arg: Option<&String>| {
+                arg.map(|d| d.parse::<DateTime<Utc>>().expect("Dates should be RFC 3339"))
+            };
+            let mut filter = RequestFilter::default();
+            Query::from_str(args.get(6).map_or("", |q| q.as_str()))
+                .unwrap_or_else(|e| panic!("Invalid query: {}", e))
+                .apply(&mut filter);
+            let file = std::fs::File::create(&args[3]).expect("Failed to create export file");
+            match export_requests(
+                date(args.get(4)),
+                date(args.get(5)),
+                &filter,
+                None,
+                format,
+                std::io::BufWriter::new(file),
+                &connect_db(&db_path),
+            ) {
+                Ok(count) => println!("Exported {} requests to {}", count, args[3]),
+                Err(e) => {
+                    eprintln!("Failed to export requests: {}", e);
+                    process::exit(1);
+                }
+            }
+            process::exit(0);
+        }
         "--no-auth" => {
             println!("Skipping authorization");
         }
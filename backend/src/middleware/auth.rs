@@ -16,6 +16,16 @@ pub struct User {
     pub credentials: Option<UserCredentials>,
 }
 
+impl User {
+    /// Name of the authenticated user, or `anonymous` when authentication is skipped
+    pub fn name(&self) -> String {
+        self.credentials
+            .as_ref()
+            .map(|c| c.user.clone())
+            .unwrap_or("anonymous".to_string())
+    }
+}
+
 impl FromRequest for User {
     type Error = Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
@@ -0,0 +1,511 @@
+//! Anomaly detection on endpoint traffic.
+//!
+//! Every completed hour, the volume, 5xx rate and p95 latency of each endpoint template and method
+//! are compared with a baseline learned for the same hour of the week, an exponentially weighted
+//! mean and variance of the previous weeks. Values more than `ANOMALY_SCORE_THRESHOLD` standard
+//! deviations off the baseline are recorded as anomalies.
+
+use super::{endpoints::endpoint_metrics, filters::RequestFilter, stats::TimeWindow};
+use anyhow::Result;
+use chrono::{DateTime, Datelike, Duration, DurationRound, Timelike, Utc};
+use log::info;
+use rusqlite::{params, params_from_iter, types::Value as SqlValue, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, str::FromStr, thread};
+use strum::{Display, EnumString};
+
+/// How often the job looks for newly completed buckets
+pub const ANOMALY_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+/// Standard deviations from the baseline from which a bucket is anomalous
+pub const ANOMALY_SCORE_THRESHOLD: f64 = 3.0;
+/// Weight of the newest bucket in the baselines
+pub const BASELINE_ALPHA: f64 = 0.3;
+/// Weeks of data a baseline needs before it flags anything
+pub const MIN_BASELINE_SAMPLES: i64 = 3;
+/// Requests a bucket needs for its error rate and latency to be checked
+pub const MIN_BUCKET_REQUESTS: usize = 10;
+/// Hours of past data learned from on the first run
+pub const ANOMALY_BACKFILL_HOURS: i64 = 4 * 7 * 24;
+
+#[derive(Serialize, Deserialize, Display, EnumString, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum AnomalyMetric {
+    /// Requests in the bucket
+    Volume,
+    /// Share of 5xx responses
+    ErrorRate,
+    /// p95 response time
+    Latency,
+}
+
+impl AnomalyMetric {
+    /// Smallest deviation considered, so flat baselines don't flag tiny changes
+    fn min_stddev(&self, mean: f64) -> f64 {
+        match self {
+            AnomalyMetric::Volume => (mean * 0.1).max(1.),
+            AnomalyMetric::ErrorRate => 0.01,
+            AnomalyMetric::Latency => (mean * 0.1).max(5.),
+        }
+    }
+
+    /// Whether a drop is as worrying as a rise
+    fn two_sided(&self) -> bool {
+        *self == AnomalyMetric::Volume
+    }
+
+    /// Whether a score is far enough off the baseline to be an anomaly
+    fn is_anomalous(&self, score: f64) -> bool {
+        score >= ANOMALY_SCORE_THRESHOLD || (self.two_sided() && score <= -ANOMALY_SCORE_THRESHOLD)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Anomaly {
+    pub id: Option<i64>,
+    pub endpoint: String,
+    pub method: String,
+    pub metric: AnomalyMetric,
+    /// Start of the hour the anomaly was found in
+    pub bucket: DateTime<Utc>,
+    pub value: f64,
+    /// Baseline mean for that hour of the week
+    pub expected: f64,
+    pub stddev: f64,
+    /// Standard deviations from the baseline, negative for drops
+    pub score: f64,
+}
+
+impl Anomaly {
+    fn from_row(row: &rusqlite::Row) -> Result<Anomaly> {
+        Ok(Anomaly {
+            id: row.get(0)?,
+            endpoint: row.get(1)?,
+            method: row.get(2)?,
+            metric: AnomalyMetric::from_str(&row.get::<_, String>(3)?)?,
+            bucket: DateTime::parse_from_rfc3339(&row.get::<_, String>(4)?)?.with_timezone(&Utc),
+            value: row.get(5)?,
+            expected: row.get(6)?,
+            stddev: row.get(7)?,
+            score: row.get(8)?,
+        })
+    }
+}
+
+/// Which anomalies to list, also used as an alert condition on the latest bucket
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct AnomalyCondition {
+    /// Endpoint template, with `*` matching anything
+    pub endpoint: Option<String>,
+    pub method: Option<String>,
+    pub metric: Option<AnomalyMetric>,
+    /// Smallest absolute score
+    pub min_score: Option<f64>,
+}
+
+impl AnomalyCondition {
+    fn filter(&self) -> RequestFilter {
+        let mut filter = RequestFilter::default();
+        if let Some(endpoint) = &self.endpoint {
+            filter.push("endpoint GLOB ?", vec![SqlValue::Text(endpoint.clone())]);
+        }
+        if let Some(method) = &self.method {
+            filter.push("method = ?", vec![SqlValue::Text(method.to_uppercase())]);
+        }
+        if let Some(metric) = self.metric {
+            filter.push("metric = ?", vec![SqlValue::Text(metric.to_string())]);
+        }
+        if let Some(min_score) = self.min_score {
+            filter.push("ABS(score) >= ?", vec![SqlValue::Real(min_score)]);
+        }
+        filter
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Baseline {
+    mean: f64,
+    variance: f64,
+    samples: i64,
+}
+
+impl Baseline {
+    fn first(value: f64) -> Baseline {
+        Baseline {
+            mean: value,
+            variance: 0.,
+            samples: 1,
+        }
+    }
+
+    /// Exponentially weighted update of the mean and variance
+    fn update(&self, value: f64) -> Baseline {
+        let diff = value - self.mean;
+        Baseline {
+            mean: self.mean + BASELINE_ALPHA * diff,
+            variance: (1. - BASELINE_ALPHA) * (self.variance + BASELINE_ALPHA * diff * diff),
+            samples: self.samples + 1,
+        }
+    }
+
+    fn stddev(&self, metric: AnomalyMetric) -> f64 {
+        self.variance.sqrt().max(metric.min_stddev(self.mean))
+    }
+
+    /// Value learned in place of `value`. Outliers are learned at the threshold, so a spike doesn't
+    /// inflate the baseline and mask the next one, while a lasting change still shifts it over a
+    /// few weeks.
+    fn learned(&self, metric: AnomalyMetric, value: f64) -> f64 {
+        let bound = ANOMALY_SCORE_THRESHOLD * self.stddev(metric);
+        value.clamp(self.mean - bound, self.mean + bound)
+    }
+}
+
+fn hour_of_week(bucket: DateTime<Utc>) -> i64 {
+    (bucket.weekday().num_days_from_monday() * 24 + bucket.hour()) as i64
+}
+
+fn format_date(date: DateTime<Utc>) -> String {
+    date.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+/// Checks one hourly bucket against the baselines for its hour of the week, then updates them
+fn evaluate_bucket(conn: &Connection, bucket: DateTime<Utc>) -> Result<Vec<Anomaly>> {
+    let window = TimeWindow {
+        from: bucket,
+        to: bucket + Duration::hours(1),
+    };
+    let slot = hour_of_week(bucket);
+    let metrics = endpoint_metrics(&window, &RequestFilter::default(), conn)?;
+
+    let mut baselines = {
+        let mut stmt = conn.prepare(
+            "SELECT endpoint, method, metric, mean, variance, samples
+            FROM anomaly_baselines WHERE hour_of_week = ?1",
+        )?;
+        let rows = stmt.query_map(params![slot], |row| {
+            Ok((
+                (
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ),
+                Baseline {
+                    mean: row.get(3)?,
+                    variance: row.get(4)?,
+                    samples: row.get(5)?,
+                },
+            ))
+        })?;
+        let mut baselines = HashMap::new();
+        for row in rows {
+            let ((endpoint, method, metric), baseline) = row?;
+            if let Ok(metric) = AnomalyMetric::from_str(&metric) {
+                baselines.insert((endpoint, method, metric), baseline);
+            }
+        }
+        baselines
+    };
+
+    let mut values = vec![];
+    for ((endpoint, method), m) in &metrics {
+        let key = |metric| (endpoint.clone(), method.clone(), metric);
+        values.push((key(AnomalyMetric::Volume), m.request_count as f64));
+        if m.request_count >= MIN_BUCKET_REQUESTS {
+            values.push((key(AnomalyMetric::ErrorRate), m.error_rate));
+            values.push((key(AnomalyMetric::Latency), m.p95_latency as f64));
+        }
+    }
+    // Endpoints usually busy at this hour that got no traffic at all
+    for (endpoint, method, metric) in baselines.keys() {
+        if *metric == AnomalyMetric::Volume && !metrics.contains_key(&(endpoint.clone(), method.clone()))
+        {
+            values.push(((endpoint.clone(), method.clone(), *metric), 0.));
+        }
+    }
+
+    let mut anomalies = vec![];
+    let tx = conn.unchecked_transaction()?;
+    for ((endpoint, method, metric), value) in values {
+        let baseline = baselines.remove(&(endpoint.clone(), method.clone(), metric));
+        let mut learned = value;
+        if let Some(baseline) = baseline.filter(|b| b.samples >= MIN_BASELINE_SAMPLES) {
+            let stddev = baseline.stddev(metric);
+            let score = (value - baseline.mean) / stddev;
+            learned = baseline.learned(metric, value);
+            if metric.is_anomalous(score) {
+                anomalies.push(Anomaly {
+                    id: None,
+                    endpoint: endpoint.clone(),
+                    method: method.clone(),
+                    metric,
+                    bucket,
+                    value,
+                    expected: baseline.mean,
+                    stddev,
+                    score,
+                });
+            }
+        }
+        let updated = baseline.map_or(Baseline::first(value), |b| b.update(learned));
+        tx.execute(
+            "INSERT OR REPLACE INTO anomaly_baselines
+            (endpoint, method, metric, hour_of_week, mean, variance, samples)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                endpoint,
+                method,
+                metric.to_string(),
+                slot,
+                updated.mean,
+                updated.variance,
+                updated.samples
+            ],
+        )?;
+    }
+    for anomaly in anomalies.iter_mut() {
+        tx.execute(
+            "INSERT OR REPLACE INTO anomalies
+            (endpoint, method, metric, bucket, value, expected, stddev, score)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                anomaly.endpoint,
+                anomaly.method,
+                anomaly.metric.to_string(),
+                format_date(anomaly.bucket),
+                anomaly.value,
+                anomaly.expected,
+                anomaly.stddev,
+                anomaly.score
+            ],
+        )?;
+        anomaly.id = Some(tx.last_insert_rowid());
+    }
+    tx.execute(
+        "INSERT OR REPLACE INTO anomaly_runs (bucket, evaluated_at) VALUES (?1, ?2)",
+        params![format_date(bucket), format_date(Utc::now())],
+    )?;
+    tx.commit()?;
+    Ok(anomalies)
+}
+
+/// Start of the latest bucket that was checked
+pub fn last_evaluated_bucket(conn: &Connection) -> Result<Option<DateTime<Utc>>> {
+    let bucket = conn
+        .query_row("SELECT MAX(bucket) FROM anomaly_runs", [], |row| {
+            row.get::<_, Option<String>>(0)
+        })
+        .optional()?
+        .flatten();
+    Ok(bucket
+        .map(|b| DateTime::parse_from_rfc3339(&b).map(|d| d.with_timezone(&Utc)))
+        .transpose()?)
+}
+
+/// Checks every hourly bucket completed since the last run, learning from up to
+/// `ANOMALY_BACKFILL_HOURS` of history on the first one. Returns the anomalies found.
+pub fn detect_anomalies(conn: &Connection, now: DateTime<Utc>) -> Result<Vec<Anomaly>> {
+    let latest = now.duration_trunc(Duration::hours(1))? - Duration::hours(1);
+    let mut bucket = match last_evaluated_bucket(conn)? {
+        Some(bucket) => bucket + Duration::hours(1),
+        None => {
+            let first_request = conn
+                .query_row("SELECT MIN(date) FROM requests", [], |row| {
+                    row.get::<_, Option<String>>(0)
+                })?
+                .and_then(|d| DateTime::parse_from_rfc3339(&d).ok())
+                .map(|d| d.with_timezone(&Utc).duration_trunc(Duration::hours(1)))
+                .transpose()?;
+            first_request
+                .unwrap_or(latest)
+                .max(latest - Duration::hours(ANOMALY_BACKFILL_HOURS))
+        }
+    };
+    let mut anomalies = vec![];
+    while bucket <= latest {
+        anomalies.extend(evaluate_bucket(conn, bucket)?);
+        bucket += Duration::hours(1);
+    }
+    Ok(anomalies)
+}
+
+/// Runs `detect_anomalies` every `ANOMALY_CHECK_INTERVAL` on its own thread and connection, as
+/// catching up reads hours of requests
+pub fn start_anomaly_detection(db_path: String) {
+    let conn = match Connection::open(db_path) {
+        Ok(conn) => conn,
+        Err(err) => panic!("Error opening database: {:?}", err),
+    };
+    let spawned = thread::Builder::new()
+        .name("anomaly-detection".to_string())
+        .spawn(move || loop {
+            match detect_anomalies(&conn, Utc::now()) {
+                Ok(anomalies) => {
+                    for a in anomalies {
+                        info!(
+                            "Anomaly on {} {}: {} was {:.2}, expected {:.2} (score {:.1})",
+                            a.method, a.endpoint, a.metric, a.value, a.expected, a.score
+                        );
+                    }
+                }
+                Err(e) => info!("Failed to detect anomalies: {}", e),
+            }
+            thread::sleep(ANOMALY_CHECK_INTERVAL);
+        });
+    if let Err(err) = spawned {
+        panic!("Error starting anomaly detection: {:?}", err);
+    }
+}
+
+/// Anomalies matching `condition` in buckets starting within `window`, most recent first.
+/// Returns them along with the total number of matches.
+pub fn anomalies(
+    window: &TimeWindow,
+    condition: &AnomalyCondition,
+    index: usize,
+    limit: Option<usize>,
+    conn: &Connection,
+) -> Result<(Vec<Anomaly>, usize)> {
+    let filter = condition.filter();
+    let (from, to) = window.bounds();
+    let mut params = vec![SqlValue::Text(from), SqlValue::Text(to)];
+    params.extend_from_slice(filter.params());
+    let from_where = format!(
+        "FROM anomalies WHERE bucket >= ? AND bucket < ? AND {}",
+        filter.sql()
+    );
+    let total = conn.query_row(
+        &format!("SELECT COUNT(*) {}", from_where),
+        params_from_iter(&params),
+        |row| row.get::<_, usize>(0),
+    )?;
+    params.extend([
+        SqlValue::Integer(limit.map_or(-1, |l| l as i64)),
+        SqlValue::Integer(index as i64),
+    ]);
+    let mut stmt = conn.prepare(&format!(
+        "SELECT id, endpoint, method, metric, bucket, value, expected, stddev, score {}
+        ORDER BY bucket DESC, ABS(score) DESC
+        LIMIT ? OFFSET ?",
+        from_where
+    ))?;
+    let mut rows = stmt.query(params_from_iter(params))?;
+    let mut results = vec![];
+    while let Some(row) = rows.next()? {
+        results.push(Anomaly::from_row(row)?);
+    }
+    Ok((results, total))
+}
+
+/// Anomalies matching `condition` in the latest checked bucket, firing while it isn't empty
+pub fn active_anomalies(
+    condition: &AnomalyCondition,
+    conn: &Connection,
+) -> Result<(Option<DateTime<Utc>>, Vec<Anomaly>)> {
+    let Some(bucket) = last_evaluated_bucket(conn)? else {
+        return Ok((None, vec![]));
+    };
+    let window = TimeWindow {
+        from: bucket,
+        to: bucket + Duration::hours(1),
+    };
+    let (active, _) = anomalies(&window, condition, 0, None, conn)?;
+    Ok((Some(bucket), active))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn slots_hours_of_the_week() {
+        // 2026-10-19 is a Monday
+        assert_eq!(
+            hour_of_week(Utc.with_ymd_and_hms(2026, 10, 19, 0, 0, 0).unwrap()),
+            0
+        );
+        assert_eq!(
+            hour_of_week(Utc.with_ymd_and_hms(2026, 10, 20, 14, 0, 0).unwrap()),
+            38
+        );
+        assert_eq!(
+            hour_of_week(Utc.with_ymd_and_hms(2026, 10, 25, 23, 0, 0).unwrap()),
+            167
+        );
+        assert_eq!(
+            hour_of_week(Utc.with_ymd_and_hms(2026, 10, 26, 9, 0, 0).unwrap()),
+            hour_of_week(Utc.with_ymd_and_hms(2026, 10, 19, 9, 0, 0).unwrap())
+        );
+    }
+
+    #[test]
+    fn updates_baselines() {
+        let baseline = Baseline::first(10.).update(20.);
+        assert!(close(baseline.mean, 13.));
+        assert!(close(baseline.variance, 21.));
+        assert_eq!(baseline.samples, 2);
+
+        let mut steady = Baseline::first(50.);
+        for _ in 0..20 {
+            steady = steady.update(50.);
+        }
+        assert!(close(steady.mean, 50.));
+        assert!(close(steady.variance, 0.));
+        assert_eq!(steady.samples, 21);
+    }
+
+    #[test]
+    fn floors_deviations() {
+        let flat = |mean| Baseline {
+            mean,
+            variance: 0.,
+            samples: 5,
+        };
+        assert!(close(flat(100.).stddev(AnomalyMetric::Volume), 10.));
+        assert!(close(flat(2.).stddev(AnomalyMetric::Volume), 1.));
+        assert!(close(flat(20.).stddev(AnomalyMetric::Latency), 5.));
+        assert!(close(flat(0.).stddev(AnomalyMetric::ErrorRate), 0.01));
+        let noisy = Baseline {
+            mean: 100.,
+            variance: 400.,
+            samples: 5,
+        };
+        assert!(close(noisy.stddev(AnomalyMetric::Volume), 20.));
+    }
+
+    #[test]
+    fn clamps_outliers() {
+        let baseline = Baseline {
+            mean: 100.,
+            variance: 100.,
+            samples: 5,
+        };
+        assert!(close(baseline.learned(AnomalyMetric::Volume, 110.), 110.));
+        assert!(close(baseline.learned(AnomalyMetric::Volume, 1000.), 130.));
+        assert!(close(baseline.learned(AnomalyMetric::Volume, 0.), 70.));
+
+        // A spike only nudges the baseline, so the same spike next week is still flagged
+        let next = baseline.update(baseline.learned(AnomalyMetric::Volume, 1000.));
+        assert!(close(next.mean, 109.));
+        let score = (1000. - next.mean) / next.stddev(AnomalyMetric::Volume);
+        assert!(AnomalyMetric::Volume.is_anomalous(score));
+    }
+
+    #[test]
+    fn flags_scores_past_the_threshold() {
+        assert!(AnomalyMetric::Latency.is_anomalous(ANOMALY_SCORE_THRESHOLD));
+        assert!(!AnomalyMetric::Latency.is_anomalous(ANOMALY_SCORE_THRESHOLD - 0.1));
+        assert!(!AnomalyMetric::Latency.is_anomalous(-ANOMALY_SCORE_THRESHOLD));
+        assert!(!AnomalyMetric::ErrorRate.is_anomalous(-10.));
+        assert!(AnomalyMetric::Volume.is_anomalous(-ANOMALY_SCORE_THRESHOLD));
+        assert!(AnomalyMetric::Volume.is_anomalous(10.));
+        assert!(!AnomalyMetric::Volume.is_anomalous(-1.));
+    }
+}
@@ -0,0 +1,193 @@
+use crate::middleware::auth::User;
+use actix_web::HttpRequest;
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub const DEFAULT_AUDIT_LOG_RETENTION_DAYS: i64 = 365;
+
+/// A single administrative action to be appended to the audit log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    pub actor: String,
+    pub action: String,
+    pub target: Option<String>,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+    pub source_ip: Option<String>,
+}
+
+impl AuditEntry {
+    /// Builds an entry for an action performed through the API, taking the actor from the
+    /// authenticated user and the source IP from the request.
+    pub fn from_request(user: &User, req: &HttpRequest, action: &str, target: Option<String>) -> Self {
+        AuditEntry {
+            actor: user.name(),
+            action: action.to_string(),
+            target,
+            before: None,
+            after: None,
+            source_ip: req
+                .connection_info()
+                .realip_remote_addr()
+                .map(|ip| ip.to_string()),
+        }
+    }
+
+    pub fn before(mut self, before: Option<Value>) -> Self {
+        self.before = before;
+        self
+    }
+
+    pub fn after(mut self, after: Option<Value>) -> Self {
+        self.after = after;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditLog {
+    pub id: i64,
+    pub date: DateTime<Utc>,
+    pub actor: String,
+    pub action: String,
+    pub target: Option<String>,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+    pub source_ip: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PaginatedAuditLog {
+    pub entries: Vec<AuditLog>,
+    pub total_items: usize,
+}
+
+/// Appends an entry to the audit log and prunes entries older than the retention period.
+pub fn record_audit(conn: &Connection, entry: &AuditEntry, retention_days: i64) -> Result<()> {
+    conn.execute(
+        "INSERT INTO audit_log (date, actor, action, target, before, after, source_ip)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+            entry.actor,
+            entry.action,
+            entry.target,
+            entry.before.as_ref().map(|v| v.to_string()),
+            entry.after.as_ref().map(|v| v.to_string()),
+            entry.source_ip,
+        ],
+    )?;
+    prune_audit_log(conn, retention_days)?;
+    Ok(())
+}
+
+/// Applies a change and appends its audit entry in one transaction, so that no change is committed
+/// without its entry. `entry` builds the entry from the change's result, `None` recording nothing,
+/// e.g. when the target didn't exist.
+pub fn audited<T>(
+    conn: &Connection,
+    retention_days: i64,
+    change: impl FnOnce(&Connection) -> Result<T>,
+    entry: impl FnOnce(&T) -> Option<AuditEntry>,
+) -> Result<T> {
+    let tx = conn.unchecked_transaction()?;
+    let result = change(&tx)?;
+    if let Some(entry) = entry(&result) {
+        record_audit(&tx, &entry, retention_days)?;
+    }
+    tx.commit()?;
+    Ok(result)
+}
+
+/// Deletes audit entries older than `retention_days`. A non positive retention keeps everything.
+pub fn prune_audit_log(conn: &Connection, retention_days: i64) -> Result<usize> {
+    if retention_days <= 0 {
+        return Ok(0);
+    }
+    let cutoff = Utc::now() - Duration::days(retention_days);
+    let deleted = conn.execute(
+        "DELETE FROM audit_log WHERE date < ?1",
+        params![cutoff.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()],
+    )?;
+    Ok(deleted)
+}
+
+pub fn get_audit_logs(
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    actor: Option<String>,
+    action: Option<String>,
+    target: Option<String>,
+    index: usize,
+    limit: usize,
+    conn: &Connection,
+) -> Result<PaginatedAuditLog> {
+    let mut stmt = conn.prepare(
+        "
+        WITH filtered_audit_log AS (
+            SELECT *
+            FROM audit_log
+            WHERE (date > ?1 OR ?1 IS NULL)
+              AND (date <= ?2 OR ?2 IS NULL)
+              AND (actor = ?3 OR ?3 IS NULL)
+              AND (action = ?4 OR ?4 IS NULL)
+              AND (target LIKE ?5 OR ?5 IS NULL)
+        )
+        SELECT id, date, actor, action, target, before, after, source_ip,
+               (SELECT COUNT(*) FROM filtered_audit_log) AS total_count
+        FROM filtered_audit_log
+        ORDER BY date DESC, id DESC
+        LIMIT ?6 OFFSET ?7;
+        ",
+    )?;
+
+    let rows = stmt.query_map(
+        params![
+            from.map(|d| d.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()),
+            to.map(|d| d.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()),
+            actor,
+            action,
+            target.map(|t| format!("%{}%", t)),
+            limit,
+            index,
+        ],
+        |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, Option<String>>(4)?,
+                row.get::<_, Option<String>>(5)?,
+                row.get::<_, Option<String>>(6)?,
+                row.get::<_, Option<String>>(7)?,
+                row.get::<_, usize>(8)?,
+            ))
+        },
+    )?;
+
+    let mut entries = vec![];
+    let mut total_items = 0;
+    for row in rows {
+        let (id, date, actor, action, target, before, after, source_ip, count) = row?;
+        entries.push(AuditLog {
+            id,
+            date: DateTime::parse_from_rfc3339(&date)?.with_timezone(&Utc),
+            actor,
+            action,
+            target,
+            before: before.map(|b| serde_json::from_str(&b)).transpose()?,
+            after: after.map(|a| serde_json::from_str(&a)).transpose()?,
+            source_ip,
+        });
+        total_items = count;
+    }
+
+    Ok(PaginatedAuditLog { entries, total_items })
+}
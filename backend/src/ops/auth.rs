@@ -1,3 +1,5 @@
+use super::audit::{record_audit, AuditEntry};
+use crate::db::connection::connect_db;
 use chrono::Utc;
 use dotenv::dotenv;
 use serde::{Deserialize, Serialize};
@@ -26,7 +28,7 @@ struct ApiKeyEntry {
     description: String,
 }
 
-pub fn generate_api_key(client_id: String, desc: String) {
+pub fn generate_api_key(client_id: String, desc: String, audit_retention_days: i64) {
     let api_key = Uuid::new_v4().to_string();
 
     dotenv().ok();
@@ -43,9 +45,10 @@ pub fn generate_api_key(client_id: String, desc: String) {
     )
     .expect("Failed to parse API keys file");
 
+    let previous_description = api_keys[client_id.clone()]["description"].clone();
     let entry = ApiKeyEntry {
         hashed_key: encrypted_key.clone(),
-        description: desc,
+        description: desc.clone(),
     };
 
     api_keys[client_id.clone()] =
@@ -58,6 +61,23 @@ pub fn generate_api_key(client_id: String, desc: String) {
         serde_json::to_string_pretty(&api_keys).expect("Failed to serialize API keys"),
     )
     .expect("Failed to write API keys file");
+
+    // Key generation happens outside the server, so the audit entry is written straight to the db
+    if let Ok(db_path) = env::var("DB_PATH") {
+        let conn = connect_db(&db_path);
+        let audit_entry = AuditEntry {
+            actor: "cli".to_string(),
+            action: "api_key.generate".to_string(),
+            target: Some(client_id),
+            before: (!previous_description.is_null())
+                .then(|| serde_json::json!({ "description": previous_description })),
+            after: Some(serde_json::json!({ "description": desc })),
+            source_ip: None,
+        };
+        if let Err(e) = record_audit(&conn, &audit_entry, audit_retention_days) {
+            eprintln!("Failed to record audit log: {}", e);
+        }
+    }
 }
 
 pub fn verify_encrypted_key_for_client(api_key: &str, client: &str) -> bool {
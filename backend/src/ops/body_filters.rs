@@ -0,0 +1,244 @@
+use super::json_path::{sqlite_path, JsonPath, JsonPathSegment};
+use anyhow::{anyhow, Result};
+use rusqlite::types::Value as SqlValue;
+use serde_json::Value;
+use std::{str::FromStr, time::Duration};
+use strum::{Display, EnumString};
+
+/// Body filters allowed in a single query
+pub const MAX_BODY_FILTERS: usize = 5;
+/// Segments allowed in a body filter path
+pub const MAX_BODY_FILTER_DEPTH: usize = 8;
+/// Longest value a body filter can compare against
+pub const MAX_BODY_FILTER_VALUE_LEN: usize = 256;
+/// How long a query with body filters may run before it is interrupted
+pub const BODY_FILTER_TIME_BUDGET: Duration = Duration::from_secs(5);
+
+#[derive(Display, EnumString, Debug, Clone, Copy, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum BodyColumn {
+    ReqBody,
+    ResBody,
+}
+
+#[derive(Display, EnumString, Debug, Clone, Copy, PartialEq)]
+pub enum BodyOperator {
+    #[strum(serialize = "=")]
+    Eq,
+    #[strum(serialize = "!=")]
+    Ne,
+    #[strum(serialize = ">")]
+    Gt,
+    #[strum(serialize = ">=")]
+    Ge,
+    #[strum(serialize = "<")]
+    Lt,
+    #[strum(serialize = "<=")]
+    Le,
+    #[strum(serialize = "contains")]
+    Contains,
+    #[strum(serialize = "exists")]
+    Exists,
+    #[strum(serialize = "!exists")]
+    NotExists,
+}
+
+/// Filter over a JSON field of the request or response body, written as
+/// `<req_body|res_body>.<path> <operator> [value]`, e.g. `res_body.$.error.code = "CARD_DECLINED"`
+/// or `req_body.$.items[*].sku contains ABC-1`.
+///
+/// Paths can contain at most one `[*]`, which matches when any element satisfies the condition.
+/// Values are JSON literals, anything that isn't valid JSON is compared as a string.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BodyFilter {
+    pub column: BodyColumn,
+    pub path: String,
+    /// Path applied to each element when the filter has a `[*]`
+    pub element_path: Option<String>,
+    pub operator: BodyOperator,
+    pub value: Value,
+}
+
+impl FromStr for BodyFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let (column, rest) = s.split_once('.').ok_or(anyhow!(
+            "Body filter '{}' must start with 'req_body.' or 'res_body.'",
+            s
+        ))?;
+        let column = BodyColumn::from_str(column)
+            .map_err(|_| anyhow!("Body filter '{}' must start with 'req_body.' or 'res_body.'", s))?;
+
+        let (path, rest) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let rest = rest.trim_start();
+        let (operator, value) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let operator = BodyOperator::from_str(operator)
+            .map_err(|_| anyhow!("Unknown operator '{}' in body filter '{}'", operator, s))?;
+        let value = value.trim();
+
+        let json_path = JsonPath::from_str(path)?;
+        if json_path.0.len() > MAX_BODY_FILTER_DEPTH {
+            return Err(anyhow!(
+                "Body filter paths can be at most {} deep",
+                MAX_BODY_FILTER_DEPTH
+            ));
+        }
+        let wildcards = json_path
+            .0
+            .iter()
+            .filter(|s| **s == JsonPathSegment::Wildcard)
+            .count();
+        let (path, element_path) = match wildcards {
+            0 => (sqlite_path(&json_path.0)?, None),
+            1 => {
+                let at = json_path
+                    .0
+                    .iter()
+                    .position(|s| *s == JsonPathSegment::Wildcard)
+                    .unwrap_or_default();
+                (
+                    sqlite_path(&json_path.0[..at])?,
+                    Some(sqlite_path(&json_path.0[at + 1..])?),
+                )
+            }
+            _ => return Err(anyhow!("Body filter paths can contain at most one '[*]'")),
+        };
+
+        let value = match operator {
+            BodyOperator::Exists | BodyOperator::NotExists if !value.is_empty() => {
+                return Err(anyhow!("'{}' doesn't take a value", operator))
+            }
+            BodyOperator::Exists | BodyOperator::NotExists => Value::Null,
+            _ if value.is_empty() => return Err(anyhow!("Body filter '{}' is missing a value", s)),
+            _ if value.len() > MAX_BODY_FILTER_VALUE_LEN => {
+                return Err(anyhow!(
+                    "Body filter values can be at most {} characters",
+                    MAX_BODY_FILTER_VALUE_LEN
+                ))
+            }
+            _ => serde_json::from_str(value).unwrap_or(Value::String(value.to_string())),
+        };
+        if operator == BodyOperator::Contains && (value.is_object() || value.is_array()) {
+            return Err(anyhow!("'contains' takes a string, number or boolean"));
+        }
+
+        Ok(BodyFilter {
+            column,
+            path,
+            element_path,
+            operator,
+            value,
+        })
+    }
+}
+
+/// SQL expressions for the JSON value a filter looks at, each with its parameters
+struct Target {
+    value: (String, Vec<SqlValue>),
+    json_type: (String, Vec<SqlValue>),
+    each: (String, Vec<SqlValue>),
+}
+
+fn sql_value(value: &Value) -> SqlValue {
+    match value {
+        Value::String(s) => SqlValue::Text(s.clone()),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => SqlValue::Integer(i),
+            None => SqlValue::Real(n.as_f64().unwrap_or_default()),
+        },
+        Value::Bool(b) => SqlValue::Integer(*b as i64),
+        Value::Null => SqlValue::Null,
+        other => SqlValue::Text(other.to_string()),
+    }
+}
+
+impl BodyFilter {
+    fn condition(&self, target: Target) -> (String, Vec<SqlValue>) {
+        let Target {
+            value: (value, value_params),
+            json_type: (json_type, type_params),
+            each: (each, each_params),
+        } = target;
+        match self.operator {
+            BodyOperator::Exists => (format!("{} IS NOT NULL", json_type), type_params),
+            BodyOperator::NotExists => (format!("{} IS NULL", json_type), type_params),
+            BodyOperator::Eq if self.value.is_null() => (format!("{} = 'null'", json_type), type_params),
+            BodyOperator::Ne if self.value.is_null() => {
+                (format!("{} <> 'null'", json_type), type_params)
+            }
+            BodyOperator::Contains => {
+                let text = match &self.value {
+                    Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                };
+                let mut params = type_params;
+                params.extend(each_params);
+                params.push(sql_value(&self.value));
+                params.extend(value_params);
+                params.push(SqlValue::Text(text));
+                (
+                    format!(
+                        "CASE {} WHEN 'array' THEN EXISTS (SELECT 1 FROM {} c WHERE c.value = ?) \
+                        WHEN 'text' THEN instr({}, ?) > 0 ELSE 0 END",
+                        json_type, each, value
+                    ),
+                    params,
+                )
+            }
+            operator => {
+                let mut params = value_params;
+                params.push(sql_value(&self.value));
+                (format!("{} {} ?", value, operator), params)
+            }
+        }
+    }
+
+    /// SQL condition over the `requests` table and its parameters
+    pub fn to_sql(&self) -> (String, Vec<SqlValue>) {
+        let column = format!("requests.{}", self.column);
+        let path = SqlValue::Text(self.path.clone());
+        match &self.element_path {
+            None => self.condition(Target {
+                value: (format!("json_extract({}, ?)", column), vec![path.clone()]),
+                json_type: (format!("json_type({}, ?)", column), vec![path.clone()]),
+                each: (format!("json_each({}, ?)", column), vec![path]),
+            }),
+            Some(element_path) if element_path == "$" => {
+                let (condition, params) = self.condition(Target {
+                    value: ("w.value".to_string(), vec![]),
+                    json_type: ("w.type".to_string(), vec![]),
+                    each: ("json_each(w.value)".to_string(), vec![]),
+                });
+                let mut all_params = vec![path];
+                all_params.extend(params);
+                (
+                    format!(
+                        "EXISTS (SELECT 1 FROM json_each({}, ?) w WHERE {})",
+                        column, condition
+                    ),
+                    all_params,
+                )
+            }
+            Some(element_path) => {
+                let element_path = SqlValue::Text(element_path.clone());
+                let (condition, params) = self.condition(Target {
+                    value: ("json_extract(w.value, ?)".to_string(), vec![element_path.clone()]),
+                    json_type: ("json_type(w.value, ?)".to_string(), vec![element_path.clone()]),
+                    each: ("json_each(w.value, ?)".to_string(), vec![element_path]),
+                });
+                let mut all_params = vec![path];
+                all_params.extend(params);
+                (
+                    format!(
+                        "EXISTS (SELECT 1 FROM json_each({}, ?) w \
+                        WHERE w.type IN ('object', 'array') AND {})",
+                        column, condition
+                    ),
+                    all_params,
+                )
+            }
+        }
+    }
+}
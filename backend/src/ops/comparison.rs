@@ -0,0 +1,219 @@
+use super::{
+    endpoints::{endpoint_metrics, EndpointMetrics},
+    filters::RequestFilter,
+    requests::{window_slis, ServiceLevelIndicators},
+    stats::TimeWindow,
+};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+/// Requests an endpoint needs in both ranges to be listed as a mover
+pub const MIN_MOVER_REQUESTS: usize = 10;
+/// Movers listed each way when no limit is given
+pub const DEFAULT_MOVERS_LIMIT: usize = 5;
+pub const MAX_MOVERS_LIMIT: usize = 50;
+
+/// Range a time range is compared against
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ComparisonBaseline {
+    /// The range of equal length right before
+    #[default]
+    Previous,
+    /// The same range a week earlier
+    LastWeek,
+    /// A range given with `baselineFrom` and `baselineTo`
+    Custom,
+}
+
+impl ComparisonBaseline {
+    pub fn window(
+        &self,
+        current: &TimeWindow,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<TimeWindow> {
+        match self {
+            ComparisonBaseline::Previous => Ok(current.previous()),
+            ComparisonBaseline::LastWeek => Ok(current.shifted(-Duration::weeks(1))),
+            ComparisonBaseline::Custom => match (from, to) {
+                (Some(from), Some(to)) if from < to => Ok(TimeWindow { from, to }),
+                (Some(_), Some(_)) => Err(anyhow!("baselineTo must be after baselineFrom")),
+                _ => Err(anyhow!("A custom baseline needs baselineFrom and baselineTo")),
+            },
+        }
+    }
+}
+
+/// Change from the baseline to the current value, `relative` being `None` when the baseline is 0
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Delta {
+    pub absolute: f64,
+    pub relative: Option<f64>,
+}
+
+impl Delta {
+    pub fn new(current: f64, baseline: f64) -> Delta {
+        Delta {
+            absolute: current - baseline,
+            relative: (baseline != 0.).then(|| (current - baseline) / baseline),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SliDeltas {
+    pub request_count: Delta,
+    pub error_rate: Delta,
+    pub average_latency: Delta,
+    pub p95_latency: Delta,
+    pub throughput: Delta,
+}
+
+/// Metric endpoints are ranked on as movers
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum MoverMetric {
+    #[default]
+    P95Latency,
+    AvgLatency,
+    ErrorRate,
+    /// Drops in traffic count as regressions
+    RequestCount,
+}
+
+impl MoverMetric {
+    fn value(&self, metrics: &EndpointMetrics) -> f64 {
+        match self {
+            MoverMetric::P95Latency => metrics.p95_latency as f64,
+            MoverMetric::AvgLatency => metrics.avg_latency,
+            MoverMetric::ErrorRate => metrics.error_rate,
+            MoverMetric::RequestCount => metrics.request_count as f64,
+        }
+    }
+
+    fn is_regression(&self, delta: &Delta) -> bool {
+        match self {
+            MoverMetric::RequestCount => delta.absolute < 0.,
+            _ => delta.absolute > 0.,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Mover {
+    pub endpoint: String,
+    pub method: String,
+    pub current: EndpointMetrics,
+    pub baseline: EndpointMetrics,
+    pub delta: Delta,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Movers {
+    pub metric: MoverMetric,
+    /// Endpoints that got worse the most, first
+    pub regressions: Vec<Mover>,
+    /// Endpoints that got better the most, first
+    pub improvements: Vec<Mover>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Comparison {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub baseline_from: DateTime<Utc>,
+    pub baseline_to: DateTime<Utc>,
+    pub current: ServiceLevelIndicators,
+    pub baseline: ServiceLevelIndicators,
+    pub deltas: SliDeltas,
+    pub movers: Movers,
+}
+
+/// Endpoints whose `metric` changed the most between the two ranges, ranked by absolute change
+pub fn endpoint_movers(
+    current: &TimeWindow,
+    baseline: &TimeWindow,
+    filter: &RequestFilter,
+    metric: MoverMetric,
+    limit: usize,
+    conn: &Connection,
+) -> Result<Movers> {
+    let mut baseline_metrics = endpoint_metrics(baseline, filter, conn)?;
+    let mut movers = endpoint_metrics(current, filter, conn)?
+        .into_iter()
+        .filter_map(|((endpoint, method), current)| {
+            let baseline = baseline_metrics.remove(&(endpoint.clone(), method.clone()))?;
+            if current.request_count < MIN_MOVER_REQUESTS || baseline.request_count < MIN_MOVER_REQUESTS
+            {
+                return None;
+            }
+            let delta = Delta::new(metric.value(&current), metric.value(&baseline));
+            Some(Mover {
+                endpoint,
+                method,
+                current,
+                baseline,
+                delta,
+            })
+        })
+        .filter(|mover| mover.delta.absolute != 0.)
+        .collect::<Vec<Mover>>();
+    movers.sort_by(|a, b| {
+        b.delta
+            .absolute
+            .abs()
+            .total_cmp(&a.delta.absolute.abs())
+            .then_with(|| a.endpoint.cmp(&b.endpoint))
+            .then_with(|| a.method.cmp(&b.method))
+    });
+    let (mut regressions, mut improvements): (Vec<Mover>, Vec<Mover>) = movers
+        .into_iter()
+        .partition(|mover| metric.is_regression(&mover.delta));
+    regressions.truncate(limit);
+    improvements.truncate(limit);
+    Ok(Movers {
+        metric,
+        regressions,
+        improvements,
+    })
+}
+
+/// Compares the indicators of `current` with those of `baseline`, along with the endpoints that moved the most
+pub fn compare_windows(
+    current: &TimeWindow,
+    baseline: &TimeWindow,
+    filter: &RequestFilter,
+    mover_metric: MoverMetric,
+    movers_limit: usize,
+    conn: &Connection,
+) -> Result<Comparison> {
+    let current_slis = window_slis(current, filter, conn)?;
+    let baseline_slis = window_slis(baseline, filter, conn)?;
+    let deltas = SliDeltas {
+        request_count: Delta::new(
+            current_slis.request_count as f64,
+            baseline_slis.request_count as f64,
+        ),
+        error_rate: Delta::new(current_slis.error_rate, baseline_slis.error_rate),
+        average_latency: Delta::new(current_slis.average_latency, baseline_slis.average_latency),
+        p95_latency: Delta::new(current_slis.p95_latency as f64, baseline_slis.p95_latency as f64),
+        throughput: Delta::new(current_slis.throughput, baseline_slis.throughput),
+    };
+    Ok(Comparison {
+        from: current.from,
+        to: current.to,
+        baseline_from: baseline.from,
+        baseline_to: baseline.to,
+        current: current_slis,
+        baseline: baseline_slis,
+        deltas,
+        movers: endpoint_movers(current, baseline, filter, mover_metric, movers_limit, conn)?,
+    })
+}
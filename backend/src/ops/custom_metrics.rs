@@ -0,0 +1,865 @@
+//! Custom application metrics sent as JSON or with Prometheus remote-write. Each name and label set
+//! is a series, whose samples are stored as they come and aggregated into time buckets on query.
+
+use super::{filters::text, series::Series};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+use regex::Regex;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    sync::LazyLock,
+};
+
+/// Most labels a sample can have
+pub const MAX_METRIC_LABELS: usize = 16;
+/// Most samples a single ingestion request can hold
+pub const MAX_INGESTED_SAMPLES: usize = 50_000;
+pub const DEFAULT_METRIC_RETENTION_DAYS: i64 = 30;
+
+static METRIC_NAME: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[a-zA-Z_:][a-zA-Z0-9_:]*$").unwrap());
+static LABEL_NAME: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[a-zA-Z_][a-zA-Z0-9_]*$").unwrap());
+
+pub type Labels = BTreeMap<String, String>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricKind {
+    /// Cumulative value that only goes up, until the process reporting it restarts
+    Counter,
+    Gauge,
+    /// Cumulative observation counts by upper bound, with their sum
+    Histogram,
+}
+
+impl MetricKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => "histogram",
+        }
+    }
+}
+
+impl FromStr for MetricKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "counter" => Ok(MetricKind::Counter),
+            "gauge" => Ok(MetricKind::Gauge),
+            "histogram" => Ok(MetricKind::Histogram),
+            _ => Err(anyhow!("Unknown metric type '{}'", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct HistogramValue {
+    /// Cumulative counts by upper bound, sorted, the last bound being `+Inf`
+    pub buckets: Vec<(f64, f64)>,
+    pub sum: f64,
+    pub count: f64,
+}
+
+impl HistogramValue {
+    fn buckets_json(&self) -> String {
+        let buckets = self
+            .buckets
+            .iter()
+            .map(|(le, count)| (format_bound(*le), *count))
+            .collect::<BTreeMap<String, f64>>();
+        serde_json::to_string(&buckets).unwrap_or_default()
+    }
+
+    fn from_json(buckets: &str, sum: f64, count: f64) -> Result<HistogramValue> {
+        let buckets = serde_json::from_str::<HashMap<String, f64>>(buckets)?
+            .into_iter()
+            .map(|(le, count)| Ok((le.parse::<f64>()?, count)))
+            .collect::<Result<Vec<(f64, f64)>>>()?;
+        Ok(HistogramValue::new(buckets, sum, count))
+    }
+
+    pub fn new(mut buckets: Vec<(f64, f64)>, sum: f64, count: f64) -> HistogramValue {
+        buckets.sort_by(|a, b| a.0.total_cmp(&b.0));
+        if buckets.last().is_none_or(|(le, _)| *le != f64::INFINITY) {
+            buckets.push((f64::INFINITY, count));
+        }
+        HistogramValue { buckets, sum, count }
+    }
+
+    /// Observations between the `previous` sample of the series and this one
+    fn since(&self, previous: &HistogramValue) -> HistogramValue {
+        // A lower count means the process restarted and counted from 0 again
+        if self.count < previous.count {
+            return self.clone();
+        }
+        let buckets = self
+            .buckets
+            .iter()
+            .map(|(le, count)| {
+                let before = previous
+                    .buckets
+                    .iter()
+                    .find(|(previous_le, _)| previous_le == le)
+                    .map(|(_, count)| *count)
+                    .unwrap_or_default();
+                (*le, (count - before).max(0.))
+            })
+            .collect();
+        HistogramValue {
+            buckets,
+            sum: self.sum - previous.sum,
+            count: self.count - previous.count,
+        }
+    }
+
+    fn add(&mut self, other: &HistogramValue) {
+        for (le, count) in &other.buckets {
+            match self.buckets.iter_mut().find(|(bound, _)| bound == le) {
+                Some((_, total)) => *total += count,
+                None => self.buckets.push((*le, *count)),
+            }
+        }
+        self.buckets.sort_by(|a, b| a.0.total_cmp(&b.0));
+        self.sum += other.sum;
+        self.count += other.count;
+    }
+
+    /// Estimates the `q` quantile by interpolating linearly inside the bucket it falls in
+    fn quantile(&self, q: f64) -> Option<f64> {
+        let total = self.buckets.last()?.1;
+        if total <= 0. {
+            return None;
+        }
+        let rank = q * total;
+        let mut lower = (0., 0.);
+        for (le, count) in &self.buckets {
+            if *count >= rank {
+                // The `+Inf` bucket has no upper bound to interpolate towards
+                if le.is_infinite() {
+                    return Some(lower.0);
+                }
+                let width = count - lower.1;
+                let position = if width > 0. { (rank - lower.1) / width } else { 1. };
+                return Some(lower.0 + (le - lower.0) * position);
+            }
+            lower = (*le, *count);
+        }
+        None
+    }
+}
+
+fn format_bound(le: f64) -> String {
+    match le.is_infinite() {
+        true => "+Inf".to_string(),
+        false => le.to_string(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetricValue {
+    Number(f64),
+    Histogram(HistogramValue),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricSample {
+    pub name: String,
+    pub kind: MetricKind,
+    pub labels: Labels,
+    pub date: DateTime<Utc>,
+    pub value: MetricValue,
+}
+
+impl MetricSample {
+    fn validate(&self) -> Result<()> {
+        if !METRIC_NAME.is_match(&self.name) {
+            return Err(anyhow!("Invalid metric name '{}'", self.name));
+        }
+        if self.labels.len() > MAX_METRIC_LABELS {
+            return Err(anyhow!(
+                "'{}' has more than {} labels",
+                self.name,
+                MAX_METRIC_LABELS
+            ));
+        }
+        if let Some(label) = self
+            .labels
+            .keys()
+            .find(|label| !LABEL_NAME.is_match(label) || label.starts_with("__"))
+        {
+            return Err(anyhow!("Invalid label name '{}'", label));
+        }
+        let finite = match &self.value {
+            MetricValue::Number(value) => value.is_finite(),
+            MetricValue::Histogram(histogram) => {
+                histogram.sum.is_finite() && histogram.buckets.iter().all(|(_, count)| count.is_finite())
+            }
+        };
+        match finite {
+            true => Ok(()),
+            false => Err(anyhow!("'{}' has a value that isn't a finite number", self.name)),
+        }
+    }
+}
+
+/// A sample sent to `/metrics/ingest`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricSampleRequest {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: MetricKind,
+    #[serde(default)]
+    pub labels: Labels,
+    /// Value of counters and gauges
+    pub value: Option<f64>,
+    /// Cumulative count of histogram observations by upper bound, e.g. `{"0.1": 3, "+Inf": 10}`
+    pub buckets: Option<HashMap<String, f64>>,
+    pub sum: Option<f64>,
+    /// Number of histogram observations, the `+Inf` bucket when not given
+    pub count: Option<f64>,
+    /// Now when not given
+    pub date: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<MetricSampleRequest> for MetricSample {
+    type Error = anyhow::Error;
+
+    fn try_from(sample: MetricSampleRequest) -> Result<Self> {
+        let value = match sample.kind {
+            MetricKind::Histogram => {
+                let buckets = sample
+                    .buckets
+                    .ok_or(anyhow!("Histogram '{}' has no buckets", sample.name))?
+                    .into_iter()
+                    .map(|(le, count)| match le.parse::<f64>() {
+                        Ok(le) => Ok((le, count)),
+                        Err(_) => Err(anyhow!("Invalid bucket bound '{}'", le)),
+                    })
+                    .collect::<Result<Vec<(f64, f64)>>>()?;
+                let count = match sample.count {
+                    Some(count) => count,
+                    None => buckets
+                        .iter()
+                        .find(|(le, _)| le.is_infinite())
+                        .map(|(_, count)| *count)
+                        .ok_or(anyhow!(
+                            "Histogram '{}' needs a count or a +Inf bucket",
+                            sample.name
+                        ))?,
+                };
+                MetricValue::Histogram(HistogramValue::new(
+                    buckets,
+                    sample.sum.unwrap_or_default(),
+                    count,
+                ))
+            }
+            _ => MetricValue::Number(sample.value.ok_or(anyhow!("'{}' has no value", sample.name))?),
+        };
+        Ok(MetricSample {
+            name: sample.name,
+            kind: sample.kind,
+            labels: sample.labels,
+            date: sample.date.unwrap_or(Utc::now()),
+            value,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct IngestResult {
+    pub accepted: usize,
+    /// Why samples were rejected, at most one message per metric
+    pub rejected: Vec<String>,
+}
+
+fn format_date(date: DateTime<Utc>) -> String {
+    date.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+/// Id of the series of a sample, created on its first sample. Every series of a metric has the
+/// kind of the first one.
+fn series_id(sample: &MetricSample, conn: &Connection) -> Result<i64> {
+    let kind = conn
+        .query_row(
+            "SELECT kind FROM metric_series WHERE name = ? LIMIT 1",
+            [&sample.name],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()?;
+    if let Some(kind) = kind.filter(|kind| kind != sample.kind.as_str()) {
+        return Err(anyhow!(
+            "'{}' is a {}, not a {}",
+            sample.name,
+            kind,
+            sample.kind.as_str()
+        ));
+    }
+    let labels = serde_json::to_string(&sample.labels)?;
+    conn.execute(
+        "INSERT INTO metric_series(name, kind, labels) VALUES (?1, ?2, ?3)
+        ON CONFLICT(name, labels) DO NOTHING",
+        params![sample.name, sample.kind.as_str(), labels],
+    )?;
+    Ok(conn.query_row(
+        "SELECT id FROM metric_series WHERE name = ?1 AND labels = ?2",
+        params![sample.name, labels],
+        |row| row.get(0),
+    )?)
+}
+
+/// Stores the valid samples, a sample replacing the one of its series at the same date, then
+/// prunes the samples older than the retention period
+pub fn insert_metric_samples(
+    samples: Vec<MetricSample>,
+    retention_days: i64,
+    conn: &Connection,
+) -> Result<IngestResult> {
+    let mut result = IngestResult::default();
+    let mut reject = |e: anyhow::Error| {
+        let msg = e.to_string();
+        if !result.rejected.contains(&msg) {
+            result.rejected.push(msg);
+        }
+    };
+    let mut accepted = 0;
+    let tx = conn.unchecked_transaction()?;
+    for sample in samples {
+        if let Err(e) = sample.validate() {
+            reject(e);
+            continue;
+        }
+        let id = match series_id(&sample, &tx) {
+            Ok(id) => id,
+            Err(e) => {
+                reject(e);
+                continue;
+            }
+        };
+        let (value, count, buckets) = match &sample.value {
+            MetricValue::Number(value) => (*value, None, None),
+            MetricValue::Histogram(histogram) => (
+                histogram.sum,
+                Some(histogram.count),
+                Some(histogram.buckets_json()),
+            ),
+        };
+        tx.execute(
+            "INSERT OR REPLACE INTO metric_samples(series_id, date, value, count, buckets)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![id, format_date(sample.date), value, count, buckets],
+        )?;
+        accepted += 1;
+    }
+    tx.commit()?;
+    prune_metric_samples(conn, retention_days)?;
+    result.accepted = accepted;
+    Ok(result)
+}
+
+/// Deletes samples older than `retention_days`, and the series left without samples. A non
+/// positive retention keeps everything.
+pub fn prune_metric_samples(conn: &Connection, retention_days: i64) -> Result<usize> {
+    if retention_days <= 0 {
+        return Ok(0);
+    }
+    let cutoff = Utc::now() - Duration::days(retention_days);
+    let deleted = conn.execute(
+        "DELETE FROM metric_samples WHERE date < ?1",
+        params![format_date(cutoff)],
+    )?;
+    if deleted > 0 {
+        conn.execute(
+            "DELETE FROM metric_series WHERE id NOT IN (SELECT series_id FROM metric_samples)",
+            [],
+        )?;
+    }
+    Ok(deleted)
+}
+
+/// Kind of a metric, `None` when nothing was ingested for it
+pub fn metric_kind(name: &str, conn: &Connection) -> Result<Option<MetricKind>> {
+    conn.query_row(
+        "SELECT kind FROM metric_series WHERE name = ? LIMIT 1",
+        [name],
+        |row| row.get::<_, String>(0),
+    )
+    .optional()?
+    .map(|kind| MetricKind::from_str(&kind))
+    .transpose()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricAggregation {
+    /// Mean of the samples, or of the observations of histograms
+    Avg,
+    Min,
+    Max,
+    /// Sum of the last sample of each series
+    Last,
+    /// Counter increase, or histogram observations, per second
+    Rate,
+    Increase,
+    P50,
+    P90,
+    P95,
+    P99,
+}
+
+impl MetricAggregation {
+    pub fn default_for(kind: MetricKind) -> MetricAggregation {
+        match kind {
+            MetricKind::Counter => MetricAggregation::Rate,
+            MetricKind::Gauge => MetricAggregation::Avg,
+            MetricKind::Histogram => MetricAggregation::P95,
+        }
+    }
+
+    fn quantile(&self) -> Option<f64> {
+        match self {
+            MetricAggregation::P50 => Some(0.5),
+            MetricAggregation::P90 => Some(0.9),
+            MetricAggregation::P95 => Some(0.95),
+            MetricAggregation::P99 => Some(0.99),
+            _ => None,
+        }
+    }
+
+    /// Whether the aggregation makes sense for metrics of `kind`
+    pub fn supports(&self, kind: MetricKind) -> bool {
+        match self {
+            MetricAggregation::Avg => true,
+            MetricAggregation::Min | MetricAggregation::Max | MetricAggregation::Last => {
+                kind != MetricKind::Histogram
+            }
+            MetricAggregation::Rate | MetricAggregation::Increase => kind != MetricKind::Gauge,
+            _ => kind == MetricKind::Histogram,
+        }
+    }
+
+    /// Whether values are computed from the difference between consecutive samples
+    fn uses_deltas(&self, kind: MetricKind) -> bool {
+        kind == MetricKind::Histogram
+            || matches!(self, MetricAggregation::Rate | MetricAggregation::Increase)
+    }
+}
+
+/// Condition on a label, `*` matching anything
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelMatcher {
+    pub name: String,
+    pub pattern: String,
+}
+
+impl FromStr for LabelMatcher {
+    type Err = anyhow::Error;
+
+    /// Parses `name:pattern`
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, pattern) = s
+            .split_once(':')
+            .ok_or(anyhow!("Invalid label filter '{}', expected name:value", s))?;
+        validate_label_name(name)?;
+        Ok(LabelMatcher {
+            name: name.to_string(),
+            pattern: pattern.to_string(),
+        })
+    }
+}
+
+/// Checks that a label named in a query is a valid label name
+pub fn validate_label_name(name: &str) -> Result<()> {
+    if !LABEL_NAME.is_match(name) {
+        return Err(anyhow!("Invalid label name '{}'", name));
+    }
+    Ok(())
+}
+
+fn label_path(label: &str) -> String {
+    format!("$.\"{}\"", label)
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricSeriesData {
+    pub name: String,
+    pub kind: MetricKind,
+    pub aggregation: MetricAggregation,
+    pub group_by: Option<String>,
+    /// Start of each bucket, in the requested timezone
+    pub buckets: Vec<DateTime<Tz>>,
+    /// Largest groups first, then the other series if there is one
+    pub series: Vec<Series>,
+}
+
+/// What a group gathered in one bucket
+#[derive(Debug, Clone, Default)]
+struct Accumulator {
+    samples: usize,
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+    /// Last value of each series
+    last: HashMap<i64, f64>,
+    increase: f64,
+    histogram: HistogramValue,
+}
+
+impl Accumulator {
+    fn value(&self, aggregation: MetricAggregation, kind: MetricKind, seconds: f64) -> Option<f64> {
+        match aggregation {
+            _ if kind == MetricKind::Histogram => match aggregation.quantile() {
+                Some(q) => self.histogram.quantile(q),
+                None if self.histogram.count <= 0. => None,
+                None => match aggregation {
+                    MetricAggregation::Avg => Some(self.histogram.sum / self.histogram.count),
+                    MetricAggregation::Rate => Some(self.histogram.count / seconds),
+                    _ => Some(self.histogram.count),
+                },
+            },
+            MetricAggregation::Rate => Some(self.increase / seconds),
+            MetricAggregation::Increase => Some(self.increase),
+            _ if self.samples == 0 => None,
+            MetricAggregation::Avg => Some(self.sum / self.samples as f64),
+            MetricAggregation::Min => self.min,
+            MetricAggregation::Max => self.max,
+            _ => Some(self.last.values().sum()),
+        }
+    }
+}
+
+struct StoredSample {
+    series_id: i64,
+    date: DateTime<Utc>,
+    value: MetricValue,
+}
+
+fn stored_sample(row: &rusqlite::Row) -> Result<StoredSample> {
+    let value = row.get::<_, f64>(2)?;
+    let value = match row.get::<_, Option<String>>(4)? {
+        Some(buckets) => MetricValue::Histogram(HistogramValue::from_json(
+            &buckets,
+            value,
+            row.get::<_, Option<f64>>(3)?.unwrap_or_default(),
+        )?),
+        None => MetricValue::Number(value),
+    };
+    Ok(StoredSample {
+        series_id: row.get(0)?,
+        date: DateTime::parse_from_rfc3339(&row.get::<_, String>(1)?)?.with_timezone(&Utc),
+        value,
+    })
+}
+
+/// `aggregation` of the metric `name` in each bucket, for the series whose labels match
+/// `matchers`, one series per value of the `group_by` label. Only the `top_n` groups with the
+/// most samples get their own series, the rest being gathered in an other series.
+pub fn metric_series(
+    name: &str,
+    kind: MetricKind,
+    matchers: &[LabelMatcher],
+    group_by: Option<&str>,
+    aggregation: MetricAggregation,
+    top_n: usize,
+    buckets: &[(DateTime<Tz>, DateTime<Tz>)],
+    conn: &Connection,
+) -> Result<MetricSeriesData> {
+    let mut data = MetricSeriesData {
+        name: name.to_string(),
+        kind,
+        aggregation,
+        group_by: group_by.map(|g| g.to_string()),
+        buckets: buckets.iter().map(|(start, _)| *start).collect(),
+        series: vec![],
+    };
+    let (Some((from, _)), Some((_, to))) = (buckets.first(), buckets.last()) else {
+        return Ok(data);
+    };
+    let (from, to) = (from.with_timezone(&Utc), to.with_timezone(&Utc));
+
+    let mut series_sql = "SELECT id FROM metric_series WHERE name = ?".to_string();
+    let mut series_params = vec![text(name.to_string())];
+    for matcher in matchers {
+        series_sql.push_str(" AND COALESCE(json_extract(labels, ?), '') GLOB ?");
+        series_params.push(text(label_path(&matcher.name)));
+        series_params.push(text(matcher.pattern.clone()));
+    }
+
+    // Group of each matching series
+    let mut stmt = conn.prepare(&format!(
+        "SELECT id, COALESCE(json_extract(labels, ?), '') FROM metric_series WHERE id IN ({})",
+        series_sql
+    ))?;
+    let mut params = vec![text(label_path(group_by.unwrap_or_default()))];
+    params.extend(series_params.iter().cloned());
+    let groups = stmt
+        .query_map(params_from_iter(params), |row| {
+            let group = match group_by {
+                Some(_) => row.get::<_, String>(1)?,
+                None => "all".to_string(),
+            };
+            Ok((row.get::<_, i64>(0)?, group))
+        })?
+        .collect::<Result<HashMap<i64, String>, _>>()?;
+
+    // Samples in range, preceded by the last one before it for the series computed from deltas
+    let uses_deltas = aggregation.uses_deltas(kind);
+    let mut params = vec![text(format_date(from)), text(format_date(to))];
+    params.extend(series_params.iter().cloned());
+    let previous = match uses_deltas {
+        true => {
+            params.push(text(format_date(from)));
+            params.extend(series_params.iter().cloned());
+            format!(
+                "UNION ALL
+                SELECT series_id, MAX(date), value, count, buckets FROM metric_samples
+                WHERE date < ? AND series_id IN ({})
+                GROUP BY series_id",
+                series_sql
+            )
+        }
+        false => String::new(),
+    };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT * FROM (
+            SELECT series_id, date, value, count, buckets FROM metric_samples
+            WHERE date >= ? AND date < ? AND series_id IN ({})
+            {}
+        )
+        ORDER BY series_id, date",
+        series_sql, previous
+    ))?;
+    let mut rows = stmt.query(params_from_iter(params))?;
+    let mut samples = vec![];
+    while let Some(row) = rows.next()? {
+        samples.push(stored_sample(row)?);
+    }
+
+    // Largest groups by samples in range
+    let mut sizes = HashMap::<&str, usize>::new();
+    for sample in samples.iter().filter(|sample| sample.date >= from) {
+        if let Some(group) = groups.get(&sample.series_id) {
+            *sizes.entry(group.as_str()).or_default() += 1;
+        }
+    }
+    let mut ranked = sizes.into_iter().collect::<Vec<(&str, usize)>>();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    let has_other = ranked.len() > top_n;
+    let position = ranked
+        .iter()
+        .take(top_n)
+        .enumerate()
+        .map(|(i, (group, _))| (*group, i))
+        .collect::<HashMap<&str, usize>>();
+    let series_count = position.len() + has_other as usize;
+
+    let mut accumulators = vec![vec![Accumulator::default(); buckets.len()]; series_count];
+    let mut previous: Option<&StoredSample> = None;
+    for sample in &samples {
+        let last = previous
+            .replace(sample)
+            .filter(|last| last.series_id == sample.series_id);
+        let Some(group) = groups.get(&sample.series_id) else {
+            continue;
+        };
+        let index = position.get(group.as_str()).copied().unwrap_or(top_n);
+        let bucket = buckets.partition_point(|(_, end)| end.with_timezone(&Utc) <= sample.date);
+        if sample.date < from || bucket >= buckets.len() || index >= series_count {
+            continue;
+        }
+        let accumulator = &mut accumulators[index][bucket];
+        match (&sample.value, last.map(|last| &last.value)) {
+            (MetricValue::Histogram(histogram), Some(MetricValue::Histogram(before))) => {
+                accumulator.histogram.add(&histogram.since(before));
+            }
+            (MetricValue::Histogram(_), _) => {}
+            (MetricValue::Number(value), before) => {
+                accumulator.samples += 1;
+                accumulator.sum += value;
+                accumulator.min = Some(accumulator.min.map_or(*value, |min| min.min(*value)));
+                accumulator.max = Some(accumulator.max.map_or(*value, |max| max.max(*value)));
+                accumulator.last.insert(sample.series_id, *value);
+                if let Some(MetricValue::Number(before)) = before {
+                    // A lower value means the counter was reset
+                    accumulator.increase += if value < before { *value } else { value - before };
+                }
+            }
+        }
+    }
+
+    let names = ranked
+        .iter()
+        .take(top_n)
+        .map(|(group, _)| group.to_string())
+        .chain(has_other.then(|| "other".to_string()));
+    data.series = names
+        .zip(accumulators)
+        .enumerate()
+        .map(|(i, (name, accumulators))| Series {
+            name,
+            other: i == top_n,
+            values: accumulators
+                .iter()
+                .zip(buckets)
+                .map(|(accumulator, (start, end))| {
+                    let seconds = end.signed_duration_since(start).num_milliseconds() as f64 / 1000.;
+                    accumulator.value(aggregation, kind, seconds)
+                })
+                .collect(),
+        })
+        .collect();
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::connection::connect_db;
+    use chrono::TimeZone;
+
+    fn histogram(buckets: &[(f64, f64)], sum: f64) -> HistogramValue {
+        let count = buckets.last().map_or(0., |(_, count)| *count);
+        HistogramValue::new(buckets.to_vec(), sum, count)
+    }
+
+    fn sample(name: &str, kind: MetricKind, minute: u32, value: MetricValue) -> MetricSample {
+        MetricSample {
+            name: name.to_string(),
+            kind,
+            labels: Labels::new(),
+            date: Utc.with_ymd_and_hms(2026, 10, 19, 7, minute, 0).unwrap(),
+            value,
+        }
+    }
+
+    fn one_bucket() -> Vec<(DateTime<Tz>, DateTime<Tz>)> {
+        let start = Utc.with_ymd_and_hms(2026, 10, 19, 7, 0, 0).unwrap();
+        vec![(
+            start.with_timezone(&Tz::UTC),
+            (start + Duration::hours(1)).with_timezone(&Tz::UTC),
+        )]
+    }
+
+    #[test]
+    fn completes_histograms() {
+        let value = HistogramValue::new(vec![(1., 5.), (0.1, 2.)], 3., 8.);
+        assert_eq!(value.buckets, vec![(0.1, 2.), (1., 5.), (f64::INFINITY, 8.)]);
+        let json = value.buckets_json();
+        assert_eq!(json, r#"{"+Inf":8.0,"0.1":2.0,"1":5.0}"#);
+        assert_eq!(HistogramValue::from_json(&json, 3., 8.).unwrap(), value);
+    }
+
+    #[test]
+    fn interpolates_quantiles() {
+        let value = histogram(&[(0.1, 10.), (0.5, 30.), (1., 40.), (f64::INFINITY, 40.)], 12.);
+        assert_eq!(value.quantile(0.25), Some(0.1));
+        assert!((value.quantile(0.5).unwrap() - 0.3).abs() < 1e-9);
+        assert_eq!(value.quantile(1.), Some(1.));
+        // Observations past the last finite bound are reported at that bound
+        let value = histogram(&[(1., 5.), (f64::INFINITY, 10.)], 30.);
+        assert_eq!(value.quantile(0.9), Some(1.));
+        assert_eq!(
+            histogram(&[(1., 0.), (f64::INFINITY, 0.)], 0.).quantile(0.5),
+            None
+        );
+    }
+
+    #[test]
+    fn subtracts_previous_histograms() {
+        let before = histogram(&[(0.1, 2.), (1., 5.), (f64::INFINITY, 6.)], 4.);
+        let after = histogram(&[(0.1, 3.), (1., 9.), (f64::INFINITY, 10.)], 7.);
+        assert_eq!(
+            after.since(&before),
+            HistogramValue {
+                buckets: vec![(0.1, 1.), (1., 4.), (f64::INFINITY, 4.)],
+                sum: 3.,
+                count: 4.,
+            }
+        );
+        // The process restarted, so everything counted since is new
+        let restarted = histogram(&[(0.1, 1.), (1., 1.), (f64::INFINITY, 2.)], 0.5);
+        assert_eq!(restarted.since(&after), restarted);
+    }
+
+    #[test]
+    fn handles_counter_resets() {
+        let conn = connect_db(":memory:");
+        let samples = [10., 20., 5., 15.]
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                sample(
+                    "jobs_total",
+                    MetricKind::Counter,
+                    i as u32 * 10,
+                    MetricValue::Number(*value),
+                )
+            })
+            .collect();
+        let result = insert_metric_samples(samples, 0, &conn).unwrap();
+        assert_eq!(result.accepted, 4);
+
+        let data = metric_series(
+            "jobs_total",
+            MetricKind::Counter,
+            &[],
+            None,
+            MetricAggregation::Increase,
+            5,
+            &one_bucket(),
+            &conn,
+        )
+        .unwrap();
+        // 10 before the reset, then 5 counted from 0 and 10 more
+        assert_eq!(data.series[0].values, vec![Some(25.)]);
+    }
+
+    #[test]
+    fn aggregates_histogram_deltas() {
+        let conn = connect_db(":memory:");
+        let samples = vec![
+            sample(
+                "latency",
+                MetricKind::Histogram,
+                0,
+                MetricValue::Histogram(histogram(&[(0.1, 10.), (1., 10.)], 0.5)),
+            ),
+            sample(
+                "latency",
+                MetricKind::Histogram,
+                30,
+                MetricValue::Histogram(histogram(&[(0.1, 10.), (1., 30.)], 10.5)),
+            ),
+        ];
+        insert_metric_samples(samples, 0, &conn).unwrap();
+
+        let series = |aggregation| {
+            metric_series(
+                "latency",
+                MetricKind::Histogram,
+                &[],
+                None,
+                aggregation,
+                5,
+                &one_bucket(),
+                &conn,
+            )
+            .unwrap()
+            .series[0]
+                .values[0]
+        };
+        // Only the 20 observations between the two samples, all between 0.1 and 1
+        assert!((series(MetricAggregation::P50).unwrap() - 0.55).abs() < 1e-9);
+        assert_eq!(series(MetricAggregation::Increase), Some(20.));
+        assert_eq!(series(MetricAggregation::Avg), Some(0.5));
+    }
+}
@@ -0,0 +1,546 @@
+use super::{
+    filters::{text, RequestFilter},
+    requests::{Order, ENDPOINT_GROUP_SQL, ERROR_STATUS_SQL},
+    stats::TimeWindow,
+};
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use regex::Regex;
+use rusqlite::{params, params_from_iter, Connection};
+use serde::{Deserialize, Serialize};
+use std::{cmp::Ordering, collections::HashMap, sync::LazyLock};
+
+/// Placeholder for path segments recognized as ids
+pub const ID_PLACEHOLDER: &str = ":id";
+
+static UUID: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^[0-9a-fA-F]{8}-?[0-9a-fA-F]{4}-?[0-9a-fA-F]{4}-?[0-9a-fA-F]{4}-?[0-9a-fA-F]{12}$")
+        .unwrap()
+});
+/// Hashes and object ids, e.g. sha1 digests or 24 char Mongo ids
+static HEX_ID: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[0-9a-fA-F]{16,}$").unwrap());
+/// Long opaque tokens mixing letters and digits, e.g. `cus_9s6XKzkNRiz8i3`
+static TOKEN_ID: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[A-Za-z0-9_-]{16,}$").unwrap());
+
+/// Whether a path segment looks like an id rather than part of the route
+pub fn is_id_segment(segment: &str) -> bool {
+    (!segment.is_empty() && segment.chars().all(|c| c.is_ascii_digit()))
+        || UUID.is_match(segment)
+        || HEX_ID.is_match(segment)
+        || (TOKEN_ID.is_match(segment)
+            && segment.chars().any(|c| c.is_ascii_digit())
+            && segment.chars().any(|c| c.is_ascii_alphabetic()))
+}
+
+/// Splits a logged endpoint into its `scheme://host` origin, empty for bare paths, and its path
+pub fn split_origin(endpoint: &str) -> (&str, &str) {
+    let Some(scheme_end) = endpoint.find("://") else {
+        return ("", endpoint);
+    };
+    match endpoint[scheme_end + 3..].find('/') {
+        Some(path_start) => endpoint.split_at(scheme_end + 3 + path_start),
+        None => (endpoint, "/"),
+    }
+}
+
+/// Host of a logged endpoint without credentials or port, `None` for bare paths
+pub fn endpoint_host(endpoint: &str) -> Option<String> {
+    let (origin, _) = split_origin(endpoint);
+    let authority = &origin[origin.find("://")? + 3..];
+    let host = authority.rsplit('@').next().unwrap_or(authority);
+    let host = match host.rfind(':') {
+        // Keep IPv6 addresses like `[::1]` whole
+        Some(port) if !host[port..].contains(']') => &host[..port],
+        _ => host,
+    };
+    (!host.is_empty()).then(|| host.to_lowercase())
+}
+
+/// User defined route like `/users/{id}/orders`, taking precedence over automatic normalization
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EndpointPattern {
+    pub id: Option<i64>,
+    pub pattern: String,
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum PatternSegment {
+    Literal(String),
+    Param(String),
+}
+
+/// Maps raw endpoints to route templates, e.g. `https://api.example.com/users/123` to
+/// `https://api.example.com/users/:id`
+#[derive(Debug, Default)]
+pub struct EndpointTemplater {
+    patterns: Vec<Vec<PatternSegment>>,
+}
+
+fn parse_pattern(pattern: &str) -> Result<Vec<PatternSegment>> {
+    let path = pattern
+        .strip_prefix('/')
+        .ok_or(anyhow!("Pattern '{}' must start with '/'", pattern))?;
+    if path.is_empty() {
+        return Ok(vec![]);
+    }
+    path.split('/')
+        .map(
+            |segment| match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                Some(name)
+                    if !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_') =>
+                {
+                    Ok(PatternSegment::Param(name.to_string()))
+                }
+                Some(_) => Err(anyhow!(
+                    "Invalid parameter '{}' in pattern '{}'",
+                    segment,
+                    pattern
+                )),
+                None if segment.is_empty() => Err(anyhow!("Pattern '{}' has an empty segment", pattern)),
+                None => Ok(PatternSegment::Literal(segment.to_string())),
+            },
+        )
+        .collect()
+}
+
+/// Checks that a pattern is a path made of literal and `{name}` segments
+pub fn validate_endpoint_pattern(pattern: &str) -> Result<()> {
+    parse_pattern(pattern).map(|_| ())
+}
+
+impl EndpointTemplater {
+    /// Compiles the patterns, skipping invalid ones. Patterns with more literal segments win.
+    pub fn from_patterns(patterns: &[EndpointPattern]) -> EndpointTemplater {
+        let mut patterns = patterns
+            .iter()
+            .filter_map(|p| parse_pattern(&p.pattern).ok())
+            .collect::<Vec<Vec<PatternSegment>>>();
+        patterns.sort_by_key(|segments| {
+            std::cmp::Reverse(
+                segments
+                    .iter()
+                    .filter(|s| matches!(s, PatternSegment::Literal(_)))
+                    .count(),
+            )
+        });
+        EndpointTemplater { patterns }
+    }
+
+    pub fn template(&self, endpoint: &str) -> String {
+        let (origin, path) = split_origin(endpoint);
+        let trailing_slash = path.len() > 1 && path.ends_with('/');
+        let segments = path
+            .trim_matches('/')
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<&str>>();
+
+        let configured = self.patterns.iter().find(|pattern| {
+            pattern.len() == segments.len()
+                && pattern.iter().zip(&segments).all(|(p, s)| match p {
+                    PatternSegment::Literal(literal) => literal == s,
+                    PatternSegment::Param(_) => true,
+                })
+        });
+        let templated = match configured {
+            Some(pattern) => pattern
+                .iter()
+                .map(|p| match p {
+                    PatternSegment::Literal(literal) => literal.clone(),
+                    PatternSegment::Param(name) => format!(":{}", name),
+                })
+                .collect::<Vec<String>>(),
+            None => segments
+                .iter()
+                .map(|s| match is_id_segment(s) {
+                    true => ID_PLACEHOLDER.to_string(),
+                    false => s.to_string(),
+                })
+                .collect(),
+        };
+        format!(
+            "{}/{}{}",
+            origin,
+            templated.join("/"),
+            if trailing_slash { "/" } else { "" }
+        )
+    }
+}
+
+pub fn endpoint_patterns(conn: &Connection) -> Result<Vec<EndpointPattern>> {
+    let mut stmt = conn.prepare("SELECT id, pattern, description FROM endpoint_patterns ORDER BY id")?;
+    let rows = stmt.query_map([], |row| {
+        Ok(EndpointPattern {
+            id: row.get(0)?,
+            pattern: row.get(1)?,
+            description: row.get(2)?,
+        })
+    })?;
+    Ok(rows.collect::<Result<Vec<EndpointPattern>, _>>()?)
+}
+
+pub fn insert_endpoint_pattern(conn: &Connection, pattern: &EndpointPattern) -> Result<i64> {
+    conn.execute(
+        "INSERT INTO endpoint_patterns (pattern, description, created_at) VALUES (?1, ?2, ?3)",
+        params![
+            pattern.pattern,
+            pattern.description,
+            Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+        ],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Deletes a pattern, returning it if it existed
+pub fn delete_endpoint_pattern(conn: &Connection, id: i64) -> Result<Option<EndpointPattern>> {
+    let pattern = endpoint_patterns(conn)?.into_iter().find(|p| p.id == Some(id));
+    conn.execute("DELETE FROM endpoint_patterns WHERE id = ?1", params![id])?;
+    Ok(pattern)
+}
+
+pub fn load_templater(conn: &Connection) -> Result<EndpointTemplater> {
+    Ok(EndpointTemplater::from_patterns(&endpoint_patterns(conn)?))
+}
+
+/// Recomputes the template of every logged endpoint, e.g. after the patterns changed.
+/// Returns the number of distinct endpoints whose template changed.
+pub fn retemplate_requests(conn: &Connection, templater: &EndpointTemplater) -> Result<usize> {
+    let endpoints = {
+        let mut stmt = conn.prepare(
+            "SELECT endpoint, endpoint_template FROM requests GROUP BY endpoint, endpoint_template",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
+        })?;
+        rows.collect::<Result<Vec<(String, Option<String>)>, _>>()?
+    };
+
+    let tx = conn.unchecked_transaction()?;
+    tx.execute_batch(
+        "CREATE TEMP TABLE IF NOT EXISTS endpoint_templates(endpoint TEXT PRIMARY KEY, template TEXT NOT NULL);
+        DELETE FROM temp.endpoint_templates;",
+    )?;
+    let mut changed = 0;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT OR REPLACE INTO temp.endpoint_templates (endpoint, template) VALUES (?1, ?2)",
+        )?;
+        for (endpoint, current) in endpoints {
+            let template = templater.template(&endpoint);
+            if current.as_ref() != Some(&template) {
+                stmt.execute(params![endpoint, template])?;
+                changed += 1;
+            }
+        }
+    }
+    // One pass over requests instead of one per endpoint
+    tx.execute_batch(
+        "UPDATE requests
+        SET endpoint_template = (
+            SELECT template FROM temp.endpoint_templates t WHERE t.endpoint = requests.endpoint
+        )
+        WHERE endpoint IN (SELECT endpoint FROM temp.endpoint_templates);
+        DELETE FROM temp.endpoint_templates;",
+    )?;
+    tx.commit()?;
+    Ok(changed)
+}
+
+/// Sets the service of requests logged without one to the host of their endpoint
+pub fn backfill_request_services(conn: &Connection) -> Result<usize> {
+    let endpoints = {
+        let mut stmt = conn.prepare("SELECT DISTINCT endpoint FROM requests WHERE service IS NULL")?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        rows.collect::<Result<Vec<String>, _>>()?
+    };
+
+    let tx = conn.unchecked_transaction()?;
+    tx.execute_batch(
+        "CREATE TEMP TABLE IF NOT EXISTS endpoint_services(endpoint TEXT PRIMARY KEY, service TEXT NOT NULL);
+        DELETE FROM temp.endpoint_services;",
+    )?;
+    let mut backfilled = 0;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT OR REPLACE INTO temp.endpoint_services (endpoint, service) VALUES (?1, ?2)",
+        )?;
+        for endpoint in endpoints {
+            if let Some(service) = endpoint_host(&endpoint) {
+                stmt.execute(params![endpoint, service])?;
+                backfilled += 1;
+            }
+        }
+    }
+    tx.execute_batch(
+        "UPDATE requests
+        SET service = (
+            SELECT service FROM temp.endpoint_services s WHERE s.endpoint = requests.endpoint
+        )
+        WHERE service IS NULL AND endpoint IN (SELECT endpoint FROM temp.endpoint_services);
+        DELETE FROM temp.endpoint_services;",
+    )?;
+    tx.commit()?;
+    Ok(backfilled)
+}
+
+/// Latency, traffic and errors of one endpoint and method over a time window
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EndpointMetrics {
+    pub request_count: usize,
+    pub error_count: usize,
+    /// Share of requests answered with a 5xx
+    pub error_rate: f64,
+    pub avg_latency: f64,
+    pub p50_latency: u32,
+    pub p95_latency: u32,
+    pub p99_latency: u32,
+    /// Requests per second over the whole window
+    pub throughput: f64,
+}
+
+/// Relative change of the metrics since the previous window, e.g. `0.5` for 50% more requests.
+/// The error rate change is the difference between the two rates.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EndpointTrend {
+    pub request_count: f64,
+    pub error_rate: f64,
+    pub avg_latency: f64,
+    pub p95_latency: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EndpointStats {
+    pub endpoint: String,
+    pub method: String,
+    #[serde(flatten)]
+    pub metrics: EndpointMetrics,
+    /// Metrics over the previous window, `None` when the endpoint had no traffic then
+    pub previous: Option<EndpointMetrics>,
+    pub trend: Option<EndpointTrend>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum EndpointSort {
+    #[default]
+    RequestCount,
+    ErrorRate,
+    AvgLatency,
+    P50Latency,
+    P95Latency,
+    P99Latency,
+    Throughput,
+    Endpoint,
+}
+
+impl EndpointSort {
+    fn compare(&self, a: &EndpointStats, b: &EndpointStats) -> Ordering {
+        let (m, n) = (&a.metrics, &b.metrics);
+        match self {
+            EndpointSort::RequestCount => m.request_count.cmp(&n.request_count),
+            EndpointSort::ErrorRate => m.error_rate.total_cmp(&n.error_rate),
+            EndpointSort::AvgLatency => m.avg_latency.total_cmp(&n.avg_latency),
+            EndpointSort::P50Latency => m.p50_latency.cmp(&n.p50_latency),
+            EndpointSort::P95Latency => m.p95_latency.cmp(&n.p95_latency),
+            EndpointSort::P99Latency => m.p99_latency.cmp(&n.p99_latency),
+            EndpointSort::Throughput => m.throughput.total_cmp(&n.throughput),
+            EndpointSort::Endpoint => a.endpoint.cmp(&b.endpoint).then_with(|| a.method.cmp(&b.method)),
+        }
+    }
+}
+
+/// Metrics of every endpoint template and method with traffic in `window`
+pub fn endpoint_metrics(
+    window: &TimeWindow,
+    filter: &RequestFilter,
+    conn: &Connection,
+) -> Result<HashMap<(String, String), EndpointMetrics>> {
+    // Percentiles use the nearest rank, the smallest latency with at least p% of requests at or below it
+    let mut stmt = conn.prepare(&format!(
+        "
+        WITH ranked AS (
+            SELECT
+                {group} AS endpoint,
+                method,
+                status,
+                res_time,
+                ROW_NUMBER() OVER (PARTITION BY {group}, method ORDER BY res_time) AS position,
+                COUNT(*) OVER (PARTITION BY {group}, method) AS total
+            FROM requests
+            WHERE date > ? AND date <= ? AND {filter}
+        )
+        SELECT
+            endpoint,
+            method,
+            COUNT(*),
+            COUNT(CASE WHEN {error} THEN 1 END),
+            AVG(res_time),
+            MIN(CASE WHEN position * 100 >= total * 50 THEN res_time END),
+            MIN(CASE WHEN position * 100 >= total * 95 THEN res_time END),
+            MIN(CASE WHEN position * 100 >= total * 99 THEN res_time END)
+        FROM ranked
+        GROUP BY endpoint, method;
+        ",
+        group = ENDPOINT_GROUP_SQL,
+        error = ERROR_STATUS_SQL,
+        filter = filter.sql()
+    ))?;
+    let (from, to) = window.bounds();
+    let mut params = vec![text(from), text(to)];
+    params.extend_from_slice(filter.params());
+    let seconds = window.seconds().max(1.);
+    let rows = stmt.query_map(params_from_iter(params), |row| {
+        let request_count = row.get::<_, usize>(2)?;
+        let error_count = row.get::<_, usize>(3)?;
+        Ok((
+            (row.get::<_, String>(0)?, row.get::<_, String>(1)?),
+            EndpointMetrics {
+                request_count,
+                error_count,
+                error_rate: error_count as f64 / request_count as f64,
+                avg_latency: row.get(4)?,
+                p50_latency: row.get(5)?,
+                p95_latency: row.get(6)?,
+                p99_latency: row.get(7)?,
+                throughput: request_count as f64 / seconds,
+            },
+        ))
+    })?;
+    Ok(rows.collect::<Result<HashMap<_, _>, _>>()?)
+}
+
+fn relative_change(current: f64, previous: f64) -> f64 {
+    if previous == 0. {
+        return 0.;
+    }
+    (current - previous) / previous
+}
+
+/// Per endpoint metrics over `window` compared with the previous window, sorted by `sort`
+pub fn endpoint_stats(
+    window: &TimeWindow,
+    filter: &RequestFilter,
+    sort: EndpointSort,
+    order: Order,
+    conn: &Connection,
+) -> Result<Vec<EndpointStats>> {
+    let current = endpoint_metrics(window, filter, conn)?;
+    let mut previous = endpoint_metrics(&window.previous(), filter, conn)?;
+
+    let mut stats = current
+        .into_iter()
+        .map(|((endpoint, method), metrics)| {
+            let previous = previous.remove(&(endpoint.clone(), method.clone()));
+            let trend = previous.as_ref().map(|p| EndpointTrend {
+                request_count: relative_change(metrics.request_count as f64, p.request_count as f64),
+                error_rate: metrics.error_rate - p.error_rate,
+                avg_latency: relative_change(metrics.avg_latency, p.avg_latency),
+                p95_latency: relative_change(metrics.p95_latency as f64, p.p95_latency as f64),
+            });
+            EndpointStats {
+                endpoint,
+                method,
+                metrics,
+                previous,
+                trend,
+            }
+        })
+        .collect::<Vec<EndpointStats>>();
+    // Ties are always listed by endpoint and method, whatever the order
+    stats.sort_by(|a, b| {
+        let ordering = match order {
+            Order::Ascending => sort.compare(a, b),
+            Order::Descending => sort.compare(a, b).reverse(),
+        };
+        ordering
+            .then_with(|| a.endpoint.cmp(&b.endpoint))
+            .then_with(|| a.method.cmp(&b.method))
+    });
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(pattern: &str) -> EndpointPattern {
+        EndpointPattern {
+            id: None,
+            pattern: pattern.to_string(),
+            description: None,
+        }
+    }
+
+    #[test]
+    fn recognizes_ids() {
+        assert!(is_id_segment("42"));
+        assert!(is_id_segment("3f2b8c1e-9d4a-4b7e-a1c2-5d6e7f8a9b0c"));
+        assert!(is_id_segment("3F2B8C1E9D4A4B7EA1C25D6E7F8A9B0C"));
+        assert!(is_id_segment("507f1f77bcf86cd799439011"));
+        assert!(is_id_segment("cus_9s6XKzkNRiz8i3"));
+
+        assert!(!is_id_segment(""));
+        assert!(!is_id_segment("v1"));
+        assert!(!is_id_segment("healthcheck"));
+        assert!(!is_id_segment("deadbeef"));
+        assert!(!is_id_segment("notifications_settings"));
+        assert!(!is_id_segment("-12"));
+    }
+
+    #[test]
+    fn templates_endpoints() {
+        let templater = EndpointTemplater::default();
+        assert_eq!(templater.template("/users/42/orders"), "/users/:id/orders");
+        assert_eq!(templater.template("/cus_9s6XKzkNRiz8i3"), "/:id");
+        assert_eq!(templater.template("/v1/healthcheck"), "/v1/healthcheck");
+        assert_eq!(
+            templater.template("https://api.example.com/users/42/"),
+            "https://api.example.com/users/:id/"
+        );
+        assert_eq!(
+            templater.template("https://api.example.com"),
+            "https://api.example.com/"
+        );
+        assert_eq!(templater.template("/"), "/");
+    }
+
+    #[test]
+    fn prefers_configured_patterns() {
+        let templater = EndpointTemplater::from_patterns(&[
+            pattern("/users/{user}/{section}"),
+            pattern("/users/{user}/orders"),
+            pattern("/missing-slash"),
+        ]);
+        assert_eq!(
+            templater.template("https://api.example.com/users/jane/orders"),
+            "https://api.example.com/users/:user/orders"
+        );
+        assert_eq!(templater.template("/users/jane/profile"), "/users/:user/:section");
+        assert_eq!(templater.template("/users/42"), "/users/:id");
+
+        assert!(validate_endpoint_pattern("/users/{id}").is_ok());
+        assert!(validate_endpoint_pattern("users/{id}").is_err());
+        assert!(validate_endpoint_pattern("/users//{id}").is_err());
+        assert!(validate_endpoint_pattern("/users/{i-d}").is_err());
+    }
+
+    #[test]
+    fn splits_hosts() {
+        assert_eq!(
+            split_origin("https://api.example.com/users?x=1"),
+            ("https://api.example.com", "/users?x=1")
+        );
+        assert_eq!(split_origin("/users"), ("", "/users"));
+        assert_eq!(
+            endpoint_host("https://user:pw@API.example.com:8443/x"),
+            Some("api.example.com".to_string())
+        );
+        assert_eq!(endpoint_host("http://[::1]:80/x"), Some("[::1]".to_string()));
+        assert_eq!(endpoint_host("/users"), None);
+    }
+}
@@ -0,0 +1,290 @@
+use super::{
+    filters::{text, RequestFilter},
+    requests::{RequestLog, REQUEST_LOG_COLUMNS},
+};
+use actix_web::web::Bytes;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use parquet::{
+    basic::Compression,
+    data_type::{ByteArray, ByteArrayType, Int32Type, Int64Type},
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::parser::parse_message_type,
+};
+use rusqlite::{params_from_iter, types::Value as SqlValue, Connection};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{self, Write},
+    mem,
+    sync::Arc,
+};
+use strum::{Display, EnumString};
+use tokio::sync::mpsc::Sender;
+
+/// Rows buffered per Parquet row group
+pub const PARQUET_ROW_GROUP_SIZE: usize = 10_000;
+/// Bytes buffered before a chunk is sent to the client
+pub const EXPORT_CHUNK_SIZE: usize = 64 * 1024;
+
+const PARQUET_SCHEMA: &str = "
+message request_log {
+    REQUIRED INT64 id;
+    REQUIRED INT64 date (TIMESTAMP(MILLIS, true));
+    REQUIRED BYTE_ARRAY method (UTF8);
+    REQUIRED BYTE_ARRAY endpoint (UTF8);
+    REQUIRED BYTE_ARRAY endpoint_template (UTF8);
+    REQUIRED BYTE_ARRAY service (UTF8);
+    REQUIRED BYTE_ARRAY trace_id (UTF8);
+    REQUIRED BYTE_ARRAY span_id (UTF8);
+    REQUIRED BYTE_ARRAY parent_span_id (UTF8);
+    REQUIRED BYTE_ARRAY params (UTF8);
+    REQUIRED INT32 status;
+    REQUIRED INT64 res_time;
+    REQUIRED BYTE_ARRAY req_headers (JSON);
+    REQUIRED BYTE_ARRAY res_headers (JSON);
+    REQUIRED BYTE_ARRAY req_body (JSON);
+    REQUIRED BYTE_ARRAY res_body (JSON);
+}
+";
+
+#[derive(Serialize, Deserialize, Display, EnumString, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    Ndjson,
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+}
+
+/// Writes to a channel in chunks, so exports can stream to a client from a blocking thread.
+/// Fails with `BrokenPipe` once the receiving end is dropped.
+pub struct ChannelWriter {
+    sender: Sender<Result<Bytes, io::Error>>,
+    buffer: Vec<u8>,
+}
+
+impl ChannelWriter {
+    pub fn new(sender: Sender<Result<Bytes, io::Error>>) -> ChannelWriter {
+        ChannelWriter {
+            sender,
+            buffer: Vec::with_capacity(EXPORT_CHUNK_SIZE),
+        }
+    }
+
+    /// Sends an error to the client in place of the rest of the export
+    pub fn fail(self, error: io::Error) {
+        let _ = self.sender.blocking_send(Err(error));
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= EXPORT_CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(mem::take(&mut self.buffer));
+        self.sender
+            .blocking_send(Ok(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Export receiver dropped"))
+    }
+}
+
+/// Writes the requests matching `filter`, oldest first, to `out` without holding more than a
+/// row group in memory. Returns the number of exported requests.
+pub fn export_requests(
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    filter: &RequestFilter,
+    limit: Option<usize>,
+    format: ExportFormat,
+    out: impl Write + Send,
+    conn: &Connection,
+) -> Result<usize> {
+    let mut params = vec![
+        text(
+            from.unwrap_or(DateTime::UNIX_EPOCH)
+                .format("%Y-%m-%dT%H:%M:%S%.3fZ")
+                .to_string(),
+        ),
+        text(
+            to.unwrap_or(Utc::now())
+                .format("%Y-%m-%dT%H:%M:%S%.3fZ")
+                .to_string(),
+        ),
+    ];
+    params.extend_from_slice(filter.params());
+    params.push(SqlValue::Integer(limit.map_or(-1, |l| l as i64)));
+    let mut stmt = conn.prepare(&format!(
+        "
+        SELECT {}
+        FROM requests
+        WHERE date > ? AND date <= ? AND {}
+        ORDER BY date ASC, requests.id ASC
+        LIMIT ?;
+        ",
+        REQUEST_LOG_COLUMNS,
+        filter.sql()
+    ))?;
+    // Expensive filters bound how long the export can run
+    filter.run(conn, || {
+        let mut rows = stmt.query(params_from_iter(params))?;
+        let mut next = move || -> Result<Option<RequestLog>> {
+            match rows.next()? {
+                Some(row) => Ok(Some(RequestLog::from_row(row)?)),
+                None => Ok(None),
+            }
+        };
+
+        let mut count = 0;
+        match format {
+            ExportFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(out);
+                writer.write_record([
+                    "id",
+                    "date",
+                    "method",
+                    "endpoint",
+                    "endpoint_template",
+                    "service",
+                    "trace_id",
+                    "span_id",
+                    "parent_span_id",
+                    "params",
+                    "status",
+                    "res_time",
+                    "req_headers",
+                    "res_headers",
+                    "req_body",
+                    "res_body",
+                ])?;
+                while let Some(log) = next()? {
+                    writer.write_record(csv_record(&log))?;
+                    count += 1;
+                }
+                writer.flush()?;
+            }
+            ExportFormat::Ndjson => {
+                let mut out = out;
+                while let Some(log) = next()? {
+                    serde_json::to_writer(&mut out, &log)?;
+                    out.write_all(b"\n")?;
+                    count += 1;
+                }
+                out.flush()?;
+            }
+            ExportFormat::Parquet => {
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build();
+                let mut writer = SerializedFileWriter::new(
+                    out,
+                    Arc::new(parse_message_type(PARQUET_SCHEMA)?),
+                    Arc::new(properties),
+                )?;
+                let mut row_group = Vec::with_capacity(PARQUET_ROW_GROUP_SIZE);
+                loop {
+                    let log = next()?;
+                    let done = log.is_none();
+                    row_group.extend(log);
+                    if row_group.len() == PARQUET_ROW_GROUP_SIZE || (done && !row_group.is_empty()) {
+                        count += row_group.len();
+                        write_row_group(&mut writer, &mem::take(&mut row_group))?;
+                    }
+                    if done {
+                        break;
+                    }
+                }
+                writer.into_inner()?.flush()?;
+            }
+        }
+        Ok(count)
+    })
+}
+
+/// Fields of a log in the order of the CSV header and the Parquet schema
+fn csv_record(log: &RequestLog) -> [String; 16] {
+    [
+        log.id.unwrap_or_default().to_string(),
+        log.date.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+        log.method.clone(),
+        log.endpoint.clone(),
+        log.endpoint_template
+            .clone()
+            .unwrap_or_else(|| log.endpoint.clone()),
+        log.service.clone().unwrap_or_default(),
+        log.trace_id.clone().unwrap_or_default(),
+        log.span_id.clone().unwrap_or_default(),
+        log.parent_span_id.clone().unwrap_or_default(),
+        log.params.clone(),
+        log.status.to_string(),
+        log.res_time.to_string(),
+        log.req_headers.to_stored(),
+        log.res_headers.to_stored(),
+        log.req_body.clone().unwrap_or_default().to_string(),
+        log.res_body.clone().unwrap_or_default().to_string(),
+    ]
+}
+
+fn write_row_group<W: Write + Send>(
+    writer: &mut SerializedFileWriter<W>,
+    logs: &[RequestLog],
+) -> Result<()> {
+    let records = logs.iter().map(csv_record).collect::<Vec<[String; 16]>>();
+    let mut row_group = writer.next_row_group()?;
+    let mut index = 0;
+    while let Some(mut column) = row_group.next_column()? {
+        match index {
+            0 => {
+                let ids = logs
+                    .iter()
+                    .map(|l| l.id.unwrap_or_default())
+                    .collect::<Vec<i64>>();
+                column.typed::<Int64Type>().write_batch(&ids, None, None)?;
+            }
+            1 => {
+                let dates = logs
+                    .iter()
+                    .map(|l| l.date.timestamp_millis())
+                    .collect::<Vec<i64>>();
+                column.typed::<Int64Type>().write_batch(&dates, None, None)?;
+            }
+            10 => {
+                let statuses = logs.iter().map(|l| l.status as i32).collect::<Vec<i32>>();
+                column.typed::<Int32Type>().write_batch(&statuses, None, None)?;
+            }
+            11 => {
+                let res_times = logs.iter().map(|l| l.res_time as i64).collect::<Vec<i64>>();
+                column.typed::<Int64Type>().write_batch(&res_times, None, None)?;
+            }
+            _ => {
+                let values = records
+                    .iter()
+                    .map(|record| ByteArray::from(record[index].as_bytes().to_vec()))
+                    .collect::<Vec<ByteArray>>();
+                column.typed::<ByteArrayType>().write_batch(&values, None, None)?;
+            }
+        }
+        column.close()?;
+        index += 1;
+    }
+    row_group.close()?;
+    Ok(())
+}
@@ -0,0 +1,70 @@
+use anyhow::{anyhow, Result};
+use rusqlite::{types::Value as SqlValue, Connection, ErrorCode};
+use std::time::{Duration, Instant};
+
+/// Conditions over the `requests` table, combined with AND, along with their positional parameters
+#[derive(Debug, Clone, Default)]
+pub struct RequestFilter {
+    clauses: Vec<String>,
+    params: Vec<SqlValue>,
+    /// Set by expensive conditions to bound how long queries using the filter may run
+    time_budget: Option<Duration>,
+}
+
+impl RequestFilter {
+    /// Adds a condition using `?` placeholders for its parameters
+    pub fn push(&mut self, clause: impl Into<String>, params: Vec<SqlValue>) -> &mut Self {
+        self.clauses.push(clause.into());
+        self.params.extend(params);
+        self
+    }
+
+    /// The conditions as a single SQL expression, `1` when there are none
+    pub fn sql(&self) -> String {
+        if self.clauses.is_empty() {
+            return "1".to_string();
+        }
+        self.clauses
+            .iter()
+            .map(|clause| format!("({})", clause))
+            .collect::<Vec<String>>()
+            .join(" AND ")
+    }
+
+    pub fn params(&self) -> &[SqlValue] {
+        &self.params
+    }
+
+    /// Bounds how long queries using this filter may run, keeping the tightest budget set
+    pub fn limit_time(&mut self, budget: Duration) -> &mut Self {
+        self.time_budget = Some(self.time_budget.map_or(budget, |b| b.min(budget)));
+        self
+    }
+
+    /// Runs the queries in `f`, interrupting them once the filter's time budget is spent
+    pub fn run<T>(&self, conn: &Connection, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let Some(budget) = self.time_budget else {
+            return f();
+        };
+        let deadline = Instant::now() + budget;
+        conn.progress_handler(1000, Some(move || Instant::now() > deadline));
+        let result = f();
+        conn.progress_handler(0, None::<fn() -> bool>);
+
+        result.map_err(|e| match e.downcast_ref::<rusqlite::Error>() {
+            Some(rusqlite::Error::SqliteFailure(err, _))
+                if err.code == ErrorCode::OperationInterrupted =>
+            {
+                anyhow!(
+                    "Query took longer than {}s, narrow down the filters",
+                    budget.as_secs()
+                )
+            }
+            _ => e,
+        })
+    }
+}
+
+pub fn text(value: impl Into<String>) -> SqlValue {
+    SqlValue::Text(value.into())
+}
@@ -0,0 +1,402 @@
+use anyhow::{anyhow, Result};
+use rusqlite::{params, types::Value as SqlValue, Connection};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+use strum::{Display, EnumString};
+
+/// Captured request or response headers.
+///
+/// Accepted as a JSON object (values can be strings or arrays of strings), an array of
+/// `[name, value]` pairs or raw `Name: value` lines. Anything that parses is stored normalized
+/// with lowercase names and returned as a JSON object, otherwise the raw text is kept as is.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Headers {
+    Structured(Vec<(String, String)>),
+    Raw(String),
+}
+
+impl Default for Headers {
+    fn default() -> Self {
+        Headers::Structured(vec![])
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HeadersInput {
+    Object(Map<String, Value>),
+    Pairs(Vec<(String, String)>),
+    Text(String),
+}
+
+impl Headers {
+    fn from_object(map: Map<String, Value>) -> Result<Headers> {
+        let mut pairs = vec![];
+        for (name, value) in map {
+            match value {
+                Value::String(value) => pairs.push((name, value)),
+                Value::Array(values) => {
+                    for value in values {
+                        let value = value
+                            .as_str()
+                            .ok_or(anyhow!("Header '{}' values must be strings", name))?;
+                        pairs.push((name.clone(), value.to_string()));
+                    }
+                }
+                Value::Number(_) | Value::Bool(_) => pairs.push((name, value.to_string())),
+                _ => {
+                    return Err(anyhow!(
+                        "Header '{}' must be a string or an array of strings",
+                        name
+                    ))
+                }
+            }
+        }
+        Ok(Headers::from_pairs(pairs))
+    }
+
+    fn from_pairs(pairs: Vec<(String, String)>) -> Headers {
+        Headers::Structured(
+            pairs
+                .into_iter()
+                .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+                .filter(|(name, _)| !name.is_empty())
+                .collect(),
+        )
+    }
+
+    /// Parses `Name: value` lines, keeping the text raw if any line isn't a header
+    fn from_text(text: String) -> Headers {
+        let lines = text
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .collect::<Vec<&str>>();
+        let pairs = lines
+            .iter()
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, _)| !name.trim().is_empty() && !name.trim().contains(' '))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<(String, String)>>();
+        if lines.is_empty() || pairs.len() != lines.len() {
+            return Headers::Raw(text);
+        }
+        Headers::from_pairs(pairs)
+    }
+
+    /// Reads headers back from the text stored in the requests table
+    pub fn from_stored(stored: &str) -> Headers {
+        match serde_json::from_str::<Value>(stored) {
+            Ok(Value::Object(map)) => {
+                Headers::from_object(map).unwrap_or(Headers::Raw(stored.to_string()))
+            }
+            _ => Headers::from_text(stored.to_string()),
+        }
+    }
+
+    /// Text stored in the requests table
+    pub fn to_stored(&self) -> String {
+        match self {
+            Headers::Structured(_) => serde_json::to_string(self).unwrap_or_default(),
+            Headers::Raw(text) => text.clone(),
+        }
+    }
+
+    pub fn pairs(&self) -> &[(String, String)] {
+        match self {
+            Headers::Structured(pairs) => pairs,
+            Headers::Raw(_) => &[],
+        }
+    }
+
+    /// Pairs of structured headers, or of the `Name: value` lines of raw ones, along with the
+    /// number of non-empty lines that aren't headers
+    pub fn parsed_pairs(&self) -> (Vec<(String, String)>, usize) {
+        let text = match self {
+            Headers::Structured(pairs) => return (pairs.clone(), 0),
+            Headers::Raw(text) => text,
+        };
+        let mut pairs = vec![];
+        let mut unparsed = 0;
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            match line.split_once(':') {
+                Some((name, value)) if !name.trim().is_empty() && !name.trim().contains(' ') => {
+                    pairs.push((name.trim().to_lowercase(), value.trim().to_string()))
+                }
+                _ => unparsed += 1,
+            }
+        }
+        (pairs, unparsed)
+    }
+}
+
+impl Serialize for Headers {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Headers::Structured(pairs) => {
+                let mut map = Map::new();
+                for (name, value) in pairs {
+                    match map.get_mut(name) {
+                        Some(Value::Array(values)) => values.push(Value::String(value.clone())),
+                        Some(existing) => {
+                            *existing =
+                                Value::Array(vec![existing.clone(), Value::String(value.clone())])
+                        }
+                        None => {
+                            map.insert(name.clone(), Value::String(value.clone()));
+                        }
+                    }
+                }
+                map.serialize(serializer)
+            }
+            Headers::Raw(text) => serializer.serialize_str(text),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Headers {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match HeadersInput::deserialize(deserializer)? {
+            HeadersInput::Object(map) => Headers::from_object(map).map_err(de::Error::custom),
+            HeadersInput::Pairs(pairs) => Ok(Headers::from_pairs(pairs)),
+            HeadersInput::Text(text) => Ok(Headers::from_text(text)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Display, EnumString, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum HeaderDirection {
+    Req,
+    Res,
+}
+
+/// Stores the normalized headers of a request so they can be filtered on, including the header
+/// lines of raw ones
+pub fn insert_request_headers(
+    conn: &Connection,
+    request_id: i64,
+    direction: HeaderDirection,
+    headers: &Headers,
+) -> Result<()> {
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO request_headers (request_id, direction, name, value) VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    for (name, value) in headers.parsed_pairs().0 {
+        stmt.execute(params![request_id, direction.to_string(), name, value])?;
+    }
+    Ok(())
+}
+
+/// Fills request_headers from requests logged before headers were normalized
+pub fn backfill_request_headers(conn: &Connection) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    let mut stmt = tx.prepare("SELECT id, req_headers, res_headers FROM requests")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+        ))
+    })?;
+    for row in rows {
+        let (id, req_headers, res_headers) = row?;
+        insert_request_headers(&tx, id, HeaderDirection::Req, &Headers::from_stored(&req_headers))?;
+        insert_request_headers(&tx, id, HeaderDirection::Res, &Headers::from_stored(&res_headers))?;
+    }
+    drop(stmt);
+    tx.commit()?;
+    Ok(())
+}
+
+/// Header filter, written as `name` (exists), `!name` (missing) or `name:value` (equals)
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeaderFilter {
+    pub direction: HeaderDirection,
+    pub name: String,
+    pub value: Option<String>,
+    pub negate: bool,
+}
+
+impl HeaderFilter {
+    pub fn parse(direction: HeaderDirection, filter: &str) -> Result<HeaderFilter> {
+        let (negate, filter) = match filter.trim().strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, filter.trim()),
+        };
+        let (name, value) = match filter.split_once(':') {
+            Some((name, value)) => (name, Some(value.trim().to_string())),
+            None => (filter, None),
+        };
+        let name = name.trim().to_lowercase();
+        if name.is_empty() {
+            return Err(anyhow!("Header filter '{}' is missing a header name", filter));
+        }
+        Ok(HeaderFilter {
+            direction,
+            name,
+            value,
+            negate,
+        })
+    }
+
+    /// SQL condition over the `requests` table and its parameters
+    pub fn to_sql(&self) -> (String, Vec<SqlValue>) {
+        let mut params = vec![
+            SqlValue::Text(self.direction.to_string()),
+            SqlValue::Text(self.name.clone()),
+        ];
+        let mut clause = "EXISTS (SELECT 1 FROM request_headers h \
+            WHERE h.request_id = requests.id AND h.direction = ? AND h.name = ?"
+            .to_string();
+        if let Some(value) = &self.value {
+            clause.push_str(" AND h.value = ?");
+            params.push(SqlValue::Text(value.clone()));
+        }
+        clause.push(')');
+        if self.negate {
+            clause = format!("NOT {}", clause);
+        }
+        (clause, params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn pairs(pairs: &[(&str, &str)]) -> Headers {
+        Headers::Structured(
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn parses_header_formats() {
+        let object: Headers = serde_json::from_value(json!({
+            "Content-Type": " application/json ",
+            "Set-Cookie": ["a=1", "b=2"],
+            "Content-Length": 12
+        }))
+        .unwrap();
+        assert_eq!(
+            object,
+            pairs(&[
+                ("content-length", "12"),
+                ("content-type", "application/json"),
+                ("set-cookie", "a=1"),
+                ("set-cookie", "b=2"),
+            ])
+        );
+        assert!(serde_json::from_value::<Headers>(json!({ "x": { "y": 1 } })).is_err());
+
+        let array: Headers =
+            serde_json::from_value(json!([["Accept", "*/*"], [" ", "dropped"]])).unwrap();
+        assert_eq!(array, pairs(&[("accept", "*/*")]));
+
+        let text: Headers =
+            serde_json::from_value(json!("Accept: */*\r\nX-Trace:  abc:def \n\n")).unwrap();
+        assert_eq!(text, pairs(&[("accept", "*/*"), ("x-trace", "abc:def")]));
+        assert_eq!(
+            serde_json::to_value(&object).unwrap(),
+            json!({
+                "content-type": "application/json",
+                "set-cookie": ["a=1", "b=2"],
+                "content-length": "12"
+            })
+        );
+    }
+
+    #[test]
+    fn keeps_unparsed_text_raw() {
+        let text = "GET / HTTP/1.1\nHost: example.com\nX-Id: 7";
+        let headers = Headers::from_text(text.to_string());
+        assert_eq!(headers, Headers::Raw(text.to_string()));
+        assert!(headers.pairs().is_empty());
+        assert_eq!(
+            headers.parsed_pairs(),
+            (
+                vec![
+                    ("host".to_string(), "example.com".to_string()),
+                    ("x-id".to_string(), "7".to_string())
+                ],
+                1
+            )
+        );
+        assert_eq!(Headers::from_text(String::new()), Headers::Raw(String::new()));
+        assert_eq!(headers.to_stored(), text);
+        assert_eq!(Headers::from_stored(text), headers);
+        assert_eq!(
+            Headers::from_stored(&pairs(&[("accept", "*/*")]).to_stored()),
+            pairs(&[("accept", "*/*")])
+        );
+    }
+
+    #[test]
+    fn parses_filters() {
+        assert_eq!(
+            HeaderFilter::parse(HeaderDirection::Req, " X-Api-Key ").unwrap(),
+            HeaderFilter {
+                direction: HeaderDirection::Req,
+                name: "x-api-key".to_string(),
+                value: None,
+                negate: false,
+            }
+        );
+        assert_eq!(
+            HeaderFilter::parse(HeaderDirection::Res, "!Cache-Control").unwrap(),
+            HeaderFilter {
+                direction: HeaderDirection::Res,
+                name: "cache-control".to_string(),
+                value: None,
+                negate: true,
+            }
+        );
+        assert_eq!(
+            HeaderFilter::parse(HeaderDirection::Res, "Content-Type: text/html; charset=utf-8").unwrap(),
+            HeaderFilter {
+                direction: HeaderDirection::Res,
+                name: "content-type".to_string(),
+                value: Some("text/html; charset=utf-8".to_string()),
+                negate: false,
+            }
+        );
+        assert!(HeaderFilter::parse(HeaderDirection::Req, "!").is_err());
+        assert!(HeaderFilter::parse(HeaderDirection::Req, ":value").is_err());
+    }
+
+    #[test]
+    fn filters_raw_headers() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE requests (id INTEGER PRIMARY KEY, req_headers TEXT, res_headers TEXT);
+            CREATE TABLE request_headers (request_id INTEGER, direction TEXT, name TEXT, value TEXT);
+            INSERT INTO requests VALUES (1, 'GET / HTTP/1.1\nX-Id: 7', '{}');
+            INSERT INTO requests VALUES (2, '{\"accept\": \"*/*\"}', '{}');",
+        )
+        .unwrap();
+        backfill_request_headers(&conn).unwrap();
+
+        let matching = |filter: &str| {
+            let (clause, params) = HeaderFilter::parse(HeaderDirection::Req, filter)
+                .unwrap()
+                .to_sql();
+            let mut stmt = conn
+                .prepare(&format!("SELECT id FROM requests WHERE {} ORDER BY id", clause))
+                .unwrap();
+            stmt.query_map(rusqlite::params_from_iter(params), |row| row.get(0))
+                .unwrap()
+                .collect::<rusqlite::Result<Vec<i64>>>()
+                .unwrap()
+        };
+        assert_eq!(matching("x-id"), vec![1]);
+        assert_eq!(matching("X-Id:7"), vec![1]);
+        assert_eq!(matching("x-id:8"), Vec::<i64>::new());
+        assert_eq!(matching("!x-id"), vec![2]);
+        assert_eq!(matching("!accept"), vec![1]);
+    }
+}
@@ -0,0 +1,600 @@
+//! Groups failed requests into issues by method, endpoint template, status and a fingerprint of the
+//! error message found in their response body, so repeated failures show up once.
+
+use super::requests::{request_summaries, RequestLog, RequestSummary, REQUEST_LOG_COLUMNS};
+use anyhow::Result;
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use regex::Regex;
+use rusqlite::{named_params, params, types::Value as SqlValue, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, str::FromStr, sync::LazyLock};
+use strum::{Display, EnumString};
+
+/// Responses from this status on are grouped into issues
+pub const MIN_ISSUE_STATUS: u16 = 400;
+/// Longest normalized message kept for an issue
+const MAX_MESSAGE_LENGTH: usize = 200;
+/// Hours of occurrence counts returned with an issue
+pub const ISSUE_TREND_HOURS: i64 = 24 * 7;
+
+/// Body fields holding the error message, tried in order
+const MESSAGE_PATHS: [&[&str]; 7] = [
+    &["error", "message"],
+    &["error"],
+    &["message"],
+    &["detail"],
+    &["title"],
+    &["error_description"],
+    &["errors", "0", "message"],
+];
+
+static UUID: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)\b[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}\b").unwrap()
+});
+static EMAIL: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"[\w.+-]+@[\w-]+\.[\w.-]+").unwrap());
+static QUOTED: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#""[^"]*"|'[^']*'"#).unwrap());
+static HEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)\b(0x)?[0-9a-f]*\d[0-9a-f]*\b").unwrap());
+static SPACES: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\s+").unwrap());
+
+fn format_date(date: DateTime<Utc>) -> String {
+    date.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+fn hour(date: DateTime<Utc>) -> String {
+    format_date(date.duration_trunc(Duration::hours(1)).unwrap_or(date))
+}
+
+/// Error message of a response body: the first string at a usual error field of a JSON body,
+/// or the first line of a text body
+pub fn error_message(body: &Value) -> Option<String> {
+    let message = match body {
+        Value::String(text) => text.lines().find(|l| !l.trim().is_empty()).map(str::to_string),
+        _ => MESSAGE_PATHS.iter().find_map(|path| {
+            path.iter()
+                .try_fold(body, |value, key| match value {
+                    Value::Array(items) => items.get(key.parse::<usize>().ok()?),
+                    _ => value.get(key),
+                })?
+                .as_str()
+                .map(str::to_string)
+        }),
+    };
+    message.filter(|m| !m.trim().is_empty())
+}
+
+/// Replaces the variable parts of a message, like ids, numbers and quoted values, with placeholders
+pub fn normalize_message(message: &str) -> String {
+    let message = UUID.replace_all(message, "<id>");
+    let message = EMAIL.replace_all(&message, "<email>");
+    let message = QUOTED.replace_all(&message, "<value>");
+    let message = HEX.replace_all(&message, "<n>");
+    SPACES
+        .replace_all(message.trim(), " ")
+        .chars()
+        .take(MAX_MESSAGE_LENGTH)
+        .collect()
+}
+
+/// What failed requests of the same issue have in common
+#[derive(Debug, Clone, PartialEq)]
+pub struct IssueKey {
+    pub method: String,
+    pub endpoint_template: String,
+    pub status: u16,
+    pub message: Option<String>,
+}
+
+impl IssueKey {
+    /// Key of a failed request, `None` for successful ones
+    pub fn from_log(log: &RequestLog) -> Option<IssueKey> {
+        if log.status < MIN_ISSUE_STATUS {
+            return None;
+        }
+        Some(IssueKey {
+            method: log.method.clone(),
+            endpoint_template: log.endpoint_template.clone().unwrap_or(log.endpoint.clone()),
+            status: log.status,
+            message: log
+                .res_body
+                .as_ref()
+                .and_then(error_message)
+                .map(|m| normalize_message(&m)),
+        })
+    }
+
+    pub fn fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        for part in [
+            self.method.as_str(),
+            self.endpoint_template.as_str(),
+            &self.status.to_string(),
+            self.message.as_deref().unwrap_or_default(),
+        ] {
+            hasher.update(part.as_bytes());
+            hasher.update([0]);
+        }
+        hasher.finalize()[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Display, EnumString, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum IssueState {
+    Open,
+    Resolved,
+    /// Still counted but never reopened
+    Ignored,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IssueTrend {
+    /// First seen in the last day
+    New,
+    Rising,
+    Falling,
+    Steady,
+}
+
+impl IssueTrend {
+    /// Compares the occurrences of the last day with the day before
+    fn between(first_seen: DateTime<Utc>, last_day: usize, previous_day: usize) -> IssueTrend {
+        if first_seen > Utc::now() - Duration::days(1) {
+            IssueTrend::New
+        } else if last_day * 2 > previous_day * 3 {
+            IssueTrend::Rising
+        } else if last_day * 3 < previous_day * 2 {
+            IssueTrend::Falling
+        } else {
+            IssueTrend::Steady
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Issue {
+    pub id: i64,
+    pub fingerprint: String,
+    pub method: String,
+    pub endpoint_template: String,
+    pub status: u16,
+    /// Normalized error message, missing when the bodies had none
+    pub message: Option<String>,
+    pub state: IssueState,
+    /// Set when the issue came back after being resolved, until it is resolved again
+    pub regressed: bool,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub resolved_at: Option<DateTime<Utc>>,
+    pub occurrences: i64,
+    /// Occurrences in the last 24 hours and the 24 hours before
+    pub last_day: usize,
+    pub previous_day: usize,
+    pub trend: IssueTrend,
+    pub last_request_id: Option<i64>,
+}
+
+const ISSUE_COLUMNS: &str = "id, fingerprint, method, endpoint_template, status, message, state, \
+    regressed, first_seen, last_seen, resolved_at, occurrences, last_request_id, \
+    (SELECT COALESCE(SUM(count), 0) FROM issue_occurrences o \
+        WHERE o.issue_id = issues.id AND o.bucket >= :last_day) AS last_day, \
+    (SELECT COALESCE(SUM(count), 0) FROM issue_occurrences o \
+        WHERE o.issue_id = issues.id AND o.bucket >= :previous_day AND o.bucket < :last_day) AS previous_day";
+
+fn parse_date(date: String) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(&date)?.with_timezone(&Utc))
+}
+
+impl Issue {
+    /// Builds an issue from a row selected with `ISSUE_COLUMNS`
+    fn from_row(row: &Row) -> Result<Issue> {
+        let first_seen = parse_date(row.get(8)?)?;
+        let last_day = row.get(13)?;
+        let previous_day = row.get(14)?;
+        Ok(Issue {
+            id: row.get(0)?,
+            fingerprint: row.get(1)?,
+            method: row.get(2)?,
+            endpoint_template: row.get(3)?,
+            status: row.get(4)?,
+            message: row.get(5)?,
+            state: IssueState::from_str(&row.get::<_, String>(6)?)?,
+            regressed: row.get(7)?,
+            first_seen,
+            last_seen: parse_date(row.get(9)?)?,
+            resolved_at: row.get::<_, Option<String>>(10)?.map(parse_date).transpose()?,
+            occurrences: row.get(11)?,
+            last_request_id: row.get(12)?,
+            last_day,
+            previous_day,
+            trend: IssueTrend::between(first_seen, last_day, previous_day),
+        })
+    }
+}
+
+/// Day boundaries `ISSUE_COLUMNS` counts occurrences from
+fn trend_params() -> (String, String) {
+    let now = Utc::now();
+    (hour(now - Duration::days(1)), hour(now - Duration::days(2)))
+}
+
+/// Counts a failed request towards its issue, opening the issue if it's new and reopening it as a
+/// regression if it was resolved before the request. Returns the issue id, `None` for successes.
+pub fn record_issue(log: &RequestLog, conn: &Connection) -> Result<Option<i64>> {
+    let tx = conn.unchecked_transaction()?;
+    let id = count_issue(log, &tx)?;
+    tx.commit()?;
+    Ok(id)
+}
+
+/// `record_issue` within the caller's transaction
+fn count_issue(log: &RequestLog, tx: &Connection) -> Result<Option<i64>> {
+    let (Some(key), Some(request_id)) = (IssueKey::from_log(log), log.id) else {
+        return Ok(None);
+    };
+    let fingerprint = key.fingerprint();
+    let date = format_date(log.date);
+    let existing = tx
+        .query_row(
+            "SELECT id, state, resolved_at FROM issues WHERE fingerprint = ?1",
+            params![fingerprint],
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                ))
+            },
+        )
+        .optional()?;
+    let id = match existing {
+        Some((id, state, resolved_at)) => {
+            let regressed = state == IssueState::Resolved.to_string()
+                && resolved_at.is_none_or(|resolved_at| date > resolved_at);
+            tx.execute(
+                "UPDATE issues SET
+                    occurrences = occurrences + 1,
+                    first_seen = MIN(first_seen, ?2),
+                    last_request_id = CASE WHEN ?2 >= last_seen THEN ?3 ELSE last_request_id END,
+                    last_seen = MAX(last_seen, ?2),
+                    state = CASE WHEN ?4 THEN 'open' ELSE state END,
+                    regressed = regressed OR ?4
+                WHERE id = ?1",
+                params![id, date, request_id, regressed],
+            )?;
+            id
+        }
+        None => {
+            tx.execute(
+                "INSERT INTO issues (fingerprint, method, endpoint_template, status, message, state,
+                    regressed, first_seen, last_seen, occurrences, last_request_id)
+                VALUES (?1, ?2, ?3, ?4, ?5, 'open', 0, ?6, ?6, 1, ?7)",
+                params![
+                    fingerprint,
+                    key.method,
+                    key.endpoint_template,
+                    key.status,
+                    key.message,
+                    date,
+                    request_id
+                ],
+            )?;
+            tx.last_insert_rowid()
+        }
+    };
+    tx.execute(
+        "INSERT INTO issue_occurrences (issue_id, bucket, count) VALUES (?1, ?2, 1)
+        ON CONFLICT(issue_id, bucket) DO UPDATE SET count = count + 1",
+        params![id, hour(log.date)],
+    )?;
+    tx.execute(
+        "UPDATE requests SET issue_id = ?1 WHERE id = ?2",
+        params![id, request_id],
+    )?;
+    Ok(Some(id))
+}
+
+/// Groups the failed requests logged before issues existed, oldest first. Runs once, in a single
+/// transaction that also records it in `backfills`, so it is retried whole if it fails.
+pub fn backfill_issues(conn: &Connection) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    let claimed = tx.execute(
+        "INSERT OR IGNORE INTO backfills (name, completed_at) VALUES ('issues', ?1)",
+        params![format_date(Utc::now())],
+    )?;
+    if claimed == 0 {
+        return Ok(());
+    }
+    {
+        let mut stmt = tx.prepare(&format!(
+            "SELECT {} FROM requests WHERE CAST(status AS INTEGER) >= ?1 ORDER BY date, id",
+            REQUEST_LOG_COLUMNS
+        ))?;
+        let mut rows = stmt.query(params![MIN_ISSUE_STATUS])?;
+        while let Some(row) = rows.next()? {
+            count_issue(&RequestLog::from_row(row)?, &tx)?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum IssueSort {
+    #[default]
+    LastSeen,
+    FirstSeen,
+    Occurrences,
+    /// Occurrences in the last 24 hours
+    LastDay,
+}
+
+impl IssueSort {
+    fn sql(&self) -> &'static str {
+        match self {
+            IssueSort::LastSeen => "last_seen DESC, id DESC",
+            IssueSort::FirstSeen => "first_seen DESC, id DESC",
+            IssueSort::Occurrences => "occurrences DESC, last_seen DESC",
+            IssueSort::LastDay => "last_day DESC, last_seen DESC",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PaginatedIssues {
+    pub issues: Vec<Issue>,
+    pub total_items: usize,
+}
+
+pub fn get_issues(
+    state: Option<IssueState>,
+    status_class: Option<u16>,
+    regressed: Option<bool>,
+    sort: IssueSort,
+    index: usize,
+    limit: usize,
+    conn: &Connection,
+) -> Result<PaginatedIssues> {
+    let (last_day, previous_day) = trend_params();
+    let filter = "(state = :state OR :state IS NULL)
+        AND (status / 100 = :status_class OR :status_class IS NULL)
+        AND (regressed = :regressed OR :regressed IS NULL)";
+    let state = state.map(|s| s.to_string());
+    let total_items = conn.query_row(
+        &format!("SELECT COUNT(*) FROM issues WHERE {}", filter),
+        named_params! {":state": state, ":status_class": status_class, ":regressed": regressed},
+        |row| row.get::<_, usize>(0),
+    )?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM issues WHERE {} ORDER BY {} LIMIT :limit OFFSET :index",
+        ISSUE_COLUMNS,
+        filter,
+        sort.sql()
+    ))?;
+    let mut rows = stmt.query(named_params! {
+        ":last_day": last_day,
+        ":previous_day": previous_day,
+        ":state": state,
+        ":status_class": status_class,
+        ":regressed": regressed,
+        ":limit": limit,
+        ":index": index,
+    })?;
+    let mut issues = vec![];
+    while let Some(row) = rows.next()? {
+        issues.push(Issue::from_row(row)?);
+    }
+    Ok(PaginatedIssues { issues, total_items })
+}
+
+pub fn issue_by_id(id: i64, conn: &Connection) -> Result<Option<Issue>> {
+    let (last_day, previous_day) = trend_params();
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM issues WHERE id = :id", ISSUE_COLUMNS))?;
+    let mut rows = stmt.query(named_params! {
+        ":last_day": last_day,
+        ":previous_day": previous_day,
+        ":id": id,
+    })?;
+    match rows.next()? {
+        Some(row) => Ok(Some(Issue::from_row(row)?)),
+        None => Ok(None),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct IssueBucket {
+    pub date: DateTime<Utc>,
+    pub count: usize,
+}
+
+/// Hourly occurrences of an issue over the last `ISSUE_TREND_HOURS`, with empty hours filled in
+pub fn issue_trend(id: i64, conn: &Connection) -> Result<Vec<IssueBucket>> {
+    let now = Utc::now().duration_trunc(Duration::hours(1))?;
+    let start = now - Duration::hours(ISSUE_TREND_HOURS - 1);
+    let mut stmt = conn
+        .prepare("SELECT bucket, count FROM issue_occurrences WHERE issue_id = ?1 AND bucket >= ?2")?;
+    let counts = stmt
+        .query_map(params![id, format_date(start)], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, usize>(1)?))
+        })?
+        .collect::<Result<HashMap<String, usize>, _>>()?;
+    Ok((0..ISSUE_TREND_HOURS)
+        .map(|h| {
+            let date = start + Duration::hours(h);
+            IssueBucket {
+                date,
+                count: counts.get(&format_date(date)).copied().unwrap_or_default(),
+            }
+        })
+        .collect())
+}
+
+/// Latest requests of an issue
+pub fn issue_requests(id: i64, limit: usize, conn: &Connection) -> Result<Vec<RequestSummary>> {
+    request_summaries(
+        "SELECT id, date, method, status, res_time FROM requests
+        WHERE issue_id = ? ORDER BY date DESC, id DESC LIMIT ?",
+        &[SqlValue::Integer(id), SqlValue::Integer(limit as i64)],
+        conn,
+    )
+}
+
+/// Moves an issue to `state`, returning it before and after the change
+pub fn set_issue_state(id: i64, state: IssueState, conn: &Connection) -> Result<Option<(Issue, Issue)>> {
+    let Some(before) = issue_by_id(id, conn)? else {
+        return Ok(None);
+    };
+    conn.execute(
+        "UPDATE issues SET
+            state = ?2,
+            resolved_at = CASE WHEN ?2 = 'resolved' THEN ?3 ELSE NULL END,
+            regressed = CASE WHEN ?2 = 'open' THEN regressed ELSE 0 END
+        WHERE id = ?1",
+        params![id, state.to_string(), format_date(Utc::now())],
+    )?;
+    let after = issue_by_id(id, conn)?.unwrap_or(before.clone());
+    Ok(Some((before, after)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db::connection::connect_db, ops::requests::insert_request_log};
+    use serde_json::json;
+
+    fn log(status: u16, body: Value, date: DateTime<Utc>) -> RequestLog {
+        RequestLog {
+            id: None,
+            method: "POST".to_string(),
+            endpoint: "https://api.example.com/orders/42".to_string(),
+            endpoint_template: Some("https://api.example.com/orders/:id".to_string()),
+            service: None,
+            trace_id: None,
+            span_id: None,
+            parent_span_id: None,
+            params: String::new(),
+            req_headers: Default::default(),
+            res_headers: Default::default(),
+            date,
+            status,
+            req_body: None,
+            res_body: Some(body),
+            res_time: 10,
+        }
+    }
+
+    fn fingerprint(status: u16, body: Value) -> Option<String> {
+        IssueKey::from_log(&log(status, body, Utc::now())).map(|key| key.fingerprint())
+    }
+
+    #[test]
+    fn finds_error_messages() {
+        let message = |body: Value| error_message(&body);
+        assert_eq!(
+            message(json!({ "error": { "message": "Card declined" }, "message": "Bad request" })),
+            Some("Card declined".to_string())
+        );
+        assert_eq!(
+            message(json!({ "error": { "code": 12 }, "message": "Bad request" })),
+            Some("Bad request".to_string())
+        );
+        assert_eq!(
+            message(json!({ "errors": [{ "message": "Name is required" }] })),
+            Some("Name is required".to_string())
+        );
+        assert_eq!(
+            message(json!("\n  \nUpstream timed out\nat line 3")),
+            Some("Upstream timed out".to_string())
+        );
+        assert_eq!(message(json!({ "error": " " })), None);
+        assert_eq!(message(json!({ "code": 500 })), None);
+        assert_eq!(message(json!(null)), None);
+    }
+
+    #[test]
+    fn normalizes_messages() {
+        assert_eq!(normalize_message("Order 123 failed"), "Order <n> failed");
+        assert_eq!(
+            normalize_message("User 3f2b8c1e-9d4a-4b7e-a1c2-5d6e7f8a9b0c not found"),
+            "User <id> not found"
+        );
+        assert_eq!(
+            normalize_message("No account for jane.doe+test@example.com"),
+            "No account for <email>"
+        );
+        assert_eq!(
+            normalize_message("Invalid value 'abc' for \"currency\""),
+            "Invalid value <value> for <value>"
+        );
+        assert_eq!(
+            normalize_message("  Checksum  0x1f3a\tdidn't match a1b2 "),
+            "Checksum <n> didn't match <n>"
+        );
+        assert_eq!(normalize_message(&"x".repeat(500)).len(), MAX_MESSAGE_LENGTH);
+    }
+
+    #[test]
+    fn fingerprints_failures() {
+        let order = |id: u32| json!({ "error": format!("Order {} failed", id) });
+        assert!(fingerprint(500, order(123)).is_some());
+        assert_eq!(fingerprint(500, order(123)), fingerprint(500, order(456)));
+        assert_ne!(fingerprint(500, order(123)), fingerprint(502, order(123)));
+        assert_ne!(
+            fingerprint(500, order(123)),
+            fingerprint(500, json!({ "error": "Out of stock" }))
+        );
+        assert_eq!(fingerprint(500, json!({})), fingerprint(500, json!("")));
+        assert_eq!(fingerprint(399, order(123)), None);
+
+        let mut untemplated = log(404, json!({}), Utc::now());
+        untemplated.endpoint_template = None;
+        let key = IssueKey::from_log(&untemplated).unwrap();
+        assert_eq!(key.endpoint_template, "https://api.example.com/orders/42");
+        assert_eq!(key.message, None);
+        assert_eq!(key.fingerprint().len(), 16);
+    }
+
+    #[test]
+    fn flags_regressions() {
+        let conn = connect_db(":memory:");
+        let record = |date: DateTime<Utc>| {
+            let mut log = log(500, json!({ "error": "Order 1 failed" }), date);
+            log.id = Some(insert_request_log(&conn, &log).unwrap());
+            record_issue(&log, &conn).unwrap().unwrap()
+        };
+        let id = record(Utc::now() - Duration::hours(2));
+        assert_eq!(record(Utc::now() - Duration::hours(1)), id);
+        let issue = issue_by_id(id, &conn).unwrap().unwrap();
+        assert_eq!(
+            (issue.occurrences, issue.state, issue.regressed),
+            (2, IssueState::Open, false)
+        );
+
+        set_issue_state(id, IssueState::Resolved, &conn).unwrap();
+        // A late log of a failure from before the fix doesn't reopen the issue
+        record(Utc::now() - Duration::minutes(30));
+        let issue = issue_by_id(id, &conn).unwrap().unwrap();
+        assert_eq!((issue.state, issue.regressed), (IssueState::Resolved, false));
+
+        record(Utc::now() + Duration::minutes(1));
+        let issue = issue_by_id(id, &conn).unwrap().unwrap();
+        assert_eq!((issue.state, issue.regressed), (IssueState::Open, true));
+        assert_eq!(issue.occurrences, 4);
+
+        set_issue_state(id, IssueState::Ignored, &conn).unwrap();
+        record(Utc::now() + Duration::minutes(2));
+        let issue = issue_by_id(id, &conn).unwrap().unwrap();
+        assert_eq!((issue.state, issue.regressed), (IssueState::Ignored, false));
+    }
+}
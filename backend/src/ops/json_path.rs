@@ -0,0 +1,153 @@
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonPathSegment {
+    Key(String),
+    Index(usize),
+    Wildcard,
+    /// `..key`, matches the key at any depth
+    Descendant(String),
+}
+
+/// A JSON path such as `$.card.number`, `$.items[*].token`, `$['a key']` or `$..password`
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonPath(pub Vec<JsonPathSegment>);
+
+impl FromStr for JsonPath {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let rest = s
+            .strip_prefix('$')
+            .ok_or(anyhow!("JSON path '{}' must start with '$'", s))?;
+        let mut segments = vec![];
+        let mut chars = rest.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '.' => {
+                    let descendant = chars.peek() == Some(&'.');
+                    if descendant {
+                        chars.next();
+                    }
+                    let mut key = String::new();
+                    while let Some(&c) = chars.peek() {
+                        if c == '.' || c == '[' {
+                            break;
+                        }
+                        key.push(c);
+                        chars.next();
+                    }
+                    match (descendant, key.as_str()) {
+                        (_, "") => return Err(anyhow!("Empty key in JSON path '{}'", s)),
+                        (false, "*") => segments.push(JsonPathSegment::Wildcard),
+                        (true, _) => segments.push(JsonPathSegment::Descendant(key)),
+                        (false, _) => segments.push(JsonPathSegment::Key(key)),
+                    }
+                }
+                '[' => {
+                    let mut inner = String::new();
+                    for c in chars.by_ref() {
+                        if c == ']' {
+                            break;
+                        }
+                        inner.push(c);
+                    }
+                    let inner = inner.trim();
+                    if inner == "*" {
+                        segments.push(JsonPathSegment::Wildcard);
+                    } else if let Ok(index) = inner.parse::<usize>() {
+                        segments.push(JsonPathSegment::Index(index));
+                    } else {
+                        let key = inner.trim_matches(|c| c == '\'' || c == '"');
+                        if key.is_empty() {
+                            return Err(anyhow!("Invalid selector '[{}]' in JSON path '{}'", inner, s));
+                        }
+                        segments.push(JsonPathSegment::Key(key.to_string()));
+                    }
+                }
+                _ => return Err(anyhow!("Unexpected '{}' in JSON path '{}'", c, s)),
+            }
+        }
+        Ok(JsonPath(segments))
+    }
+}
+
+impl JsonPath {
+    /// Calls `f` on every value matched by the path
+    pub fn visit_mut(&self, value: &mut Value, f: &mut dyn FnMut(&mut Value)) {
+        visit_segments(&self.0, value, f);
+    }
+
+    /// The first value matched by the path
+    pub fn first(&self, value: &Value) -> Option<Value> {
+        let mut value = value.clone();
+        let mut first = None;
+        self.visit_mut(&mut value, &mut |v| {
+            if first.is_none() {
+                first = Some(v.clone());
+            }
+        });
+        first
+    }
+}
+
+fn visit_segments(segments: &[JsonPathSegment], value: &mut Value, f: &mut dyn FnMut(&mut Value)) {
+    let Some((segment, rest)) = segments.split_first() else {
+        f(value);
+        return;
+    };
+    match segment {
+        JsonPathSegment::Key(key) => {
+            if let Some(child) = value.get_mut(key.as_str()) {
+                visit_segments(rest, child, f);
+            }
+        }
+        JsonPathSegment::Index(index) => {
+            if let Some(child) = value.get_mut(*index) {
+                visit_segments(rest, child, f);
+            }
+        }
+        JsonPathSegment::Wildcard => match value {
+            Value::Object(map) => map.values_mut().for_each(|child| visit_segments(rest, child, f)),
+            Value::Array(items) => items.iter_mut().for_each(|child| visit_segments(rest, child, f)),
+            _ => (),
+        },
+        JsonPathSegment::Descendant(key) => match value {
+            Value::Object(map) => {
+                for (k, child) in map.iter_mut() {
+                    if k == key {
+                        visit_segments(rest, child, f);
+                    } else {
+                        visit_segments(segments, child, f);
+                    }
+                }
+            }
+            Value::Array(items) => items
+                .iter_mut()
+                .for_each(|child| visit_segments(segments, child, f)),
+            _ => (),
+        },
+    }
+}
+
+/// Renders path segments as a SQLite JSON1 path. Wildcards and `..key` have no JSON1 equivalent.
+pub fn sqlite_path(segments: &[JsonPathSegment]) -> Result<String> {
+    let mut path = "$".to_string();
+    for segment in segments {
+        match segment {
+            JsonPathSegment::Key(key) if key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') => {
+                path.push_str(&format!(".{}", key))
+            }
+            JsonPathSegment::Key(key) if !key.contains('"') => path.push_str(&format!(".\"{}\"", key)),
+            JsonPathSegment::Key(key) => return Err(anyhow!("Unsupported key '{}' in JSON path", key)),
+            JsonPathSegment::Index(index) => path.push_str(&format!("[{}]", index)),
+            JsonPathSegment::Wildcard => return Err(anyhow!("Unexpected wildcard in JSON path")),
+            JsonPathSegment::Descendant(key) => {
+                return Err(anyhow!("'..{}' is not supported in body filters", key))
+            }
+        }
+    }
+    Ok(path)
+}
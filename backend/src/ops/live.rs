@@ -0,0 +1,681 @@
+//! Live feeds of the WebSocket and Server-Sent Events endpoints. Ingestion publishes every stored
+//! log to a `RequestHub`, which fans them out to the subscribed clients without touching the
+//! database. WebSocket clients can also subscribe to rolling SLIs, per-second counters and uptime
+//! changes, every message being tagged by its channel.
+
+use super::{
+    endpoints::split_origin,
+    filters::RequestFilter,
+    query::Query,
+    requests::{window_slis, RequestLog, ServiceLevelIndicators},
+    stats::TimeWindow,
+    uptime::{uptime_states, UptimeState},
+};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
+use futures_util::future;
+use regex::Regex;
+use rusqlite::{params_from_iter, types::Value as SqlValue, Connection};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    str::FromStr,
+    sync::Arc,
+};
+use tokio::sync::broadcast::{
+    self,
+    error::{RecvError, TryRecvError},
+};
+
+/// Requests kept for each subscriber between two reads. Subscribers falling further behind
+/// skip the oldest ones.
+pub const LIVE_CHANNEL_CAPACITY: usize = 1024;
+/// Requests kept for a paused subscriber, the oldest being dropped first
+pub const PAUSE_BUFFER_SIZE: usize = 1000;
+/// Rolling window of the SLI channel when the client doesn't give one
+pub const DEFAULT_SLI_WINDOW_SECONDS: i64 = 300;
+pub const MAX_SLI_WINDOW_SECONDS: i64 = 86_400;
+/// How often rolling SLIs are recomputed
+const SLI_REFRESH_SECONDS: i64 = 5;
+/// How often uptime states are checked for changes
+const UPTIME_REFRESH_SECONDS: i64 = 1;
+
+/// Fans out newly stored request logs to live subscribers
+#[derive(Debug, Clone)]
+pub struct RequestHub {
+    sender: broadcast::Sender<Arc<RequestLog>>,
+}
+
+impl Default for RequestHub {
+    fn default() -> Self {
+        RequestHub {
+            sender: broadcast::channel(LIVE_CHANNEL_CAPACITY).0,
+        }
+    }
+}
+
+impl RequestHub {
+    /// Publishes a stored log, doing nothing when nobody is subscribed
+    pub fn publish(&self, log: RequestLog) {
+        if self.sender.receiver_count() > 0 {
+            let _ = self.sender.send(Arc::new(log));
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<RequestLog>> {
+        self.sender.subscribe()
+    }
+
+    /// Published logs not yet read by every subscriber
+    pub fn queued(&self) -> usize {
+        self.sender.len()
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.sender.receiver_count()
+    }
+}
+
+/// Requests a subscriber wants, every condition given having to match
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LiveFilter {
+    /// Any of these methods
+    #[serde(default)]
+    pub method: Vec<String>,
+    /// Any of these statuses, exact like `404` or classes like `5xx`
+    #[serde(default)]
+    pub status: Vec<String>,
+    /// Glob on the endpoint or its template, on their path when starting with '/'
+    pub endpoint: Option<String>,
+    /// Glob on the service
+    pub service: Option<String>,
+    /// Filter query, checked against the stored requests
+    pub q: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum StatusPattern {
+    Exact(u16),
+    Class(u16),
+}
+
+fn parse_status(status: &str) -> Result<StatusPattern> {
+    let invalid = || anyhow!("Invalid status '{}', expected e.g. 404 or 5xx", status);
+    match status.strip_suffix("xx") {
+        Some(class) => class.parse().map(StatusPattern::Class).map_err(|_| invalid()),
+        None => status.parse().map(StatusPattern::Exact).map_err(|_| invalid()),
+    }
+}
+
+fn glob_regex(glob: &str) -> Result<Regex> {
+    let pattern = glob
+        .split('*')
+        .map(|part| {
+            part.split('?')
+                .map(regex::escape)
+                .collect::<Vec<String>>()
+                .join(".")
+        })
+        .collect::<Vec<String>>()
+        .join(".*");
+    Ok(Regex::new(&format!("^{}$", pattern))?)
+}
+
+/// A compiled `LiveFilter`
+#[derive(Debug, Clone)]
+pub struct Subscription {
+    pub filter: LiveFilter,
+    methods: Vec<String>,
+    statuses: Vec<StatusPattern>,
+    endpoint: Option<(Regex, bool)>,
+    service: Option<Regex>,
+    query: Option<RequestFilter>,
+}
+
+impl Subscription {
+    pub fn new(filter: LiveFilter) -> Result<Subscription> {
+        let query = match filter.q.as_deref().filter(|q| !q.trim().is_empty()) {
+            Some(q) => {
+                let mut query = RequestFilter::default();
+                Query::from_str(q)
+                    .map_err(|e| anyhow!("Invalid query: {}", e))?
+                    .apply(&mut query);
+                Some(query)
+            }
+            None => None,
+        };
+        Ok(Subscription {
+            methods: filter.method.iter().map(|m| m.to_uppercase()).collect(),
+            statuses: filter
+                .status
+                .iter()
+                .map(|s| parse_status(s))
+                .collect::<Result<Vec<StatusPattern>>>()?,
+            endpoint: filter
+                .endpoint
+                .as_deref()
+                .map(|e| Ok::<_, anyhow::Error>((glob_regex(e)?, e.starts_with('/'))))
+                .transpose()?,
+            service: filter
+                .service
+                .as_deref()
+                .map(|s| glob_regex(&s.to_lowercase()))
+                .transpose()?,
+            query,
+            filter,
+        })
+    }
+
+    /// Subscription to the requests matching a filter built from request parameters
+    pub fn matching(query: RequestFilter) -> Subscription {
+        Subscription {
+            filter: LiveFilter::default(),
+            methods: vec![],
+            statuses: vec![],
+            endpoint: None,
+            service: None,
+            query: Some(query),
+        }
+    }
+
+    /// Checks the conditions that don't need the database
+    fn matches(&self, log: &RequestLog) -> bool {
+        let endpoint_matches = |endpoint: &str, (pattern, path): &(Regex, bool)| match path {
+            true => pattern.is_match(split_origin(endpoint).1),
+            false => pattern.is_match(endpoint),
+        };
+        (self.methods.is_empty() || self.methods.contains(&log.method.to_uppercase()))
+            && (self.statuses.is_empty()
+                || self.statuses.iter().any(|s| match s {
+                    StatusPattern::Exact(status) => log.status == *status,
+                    StatusPattern::Class(class) => log.status / 100 == *class,
+                }))
+            && self.endpoint.as_ref().is_none_or(|pattern| {
+                endpoint_matches(&log.endpoint, pattern)
+                    || log
+                        .endpoint_template
+                        .as_deref()
+                        .is_some_and(|template| endpoint_matches(template, pattern))
+            })
+            && self.service.as_ref().is_none_or(|pattern| {
+                log.service
+                    .as_deref()
+                    .is_some_and(|service| pattern.is_match(service))
+            })
+    }
+
+    /// The logs matching the subscription, looking up the stored rows for the `q` query
+    pub fn select(&self, logs: Vec<Arc<RequestLog>>, conn: &Connection) -> Result<Vec<Arc<RequestLog>>> {
+        let logs = logs
+            .into_iter()
+            .filter(|log| self.matches(log))
+            .collect::<Vec<Arc<RequestLog>>>();
+        let Some(query) = self.query.as_ref().filter(|_| !logs.is_empty()) else {
+            return Ok(logs);
+        };
+
+        let mut params = logs
+            .iter()
+            .map(|log| SqlValue::Integer(log.id.unwrap_or_default()))
+            .collect::<Vec<SqlValue>>();
+        params.extend_from_slice(query.params());
+        let ids = query.run(conn, || {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT requests.id FROM requests WHERE requests.id IN ({}) AND {}",
+                vec!["?"; logs.len()].join(", "),
+                query.sql()
+            ))?;
+            let rows = stmt.query_map(params_from_iter(params), |row| row.get::<_, i64>(0))?;
+            Ok(rows.collect::<Result<Vec<i64>, _>>()?)
+        })?;
+        Ok(logs
+            .into_iter()
+            .filter(|log| log.id.is_some_and(|id| ids.contains(&id)))
+            .collect())
+    }
+}
+
+/// Feeds a client can subscribe to
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    /// New requests matching a `LiveFilter`
+    #[default]
+    Requests,
+    /// Rolling service level indicators
+    Sli,
+    /// Requests and errors of each second
+    Counters,
+    /// Changes of the monitored urls' uptime
+    Uptime,
+}
+
+/// Messages sent by live tail clients once authenticated
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ClientMessage {
+    /// Subscribes to a channel, replacing the requests channel's filter or the SLI window
+    Subscribe {
+        #[serde(default)]
+        channel: Channel,
+        #[serde(default)]
+        filter: LiveFilter,
+        /// Rolling window of the SLI channel, in seconds
+        window: Option<i64>,
+    },
+    Unsubscribe {
+        channel: Channel,
+    },
+    /// Buffers requests instead of sending them, up to `PAUSE_BUFFER_SIZE`
+    Pause,
+    /// Sends the buffered requests and goes back to live
+    Resume,
+}
+
+/// Messages sent to live tail clients
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ServerMessage {
+    /// New requests, oldest first
+    Requests {
+        requests: Vec<RequestLog>,
+    },
+    /// SLIs over the last `window` seconds, sent when they change
+    Sli {
+        window: i64,
+        sli: ServiceLevelIndicators,
+    },
+    /// Requests stored during the second starting at `second`, `errors` being the 5xx ones
+    Counters {
+        second: DateTime<Utc>,
+        requests: u64,
+        errors: u64,
+    },
+    /// Urls whose uptime state changed, every monitored url right after subscribing
+    Uptime {
+        states: Vec<UptimeState>,
+    },
+    Subscribed {
+        /// Filter of the requests channel
+        #[serde(skip_serializing_if = "Option::is_none")]
+        filter: Option<LiveFilter>,
+        /// Window of the SLI channel
+        #[serde(skip_serializing_if = "Option::is_none")]
+        window: Option<i64>,
+    },
+    Unsubscribed,
+    Paused,
+    Resumed,
+    /// Requests skipped because the client was too slow or paused for too long
+    Dropped {
+        count: u64,
+    },
+    Error {
+        msg: String,
+    },
+}
+
+/// A message tagged with the channel it belongs to
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ChannelMessage {
+    pub channel: Channel,
+    #[serde(flatten)]
+    pub message: ServerMessage,
+}
+
+/// State of one live tail client
+pub struct LiveTail {
+    receiver: broadcast::Receiver<Arc<RequestLog>>,
+    subscription: Subscription,
+    paused: bool,
+    /// Received by `wait` and not polled yet
+    pending: Vec<Arc<RequestLog>>,
+    buffer: VecDeque<Arc<RequestLog>>,
+    dropped: u64,
+}
+
+impl LiveTail {
+    pub fn new(hub: &RequestHub, subscription: Subscription) -> LiveTail {
+        LiveTail {
+            receiver: hub.subscribe(),
+            subscription,
+            paused: false,
+            pending: vec![],
+            buffer: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Waits until a request is published, keeping it for the next poll. Safe to cancel, so it
+    /// can be raced against other events.
+    pub async fn wait(&mut self) {
+        match self.receiver.recv().await {
+            Ok(log) => self.pending.push(log),
+            Err(RecvError::Lagged(skipped)) => self.dropped += skipped,
+            // The hub lives as long as the server
+            Err(RecvError::Closed) => future::pending().await,
+        }
+    }
+
+    /// Collects the requests published since the last poll, returning what to send the client
+    pub fn poll(&mut self, conn: &Connection) -> Vec<ServerMessage> {
+        let mut published = std::mem::take(&mut self.pending);
+        loop {
+            match self.receiver.try_recv() {
+                Ok(log) => published.push(log),
+                Err(TryRecvError::Lagged(skipped)) => self.dropped += skipped,
+                Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+            }
+        }
+        let matching = match self.subscription.select(published, conn) {
+            Ok(matching) => matching,
+            Err(e) => return vec![ServerMessage::Error { msg: e.to_string() }],
+        };
+        for log in matching {
+            if self.buffer.len() == PAUSE_BUFFER_SIZE {
+                self.buffer.pop_front();
+                self.dropped += 1;
+            }
+            self.buffer.push_back(log);
+        }
+        if self.paused {
+            return vec![];
+        }
+
+        let mut messages = vec![];
+        if self.dropped > 0 {
+            messages.push(ServerMessage::Dropped { count: self.dropped });
+            self.dropped = 0;
+        }
+        if !self.buffer.is_empty() {
+            messages.push(ServerMessage::Requests {
+                requests: self.buffer.drain(..).map(|log| (*log).clone()).collect(),
+            });
+        }
+        messages
+    }
+}
+
+/// Rolling SLIs, recomputed every `SLI_REFRESH_SECONDS`
+struct SliFeed {
+    window: Duration,
+    last: Option<ServiceLevelIndicators>,
+    next_at: DateTime<Utc>,
+}
+
+impl SliFeed {
+    fn poll(&mut self, now: DateTime<Utc>, conn: &Connection) -> Option<ServerMessage> {
+        if now < self.next_at {
+            return None;
+        }
+        self.next_at = now + Duration::seconds(SLI_REFRESH_SECONDS);
+        let window = TimeWindow::new(now - self.window, Some(now));
+        let sli = match window_slis(&window, &RequestFilter::default(), conn) {
+            Ok(sli) => sli,
+            Err(e) => return Some(ServerMessage::Error { msg: e.to_string() }),
+        };
+        if self.last == Some(sli) {
+            return None;
+        }
+        self.last = Some(sli);
+        Some(ServerMessage::Sli {
+            window: self.window.num_seconds(),
+            sli,
+        })
+    }
+}
+
+/// Counts the requests published during each second
+struct CounterFeed {
+    receiver: broadcast::Receiver<Arc<RequestLog>>,
+    second: i64,
+    requests: u64,
+    errors: u64,
+}
+
+impl CounterFeed {
+    fn poll(&mut self, now: DateTime<Utc>) -> Option<ServerMessage> {
+        // Requests are counted in the second they are read in, at most one poll late
+        let mut message = None;
+        if now.timestamp() > self.second {
+            message = Some(ServerMessage::Counters {
+                second: DateTime::from_timestamp(self.second, 0).unwrap_or_default(),
+                requests: self.requests,
+                errors: self.errors,
+            });
+            (self.second, self.requests, self.errors) = (now.timestamp(), 0, 0);
+        }
+        loop {
+            match self.receiver.try_recv() {
+                Ok(log) => {
+                    self.requests += 1;
+                    self.errors += (log.status >= 500) as u64;
+                }
+                Err(TryRecvError::Lagged(skipped)) => self.requests += skipped,
+                Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+            }
+        }
+        message
+    }
+}
+
+/// Uptime states, checked every `UPTIME_REFRESH_SECONDS`
+struct UptimeFeed {
+    states: HashMap<String, UptimeState>,
+    initialized: bool,
+    next_at: DateTime<Utc>,
+}
+
+impl UptimeFeed {
+    fn poll(&mut self, now: DateTime<Utc>, conn: &Connection) -> Option<ServerMessage> {
+        if now < self.next_at {
+            return None;
+        }
+        self.next_at = now + Duration::seconds(UPTIME_REFRESH_SECONDS);
+        let states = match uptime_states(conn) {
+            Ok(states) => states,
+            Err(e) => return Some(ServerMessage::Error { msg: e.to_string() }),
+        };
+        let changed = states
+            .into_iter()
+            .filter(|state| {
+                self.states
+                    .get(&state.url)
+                    .is_none_or(|previous| previous.status != state.status)
+            })
+            .collect::<Vec<UptimeState>>();
+        for state in &changed {
+            self.states.insert(state.url.clone(), state.clone());
+        }
+        if changed.is_empty() && self.initialized {
+            return None;
+        }
+        self.initialized = true;
+        Some(ServerMessage::Uptime { states: changed })
+    }
+}
+
+/// Channels a WebSocket client is subscribed to
+pub struct LiveChannels {
+    hub: RequestHub,
+    requests: Option<LiveTail>,
+    sli: Option<SliFeed>,
+    counters: Option<CounterFeed>,
+    uptime: Option<UptimeFeed>,
+}
+
+impl LiveChannels {
+    /// Channels starting with the requests one, which older clients expect
+    pub fn new(hub: &RequestHub, subscription: Subscription) -> LiveChannels {
+        LiveChannels {
+            hub: hub.clone(),
+            requests: Some(LiveTail::new(hub, subscription)),
+            sli: None,
+            counters: None,
+            uptime: None,
+        }
+    }
+
+    /// Applies a message from the client, returning the reply
+    pub fn handle(&mut self, message: &str) -> ChannelMessage {
+        let message = match serde_json::from_str::<ClientMessage>(message) {
+            Ok(message) => message,
+            Err(e) => {
+                return ChannelMessage {
+                    channel: Channel::Requests,
+                    message: ServerMessage::Error {
+                        msg: format!("Invalid message: {}", e),
+                    },
+                }
+            }
+        };
+        let channel = match &message {
+            ClientMessage::Subscribe { channel, .. } | ClientMessage::Unsubscribe { channel } => {
+                *channel
+            }
+            ClientMessage::Pause | ClientMessage::Resume => Channel::Requests,
+        };
+        let message = match message {
+            ClientMessage::Subscribe {
+                channel,
+                filter,
+                window,
+            } => self.subscribe(channel, filter, window),
+            ClientMessage::Unsubscribe { channel } => {
+                match channel {
+                    Channel::Requests => self.requests = None,
+                    Channel::Sli => self.sli = None,
+                    Channel::Counters => self.counters = None,
+                    Channel::Uptime => self.uptime = None,
+                }
+                ServerMessage::Unsubscribed
+            }
+            ClientMessage::Pause | ClientMessage::Resume => match self.requests.as_mut() {
+                Some(tail) => {
+                    tail.paused = matches!(message, ClientMessage::Pause);
+                    match tail.paused {
+                        true => ServerMessage::Paused,
+                        false => ServerMessage::Resumed,
+                    }
+                }
+                None => ServerMessage::Error {
+                    msg: "Not subscribed to requests".to_string(),
+                },
+            },
+        };
+        ChannelMessage { channel, message }
+    }
+
+    fn subscribe(&mut self, channel: Channel, filter: LiveFilter, window: Option<i64>) -> ServerMessage {
+        let now = Utc::now();
+        match channel {
+            Channel::Requests => match Subscription::new(filter) {
+                Ok(subscription) => {
+                    let filter = subscription.filter.clone();
+                    match self.requests.as_mut() {
+                        Some(tail) => tail.subscription = subscription,
+                        None => self.requests = Some(LiveTail::new(&self.hub, subscription)),
+                    }
+                    ServerMessage::Subscribed {
+                        filter: Some(filter),
+                        window: None,
+                    }
+                }
+                Err(e) => ServerMessage::Error { msg: e.to_string() },
+            },
+            Channel::Sli => {
+                let window = window.unwrap_or(DEFAULT_SLI_WINDOW_SECONDS);
+                if !(1..=MAX_SLI_WINDOW_SECONDS).contains(&window) {
+                    return ServerMessage::Error {
+                        msg: format!(
+                            "The SLI window must be between 1 and {} seconds",
+                            MAX_SLI_WINDOW_SECONDS
+                        ),
+                    };
+                }
+                self.sli = Some(SliFeed {
+                    window: Duration::seconds(window),
+                    last: None,
+                    next_at: now,
+                });
+                ServerMessage::Subscribed {
+                    filter: None,
+                    window: Some(window),
+                }
+            }
+            Channel::Counters => {
+                self.counters = Some(CounterFeed {
+                    receiver: self.hub.subscribe(),
+                    second: now.timestamp(),
+                    requests: 0,
+                    errors: 0,
+                });
+                ServerMessage::Subscribed {
+                    filter: None,
+                    window: None,
+                }
+            }
+            Channel::Uptime => {
+                self.uptime = Some(UptimeFeed {
+                    states: HashMap::new(),
+                    initialized: false,
+                    next_at: now,
+                });
+                ServerMessage::Subscribed {
+                    filter: None,
+                    window: None,
+                }
+            }
+        }
+    }
+
+    /// Waits until a request is published to the requests channel, never finishing when not
+    /// subscribed to it
+    pub async fn published(&mut self) {
+        match self.requests.as_mut() {
+            Some(tail) => tail.wait().await,
+            None => future::pending().await,
+        }
+    }
+
+    /// Requests published since the last poll, or buffered while paused once resumed
+    pub fn poll_requests(&mut self, conn: &Connection) -> Vec<ChannelMessage> {
+        let Some(tail) = self.requests.as_mut() else {
+            return vec![];
+        };
+        tail.poll(conn)
+            .into_iter()
+            .map(|message| ChannelMessage {
+                channel: Channel::Requests,
+                message,
+            })
+            .collect()
+    }
+
+    /// Collects the updates of the timed channels, SLIs, counters and uptime
+    pub fn poll(&mut self, conn: &Connection) -> Vec<ChannelMessage> {
+        let now = Utc::now();
+        let mut messages = vec![];
+        let updates = [
+            (
+                Channel::Sli,
+                self.sli.as_mut().and_then(|sli| sli.poll(now, conn)),
+            ),
+            (
+                Channel::Counters,
+                self.counters.as_mut().and_then(|counters| counters.poll(now)),
+            ),
+            (
+                Channel::Uptime,
+                self.uptime.as_mut().and_then(|uptime| uptime.poll(now, conn)),
+            ),
+        ];
+        for (channel, message) in updates {
+            if let Some(message) = message {
+                messages.push(ChannelMessage { channel, message });
+            }
+        }
+        messages
+    }
+}
@@ -0,0 +1,441 @@
+//! Application log lines sent next to requests, correlated with them by trace id or by the
+//! value of a correlation header such as `X-Request-Id`.
+
+use super::{
+    filters::{text, RequestFilter},
+    pagination::{count_rows, Cursor, Page},
+    requests::{RequestLog, CORRELATION_HEADERS},
+    search::fts_match,
+    traces::{span_id, trace_id},
+};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{params, params_from_iter, types::Value as SqlValue, Connection, Row};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+use std::str::FromStr;
+use strum::{Display, EnumString};
+
+pub const DEFAULT_LOG_RETENTION_DAYS: i64 = 30;
+/// Most log lines a single append can hold
+pub const MAX_APPENDED_LOGS: usize = 10_000;
+/// How far attributes nested in objects are indexed for filtering
+const MAX_ATTRIBUTE_DEPTH: usize = 4;
+/// Most seconds around a request to look for logs of its service
+pub const MAX_CORRELATION_WINDOW: i64 = 3600;
+
+fn format_date(date: DateTime<Utc>) -> String {
+    date.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+#[derive(Serialize, Display, EnumString, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    #[strum(to_string = "warn", serialize = "warning")]
+    Warn,
+    Error,
+    #[strum(to_string = "fatal", serialize = "critical")]
+    Fatal,
+}
+
+impl<'de> Deserialize<'de> for LogLevel {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let level = String::deserialize(deserializer)?;
+        LogLevel::from_str(&level)
+            .map_err(|_| de::Error::custom(format!("Unknown log level '{}'", level)))
+    }
+}
+
+/// A log line as sent to `/append-logs`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntryRequest {
+    /// When the line was logged, now when not given
+    #[serde(default)]
+    pub timestamp: Option<DateTime<Utc>>,
+    pub level: LogLevel,
+    pub message: String,
+    #[serde(default)]
+    pub attributes: Map<String, Value>,
+    #[serde(default)]
+    pub service: Option<String>,
+    /// W3C trace context ids of the request the line was logged for
+    #[serde(default)]
+    pub trace_id: Option<String>,
+    #[serde(default)]
+    pub span_id: Option<String>,
+    /// Value of the request's correlation header, e.g. `X-Request-Id`
+    #[serde(default)]
+    pub correlation_id: Option<String>,
+}
+
+/// `/append-logs` takes a single line or an array of them
+pub struct LogEntries(pub Vec<LogEntryRequest>);
+
+impl<'de> Deserialize<'de> for LogEntries {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Picked by hand rather than untagged so errors point at the invalid field
+        match Value::deserialize(deserializer)? {
+            Value::Array(entries) => serde_json::from_value(Value::Array(entries)).map(LogEntries),
+            entry => serde_json::from_value(entry).map(|entry| LogEntries(vec![entry])),
+        }
+        .map_err(de::Error::custom)
+    }
+}
+
+impl LogEntryRequest {
+    /// Normalizes the ids and service, rejecting ids that aren't valid trace context ids
+    pub fn validate(&mut self) -> Result<()> {
+        let normalize = |id: &Option<String>, field: &str, parse: fn(&str) -> Option<String>| {
+            id.as_deref()
+                .filter(|id| !id.trim().is_empty())
+                .map(|id| parse(id).ok_or(anyhow!("'{}' is not a valid {}", id, field)))
+                .transpose()
+        };
+        self.trace_id = normalize(&self.trace_id, "trace_id", trace_id)?;
+        self.span_id = normalize(&self.span_id, "span_id", span_id)?;
+        self.service = self.service.take().filter(|s| !s.trim().is_empty());
+        self.correlation_id = self
+            .correlation_id
+            .take()
+            .map(|id| id.trim().to_string())
+            .filter(|id| !id.is_empty());
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LogEntry {
+    pub id: i64,
+    pub timestamp: DateTime<Utc>,
+    pub level: LogLevel,
+    pub message: String,
+    pub attributes: Map<String, Value>,
+    pub service: Option<String>,
+    pub trace_id: Option<String>,
+    pub span_id: Option<String>,
+    pub correlation_id: Option<String>,
+}
+
+const LOG_ENTRY_COLUMNS: &str =
+    "date, logs.id, level, message, attributes, service, trace_id, span_id, correlation_id";
+
+impl LogEntry {
+    fn from_row(row: &Row) -> Result<LogEntry> {
+        Ok(LogEntry {
+            timestamp: DateTime::parse_from_rfc3339(&row.get::<_, String>(0)?)?.with_timezone(&Utc),
+            id: row.get(1)?,
+            level: LogLevel::from_str(&row.get::<_, String>(2)?)?,
+            message: row.get(3)?,
+            attributes: serde_json::from_str(&row.get::<_, String>(4)?)?,
+            service: row.get(5)?,
+            trace_id: row.get(6)?,
+            span_id: row.get(7)?,
+            correlation_id: row.get(8)?,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PaginatedLogs {
+    pub logs: Vec<LogEntry>,
+    /// Missing when counting was skipped
+    pub total_items: Option<usize>,
+    pub total_capped: bool,
+    pub next_cursor: Option<String>,
+}
+
+/// Flattens attributes into `(name, value)` pairs, nested objects joining their keys with `.` and
+/// arrays repeating their name for each element
+fn attribute_pairs(prefix: &str, value: &Value, depth: usize, pairs: &mut Vec<(String, String)>) {
+    match value {
+        Value::Null => {}
+        Value::String(s) => pairs.push((prefix.to_string(), s.clone())),
+        Value::Object(map) if depth < MAX_ATTRIBUTE_DEPTH => {
+            for (key, value) in map {
+                attribute_pairs(&format!("{}.{}", prefix, key), value, depth + 1, pairs);
+            }
+        }
+        Value::Array(values) if depth < MAX_ATTRIBUTE_DEPTH => {
+            for value in values {
+                attribute_pairs(prefix, value, depth + 1, pairs);
+            }
+        }
+        other => pairs.push((prefix.to_string(), other.to_string())),
+    }
+}
+
+/// Stores log lines, then prunes the ones older than the retention period
+pub fn insert_logs(
+    entries: &[LogEntryRequest],
+    retention_days: i64,
+    conn: &Connection,
+) -> Result<Vec<i64>> {
+    let now = Utc::now();
+    let tx = conn.unchecked_transaction()?;
+    let mut ids = vec![];
+    {
+        let mut insert = tx.prepare_cached(
+            "INSERT INTO logs (date, level, severity, message, attributes, service, trace_id, span_id, correlation_id)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        )?;
+        let mut insert_attribute =
+            tx.prepare_cached("INSERT INTO log_attributes (log_id, name, value) VALUES (?1, ?2, ?3)")?;
+        for entry in entries {
+            insert.execute(params![
+                format_date(entry.timestamp.unwrap_or(now)),
+                entry.level.to_string(),
+                entry.level as i64,
+                entry.message,
+                Value::Object(entry.attributes.clone()).to_string(),
+                entry.service,
+                entry.trace_id,
+                entry.span_id,
+                entry.correlation_id,
+            ])?;
+            let id = tx.last_insert_rowid();
+            let mut pairs = vec![];
+            for (key, value) in &entry.attributes {
+                attribute_pairs(key, value, 0, &mut pairs);
+            }
+            for (name, value) in pairs {
+                insert_attribute.execute(params![id, name, value])?;
+            }
+            ids.push(id);
+        }
+    }
+    tx.commit()?;
+    prune_logs(conn, retention_days)?;
+    Ok(ids)
+}
+
+/// Deletes log lines older than `retention_days`. A non positive retention keeps everything.
+pub fn prune_logs(conn: &Connection, retention_days: i64) -> Result<usize> {
+    if retention_days <= 0 {
+        return Ok(0);
+    }
+    let cutoff = Utc::now() - Duration::days(retention_days);
+    let deleted = conn.execute("DELETE FROM logs WHERE date < ?1", params![format_date(cutoff)])?;
+    Ok(deleted)
+}
+
+/// Attribute filter, written as `name` (exists), `!name` (missing) or `name:value` (equals).
+/// Nested attributes are named by their path, e.g. `user.id:42`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttributeFilter {
+    pub name: String,
+    pub value: Option<String>,
+    pub negate: bool,
+}
+
+impl FromStr for AttributeFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(filter: &str) -> Result<AttributeFilter> {
+        let (negate, filter) = match filter.trim().strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, filter.trim()),
+        };
+        let (name, value) = match filter.split_once(':') {
+            Some((name, value)) => (name, Some(value.trim().to_string())),
+            None => (filter, None),
+        };
+        if name.trim().is_empty() {
+            return Err(anyhow!(
+                "Attribute filter '{}' is missing an attribute name",
+                filter
+            ));
+        }
+        Ok(AttributeFilter {
+            name: name.trim().to_string(),
+            value,
+            negate,
+        })
+    }
+}
+
+impl AttributeFilter {
+    /// SQL condition over the `logs` table and its parameters
+    pub fn to_sql(&self) -> (String, Vec<SqlValue>) {
+        let mut params = vec![text(self.name.clone())];
+        let mut clause = "EXISTS (SELECT 1 FROM log_attributes a \
+            WHERE a.log_id = logs.id AND a.name = ?"
+            .to_string();
+        if let Some(value) = &self.value {
+            clause.push_str(" AND a.value = ?");
+            params.push(text(value.clone()));
+        }
+        clause.push(')');
+        if self.negate {
+            clause = format!("NOT {}", clause);
+        }
+        (clause, params)
+    }
+}
+
+/// Filters of the log listing
+#[derive(Debug, Clone, Default)]
+pub struct LogFilter {
+    pub search: Option<String>,
+    pub levels: Vec<LogLevel>,
+    pub min_level: Option<LogLevel>,
+    pub service: Option<String>,
+    pub trace_id: Option<String>,
+    pub correlation_id: Option<String>,
+    pub attributes: Vec<AttributeFilter>,
+}
+
+impl LogFilter {
+    fn to_filter(&self) -> RequestFilter {
+        let mut filter = RequestFilter::default();
+        if let Some(search) = self.search.as_deref().and_then(fts_match) {
+            filter.push(
+                "logs.id IN (SELECT rowid FROM logs_fts WHERE logs_fts MATCH ?)",
+                vec![text(search)],
+            );
+        }
+        if !self.levels.is_empty() {
+            filter.push(
+                format!("level IN ({})", vec!["?"; self.levels.len()].join(", ")),
+                self.levels.iter().map(|l| text(l.to_string())).collect(),
+            );
+        }
+        if let Some(min_level) = self.min_level {
+            filter.push("severity >= ?", vec![SqlValue::Integer(min_level as i64)]);
+        }
+        if let Some(service) = self.service.clone().filter(|s| !s.is_empty()) {
+            filter.push("service = ?", vec![text(service)]);
+        }
+        if let Some(trace) = self.trace_id.as_deref().filter(|t| !t.is_empty()) {
+            filter.push("trace_id = ?", vec![text(trace.trim().to_lowercase())]);
+        }
+        if let Some(correlation_id) = self.correlation_id.clone().filter(|c| !c.is_empty()) {
+            filter.push("correlation_id = ?", vec![text(correlation_id)]);
+        }
+        for attribute in &self.attributes {
+            let (clause, params) = attribute.to_sql();
+            filter.push(clause, params);
+        }
+        filter
+    }
+}
+
+/// Log lines between `from` and `to`, newest first, paginated on `(date, id)`
+pub fn get_paginated_logs(
+    from: DateTime<Utc>,
+    to: Option<DateTime<Utc>>,
+    page: &Page,
+    filter: &LogFilter,
+    conn: &Connection,
+) -> Result<PaginatedLogs> {
+    let filter = filter.to_filter();
+    let from_where = format!("FROM logs WHERE date > ? AND date <= ? AND {}", filter.sql());
+    let mut where_params = vec![
+        text(format_date(from)),
+        text(format_date(to.unwrap_or(Utc::now()))),
+    ];
+    where_params.extend_from_slice(filter.params());
+
+    let mut query_params = where_params.clone();
+    let after = match &page.cursor {
+        Some(cursor) => {
+            query_params.push(text(cursor.date.clone()));
+            query_params.push(SqlValue::Integer(cursor.id));
+            "AND (date, logs.id) < (?, ?)"
+        }
+        None => "",
+    };
+    let (limit, limit_params) = page.limit_sql();
+    query_params.extend(limit_params);
+    let mut stmt = conn.prepare(&format!(
+        "
+        SELECT {}
+        {} {}
+        ORDER BY date DESC, logs.id DESC
+        {};
+        ",
+        LOG_ENTRY_COLUMNS, from_where, after, limit
+    ))?;
+
+    let mut rows = stmt.query(params_from_iter(query_params))?;
+    let mut logs = vec![];
+    while let Some(row) = rows.next()? {
+        let cursor = Cursor {
+            date: row.get(0)?,
+            id: row.get(1)?,
+            rank: None,
+            snapshot: None,
+        };
+        logs.push((LogEntry::from_row(row)?, cursor));
+    }
+    let (total_items, total_capped) = count_rows(conn, &from_where, &where_params, page.count)?;
+    let next_cursor = page.next_cursor(&mut logs);
+
+    Ok(PaginatedLogs {
+        logs: logs.into_iter().map(|(log, _)| log).collect(),
+        total_items,
+        total_capped,
+        next_cursor,
+    })
+}
+
+/// Log lines of a request's trace or sharing one of its correlation header values, oldest first.
+/// With a `window` in seconds, lines its service logged that long before or after it are included.
+pub fn correlated_logs(
+    log: &RequestLog,
+    window: Option<i64>,
+    limit: usize,
+    conn: &Connection,
+) -> Result<Vec<LogEntry>> {
+    let mut clauses = vec![];
+    let mut params = vec![];
+    if let Some(trace) = &log.trace_id {
+        clauses.push("trace_id = ?".to_string());
+        params.push(text(trace.clone()));
+    }
+    let correlation_ids = log
+        .req_headers
+        .pairs()
+        .iter()
+        .chain(log.res_headers.pairs())
+        .filter(|(name, _)| CORRELATION_HEADERS.contains(&name.as_str()))
+        .map(|(_, value)| text(value.clone()))
+        .collect::<Vec<SqlValue>>();
+    if !correlation_ids.is_empty() {
+        clauses.push(format!(
+            "correlation_id IN ({})",
+            vec!["?"; correlation_ids.len()].join(", ")
+        ));
+        params.extend(correlation_ids);
+    }
+    if let (Some(window), Some(service)) = (window.filter(|w| *w > 0), &log.service) {
+        let window = Duration::seconds(window.min(MAX_CORRELATION_WINDOW));
+        let end = log.date + Duration::milliseconds(log.res_time as i64);
+        clauses.push("(service = ? AND date >= ? AND date <= ?)".to_string());
+        params.extend([
+            text(service.clone()),
+            text(format_date(log.date - window)),
+            text(format_date(end + window)),
+        ]);
+    }
+    if clauses.is_empty() {
+        return Ok(vec![]);
+    }
+    params.push(SqlValue::Integer(limit as i64));
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM logs WHERE {} ORDER BY date ASC, logs.id ASC LIMIT ?",
+        LOG_ENTRY_COLUMNS,
+        clauses.join(" OR ")
+    ))?;
+    let mut rows = stmt.query(params_from_iter(params))?;
+    let mut logs = vec![];
+    while let Some(row) = rows.next()? {
+        logs.push(LogEntry::from_row(row)?);
+    }
+    Ok(logs)
+}
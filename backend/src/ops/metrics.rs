@@ -0,0 +1,272 @@
+//! Prometheus exposition of the ingested requests, uptime checks and the server itself
+
+use super::{
+    requests::RequestLog,
+    uptime::{uptime_settings, uptime_states},
+};
+use anyhow::Result;
+use chrono::DateTime;
+use rusqlite::Connection;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+};
+
+/// Endpoint templates tracked when `METRICS_MAX_ENDPOINTS` isn't set
+pub const DEFAULT_METRICS_MAX_ENDPOINTS: usize = 500;
+/// Endpoint label of the requests past the endpoint limit
+pub const OTHER_ENDPOINT: &str = "other";
+/// Upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10., 30.];
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    /// Non-cumulative count of each bucket, the last one being `+Inf`
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+/// Request counters and latency histograms since the server started. Endpoint templates past
+/// `max_endpoints` are counted under the `other` endpoint to keep the number of series bounded.
+#[derive(Debug, Clone)]
+pub struct RequestMetrics {
+    max_endpoints: usize,
+    /// Requests by method, status class and endpoint template
+    requests: HashMap<(String, String, String), u64>,
+    /// Latencies by method and endpoint template
+    latencies: HashMap<(String, String), Histogram>,
+    endpoints: HashSet<String>,
+    ingested: u64,
+    /// Requests counted under `other` because of the endpoint limit
+    overflowed: u64,
+}
+
+impl RequestMetrics {
+    pub fn new(max_endpoints: usize) -> RequestMetrics {
+        RequestMetrics {
+            max_endpoints,
+            requests: HashMap::new(),
+            latencies: HashMap::new(),
+            endpoints: HashSet::new(),
+            ingested: 0,
+            overflowed: 0,
+        }
+    }
+
+    pub fn record(&mut self, log: &RequestLog) {
+        let template = log
+            .endpoint_template
+            .clone()
+            .unwrap_or_else(|| log.endpoint.clone());
+        let endpoint = if self.endpoints.contains(&template) {
+            template
+        } else if self.endpoints.len() < self.max_endpoints {
+            self.endpoints.insert(template.clone());
+            template
+        } else {
+            self.overflowed += 1;
+            OTHER_ENDPOINT.to_string()
+        };
+        let method = log.method.to_uppercase();
+        let status_class = format!("{}xx", log.status / 100);
+
+        self.ingested += 1;
+        *self
+            .requests
+            .entry((method.clone(), status_class, endpoint.clone()))
+            .or_default() += 1;
+        self.latencies
+            .entry((method, endpoint))
+            .or_default()
+            .observe(log.res_time as f64 / 1000.);
+    }
+}
+
+/// State of the server itself at scrape time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ServerMetrics {
+    /// Logs waiting in the live feed
+    pub queue_depth: usize,
+    pub live_subscribers: usize,
+}
+
+/// Escapes a label value of the text exposition format
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Renders every metric in the Prometheus text exposition format
+pub fn render_metrics(
+    metrics: &RequestMetrics,
+    server: ServerMetrics,
+    conn: &Connection,
+) -> Result<String> {
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "metrinomicon_requests_total",
+        "counter",
+        "Requests ingested by method, status class and endpoint template.",
+    );
+    let mut requests = metrics.requests.iter().collect::<Vec<_>>();
+    requests.sort();
+    for ((method, status_class, endpoint), count) in requests {
+        let _ = writeln!(
+            out,
+            "metrinomicon_requests_total{{method=\"{}\",status_class=\"{}\",endpoint=\"{}\"}} {}",
+            label(method),
+            status_class,
+            label(endpoint),
+            count
+        );
+    }
+
+    header(
+        &mut out,
+        "metrinomicon_request_duration_seconds",
+        "histogram",
+        "Response time of the ingested requests by method and endpoint template.",
+    );
+    let mut latencies = metrics.latencies.iter().collect::<Vec<_>>();
+    latencies.sort_by(|a, b| a.0.cmp(b.0));
+    for ((method, endpoint), histogram) in latencies {
+        let labels = format!("method=\"{}\",endpoint=\"{}\"", label(method), label(endpoint));
+        let mut cumulative = 0;
+        for (i, count) in histogram.buckets.iter().enumerate() {
+            cumulative += count;
+            let bound = LATENCY_BUCKETS
+                .get(i)
+                .map(|bound| bound.to_string())
+                .unwrap_or("+Inf".to_string());
+            let _ = writeln!(
+                out,
+                "metrinomicon_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                labels, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "metrinomicon_request_duration_seconds_sum{{{}}} {}",
+            labels, histogram.sum
+        );
+        let _ = writeln!(
+            out,
+            "metrinomicon_request_duration_seconds_count{{{}}} {}",
+            labels, histogram.count
+        );
+    }
+
+    let states = uptime_states(conn)?
+        .into_iter()
+        .map(|state| (state.url.clone(), state))
+        .collect::<HashMap<_, _>>();
+    let settings = uptime_settings(conn)?;
+    header(
+        &mut out,
+        "metrinomicon_uptime_up",
+        "gauge",
+        "Whether the last check of a monitored url succeeded.",
+    );
+    for setting in &settings {
+        if let Some(state) = states.get(&setting.url) {
+            let _ = writeln!(
+                out,
+                "metrinomicon_uptime_up{{url=\"{}\",name=\"{}\"}} {}",
+                label(&setting.url),
+                label(&setting.name),
+                (state.status == "up") as u8
+            );
+        }
+    }
+    header(
+        &mut out,
+        "metrinomicon_uptime_last_check_timestamp_seconds",
+        "gauge",
+        "Unix time of the last check of a monitored url.",
+    );
+    for setting in &settings {
+        let checked_at = states
+            .get(&setting.url)
+            .and_then(|state| DateTime::parse_from_rfc3339(&state.checked_at).ok());
+        if let Some(checked_at) = checked_at {
+            let _ = writeln!(
+                out,
+                "metrinomicon_uptime_last_check_timestamp_seconds{{url=\"{}\",name=\"{}\"}} {}",
+                label(&setting.url),
+                label(&setting.name),
+                checked_at.timestamp()
+            );
+        }
+    }
+
+    let db_size = conn.query_row(
+        "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+        [],
+        |row| row.get::<_, i64>(0),
+    )?;
+    let self_metrics = [
+        (
+            "metrinomicon_ingested_requests_total",
+            "counter",
+            "Requests ingested since the server started.",
+            metrics.ingested as f64,
+        ),
+        (
+            "metrinomicon_metrics_overflowed_requests_total",
+            "counter",
+            "Requests counted under the other endpoint because of the endpoint limit.",
+            metrics.overflowed as f64,
+        ),
+        (
+            "metrinomicon_metrics_endpoints",
+            "gauge",
+            "Endpoint templates tracked by the request metrics.",
+            metrics.endpoints.len() as f64,
+        ),
+        (
+            "metrinomicon_live_queue_depth",
+            "gauge",
+            "Requests waiting to be read by live tail subscribers.",
+            server.queue_depth as f64,
+        ),
+        (
+            "metrinomicon_live_subscribers",
+            "gauge",
+            "Live tail and stream subscribers.",
+            server.live_subscribers as f64,
+        ),
+        (
+            "metrinomicon_db_size_bytes",
+            "gauge",
+            "Size of the SQLite database.",
+            db_size as f64,
+        ),
+    ];
+    for (name, kind, help, value) in self_metrics {
+        header(&mut out, name, kind, help);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+    Ok(out)
+}
@@ -1,6 +1,29 @@
+pub mod anomalies;
+pub mod audit;
 pub mod auth;
+pub mod body_filters;
+pub mod comparison;
+pub mod custom_metrics;
+pub mod endpoints;
+pub mod export;
+pub mod filters;
+pub mod headers;
+pub mod issues;
+pub mod json_path;
+pub mod live;
+pub mod logs;
+pub mod metrics;
+pub mod pagination;
+pub mod query;
+pub mod redaction;
+pub mod remote_write;
+pub mod replay;
 pub mod requests;
+pub mod search;
+pub mod series;
 pub mod stats;
+pub mod synthetic;
+pub mod traces;
 pub mod uptime;
 #[allow(unused)]
 pub mod utils;
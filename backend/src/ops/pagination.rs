@@ -0,0 +1,124 @@
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rusqlite::{params_from_iter, types::Value as SqlValue, Connection};
+use serde::{Deserialize, Serialize};
+
+/// Rows counted at most by `CountMode::Approx`
+pub const APPROX_COUNT_LIMIT: usize = 10_000;
+/// Page size used when none is given
+pub const DEFAULT_PAGE_LIMIT: usize = 100;
+/// Largest page that can be requested
+pub const MAX_PAGE_LIMIT: usize = 1_000;
+
+/// Opaque position of the last row of a page, on `(date, id)` and the search rank for ranked results
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Cursor {
+    pub date: String,
+    pub id: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rank: Option<f64>,
+    /// Highest id when ranked results were first paged, later rows being left out so pages don't
+    /// repeat or skip results as ranks change
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<i64>,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    pub fn decode(cursor: &str) -> Result<Cursor> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(cursor)
+            .map_err(|_| anyhow!("Invalid cursor"))?;
+        serde_json::from_slice(&bytes).map_err(|_| anyhow!("Invalid cursor"))
+    }
+}
+
+/// How the total number of matching rows is computed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CountMode {
+    /// Counts every matching row
+    #[default]
+    Exact,
+    /// Counts up to `APPROX_COUNT_LIMIT` rows
+    Approx,
+    /// Skips counting
+    None,
+}
+
+/// Page of results, either after a cursor or, for older clients, at an offset
+#[derive(Debug, Clone, Default)]
+pub struct Page {
+    pub cursor: Option<Cursor>,
+    pub index: usize,
+    pub limit: usize,
+    pub count: CountMode,
+}
+
+impl Page {
+    pub fn new(
+        cursor: Option<&str>,
+        index: Option<usize>,
+        limit: Option<usize>,
+        count: Option<CountMode>,
+    ) -> Result<Page> {
+        Ok(Page {
+            cursor: cursor.filter(|c| !c.is_empty()).map(Cursor::decode).transpose()?,
+            index: index.unwrap_or_default(),
+            limit: limit.unwrap_or(DEFAULT_PAGE_LIMIT).min(MAX_PAGE_LIMIT),
+            count: count.unwrap_or_default(),
+        })
+    }
+
+    /// SQL `LIMIT` and `OFFSET` fetching one row more than the page to tell whether there is a next one
+    pub fn limit_sql(&self) -> (String, Vec<SqlValue>) {
+        match self.cursor {
+            Some(_) => (
+                "LIMIT ?".to_string(),
+                vec![SqlValue::Integer(self.limit as i64 + 1)],
+            ),
+            None => (
+                "LIMIT ? OFFSET ?".to_string(),
+                vec![
+                    SqlValue::Integer(self.limit as i64 + 1),
+                    SqlValue::Integer(self.index as i64),
+                ],
+            ),
+        }
+    }
+
+    /// Trims the extra row fetched by `limit_sql`, returning the cursor of the next page if there is one
+    pub fn next_cursor<T>(&self, rows: &mut Vec<(T, Cursor)>) -> Option<String> {
+        if rows.len() <= self.limit {
+            return None;
+        }
+        rows.truncate(self.limit);
+        rows.last().map(|(_, cursor)| cursor.encode())
+    }
+}
+
+/// Counts the rows of `from_where`, a `FROM ... WHERE ...` clause, as `mode` asks.
+/// Returns the count and whether it stopped at `APPROX_COUNT_LIMIT`.
+pub fn count_rows(
+    conn: &Connection,
+    from_where: &str,
+    params: &[SqlValue],
+    mode: CountMode,
+) -> Result<(Option<usize>, bool)> {
+    let sql = match mode {
+        CountMode::Exact => format!("SELECT COUNT(*) {}", from_where),
+        CountMode::Approx => format!(
+            "SELECT COUNT(*) FROM (SELECT 1 {} LIMIT {})",
+            from_where, APPROX_COUNT_LIMIT
+        ),
+        CountMode::None => return Ok((None, false)),
+    };
+    let count = conn.query_row(&sql, params_from_iter(params), |row| row.get::<_, usize>(0))?;
+    Ok((
+        Some(count),
+        mode == CountMode::Approx && count >= APPROX_COUNT_LIMIT,
+    ))
+}
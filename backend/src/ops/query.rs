@@ -0,0 +1,710 @@
+//! Filter language for request logs, e.g.
+//! `method:POST status:>=500 endpoint:/api/orders* -endpoint:/health latency:>250`.
+//!
+//! Terms are `field:value` pairs combined with implicit AND, `OR` and parentheses, and negated with
+//! a leading `-` or `NOT`. Values can be comma separated to match any of them and quoted to include
+//! spaces. Queries compile to parameterized SQL over the `requests` table.
+
+use super::{
+    body_filters::{BodyFilter, BODY_FILTER_TIME_BUDGET, MAX_BODY_FILTERS},
+    filters::{text, RequestFilter},
+    headers::{HeaderDirection, HeaderFilter},
+    requests::ENDPOINT_GROUP_SQL,
+    search::fts_match,
+};
+use rusqlite::types::Value as SqlValue;
+use std::{fmt, str::FromStr};
+
+/// Terms allowed in a single query
+pub const MAX_QUERY_TERMS: usize = 32;
+/// How deep parentheses and negations can be nested
+pub const MAX_QUERY_DEPTH: usize = 8;
+
+/// Path part of a stored endpoint or template column, which can be a full URL
+fn url_path_sql(column: &str) -> String {
+    format!(
+        "CASE
+    WHEN instr({c}, '://') = 0 THEN {c}
+    WHEN instr(substr({c}, instr({c}, '://') + 3), '/') = 0 THEN '/'
+    ELSE substr({c}, instr({c}, '://') + 2 + instr(substr({c}, instr({c}, '://') + 3), '/'))
+END",
+        c = column
+    )
+}
+
+/// Matches `column` against glob patterns, on its path for patterns starting with '/'
+fn url_glob(column: &str, patterns: &[String]) -> (String, Vec<SqlValue>) {
+    any_of(
+        patterns
+            .iter()
+            .map(|p| match p.starts_with('/') {
+                true => (format!("({}) GLOB ?", url_path_sql(column)), vec![text(glob(p))]),
+                false => (format!("{} GLOB ?", column), vec![text(glob(p))]),
+            })
+            .collect(),
+    )
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryError {
+    /// Character offset into the query
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (at position {})", self.message, self.position)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+fn error<T>(position: usize, message: impl Into<String>) -> Result<T, QueryError> {
+    Err(QueryError {
+        position,
+        message: message.into(),
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LParen(usize),
+    RParen(usize),
+    Not(usize),
+    Word {
+        text: String,
+        quoted: bool,
+        position: usize,
+    },
+}
+
+impl Token {
+    fn position(&self) -> usize {
+        match self {
+            Token::LParen(p) | Token::RParen(p) | Token::Not(p) => *p,
+            Token::Word { position, .. } => *position,
+        }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self, Token::Word { text, quoted: false, .. } if text == keyword)
+    }
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>, QueryError> {
+    let chars = query.chars().collect::<Vec<char>>();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            c if c.is_whitespace() => i += 1,
+            '(' => {
+                tokens.push(Token::LParen(i));
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token::RParen(i));
+                i += 1;
+            }
+            '-' if chars.get(i + 1).is_some_and(|c| !c.is_whitespace()) => {
+                tokens.push(Token::Not(i));
+                i += 1;
+            }
+            _ => {
+                let position = i;
+                let mut text = String::new();
+                let mut quoted = false;
+                while i < chars.len() && !chars[i].is_whitespace() && chars[i] != '(' && chars[i] != ')'
+                {
+                    if chars[i] == '"' {
+                        quoted = true;
+                        let start = i;
+                        i += 1;
+                        while i < chars.len() && chars[i] != '"' {
+                            text.push(chars[i]);
+                            i += 1;
+                        }
+                        if i == chars.len() {
+                            return error(start, "Unterminated quote");
+                        }
+                    } else {
+                        text.push(chars[i]);
+                    }
+                    i += 1;
+                }
+                tokens.push(Token::Word {
+                    text,
+                    quoted,
+                    position,
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+    Eq,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl Comparison {
+    fn sql(&self) -> &'static str {
+        match self {
+            Comparison::Eq => "=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+        }
+    }
+
+    /// Splits a leading comparison operator off a value
+    fn split(value: &str) -> (Comparison, &str) {
+        for (prefix, comparison) in [
+            (">=", Comparison::Ge),
+            ("<=", Comparison::Le),
+            (">", Comparison::Gt),
+            ("<", Comparison::Lt),
+            ("=", Comparison::Eq),
+        ] {
+            if let Some(rest) = value.strip_prefix(prefix) {
+                return (comparison, rest);
+            }
+        }
+        (Comparison::Eq, value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum NumberMatch {
+    Compare(Comparison, i64),
+    Range(i64, i64),
+}
+
+impl NumberMatch {
+    fn parse(value: &str, position: usize) -> Result<NumberMatch, QueryError> {
+        let number = |v: &str| {
+            v.trim()
+                .parse::<i64>()
+                .or(error(position, format!("'{}' is not a number", v.trim())))
+        };
+        if let Some((low, high)) = value.split_once("..") {
+            return Ok(NumberMatch::Range(number(low)?, number(high)?));
+        }
+        let (comparison, value) = Comparison::split(value);
+        Ok(NumberMatch::Compare(comparison, number(value)?))
+    }
+
+    fn sql(&self, column: &str) -> (String, Vec<SqlValue>) {
+        match self {
+            NumberMatch::Compare(comparison, n) => (
+                format!("{} {} ?", column, comparison.sql()),
+                vec![SqlValue::Integer(*n)],
+            ),
+            NumberMatch::Range(low, high) => (
+                format!("{} BETWEEN ? AND ?", column),
+                vec![SqlValue::Integer(*low), SqlValue::Integer(*high)],
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Term {
+    Method(Vec<String>),
+    Status(Vec<NumberMatch>),
+    Endpoint(Vec<String>),
+    Route(Vec<String>),
+    Service(Vec<String>),
+    Trace(Vec<String>),
+    Latency(Vec<NumberMatch>),
+    Params(String),
+    Header(HeaderFilter),
+    Body(BodyFilter),
+    Text(String),
+}
+
+/// Escapes GLOB metacharacters other than `*`
+fn glob(pattern: &str) -> String {
+    pattern
+        .chars()
+        .map(|c| match c {
+            '?' => "[?]".to_string(),
+            '[' => "[[]".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+fn any_of(parts: Vec<(String, Vec<SqlValue>)>) -> (String, Vec<SqlValue>) {
+    let mut params = vec![];
+    let clauses = parts
+        .into_iter()
+        .map(|(clause, p)| {
+            params.extend(p);
+            format!("({})", clause)
+        })
+        .collect::<Vec<String>>();
+    (clauses.join(" OR "), params)
+}
+
+impl Term {
+    fn parse(word: &str, position: usize) -> Result<Term, QueryError> {
+        let Some((name, value)) = word.split_once(':') else {
+            return Ok(Term::Text(word.to_string()));
+        };
+        let field = name.to_lowercase();
+        let value_position = position + name.chars().count() + 1;
+        if value.is_empty() {
+            return error(value_position, format!("Missing value for '{}'", field));
+        }
+        let values = || value.split(',').filter(|v| !v.is_empty());
+
+        match field.as_str() {
+            "method" => Ok(Term::Method(values().map(|v| v.to_uppercase()).collect())),
+            "status" => values()
+                .map(|v| {
+                    let class = v.to_lowercase();
+                    match class.strip_suffix("xx") {
+                        Some(digit) => match digit.parse::<i64>() {
+                            Ok(d) if (1..=5).contains(&d) => {
+                                Ok(NumberMatch::Range(d * 100, d * 100 + 99))
+                            }
+                            _ => error(value_position, format!("'{}' is not a status class", v)),
+                        },
+                        None => NumberMatch::parse(v, value_position),
+                    }
+                })
+                .collect::<Result<Vec<NumberMatch>, QueryError>>()
+                .map(Term::Status),
+            "endpoint" | "path" => Ok(Term::Endpoint(values().map(str::to_string).collect())),
+            "route" | "template" => Ok(Term::Route(values().map(str::to_string).collect())),
+            "service" => Ok(Term::Service(values().map(str::to_string).collect())),
+            "trace" | "trace_id" => Ok(Term::Trace(values().map(|v| v.to_lowercase()).collect())),
+            "latency" | "res_time" => values()
+                .map(|v| NumberMatch::parse(v.trim_end_matches("ms"), value_position))
+                .collect::<Result<Vec<NumberMatch>, QueryError>>()
+                .map(Term::Latency),
+            "params" => Ok(Term::Params(value.to_string())),
+            "reqheader" | "resheader" => {
+                let direction = match field.as_str() {
+                    "reqheader" => HeaderDirection::Req,
+                    _ => HeaderDirection::Res,
+                };
+                HeaderFilter::parse(direction, value)
+                    .map(Term::Header)
+                    .or_else(|e| error(value_position, e.to_string()))
+            }
+            f if f.starts_with("req_body.") || f.starts_with("res_body.") => {
+                // Body paths keep their case, so take the field from the original word
+                let path = name;
+                let filter = match value {
+                    "*" => format!("{} exists", path),
+                    v if v.starts_with('~') => format!("{} contains {}", path, &v[1..]),
+                    v => {
+                        let (comparison, v) = Comparison::split(v);
+                        format!("{} {} {}", path, comparison.sql(), v)
+                    }
+                };
+                BodyFilter::from_str(&filter)
+                    .map(Term::Body)
+                    .or_else(|e| error(position, e.to_string()))
+            }
+            _ => error(position, format!("Unknown field '{}'", field)),
+        }
+    }
+
+    fn sql(&self) -> (String, Vec<SqlValue>) {
+        match self {
+            Term::Method(methods) => (
+                format!("method IN ({})", vec!["?"; methods.len()].join(", ")),
+                methods.iter().map(|m| text(m.clone())).collect(),
+            ),
+            Term::Status(matches) => {
+                any_of(matches.iter().map(|m| m.sql("CAST(status AS INTEGER)")).collect())
+            }
+            Term::Endpoint(patterns) => url_glob("endpoint", patterns),
+            Term::Route(patterns) => url_glob(ENDPOINT_GROUP_SQL, patterns),
+            Term::Service(patterns) => any_of(
+                patterns
+                    .iter()
+                    .map(|p| {
+                        (
+                            "lower(service) GLOB ?".to_string(),
+                            vec![text(glob(&p.to_lowercase()))],
+                        )
+                    })
+                    .collect(),
+            ),
+            Term::Trace(ids) => (
+                format!("trace_id IN ({})", vec!["?"; ids.len()].join(", ")),
+                ids.iter().map(|id| text(id.clone())).collect(),
+            ),
+            Term::Latency(matches) => any_of(matches.iter().map(|m| m.sql("res_time")).collect()),
+            Term::Params(pattern) => ("params GLOB ?".to_string(), vec![text(glob(pattern))]),
+            Term::Header(filter) => filter.to_sql(),
+            Term::Body(filter) => filter.to_sql(),
+            Term::Text(search) => match fts_match(search) {
+                Some(search) => (
+                    "requests.id IN (SELECT rowid FROM requests_fts WHERE requests_fts MATCH ?)"
+                        .to_string(),
+                    vec![text(search)],
+                ),
+                None => ("1".to_string(), vec![]),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Term(Term),
+    Not(Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+}
+
+impl Expr {
+    fn sql(&self) -> (String, Vec<SqlValue>) {
+        match self {
+            Expr::Term(term) => term.sql(),
+            Expr::Not(expr) => {
+                let (clause, params) = expr.sql();
+                (format!("NOT ({})", clause), params)
+            }
+            Expr::And(exprs) | Expr::Or(exprs) => {
+                let separator = match self {
+                    Expr::And(_) => " AND ",
+                    _ => " OR ",
+                };
+                let mut params = vec![];
+                let clauses = exprs
+                    .iter()
+                    .map(|e| {
+                        let (clause, p) = e.sql();
+                        params.extend(p);
+                        format!("({})", clause)
+                    })
+                    .collect::<Vec<String>>();
+                (clauses.join(separator), params)
+            }
+        }
+    }
+
+    fn terms(&self) -> Vec<&Term> {
+        match self {
+            Expr::Term(term) => vec![term],
+            Expr::Not(expr) => expr.terms(),
+            Expr::And(exprs) | Expr::Or(exprs) => exprs.iter().flat_map(|e| e.terms()).collect(),
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn or(&mut self, depth: usize) -> Result<Expr, QueryError> {
+        let mut exprs = vec![self.and(depth)?];
+        while self.peek().is_some_and(|t| t.is_keyword("OR")) {
+            self.next();
+            exprs.push(self.and(depth)?);
+        }
+        Ok(match exprs.len() {
+            1 => exprs.remove(0),
+            _ => Expr::Or(exprs),
+        })
+    }
+
+    fn and(&mut self, depth: usize) -> Result<Expr, QueryError> {
+        let mut exprs = vec![];
+        loop {
+            match self.peek() {
+                None | Some(Token::RParen(_)) => break,
+                Some(t) if t.is_keyword("OR") => break,
+                Some(t) if t.is_keyword("AND") => {
+                    self.next();
+                }
+                Some(_) => exprs.push(self.unary(depth)?),
+            }
+        }
+        match exprs.len() {
+            0 => error(
+                self.peek().map_or(self.end, |t| t.position()),
+                "Expected a filter",
+            ),
+            1 => Ok(exprs.remove(0)),
+            _ => Ok(Expr::And(exprs)),
+        }
+    }
+
+    fn unary(&mut self, depth: usize) -> Result<Expr, QueryError> {
+        if depth > MAX_QUERY_DEPTH {
+            return error(
+                self.peek().map_or(self.end, |t| t.position()),
+                format!("Filters can be nested at most {} deep", MAX_QUERY_DEPTH),
+            );
+        }
+        match self.next() {
+            Some(Token::Not(_)) => Ok(Expr::Not(Box::new(self.unary(depth + 1)?))),
+            Some(t) if t.is_keyword("NOT") => Ok(Expr::Not(Box::new(self.unary(depth + 1)?))),
+            Some(Token::LParen(position)) => {
+                let expr = self.or(depth + 1)?;
+                match self.next() {
+                    Some(Token::RParen(_)) => Ok(expr),
+                    _ => error(position, "Unclosed parenthesis"),
+                }
+            }
+            Some(Token::Word {
+                text,
+                quoted,
+                position,
+            }) => match quoted && !text.contains(':') {
+                // Quoted words are searched as a phrase
+                true => Ok(Expr::Term(Term::Text(format!("\"{}\"", text)))),
+                false => Term::parse(&text, position).map(Expr::Term),
+            },
+            Some(Token::RParen(position)) => error(position, "Unexpected ')'"),
+            None => error(self.end, "Expected a filter"),
+        }
+    }
+}
+
+/// A parsed filter query
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query(Option<Expr>);
+
+impl FromStr for Query {
+    type Err = QueryError;
+
+    fn from_str(query: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(query)?;
+        if tokens.is_empty() {
+            return Ok(Query(None));
+        }
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+            end: query.chars().count(),
+        };
+        let expr = parser.or(0)?;
+        if let Some(token) = parser.peek() {
+            return error(token.position(), "Unexpected ')'");
+        }
+
+        let terms = expr.terms();
+        if terms.len() > MAX_QUERY_TERMS {
+            return error(0, format!("Queries can have at most {} terms", MAX_QUERY_TERMS));
+        }
+        if terms.iter().filter(|t| matches!(t, Term::Body(_))).count() > MAX_BODY_FILTERS {
+            return error(
+                0,
+                format!("Queries can have at most {} body filters", MAX_BODY_FILTERS),
+            );
+        }
+        Ok(Query(Some(expr)))
+    }
+}
+
+impl Query {
+    /// Adds the query's conditions to a request filter
+    pub fn apply(&self, filter: &mut RequestFilter) {
+        let Some(expr) = &self.0 else {
+            return;
+        };
+        let (clause, params) = expr.sql();
+        filter.push(clause, params);
+        if expr.terms().iter().any(|t| matches!(t, Term::Body(_))) {
+            filter.limit_time(BODY_FILTER_TIME_BUDGET);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sql(query: &str) -> (String, Vec<SqlValue>) {
+        let Query(expr) = Query::from_str(query).unwrap();
+        expr.unwrap().sql()
+    }
+
+    fn texts(values: &[&str]) -> Vec<SqlValue> {
+        values.iter().map(|v| text(*v)).collect()
+    }
+
+    fn parse_error(query: &str) -> QueryError {
+        Query::from_str(query).unwrap_err()
+    }
+
+    #[test]
+    fn parses_terms() {
+        assert_eq!(
+            sql("method:get,post"),
+            ("method IN (?, ?)".to_string(), texts(&["GET", "POST"]))
+        );
+        assert_eq!(
+            sql("status:5xx,404"),
+            (
+                "(CAST(status AS INTEGER) BETWEEN ? AND ?) OR (CAST(status AS INTEGER) = ?)".to_string(),
+                vec![
+                    SqlValue::Integer(500),
+                    SqlValue::Integer(599),
+                    SqlValue::Integer(404)
+                ]
+            )
+        );
+        assert_eq!(
+            sql("latency:>=250ms"),
+            ("(res_time >= ?)".to_string(), vec![SqlValue::Integer(250)])
+        );
+        assert_eq!(
+            sql("latency:100..200"),
+            (
+                "(res_time BETWEEN ? AND ?)".to_string(),
+                vec![SqlValue::Integer(100), SqlValue::Integer(200)]
+            )
+        );
+        assert_eq!(
+            sql("endpoint:api.example.com/orders?*"),
+            (
+                "(endpoint GLOB ?)".to_string(),
+                texts(&["api.example.com/orders[?]*"])
+            )
+        );
+        assert_eq!(
+            sql("endpoint:/orders/*").0,
+            format!("(({}) GLOB ?)", url_path_sql("endpoint"))
+        );
+        assert_eq!(
+            sql("route:/orders/{id}").0,
+            format!("(({}) GLOB ?)", url_path_sql(ENDPOINT_GROUP_SQL))
+        );
+        assert_eq!(
+            sql("service:Billing*"),
+            ("(lower(service) GLOB ?)".to_string(), texts(&["billing*"]))
+        );
+        assert_eq!(
+            sql("trace:ABC123"),
+            ("trace_id IN (?)".to_string(), texts(&["abc123"]))
+        );
+        assert_eq!(
+            sql("params:*page=[2]*"),
+            ("params GLOB ?".to_string(), texts(&["*page=[[]2]*"]))
+        );
+        assert_eq!(
+            sql("reqheader:!X-Debug"),
+            HeaderFilter::parse(HeaderDirection::Req, "!X-Debug")
+                .unwrap()
+                .to_sql()
+        );
+        assert_eq!(
+            sql("resheader:content-type:text/html"),
+            HeaderFilter::parse(HeaderDirection::Res, "content-type:text/html")
+                .unwrap()
+                .to_sql()
+        );
+        assert_eq!(
+            sql("req_body.$.user.Id:>5"),
+            BodyFilter::from_str("req_body.$.user.Id > 5").unwrap().to_sql()
+        );
+        assert_eq!(
+            sql("res_body.$.error:*"),
+            BodyFilter::from_str("res_body.$.error exists").unwrap().to_sql()
+        );
+        assert_eq!(
+            sql("timeout"),
+            (
+                "requests.id IN (SELECT rowid FROM requests_fts WHERE requests_fts MATCH ?)".to_string(),
+                texts(&[&fts_match("timeout").unwrap()])
+            )
+        );
+    }
+
+    #[test]
+    fn combines_terms() {
+        assert_eq!(
+            sql("method:POST -status:200"),
+            (
+                "(method IN (?)) AND (NOT ((CAST(status AS INTEGER) = ?)))".to_string(),
+                vec![text("POST"), SqlValue::Integer(200)]
+            )
+        );
+        assert_eq!(
+            sql("(method:GET OR method:HEAD) NOT trace:a"),
+            (
+                "((method IN (?)) OR (method IN (?))) AND (NOT (trace_id IN (?)))".to_string(),
+                texts(&["GET", "HEAD", "a"])
+            )
+        );
+        assert_eq!(Query::from_str("  ").unwrap(), Query(None));
+    }
+
+    #[test]
+    fn rejects_malformed_queries() {
+        assert_eq!(parse_error("method:").message, "Missing value for 'method'");
+        assert_eq!(parse_error("method:").position, 7);
+        assert_eq!(parse_error("status:abc").message, "'abc' is not a number");
+        assert_eq!(parse_error("status:9xx").message, "'9xx' is not a status class");
+        assert_eq!(parse_error("latency:1..x").message, "'x' is not a number");
+        assert_eq!(parse_error("colour:red").message, "Unknown field 'colour'");
+        assert_eq!(
+            parse_error("reqheader:!").message,
+            "Header filter '' is missing a header name"
+        );
+        assert_eq!(parse_error("\"open").message, "Unterminated quote");
+        assert_eq!(parse_error("(method:GET").message, "Unclosed parenthesis");
+        assert_eq!(parse_error("method:GET)").position, 10);
+        assert_eq!(parse_error("method:GET OR").message, "Expected a filter");
+        assert_eq!(
+            parse_error("req_body.user:1").message,
+            "JSON path 'user' must start with '$'"
+        );
+        assert!(Query::from_str(&"(".repeat(MAX_QUERY_DEPTH + 2)).is_err());
+        assert!(Query::from_str(&"trace:a ".repeat(MAX_QUERY_TERMS + 1)).is_err());
+    }
+
+    #[test]
+    fn parses_non_ascii_input() {
+        assert_eq!(
+            sql("req_body.$.İd:1"),
+            BodyFilter::from_str("req_body.$.İd = 1").unwrap().to_sql()
+        );
+        assert_eq!(parse_error("İİ:x").message, "Unknown field 'i̇i̇'");
+        // Positions count characters of the query, not of the lowercased field
+        assert_eq!(parse_error("İİ: x").message, "Missing value for 'i̇i̇'");
+        assert_eq!(parse_error("İİ: x").position, 3);
+        assert_eq!(parse_error("é status:").position, 9);
+        assert_eq!(parse_error("ÉNDPOINT:").position, 9);
+        assert_eq!(
+            sql("service:Café"),
+            ("(lower(service) GLOB ?)".to_string(), texts(&["café"]))
+        );
+        assert_eq!(
+            sql("endpoint:/ünïcode"),
+            (
+                format!("(({}) GLOB ?)", url_path_sql("endpoint")),
+                texts(&["/ünïcode"])
+            )
+        );
+    }
+}
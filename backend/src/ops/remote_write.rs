@@ -0,0 +1,318 @@
+//! Decoding of Prometheus remote-write requests, snappy compressed protobuf `WriteRequest`s
+
+use super::custom_metrics::{HistogramValue, Labels, MetricKind, MetricSample, MetricValue};
+use anyhow::{anyhow, Result};
+use chrono::DateTime;
+use prost::Message;
+use std::collections::HashMap;
+
+/// Largest decompressed body accepted. Snappy bodies state their length up front, so bigger ones
+/// are rejected before anything is allocated.
+pub const MAX_DECOMPRESSED_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+    #[prost(message, repeated, tag = "3")]
+    pub metadata: Vec<MetricMetadata>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Sample {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    /// Milliseconds since the epoch
+    #[prost(int64, tag = "2")]
+    pub timestamp: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct MetricMetadata {
+    #[prost(enumeration = "MetricType", tag = "1")]
+    pub r#type: i32,
+    #[prost(string, tag = "2")]
+    pub metric_family_name: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+pub enum MetricType {
+    Unknown = 0,
+    Counter = 1,
+    Gauge = 2,
+    Histogram = 3,
+    GaugeHistogram = 4,
+    Summary = 5,
+    Info = 6,
+    StateSet = 7,
+}
+
+/// Part of a classic histogram, which remote-write sends as `_bucket`, `_sum` and `_count` series
+enum HistogramPart {
+    Bucket(f64),
+    Sum,
+    Count,
+}
+
+/// Decodes a remote-write body into samples. Series of the same histogram are put back together,
+/// and metrics without metadata are counters when their name ends with `_total`.
+pub fn decode_write_request(body: &[u8]) -> Result<Vec<MetricSample>> {
+    let length = snap::raw::decompress_len(body).map_err(|e| anyhow!("Invalid snappy body: {}", e))?;
+    if length > MAX_DECOMPRESSED_BYTES {
+        return Err(anyhow!(
+            "Decompressed body of {} bytes is over the limit of {} bytes",
+            length,
+            MAX_DECOMPRESSED_BYTES
+        ));
+    }
+    let body = snap::raw::Decoder::new()
+        .decompress_vec(body)
+        .map_err(|e| anyhow!("Invalid snappy body: {}", e))?;
+    let request =
+        WriteRequest::decode(body.as_slice()).map_err(|e| anyhow!("Invalid write request: {}", e))?;
+
+    let types = request
+        .metadata
+        .iter()
+        .map(|metadata| (metadata.metric_family_name.as_str(), metadata.r#type()))
+        .collect::<HashMap<&str, MetricType>>();
+    // Families with buckets, whose `_sum` and `_count` belong to the histogram
+    let histograms = request
+        .timeseries
+        .iter()
+        .filter_map(|series| {
+            let name = series.labels.iter().find(|l| l.name == "__name__")?;
+            let family = name.value.strip_suffix("_bucket")?;
+            let has_le = series.labels.iter().any(|l| l.name == "le");
+            let is_histogram = types
+                .get(family)
+                .is_none_or(|kind| *kind == MetricType::Histogram);
+            (has_le && is_histogram).then_some(family.to_string())
+        })
+        .collect::<Vec<String>>();
+
+    let mut samples = vec![];
+    let mut parts = HashMap::<(String, String, i64), (Labels, HistogramValue)>::new();
+    for series in request.timeseries {
+        let mut name = None;
+        let mut le = None;
+        let mut labels = Labels::new();
+        for label in series.labels {
+            match label.name.as_str() {
+                "__name__" => name = Some(label.value),
+                "le" => le = Some(label.value),
+                _ => {
+                    labels.insert(label.name, label.value);
+                }
+            }
+        }
+        let Some(name) = name else {
+            continue;
+        };
+        let part = histograms.iter().find_map(|family| {
+            let part = match name.strip_prefix(family.as_str())? {
+                "_bucket" => HistogramPart::Bucket(le.as_deref()?.parse().ok()?),
+                "_sum" => HistogramPart::Sum,
+                "_count" => HistogramPart::Count,
+                _ => return None,
+            };
+            Some((family.clone(), part))
+        });
+        if let Some(le) = le.filter(|_| part.is_none()) {
+            labels.insert("le".to_string(), le);
+        }
+
+        // NaN values are staleness markers
+        for sample in series.samples.iter().filter(|s| !s.value.is_nan()) {
+            let Some(date) = DateTime::from_timestamp_millis(sample.timestamp) else {
+                continue;
+            };
+            match &part {
+                Some((family, part)) => {
+                    let key = (family.clone(), serde_json::to_string(&labels)?, sample.timestamp);
+                    let (_, histogram) = parts
+                        .entry(key)
+                        .or_insert_with(|| (labels.clone(), HistogramValue::default()));
+                    match part {
+                        HistogramPart::Bucket(le) => histogram.buckets.push((*le, sample.value)),
+                        HistogramPart::Sum => histogram.sum = sample.value,
+                        HistogramPart::Count => histogram.count = sample.value,
+                    }
+                }
+                None => {
+                    let kind = match types.get(name.as_str()) {
+                        Some(MetricType::Counter) => MetricKind::Counter,
+                        Some(_) => MetricKind::Gauge,
+                        None if name.ends_with("_total") => MetricKind::Counter,
+                        None => MetricKind::Gauge,
+                    };
+                    samples.push(MetricSample {
+                        name: name.clone(),
+                        kind,
+                        labels: labels.clone(),
+                        date,
+                        value: MetricValue::Number(sample.value),
+                    });
+                }
+            }
+        }
+    }
+
+    for ((family, _, timestamp), (labels, histogram)) in parts {
+        let Some(date) = DateTime::from_timestamp_millis(timestamp) else {
+            continue;
+        };
+        samples.push(MetricSample {
+            name: family,
+            kind: MetricKind::Histogram,
+            labels,
+            date,
+            value: MetricValue::Histogram(HistogramValue::new(
+                histogram.buckets,
+                histogram.sum,
+                histogram.count,
+            )),
+        });
+    }
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(labels: &[(&str, &str)], value: f64) -> TimeSeries {
+        TimeSeries {
+            labels: labels
+                .iter()
+                .map(|(name, value)| Label {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+            samples: vec![Sample {
+                value,
+                timestamp: 1_792_393_200_000,
+            }],
+        }
+    }
+
+    fn encode(request: &WriteRequest) -> Vec<u8> {
+        snap::raw::Encoder::new()
+            .compress_vec(&request.encode_to_vec())
+            .unwrap()
+    }
+
+    #[test]
+    fn decodes_write_requests() {
+        let request = WriteRequest {
+            timeseries: vec![
+                series(
+                    &[
+                        ("__name__", "http_duration_bucket"),
+                        ("le", "0.1"),
+                        ("job", "api"),
+                    ],
+                    3.,
+                ),
+                series(
+                    &[
+                        ("__name__", "http_duration_bucket"),
+                        ("le", "+Inf"),
+                        ("job", "api"),
+                    ],
+                    5.,
+                ),
+                series(&[("__name__", "http_duration_sum"), ("job", "api")], 1.5),
+                series(&[("__name__", "http_duration_count"), ("job", "api")], 5.),
+                series(&[("__name__", "jobs_total"), ("job", "api")], 42.),
+                series(&[("__name__", "queue_size")], 7.),
+                series(&[("__name__", "queue_size"), ("queue", "stale")], f64::NAN),
+                series(&[("__name__", "restarts")], 2.),
+                series(&[("job", "unnamed")], 1.),
+            ],
+            metadata: vec![MetricMetadata {
+                r#type: MetricType::Counter as i32,
+                metric_family_name: "restarts".to_string(),
+            }],
+        };
+        let mut samples = decode_write_request(&encode(&request)).unwrap();
+        samples.sort_by(|a, b| a.name.cmp(&b.name));
+        let summary = samples
+            .iter()
+            .map(|s| (s.name.as_str(), s.kind))
+            .collect::<Vec<(&str, MetricKind)>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("http_duration", MetricKind::Histogram),
+                ("jobs_total", MetricKind::Counter),
+                ("queue_size", MetricKind::Gauge),
+                ("restarts", MetricKind::Counter),
+            ]
+        );
+
+        let histogram = &samples[0];
+        assert_eq!(
+            histogram.labels,
+            Labels::from([("job".to_string(), "api".to_string())])
+        );
+        assert_eq!(histogram.date.timestamp_millis(), 1_792_393_200_000);
+        assert_eq!(
+            histogram.value,
+            MetricValue::Histogram(HistogramValue {
+                buckets: vec![(0.1, 3.), (f64::INFINITY, 5.)],
+                sum: 1.5,
+                count: 5.,
+            })
+        );
+        assert_eq!(samples[1].value, MetricValue::Number(42.));
+    }
+
+    #[test]
+    fn keeps_le_of_buckets_not_declared_histograms() {
+        let request = WriteRequest {
+            timeseries: vec![series(&[("__name__", "limits_bucket"), ("le", "10")], 1.)],
+            metadata: vec![MetricMetadata {
+                r#type: MetricType::Gauge as i32,
+                metric_family_name: "limits".to_string(),
+            }],
+        };
+        let samples = decode_write_request(&encode(&request)).unwrap();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].name, "limits_bucket");
+        assert_eq!(samples[0].labels["le"], "10");
+    }
+
+    #[test]
+    fn rejects_invalid_bodies() {
+        assert!(decode_write_request(b"not snappy at all").is_err());
+        // A few bytes claiming a 100 MiB body are rejected before decompressing
+        let mut body = vec![];
+        let mut length = 100 * 1024 * 1024u64;
+        while length >= 0x80 {
+            body.push((length as u8) | 0x80);
+            length >>= 7;
+        }
+        body.push(length as u8);
+        let error = decode_write_request(&body).unwrap_err().to_string();
+        assert!(error.contains("over the limit"), "{}", error);
+    }
+}
@@ -0,0 +1,488 @@
+//! Re-sends logged requests to another environment, e.g. staging, and compares the responses.
+
+use super::{
+    endpoints::split_origin,
+    headers::Headers,
+    redaction::{REDACTED_HASH_PREFIX, REDACTED_MASK},
+    requests::RequestLog,
+};
+use actix_web::http::Method;
+use anyhow::{anyhow, Result};
+use awc::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    collections::BTreeSet,
+    env,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// Seconds to wait for the replayed response when no timeout is given
+pub const DEFAULT_REPLAY_TIMEOUT: u64 = 30;
+pub const MAX_REPLAY_TIMEOUT: u64 = 120;
+/// Largest replayed response body read
+const MAX_REPLAY_BODY_BYTES: usize = 10 * 1024 * 1024;
+/// Body differences listed at most
+const MAX_BODY_CHANGES: usize = 100;
+
+/// Headers describing the original connection or body rather than the request, which the client
+/// sets itself
+const CONNECTION_HEADERS: [&str; 9] = [
+    "host",
+    "content-length",
+    "connection",
+    "keep-alive",
+    "transfer-encoding",
+    "te",
+    "trailer",
+    "upgrade",
+    "accept-encoding",
+];
+
+/// Base URL requests are replayed against, set with REPLAY_TARGET_URL. Replays are disabled without it.
+pub fn replay_target() -> Option<String> {
+    env::var("REPLAY_TARGET_URL")
+        .ok()
+        .map(|url| url.trim().trim_end_matches('/').to_string())
+        .filter(|url| url.starts_with("http://") || url.starts_with("https://"))
+}
+
+/// Changes to the logged request, all optional
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayOptions {
+    /// Headers to set, replacing the logged ones with the same name, or to remove when `null`
+    #[serde(default)]
+    pub headers: Map<String, Value>,
+    /// Body sent instead of the logged one
+    pub body: Option<Value>,
+    /// Seconds to wait for the response
+    pub timeout: Option<u64>,
+}
+
+/// Whether the redactor replaced part of `value` before it was stored
+fn is_redacted(value: &str) -> bool {
+    value.contains(REDACTED_MASK) || value.contains(REDACTED_HASH_PREFIX)
+}
+
+/// URL of a logged endpoint on the replay target. Bare endpoints like `@host/x` or `.evil.com/x` are
+/// made absolute paths so they can't change the target's host.
+fn replay_url(target: &str, endpoint: &str, params: &str) -> String {
+    let (_, path) = split_origin(endpoint);
+    let separator = if path.starts_with('/') { "" } else { "/" };
+    match params.is_empty() {
+        true => format!("{}{}{}", target, separator, path),
+        false => format!("{}{}{}?{}", target, separator, path, params),
+    }
+}
+
+/// A logged request rebuilt to be sent to the replay target
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Headers,
+    #[serde(skip)]
+    pub body: Option<String>,
+    /// Redacted headers left out
+    pub stripped_headers: Vec<String>,
+    /// Redacted values that were sent as stored
+    pub warnings: Vec<String>,
+}
+
+impl ReplayRequest {
+    pub fn new(log: &RequestLog, options: &ReplayOptions, target: &str) -> Result<ReplayRequest> {
+        let url = replay_url(target, &log.endpoint, &log.params);
+
+        let overridden = options
+            .headers
+            .keys()
+            .map(|name| name.trim().to_lowercase())
+            .collect::<BTreeSet<String>>();
+        let mut stripped_headers = BTreeSet::new();
+        let mut pairs = vec![];
+        let (logged, unparsed) = log.req_headers.parsed_pairs();
+        for (name, value) in logged {
+            if CONNECTION_HEADERS.contains(&name.as_str()) || overridden.contains(&name) {
+                continue;
+            }
+            if is_redacted(&value) {
+                stripped_headers.insert(name);
+                continue;
+            }
+            pairs.push((name, value));
+        }
+        for (name, value) in &options.headers {
+            match value {
+                Value::Null => {}
+                Value::String(value) => pairs.push((name.trim().to_lowercase(), value.clone())),
+                _ => return Err(anyhow!("Header '{}' must be a string or null", name)),
+            }
+        }
+
+        let mut warnings = vec![];
+        if unparsed > 0 {
+            warnings.push(format!(
+                "{} logged header line(s) couldn't be parsed and were left out",
+                unparsed
+            ));
+        }
+        if is_redacted(&log.params) {
+            warnings.push("The query string contains redacted values".to_string());
+        }
+        let body = match options.body.as_ref().or(log.req_body.as_ref()) {
+            None | Some(Value::Null) => None,
+            Some(Value::String(text)) if text.is_empty() => None,
+            Some(Value::String(text)) => Some(text.clone()),
+            Some(body) => Some(body.to_string()),
+        };
+        if options.body.is_none() && body.as_deref().is_some_and(is_redacted) {
+            warnings.push("The body contains redacted values".to_string());
+        }
+
+        Ok(ReplayRequest {
+            method: log.method.clone(),
+            url,
+            headers: Headers::Structured(pairs),
+            body,
+            stripped_headers: stripped_headers.into_iter().collect(),
+            warnings,
+        })
+    }
+
+    /// Sends the request, returning the response's status, headers, body and time in milliseconds
+    pub async fn send(&self, timeout: Duration) -> Result<ReplayResponse> {
+        let method = Method::from_str(&self.method.to_uppercase())
+            .map_err(|_| anyhow!("Invalid method '{}'", self.method))?;
+        let mut request = Client::default().request(method, &self.url).timeout(timeout);
+        for (name, value) in self.headers.pairs() {
+            request = request.append_header((name.as_str(), value.as_str()));
+        }
+
+        let start = Instant::now();
+        let response = match &self.body {
+            Some(body) => request.send_body(body.clone()).await,
+            None => request.send().await,
+        };
+        let mut response = response.map_err(|e| anyhow!("Failed to send request: {}", e))?;
+        let bytes = response
+            .body()
+            .limit(MAX_REPLAY_BODY_BYTES)
+            .await
+            .map_err(|e| anyhow!("Failed to read response: {}", e))?;
+        let res_time = start.elapsed().as_millis() as u32;
+
+        let headers = response
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                (
+                    name.as_str().to_string(),
+                    String::from_utf8_lossy(value.as_bytes()).to_string(),
+                )
+            })
+            .collect();
+        let body = match bytes.is_empty() {
+            true => Value::Null,
+            false => serde_json::from_slice(&bytes)
+                .unwrap_or(Value::String(String::from_utf8_lossy(&bytes).to_string())),
+        };
+        Ok(ReplayResponse {
+            status: response.status().as_u16(),
+            res_time,
+            headers: Headers::Structured(headers),
+            body,
+        })
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayResponse {
+    pub status: u16,
+    pub res_time: u32,
+    pub headers: Headers,
+    pub body: Value,
+}
+
+impl ReplayResponse {
+    /// The logged response
+    pub fn original(log: &RequestLog) -> ReplayResponse {
+        ReplayResponse {
+            status: log.status,
+            res_time: log.res_time,
+            headers: log.res_headers.clone(),
+            body: log.res_body.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// A value that differs between the original and replayed bodies, missing on the side lacking it
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BodyChange {
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub original: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replayed: Option<Value>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayDiff {
+    pub status_changed: bool,
+    /// Replayed minus original response time, in milliseconds
+    pub res_time_delta: i64,
+    pub body_changed: bool,
+    pub body_changes: Vec<BodyChange>,
+    /// Whether changes past the first 100 were left out
+    pub truncated: bool,
+}
+
+fn key_path(path: &str, key: &str) -> String {
+    match !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        true => format!("{}.{}", path, key),
+        false => format!("{}['{}']", path, key.replace('\'', "\\'")),
+    }
+}
+
+/// Collects the differences between two JSON values, one more than `MAX_BODY_CHANGES` at most
+fn diff_values(
+    path: &str,
+    original: Option<&Value>,
+    replayed: Option<&Value>,
+    changes: &mut Vec<BodyChange>,
+) {
+    if changes.len() > MAX_BODY_CHANGES || original == replayed {
+        return;
+    }
+    match (original, replayed) {
+        (Some(Value::Object(a)), Some(Value::Object(b))) => {
+            let keys = a.keys().chain(b.keys()).collect::<BTreeSet<&String>>();
+            for key in keys {
+                diff_values(&key_path(path, key), a.get(key), b.get(key), changes);
+            }
+        }
+        (Some(Value::Array(a)), Some(Value::Array(b))) => {
+            for i in 0..a.len().max(b.len()) {
+                diff_values(&format!("{}[{}]", path, i), a.get(i), b.get(i), changes);
+            }
+        }
+        _ => changes.push(BodyChange {
+            path: path.to_string(),
+            original: original.cloned(),
+            replayed: replayed.cloned(),
+        }),
+    }
+}
+
+impl ReplayDiff {
+    pub fn new(original: &ReplayResponse, replayed: &ReplayResponse) -> ReplayDiff {
+        let mut body_changes = vec![];
+        diff_values("$", Some(&original.body), Some(&replayed.body), &mut body_changes);
+        let truncated = body_changes.len() > MAX_BODY_CHANGES;
+        body_changes.truncate(MAX_BODY_CHANGES);
+        ReplayDiff {
+            status_changed: original.status != replayed.status,
+            res_time_delta: replayed.res_time as i64 - original.res_time as i64,
+            body_changed: !body_changes.is_empty(),
+            body_changes,
+            truncated,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use serde_json::json;
+
+    const TARGET: &str = "https://staging.example.com";
+
+    fn log(req_headers: Headers, req_body: Option<Value>) -> RequestLog {
+        RequestLog {
+            id: Some(1),
+            method: "POST".to_string(),
+            endpoint: "https://api.example.com/orders".to_string(),
+            endpoint_template: None,
+            service: None,
+            trace_id: None,
+            span_id: None,
+            parent_span_id: None,
+            params: String::new(),
+            req_headers,
+            res_headers: Headers::default(),
+            date: Utc::now(),
+            status: 200,
+            req_body,
+            res_body: None,
+            res_time: 10,
+        }
+    }
+
+    fn pairs(headers: &[(&str, &str)]) -> Headers {
+        Headers::Structured(
+            headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    fn response(body: Value) -> ReplayResponse {
+        ReplayResponse {
+            status: 200,
+            res_time: 10,
+            headers: Headers::default(),
+            body,
+        }
+    }
+
+    #[test]
+    fn builds_urls_on_the_target() {
+        assert_eq!(
+            replay_url(TARGET, "https://api.example.com/orders/1", "expand=items"),
+            "https://staging.example.com/orders/1?expand=items"
+        );
+        assert_eq!(
+            replay_url(TARGET, "https://api.example.com", ""),
+            "https://staging.example.com/"
+        );
+        assert_eq!(
+            replay_url(TARGET, "/orders", ""),
+            "https://staging.example.com/orders"
+        );
+        assert_eq!(
+            replay_url(TARGET, "@internal-host/admin", ""),
+            "https://staging.example.com/@internal-host/admin"
+        );
+        assert_eq!(
+            replay_url(TARGET, ".evil.com/x", ""),
+            "https://staging.example.com/.evil.com/x"
+        );
+        assert_eq!(replay_url(TARGET, "", "a=1"), "https://staging.example.com/?a=1");
+    }
+
+    #[test]
+    fn strips_redacted_headers() {
+        let headers = pairs(&[
+            ("authorization", "Bearer [REDACTED]"),
+            ("x-session", "[HASH:1a2b3c]"),
+            ("host", "api.example.com"),
+            ("content-length", "12"),
+            ("accept", "application/json"),
+        ]);
+        let mut log = log(headers, Some(json!({ "card": "[REDACTED]" })));
+        log.params = "token=[REDACTED]".to_string();
+        let request = ReplayRequest::new(&log, &ReplayOptions::default(), TARGET).unwrap();
+
+        assert_eq!(request.url, "https://staging.example.com/orders?token=[REDACTED]");
+        assert_eq!(
+            request.headers.pairs(),
+            pairs(&[("accept", "application/json")]).pairs()
+        );
+        assert_eq!(request.stripped_headers, vec!["authorization", "x-session"]);
+        assert_eq!(
+            request.warnings,
+            vec![
+                "The query string contains redacted values",
+                "The body contains redacted values"
+            ]
+        );
+        assert_eq!(request.body.as_deref(), Some(r#"{"card":"[REDACTED]"}"#));
+    }
+
+    #[test]
+    fn applies_overrides() {
+        let headers = pairs(&[
+            ("authorization", "Bearer [REDACTED]"),
+            ("accept", "application/json"),
+            ("x-debug", "1"),
+        ]);
+        let log = log(headers, Some(json!({ "card": "[REDACTED]" })));
+        let mut options = ReplayOptions {
+            body: Some(json!("plain text")),
+            ..Default::default()
+        };
+        options
+            .headers
+            .insert("authorization".to_string(), json!("Bearer staging"));
+        options.headers.insert(" X-Debug ".to_string(), Value::Null);
+        let request = ReplayRequest::new(&log, &options, TARGET).unwrap();
+
+        assert_eq!(
+            request.headers.pairs(),
+            pairs(&[
+                ("accept", "application/json"),
+                ("authorization", "Bearer staging")
+            ])
+            .pairs()
+        );
+        assert!(request.stripped_headers.is_empty());
+        assert!(request.warnings.is_empty());
+        assert_eq!(request.body.as_deref(), Some("plain text"));
+
+        options.headers.insert("x-retry".to_string(), json!(3));
+        assert!(ReplayRequest::new(&log, &options, TARGET).is_err());
+    }
+
+    #[test]
+    fn warns_about_unparsed_headers() {
+        let headers = Headers::Raw("Accept: text/plain\nnot a header".to_string());
+        let request =
+            ReplayRequest::new(&log(headers, None), &ReplayOptions::default(), TARGET).unwrap();
+        assert_eq!(
+            request.headers.pairs(),
+            pairs(&[("accept", "text/plain")]).pairs()
+        );
+        assert_eq!(request.warnings.len(), 1);
+        assert_eq!(request.body, None);
+    }
+
+    #[test]
+    fn diffs_bodies() {
+        let original = json!({ "id": 1, "items": [1, 2], "total": 3, "a-b": true });
+        let replayed = json!({ "id": 1, "items": [1, 5, 6], "a-b": false, "currency": "EUR" });
+        let diff = ReplayDiff::new(&response(original), &response(replayed));
+        let change = |path: &str, original: Option<Value>, replayed: Option<Value>| BodyChange {
+            path: path.to_string(),
+            original,
+            replayed,
+        };
+
+        assert!(diff.body_changed && !diff.truncated && !diff.status_changed);
+        assert_eq!(
+            diff.body_changes,
+            vec![
+                change("$['a-b']", Some(json!(true)), Some(json!(false))),
+                change("$.currency", None, Some(json!("EUR"))),
+                change("$.items[1]", Some(json!(2)), Some(json!(5))),
+                change("$.items[2]", None, Some(json!(6))),
+                change("$.total", Some(json!(3)), None),
+            ]
+        );
+
+        let same = ReplayDiff::new(&response(json!({ "id": 1 })), &response(json!({ "id": 1 })));
+        assert!(!same.body_changed && same.body_changes.is_empty());
+    }
+
+    #[test]
+    fn truncates_body_changes() {
+        let original = Value::Array((0..150).map(|i| json!(i)).collect());
+        let replayed = Value::Array((0..150).map(|i| json!(-i - 1)).collect());
+        let diff = ReplayDiff::new(&response(original), &response(replayed));
+        assert_eq!(diff.body_changes.len(), MAX_BODY_CHANGES);
+        assert_eq!(diff.body_changes.last().unwrap().path, "$[99]");
+        assert!(diff.truncated);
+
+        let original = Value::Array((0..100).map(|i| json!(i)).collect());
+        let replayed = Value::Array((0..100).map(|i| json!(-i - 1)).collect());
+        let diff = ReplayDiff::new(&response(original), &response(replayed));
+        assert_eq!(diff.body_changes.len(), MAX_BODY_CHANGES);
+        assert!(!diff.truncated);
+    }
+}
@@ -1,30 +1,69 @@
+use super::{
+    body_filters::{BodyFilter, BODY_FILTER_TIME_BUDGET},
+    filters::{text, RequestFilter},
+    headers::{insert_request_headers, HeaderDirection, HeaderFilter, Headers},
+    pagination::{count_rows, Cursor, Page},
+    query::Query,
+    search::{fts_match, SEARCH_RANK_SQL, SNIPPET_END, SNIPPET_START, SNIPPET_TOKENS},
+    stats::TimeWindow,
+    traces::trace_id,
+};
 use anyhow::Result;
 use chrono::{DateTime, Utc};
-use rusqlite::{params, Connection};
+use rusqlite::{params, params_from_iter, types::Value as SqlValue, Connection, OptionalExtension, Row};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::cmp;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct RequestLogRequest {
     pub method: String,
     pub url: String,
-    pub req_headers: String,
-    pub res_headers: String,
+    #[serde(default)]
+    pub req_headers: Headers,
+    #[serde(default)]
+    pub res_headers: Headers,
     pub date: DateTime<Utc>,
     pub status: u16,
     pub req_body: Option<Value>,
     pub res_body: Option<Value>,
     pub res_time: u32,
+    /// Service the request was made to, the host of `url` when not given
+    #[serde(default)]
+    pub service: Option<String>,
+    /// W3C trace context ids, taken from a `traceparent` request header when not given
+    #[serde(default)]
+    pub trace_id: Option<String>,
+    #[serde(default)]
+    pub span_id: Option<String>,
+    #[serde(default)]
+    pub parent_span_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct RequestLog {
+    /// Row id, missing until the log is stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
     pub method: String,
     pub endpoint: String,
+    /// Route the endpoint belongs to, e.g. `/users/:id`, used to group requests
+    #[serde(default)]
+    pub endpoint_template: Option<String>,
+    /// Service the request was made to, e.g. `api.example.com`
+    #[serde(default)]
+    pub service: Option<String>,
+    /// Trace the request belongs to, 32 hex digits
+    #[serde(default)]
+    pub trace_id: Option<String>,
+    /// Span of the request in its trace, 16 hex digits
+    #[serde(default)]
+    pub span_id: Option<String>,
+    /// Span that made the request
+    #[serde(default)]
+    pub parent_span_id: Option<String>,
     pub params: String,
-    pub req_headers: String,
-    pub res_headers: String,
+    pub req_headers: Headers,
+    pub res_headers: Headers,
     pub date: DateTime<Utc>,
     pub status: u16,
     pub req_body: Option<Value>,
@@ -32,11 +71,67 @@ pub struct RequestLog {
     pub res_time: u32,
 }
 
+/// Route template of a request, falling back to the raw endpoint for rows without one
+pub const ENDPOINT_GROUP_SQL: &str = "COALESCE(endpoint_template, endpoint)";
+
+/// Requests counted as errors by error rates, those answered with a 5xx
+pub const ERROR_STATUS_SQL: &str = "CAST(status AS INTEGER) >= 500";
+
+/// Columns read by `RequestLog::from_row`, in order
+pub const REQUEST_LOG_COLUMNS: &str = "date, endpoint, params, method, req_headers, res_headers, \
+    status, req_body, res_body, res_time, requests.id, endpoint_template, service, trace_id, span_id, \
+    parent_span_id";
+
+impl RequestLog {
+    /// Builds a log from a row selected with `REQUEST_LOG_COLUMNS`
+    pub fn from_row(row: &Row) -> Result<RequestLog> {
+        Ok(RequestLog {
+            date: DateTime::parse_from_rfc3339(&row.get::<_, String>(0)?)?.with_timezone(&Utc),
+            endpoint: row.get(1)?,
+            params: row.get(2)?,
+            method: row.get(3)?,
+            req_headers: Headers::from_stored(&row.get::<_, String>(4)?),
+            res_headers: Headers::from_stored(&row.get::<_, String>(5)?),
+            status: row.get::<_, String>(6)?.parse()?,
+            req_body: serde_json::from_str(&row.get::<_, String>(7)?)?,
+            res_body: serde_json::from_str(&row.get::<_, String>(8)?)?,
+            res_time: row.get(9)?,
+            id: row.get(10)?,
+            endpoint_template: row.get(11)?,
+            service: row.get(12)?,
+            trace_id: row.get(13)?,
+            span_id: row.get(14)?,
+            parent_span_id: row.get(15)?,
+        })
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct PaginatedRequests {
     pub requests: Vec<RequestLog>,
-    pub total_items: usize,
+    /// Missing when counting was skipped
+    pub total_items: Option<usize>,
+    /// Whether `total_items` stopped at the approximate count limit
+    pub total_capped: bool,
+    pub next_cursor: Option<String>,
+}
+
+/// A search result with the best matching excerpt, matches wrapped in `<mark>` tags
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RequestMatch {
+    #[serde(flatten)]
+    pub log: RequestLog,
+    pub snippet: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PaginatedRequestMatches {
+    pub requests: Vec<RequestMatch>,
+    pub total_items: Option<usize>,
+    pub total_capped: bool,
+    pub next_cursor: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -45,122 +140,220 @@ pub struct ResponseBody {
     pub response_body: String,
 }
 
-pub fn get_paginated_requests(
-    from: DateTime<Utc>,
-    to: Option<DateTime<Utc>>,
-    index: usize,
-    limit: usize,
+/// Stores a request log along with its normalized headers, returning the new row id
+pub fn insert_request_log(conn: &Connection, log: &RequestLog) -> Result<i64> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        "INSERT INTO requests
+        (date, endpoint, params, method, req_headers, res_headers, status, req_body, res_body, res_time, endpoint_template, service, trace_id, span_id, parent_span_id)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+        params![
+            log.date.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+            log.endpoint,
+            log.params,
+            log.method,
+            log.req_headers.to_stored(),
+            log.res_headers.to_stored(),
+            log.status,
+            log.req_body.clone().unwrap_or_default().to_string(),
+            log.res_body.clone().unwrap_or_default().to_string(),
+            log.res_time,
+            log.endpoint_template,
+            log.service,
+            log.trace_id,
+            log.span_id,
+            log.parent_span_id
+        ],
+    )?;
+    let id = tx.last_insert_rowid();
+    insert_request_headers(&tx, id, HeaderDirection::Req, &log.req_headers)?;
+    insert_request_headers(&tx, id, HeaderDirection::Res, &log.res_headers)?;
+    tx.commit()?;
+    Ok(id)
+}
+
+/// Filters shared by the request listing and search endpoints, `query` being a parsed `q` filter
+pub fn request_filter(
     search: Option<String>,
     method: Option<String>,
     status: Option<String>,
     res_time_lt: Option<String>,
     res_time_gt: Option<String>,
+    header_filters: &[HeaderFilter],
+    body_filters: &[BodyFilter],
+    query: &Query,
+) -> RequestFilter {
+    let mut filter = RequestFilter::default();
+    // A trace id matches the requests of that trace as well as those mentioning it
+    let search_trace = search.as_deref().and_then(trace_id);
+    if let Some(search) = search.as_deref().and_then(fts_match) {
+        match search_trace {
+            Some(trace) => filter.push(
+                "trace_id = ? OR requests.id IN (SELECT rowid FROM requests_fts WHERE requests_fts MATCH ?)",
+                vec![text(trace), text(search)],
+            ),
+            None => filter.push(
+                "requests.id IN (SELECT rowid FROM requests_fts WHERE requests_fts MATCH ?)",
+                vec![text(search)],
+            ),
+        };
+    }
+    if let Some(method) = method.filter(|m| !m.is_empty() && m != "ALL") {
+        filter.push("method = ?", vec![text(method)]);
+    }
+    if let Some(status) = status.filter(|s| !s.is_empty()) {
+        filter.push("status = ?", vec![text(status)]);
+    }
+    if let Some(res_time_lt) = res_time_lt.filter(|r| !r.is_empty()) {
+        filter.push("res_time < ?", vec![text(res_time_lt)]);
+    }
+    if let Some(res_time_gt) = res_time_gt.filter(|r| !r.is_empty()) {
+        filter.push("res_time > ?", vec![text(res_time_gt)]);
+    }
+    for header_filter in header_filters {
+        let (clause, params) = header_filter.to_sql();
+        filter.push(clause, params);
+    }
+    for body_filter in body_filters {
+        let (clause, params) = body_filter.to_sql();
+        filter.push(clause, params).limit_time(BODY_FILTER_TIME_BUDGET);
+    }
+    query.apply(&mut filter);
+    filter
+}
+
+/// Cursor of a row selected with `REQUEST_LOG_COLUMNS`
+fn row_cursor(row: &Row, rank: Option<f64>) -> Result<Cursor> {
+    Ok(Cursor {
+        date: row.get(0)?,
+        id: row.get(10)?,
+        rank,
+        snapshot: None,
+    })
+}
+
+/// Requests between `from` and `to`, newest first, paginated on `(date, id)`
+pub fn get_paginated_requests(
+    from: DateTime<Utc>,
+    to: Option<DateTime<Utc>>,
+    page: &Page,
+    filter: &RequestFilter,
     conn: &Connection,
 ) -> Result<Value> {
-    // Get the current time
-    let limit = format!("LIMIT {}", limit).to_string();
-    let offset = format!("OFFSET {};", index).to_string();
-    // Prepare the SQL query
-    let mut stmt = conn.prepare(&format!(
-        "
-        WITH filtered_requests AS (
-            SELECT *
-            FROM requests
-            WHERE date > ?1 AND date <= ?2
-              AND endpoint LIKE ?3
-              AND (method = ?4 OR ?4 = \"\" OR ?4 = \"ALL\")
-              AND (status = ?5 OR ?5 = \"\")
-              AND (res_time < ?6 OR ?6 = \"\")
-              AND (res_time > ?7 OR ?7 = \"\")
-        )
-        SELECT *,
-               (SELECT COUNT(*) FROM filtered_requests) AS total_count
-        FROM filtered_requests
-        ORDER BY date DESC
-        {} {};
-        ",
-        limit, offset
-    ))?;
-
-    // Execute the query with the parameters
-    let rows = stmt.query_map(
-        params![
-            from.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+    let from_where = format!("FROM requests WHERE date > ? AND date <= ? AND {}", filter.sql());
+    let mut where_params = vec![
+        text(from.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()),
+        text(
             to.unwrap_or(Utc::now())
                 .format("%Y-%m-%dT%H:%M:%S%.3fZ")
                 .to_string(),
-            format!("%{}%", search.unwrap_or_default()),
-            method.unwrap_or("ALL".to_string()),
-            status.unwrap_or("".to_string()),
-            res_time_lt.unwrap_or("".to_string()),
-            res_time_gt.unwrap_or("".to_string())
-        ],
-        |row| {
-            Ok((
-                row.get::<_, String>(1)?,
-                row.get::<_, String>(2)?,
-                row.get::<_, String>(3)?,
-                row.get::<_, String>(4)?,
-                row.get::<_, String>(5)?,
-                row.get::<_, String>(6)?,
-                row.get::<_, String>(7)?,
-                row.get::<_, String>(8)?,
-                row.get::<_, String>(9)?,
-                row.get::<_, u32>(10)?,
-                row.get::<_, usize>(11)?,
-            ))
-        },
-    )?;
+        ),
+    ];
+    where_params.extend_from_slice(filter.params());
 
-    // Iterate over the results
-    let mut requests = vec![];
-    let mut total_items = 0;
-    for row in rows {
-        let (
-            date,
-            endpoint,
-            params,
-            method,
-            req_headers,
-            res_headers,
-            status,
-            reqb,
-            resb,
-            res_time,
-            count,
-        ) = row?;
-        let log = RequestLog {
-            date: DateTime::parse_from_rfc3339(&date)?.with_timezone(&Utc),
-            endpoint,
-            params,
-            method,
-            req_headers,
-            res_headers,
-            status: status.parse()?,
-            req_body: serde_json::from_str(&reqb)?,
-            res_body: serde_json::from_str(&resb)?,
-            res_time,
-        };
-        total_items = count;
+    let mut query_params = where_params.clone();
+    let after = match &page.cursor {
+        Some(cursor) => {
+            query_params.push(text(cursor.date.clone()));
+            query_params.push(SqlValue::Integer(cursor.id));
+            "AND (date, requests.id) < (?, ?)"
+        }
+        None => "",
+    };
+    let (limit, limit_params) = page.limit_sql();
+    query_params.extend(limit_params);
+    let mut stmt = conn.prepare(&format!(
+        "
+        SELECT {}
+        {} {}
+        ORDER BY date DESC, requests.id DESC
+        {};
+        ",
+        REQUEST_LOG_COLUMNS, from_where, after, limit
+    ))?;
 
-        requests.push(log);
-    }
+    let (mut rows, (total_items, total_capped)) = filter.run(conn, || {
+        let mut rows = stmt.query(params_from_iter(query_params))?;
+        let mut requests = vec![];
+        while let Some(row) = rows.next()? {
+            requests.push((RequestLog::from_row(row)?, row_cursor(row, None)?));
+        }
+        Ok((
+            requests,
+            count_rows(conn, &from_where, &where_params, page.count)?,
+        ))
+    })?;
+    let next_cursor = page.next_cursor(&mut rows);
 
     let result = serde_json::to_value(PaginatedRequests {
-        requests,
+        requests: rows.into_iter().map(|(log, _)| log).collect(),
         total_items,
+        total_capped,
+        next_cursor,
     })?;
     Ok(result)
 }
 
-#[derive(PartialEq, Eq)]
+/// Requests after `from`, oldest first, paginated on `(date, id)`
+pub fn requests_after(
+    from: DateTime<Utc>,
+    page: &Page,
+    filter: &RequestFilter,
+    conn: &Connection,
+) -> Result<Value> {
+    let mut query_params = vec![text(from.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string())];
+    query_params.extend_from_slice(filter.params());
+    let after = match &page.cursor {
+        Some(cursor) => {
+            query_params.push(text(cursor.date.clone()));
+            query_params.push(SqlValue::Integer(cursor.id));
+            "AND (date, requests.id) > (?, ?)"
+        }
+        None => "",
+    };
+    let (limit, limit_params) = page.limit_sql();
+    query_params.extend(limit_params);
+    let mut stmt = conn.prepare(&format!(
+        "
+        SELECT {}
+        FROM requests
+        WHERE date > ? AND {} {}
+        ORDER BY date ASC, requests.id ASC
+        {};
+        ",
+        REQUEST_LOG_COLUMNS,
+        filter.sql(),
+        after,
+        limit
+    ))?;
+
+    let mut rows = filter.run(conn, || {
+        let mut rows = stmt.query(params_from_iter(query_params))?;
+        let mut requests = vec![];
+        while let Some(row) = rows.next()? {
+            requests.push((RequestLog::from_row(row)?, row_cursor(row, None)?));
+        }
+        Ok(requests)
+    })?;
+    let next_cursor = page.next_cursor(&mut rows);
+
+    Ok(json!({
+        "requests": rows.into_iter().map(|(log, _)| log).collect::<Vec<RequestLog>>(),
+        "nextCursor": next_cursor,
+    }))
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
 pub enum Order {
+    #[serde(rename = "asc")]
     Ascending,
+    #[serde(rename = "desc")]
     Descending,
 }
 pub fn get_requests_from(
     start_time: String,
     order: Order,
+    filter: &RequestFilter,
     conn: &Connection,
 ) -> Result<Vec<RequestLog>> {
     let order_by = match order {
@@ -169,51 +362,62 @@ pub fn get_requests_from(
     };
     let mut stmt = conn.prepare(&format!(
         "
-        SELECT *
+        SELECT {}
         FROM requests
-        WHERE date > ?1
+        WHERE date > ? AND {}
         ORDER BY date {};
         ",
+        REQUEST_LOG_COLUMNS,
+        filter.sql(),
         order_by
     ))?;
 
-    let rows = stmt.query_map(params![start_time], |row| {
-        Ok((
-            row.get::<_, String>(1)?,
-            row.get::<_, String>(2)?,
-            row.get::<_, String>(3)?,
-            row.get::<_, String>(4)?,
-            row.get::<_, String>(5)?,
-            row.get::<_, String>(6)?,
-            row.get::<_, String>(7)?,
-            row.get::<_, String>(8)?,
-            row.get::<_, String>(9)?,
-            row.get::<_, u32>(10)?,
-        ))
-    })?;
+    let mut query_params = vec![text(start_time)];
+    query_params.extend_from_slice(filter.params());
 
-    // Iterate over the results
-    let mut results = vec![];
-    for row in rows {
-        let (date, endpoint, params, method, req_headers, res_headers, status, reqb, resb, res_time) =
-            row?;
-        let log = RequestLog {
-            date: DateTime::parse_from_rfc3339(&date)?.with_timezone(&Utc),
-            endpoint,
-            params,
-            method,
-            req_headers,
-            res_headers,
-            status: status.parse()?,
-            req_body: serde_json::from_str(&reqb)?,
-            res_body: serde_json::from_str(&resb)?,
-            res_time,
-        };
+    filter.run(conn, || {
+        let mut rows = stmt.query(params_from_iter(query_params))?;
 
-        results.push(log);
-    }
+        // Iterate over the results
+        let mut results = vec![];
+        while let Some(row) = rows.next()? {
+            results.push(RequestLog::from_row(row)?);
+        }
+        Ok(results)
+    })
+}
 
-    Ok(results)
+/// Requests stored after the one with id `after_id`, oldest first, to resume a live feed
+pub fn requests_since_id(
+    after_id: i64,
+    limit: usize,
+    filter: &RequestFilter,
+    conn: &Connection,
+) -> Result<Vec<RequestLog>> {
+    let mut stmt = conn.prepare(&format!(
+        "
+        SELECT {}
+        FROM requests
+        WHERE requests.id > ? AND {}
+        ORDER BY requests.id ASC
+        LIMIT ?;
+        ",
+        REQUEST_LOG_COLUMNS,
+        filter.sql(),
+    ))?;
+
+    let mut query_params = vec![SqlValue::Integer(after_id)];
+    query_params.extend_from_slice(filter.params());
+    query_params.push(SqlValue::Integer(limit as i64));
+
+    filter.run(conn, || {
+        let mut rows = stmt.query(params_from_iter(query_params))?;
+        let mut results = vec![];
+        while let Some(row) = rows.next()? {
+            results.push(RequestLog::from_row(row)?);
+        }
+        Ok(results)
+    })
 }
 
 pub fn requests_by_status(
@@ -227,7 +431,7 @@ pub fn requests_by_status(
             "WITH ranked_requests AS (
                 SELECT
                     status,
-                    endpoint,
+                    {group} AS endpoint,
                     COUNT(*) AS request_count,
                     ROW_NUMBER() OVER (PARTITION BY status ORDER BY COUNT(*) DESC) AS rank
                 FROM
@@ -235,7 +439,7 @@ pub fn requests_by_status(
                 WHERE
                     date > ?1
                 GROUP BY
-                    endpoint,
+                    {group},
                     status
             )
             SELECT
@@ -250,7 +454,8 @@ pub fn requests_by_status(
                 status,
                 request_count DESC;
             ",
-            limit.unwrap_or(3)
+            limit.unwrap_or(3),
+            group = ENDPOINT_GROUP_SQL
         )
         .as_str(),
     )?;
@@ -278,176 +483,378 @@ pub fn requests_by_status(
     Ok(results)
 }
 
+/// Routes whose template or endpoint contains `keyword`
 pub fn requests_search_suggestions(
     keyword: String,
     method: Option<String>,
     limit: Option<usize>,
     conn: &Connection,
 ) -> Result<Value> {
-    let mut stmt = conn.prepare(
+    let mut stmt = conn.prepare(&format!(
         "
-        SELECT * FROM requests
-        WHERE SUBSTR(endpoint, 1, INSTR(endpoint || '?', '?') - 1) LIKE ?1
+        SELECT DISTINCT {} FROM requests
+        WHERE (endpoint LIKE ?1 OR endpoint_template LIKE ?1)
         AND (method = ?2 OR  ?2 IS NULL)
         LIMIT ?3;
         ",
-    )?;
+        ENDPOINT_GROUP_SQL
+    ))?;
 
     let rows = stmt.query_map(
         params![format!("%{}%", keyword), method, limit.unwrap_or(5)],
-        |row| row.get::<_, String>(2),
+        |row| row.get::<_, String>(0),
     )?;
 
     let mut results = vec![];
     for row in rows {
-        let endpoint = row?;
-
-        let pos = endpoint.find('?').unwrap_or(endpoint.len());
-
-        if !results.contains(&endpoint[..pos].to_string()) {
-            results.push(endpoint[..pos].to_string());
-        }
+        results.push(row?);
     }
     Ok(json!(results))
 }
 
+/// Full-text search ranked by relevance then date, or all filtered requests by date without a
+/// search. Pages follow `(rank, date, id)`. Ranked pages only list requests up to the highest id
+/// when the first page was read, as new rows change the ranks of the others.
 pub fn requests_search(
-    keyword: String,
-    method: Option<String>,
-    index: usize,
-    limit: usize,
+    search: &str,
+    filter: &RequestFilter,
+    page: &Page,
     conn: &Connection,
 ) -> Result<Value> {
-    let mut stmt = conn.prepare(
+    let search = fts_match(search);
+    let snapshot = match (&page.cursor, &search) {
+        (_, None) => None,
+        (Some(cursor), Some(_)) => cursor.snapshot,
+        (None, Some(_)) => Some(conn.query_row(
+            "SELECT COALESCE(MAX(id), 0) FROM requests",
+            [],
+            |row| row.get::<_, i64>(0),
+        )?),
+    };
+    let (from_where, mut where_params) = match &search {
+        Some(search) => (
+            format!(
+                "FROM requests
+                JOIN (
+                    SELECT rowid, {} AS rank, snippet(requests_fts, -1, ?, ?, '…', ?) AS snippet
+                    FROM requests_fts
+                    WHERE requests_fts MATCH ?
+                ) matches ON matches.rowid = requests.id
+                WHERE {} {}",
+                SEARCH_RANK_SQL,
+                filter.sql(),
+                if snapshot.is_some() {
+                    "AND requests.id <= ?"
+                } else {
+                    ""
+                }
+            ),
+            vec![
+                text(SNIPPET_START),
+                text(SNIPPET_END),
+                SqlValue::Integer(SNIPPET_TOKENS as i64),
+                text(search.clone()),
+            ],
+        ),
+        None => (format!("FROM requests WHERE {}", filter.sql()), vec![]),
+    };
+    where_params.extend_from_slice(filter.params());
+    where_params.extend(snapshot.map(SqlValue::Integer));
+
+    let mut query_params = where_params.clone();
+    let after = match (&page.cursor, &search) {
+        (Some(cursor), Some(_)) => {
+            let rank = SqlValue::Real(cursor.rank.unwrap_or(f64::MIN));
+            query_params.extend([
+                rank.clone(),
+                rank,
+                text(cursor.date.clone()),
+                SqlValue::Integer(cursor.id),
+            ]);
+            "AND (matches.rank > ? OR (matches.rank = ? AND (date, requests.id) < (?, ?)))"
+        }
+        (Some(cursor), None) => {
+            query_params.push(text(cursor.date.clone()));
+            query_params.push(SqlValue::Integer(cursor.id));
+            "AND (date, requests.id) < (?, ?)"
+        }
+        (None, _) => "",
+    };
+    let (columns, order_by) = match search {
+        Some(_) => (
+            "matches.snippet, matches.rank",
+            "matches.rank, date DESC, requests.id DESC",
+        ),
+        None => ("NULL, NULL", "date DESC, requests.id DESC"),
+    };
+    let (limit, limit_params) = page.limit_sql();
+    query_params.extend(limit_params);
+    let mut stmt = conn.prepare(&format!(
         "
-        SELECT * FROM requests
-        WHERE endpoint LIKE ?1
-        AND (method = ?2 OR  ?2 = \"\" OR ?2 = \"ALL\")
-       	AND (status = ?3 OR ?3 = \"\")
-        AND (res_time < ?4 OR ?4 = \"\")
-        AND (res_time > ?5 OR ?5 = \"\")
-        ORDER BY date DESC;
+        SELECT {}, {}
+        {} {}
+        ORDER BY {}
+        {};
         ",
-    )?;
-    let method = method.unwrap_or("ALL".to_string());
+        REQUEST_LOG_COLUMNS, columns, from_where, after, order_by, limit
+    ))?;
 
-    let rows = stmt.query_map(params![format!("%{}%", keyword), method,], |row| {
+    let (mut rows, (total_items, total_capped)) = filter.run(conn, || {
+        let mut rows = stmt.query(params_from_iter(query_params))?;
+        let mut requests = vec![];
+        while let Some(row) = rows.next()? {
+            let request = RequestMatch {
+                log: RequestLog::from_row(row)?,
+                snippet: row.get(16)?,
+            };
+            let cursor = Cursor {
+                snapshot,
+                ..row_cursor(row, row.get(17)?)?
+            };
+            requests.push((request, cursor));
+        }
         Ok((
+            requests,
+            count_rows(conn, &from_where, &where_params, page.count)?,
+        ))
+    })?;
+    let next_cursor = page.next_cursor(&mut rows);
+
+    Ok(json!(PaginatedRequestMatches {
+        requests: rows.into_iter().map(|(request, _)| request).collect(),
+        total_items,
+        total_capped,
+        next_cursor,
+    }))
+}
+
+/// Headers whose value groups requests belonging to the same operation, in order of preference
+pub const CORRELATION_HEADERS: [&str; 3] = ["x-correlation-id", "x-request-id", "request-id"];
+
+/// Short form of a request listed next to another one
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestSummary {
+    pub id: i64,
+    pub date: String,
+    pub method: String,
+    pub status: u16,
+    pub res_time: u32,
+}
+
+/// Requests sharing a correlation header value
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CorrelationGroup {
+    pub header: String,
+    pub value: String,
+    pub requests: Vec<RequestSummary>,
+}
+
+pub fn request_by_id(id: i64, conn: &Connection) -> Result<Option<RequestLog>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM requests WHERE id = ?1",
+        REQUEST_LOG_COLUMNS
+    ))?;
+    let mut rows = stmt.query(params![id])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(RequestLog::from_row(row)?)),
+        None => Ok(None),
+    }
+}
+
+/// Summaries of the requests selected by `sql` as `id, date, method, status, res_time`
+pub fn request_summaries(
+    sql: &str,
+    params: &[SqlValue],
+    conn: &Connection,
+) -> Result<Vec<RequestSummary>> {
+    let mut stmt = conn.prepare(sql)?;
+    let rows = stmt.query_map(params_from_iter(params), |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
             row.get::<_, String>(1)?,
             row.get::<_, String>(2)?,
             row.get::<_, String>(3)?,
-            row.get::<_, String>(4)?,
-            row.get::<_, String>(5)?,
-            row.get::<_, String>(6)?,
-            row.get::<_, String>(7)?,
-            row.get::<_, String>(8)?,
-            row.get::<_, String>(9)?,
-            row.get::<_, u32>(10)?,
+            row.get::<_, u32>(4)?,
         ))
     })?;
-
-    let mut requests = vec![];
+    let mut summaries = vec![];
     for row in rows {
-        let (date, endpoint, params, method, req_headers, res_headers, status, reqb, resb, res_time) =
-            row?;
-        let request = RequestLog {
-            date: DateTime::parse_from_rfc3339(&date)?.with_timezone(&Utc),
-            endpoint,
-            params,
+        let (id, date, method, status, res_time) = row?;
+        summaries.push(RequestSummary {
+            id,
+            date,
             method,
-            req_headers,
-            res_headers,
             status: status.parse()?,
-            req_body: serde_json::from_str(&reqb)?,
-            res_body: serde_json::from_str(&resb)?,
             res_time,
-        };
-        requests.push(request);
+        });
     }
-
-    let len = requests.len();
-    let spliced = requests
-        .splice(index..cmp::min(index + limit, requests.len()), Vec::new())
-        .collect::<Vec<RequestLog>>();
-    Ok(json!(PaginatedRequests {
-        requests: spliced,
-        total_items: len,
-    }))
+    Ok(summaries)
 }
 
-/// Returns service level indicators by timeframe and previous timeframe:
-/// * Overall Average Latency
-/// * Throughput (req/s)
-/// * Error rate (err/s)
-pub fn service_level_indicators(from: DateTime<Utc>, conn: &Connection) -> Result<Value> {
-    let mut stmt = conn.prepare(
-        "
-        SELECT
-            AVG(res_time) AS average_res_time,  -- Average response time
-            CASE
-                WHEN (STRFTIME('%s', MAX(date)) - STRFTIME('%s', MIN(date))) = 0
-                THEN 0  -- Handle division by zero
-                ELSE COUNT(*) * 1.0 / (STRFTIME('%s', MAX(date)) - STRFTIME('%s', MIN(date)))  -- Requests per second
-            END AS requests_per_second,
-            COUNT(CASE WHEN status >= 400 THEN 1 END) * 1.0 / COUNT(*) AS error_rate  -- Error rate
-        FROM requests
-        WHERE date > ?1;
-        ",
+/// Up to `limit` requests to the same route right before and after a request, closest first
+pub fn neighbour_requests(
+    log: &RequestLog,
+    limit: usize,
+    conn: &Connection,
+) -> Result<(Vec<RequestSummary>, Vec<RequestSummary>)> {
+    // Rows logged before templating only group by their raw endpoint
+    let (column, value) = match &log.endpoint_template {
+        Some(template) => ("endpoint_template", template),
+        None => ("endpoint", &log.endpoint),
+    };
+    let params = [
+        text(value.clone()),
+        text(log.date.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()),
+        SqlValue::Integer(log.id.unwrap_or_default()),
+        SqlValue::Integer(limit as i64),
+    ];
+    let previous = request_summaries(
+        &format!(
+            "SELECT id, date, method, status, res_time FROM requests
+            WHERE {} = ? AND (date, id) < (?, ?)
+            ORDER BY date DESC, id DESC
+            LIMIT ?",
+            column
+        ),
+        &params,
+        conn,
     )?;
-    let rows = stmt.query_map(
-        params![from.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()],
-        |row| {
-            let average_res_time = row.get::<_, f32>(0);
-            let throughput = row.get::<_, f32>(1);
-            let error_rate = row.get::<_, f32>(2);
-            Ok((
-                average_res_time.unwrap_or(0.),
-                throughput.unwrap_or(0.),
-                error_rate.unwrap_or(0.),
-            ))
-        },
+    let next = request_summaries(
+        &format!(
+            "SELECT id, date, method, status, res_time FROM requests
+            WHERE {} = ? AND (date, id) > (?, ?)
+            ORDER BY date ASC, id ASC
+            LIMIT ?",
+            column
+        ),
+        &params,
+        conn,
     )?;
-    let slis = rows.into_iter().next().transpose()?;
+    Ok((previous, next))
+}
 
-    let duration = Utc::now().signed_duration_since(from);
-    let prev_from = from - duration;
-    let mut prev_stmt = conn.prepare(
+/// Other requests sharing the first correlation header the request was sent or answered with
+pub fn correlated_requests(
+    id: i64,
+    limit: usize,
+    conn: &Connection,
+) -> Result<Option<CorrelationGroup>> {
+    for header in CORRELATION_HEADERS {
+        let value = conn
+            .query_row(
+                "SELECT value FROM request_headers WHERE request_id = ?1 AND name = ?2 LIMIT 1",
+                params![id, header],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?;
+        let Some(value) = value else {
+            continue;
+        };
+        let requests = request_summaries(
+            "SELECT id, date, method, status, res_time FROM requests
+            WHERE id IN (SELECT request_id FROM request_headers WHERE name = ? AND value = ?)
+            AND id != ?
+            ORDER BY date ASC, id ASC
+            LIMIT ?",
+            &[
+                text(header),
+                text(value.clone()),
+                SqlValue::Integer(id),
+                SqlValue::Integer(limit as i64),
+            ],
+            conn,
+        )?;
+        return Ok(Some(CorrelationGroup {
+            header: header.to_string(),
+            value,
+            requests,
+        }));
+    }
+    Ok(None)
+}
+
+/// Service level indicators over a time window
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceLevelIndicators {
+    pub request_count: usize,
+    /// Share of requests answered with a 5xx
+    pub error_rate: f64,
+    pub average_latency: f64,
+    pub p95_latency: u32,
+    /// Requests per second over the whole window
+    pub throughput: f64,
+}
+
+pub fn window_slis(
+    window: &TimeWindow,
+    filter: &RequestFilter,
+    conn: &Connection,
+) -> Result<ServiceLevelIndicators> {
+    let (from, to) = window.bounds();
+    let mut params = vec![text(from), text(to)];
+    params.extend_from_slice(filter.params());
+    let (request_count, error_count, average_latency, p95_latency) = conn.query_row(
+        &format!(
             "
-            SELECT AVG(res_time) AS average_res_time,  -- Average response time
-                CASE
-                    WHEN (STRFTIME('%s', MAX(date)) - STRFTIME('%s', MIN(date))) = 0
-                    THEN 0  -- Handle division by zero
-                    ELSE COUNT(*) * 1.0 / (STRFTIME('%s', MAX(date)) - STRFTIME('%s', MIN(date)))  -- Requests per second
-                END AS requests_per_second,
-                COUNT(CASE WHEN status >= 500 THEN 1 END) * 1.0 / COUNT(*) AS error_rate  -- Error rate
-            FROM requests
-            WHERE date > ?1 AND date <= ?2;
+            WITH ranked AS (
+                SELECT
+                    status,
+                    res_time,
+                    ROW_NUMBER() OVER (ORDER BY res_time) AS position,
+                    COUNT(*) OVER () AS total
+                FROM requests
+                WHERE date > ? AND date <= ? AND {}
+            )
+            SELECT
+                COUNT(*),
+                COUNT(CASE WHEN {} THEN 1 END),
+                AVG(res_time),
+                MIN(CASE WHEN position * 100 >= total * 95 THEN res_time END)
+            FROM ranked;
             ",
-        )?;
-    let prev_rows = prev_stmt.query_map(
-        params![
-            prev_from.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
-            from.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
-        ],
+            filter.sql(),
+            ERROR_STATUS_SQL
+        ),
+        params_from_iter(params),
         |row| {
-            let average_res_time = row.get::<_, f32>(0);
-            let throughput = row.get::<_, f32>(1);
-            let error_rate = row.get::<_, f32>(2);
             Ok((
-                average_res_time.unwrap_or(slis.unwrap_or((0.0, 0.0, 0.0)).0),
-                throughput.unwrap_or(0.),
-                error_rate.unwrap_or(0.),
+                row.get::<_, usize>(0)?,
+                row.get::<_, usize>(1)?,
+                row.get::<_, Option<f64>>(2)?,
+                row.get::<_, Option<u32>>(3)?,
             ))
         },
     )?;
-    let prev_slis = prev_rows.into_iter().next().transpose()?;
+    Ok(ServiceLevelIndicators {
+        request_count,
+        error_rate: match request_count {
+            0 => 0.,
+            count => error_count as f64 / count as f64,
+        },
+        average_latency: average_latency.unwrap_or_default(),
+        p95_latency: p95_latency.unwrap_or_default(),
+        throughput: request_count as f64 / window.seconds().max(1.),
+    })
+}
+
+/// Returns service level indicators by timeframe and previous timeframe:
+/// * Overall Average Latency
+/// * Throughput (req/s)
+/// * Error rate (share of 5xx)
+pub fn service_level_indicators(from: DateTime<Utc>, conn: &Connection) -> Result<Value> {
+    let window = TimeWindow::new(from, None);
+    let slis = window_slis(&window, &RequestFilter::default(), conn)?;
+    let prev_slis = window_slis(&window.previous(), &RequestFilter::default(), conn)?;
 
     Ok(json!({
-        "averageLatency": slis.unwrap_or((0.0,0.0,0.0)).0,
-        "throughput":  slis.unwrap_or((0.0,0.0,0.0)).1,
-        "errorRate": slis.unwrap_or((0.0,0.0,0.0)).2,
-        "prevAverageLatency": prev_slis.unwrap_or((0.0,0.0,0.0)).0,
-        "prevThroughput": prev_slis.unwrap_or((0.0,0.0,0.0)).1,
-        "prevErrorRate": prev_slis.unwrap_or((0.0,0.0,0.0)).2,
+        "averageLatency": slis.average_latency,
+        "throughput": slis.throughput,
+        "errorRate": slis.error_rate,
+        "prevAverageLatency": prev_slis.average_latency,
+        "prevThroughput": prev_slis.throughput,
+        "prevErrorRate": prev_slis.error_rate,
     }))
 }
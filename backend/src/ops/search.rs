@@ -0,0 +1,129 @@
+use anyhow::Result;
+use rusqlite::Connection;
+
+/// Marks the start of a matched term in search snippets
+pub const SNIPPET_START: &str = "<mark>";
+/// Marks the end of a matched term in search snippets
+pub const SNIPPET_END: &str = "</mark>";
+/// Tokens of context around matches in search snippets
+pub const SNIPPET_TOKENS: usize = 12;
+
+/// bm25 weights of the requests_fts columns: endpoint, params, req_headers, res_headers, req_body, res_body
+pub const SEARCH_RANK_SQL: &str = "bm25(requests_fts, 10.0, 5.0, 1.0, 1.0, 2.0, 2.0)";
+
+/// Turns a user search into an FTS5 query.
+///
+/// Words are matched as token prefixes, like the substring search this replaced, and `"quoted text"`
+/// as an exact phrase. All of them have to match unless separated by `OR`. Everything else is quoted so user input can't
+/// produce FTS5 syntax errors. Returns `None` when nothing searchable is left.
+pub fn fts_match(search: &str) -> Option<String> {
+    let mut terms: Vec<String> = vec![];
+    let mut chars = search.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        let mut word = String::new();
+        if c == '"' {
+            for c in chars.by_ref() {
+                if c == '"' {
+                    break;
+                }
+                word.push(c);
+            }
+            if !word.trim().is_empty() {
+                terms.push(format!("\"{}\"", word.replace('"', "\"\"")));
+            }
+            continue;
+        }
+        word.push(c);
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != '"') {
+            word.push(c);
+        }
+        if word == "OR" {
+            if terms.last().is_some_and(|t| t != "OR") {
+                terms.push(word);
+            }
+            continue;
+        }
+        let word = word.trim_end_matches('*');
+        if !word.chars().any(char::is_alphanumeric) {
+            continue;
+        }
+        terms.push(format!("\"{}\"*", word.replace('"', "\"\"")));
+    }
+    if terms.last().is_some_and(|t| t == "OR") {
+        terms.pop();
+    }
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+/// Rebuilds the full-text index from the requests table
+pub fn rebuild_search_index(conn: &Connection) -> Result<()> {
+    conn.execute("INSERT INTO requests_fts(requests_fts) VALUES ('rebuild')", [])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_words_as_prefixes() {
+        assert_eq!(fts_match("time").as_deref(), Some("\"time\"*"));
+        assert_eq!(fts_match("time* out**").as_deref(), Some("\"time\"* \"out\"*"));
+        assert_eq!(fts_match("user-42").as_deref(), Some("\"user-42\"*"));
+    }
+
+    #[test]
+    fn quotes_phrases_and_syntax() {
+        assert_eq!(
+            fts_match("\"card declined\" stripe").as_deref(),
+            Some("\"card declined\" \"stripe\"*")
+        );
+        assert_eq!(fts_match("a\"b c\"").as_deref(), Some("\"a\"* \"b c\""));
+        assert_eq!(fts_match("\"unterminated").as_deref(), Some("\"unterminated\""));
+        assert_eq!(
+            fts_match("NEAR(a b) col:x AND").as_deref(),
+            Some("\"NEAR(a\"* \"b)\"* \"col:x\"* \"AND\"*")
+        );
+        assert_eq!(fts_match("\"\" - * ^").as_deref(), None);
+    }
+
+    #[test]
+    fn keeps_or_between_terms() {
+        assert_eq!(fts_match("a OR b").as_deref(), Some("\"a\"* OR \"b\"*"));
+        assert_eq!(fts_match("OR a OR OR b OR").as_deref(), Some("\"a\"* OR \"b\"*"));
+        assert_eq!(fts_match("a or b").as_deref(), Some("\"a\"* \"or\"* \"b\"*"));
+        assert_eq!(fts_match("OR").as_deref(), None);
+    }
+
+    #[test]
+    fn ignores_empty_searches() {
+        assert_eq!(fts_match(""), None);
+        assert_eq!(fts_match("   \t"), None);
+    }
+
+    #[test]
+    fn queries_are_valid_fts5() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE VIRTUAL TABLE docs USING fts5(body);
+            INSERT INTO docs (body) VALUES ('request timeout after 30s'), ('card declined by issuer');",
+        )
+        .unwrap();
+        let count = |search: &str| -> i64 {
+            conn.query_row(
+                "SELECT COUNT(*) FROM docs WHERE docs MATCH ?1",
+                [fts_match(search).unwrap()],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        assert_eq!(count("time"), 1);
+        assert_eq!(count("\"card declined\""), 1);
+        assert_eq!(count("\"card issuer\""), 0);
+        assert_eq!(count("timeout OR declined"), 2);
+        assert_eq!(count("NEAR(a b) col:x AND"), 0);
+    }
+}
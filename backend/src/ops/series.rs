@@ -0,0 +1,264 @@
+use super::{
+    filters::{text, RequestFilter},
+    requests::{ENDPOINT_GROUP_SQL, ERROR_STATUS_SQL},
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use rusqlite::{params_from_iter, types::Value as SqlValue, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Series returned when no `topN` is given
+pub const DEFAULT_SERIES_TOP_N: usize = 5;
+pub const MAX_SERIES_TOP_N: usize = 20;
+/// Most buckets a series can have, keeping the query under SQLite's parameter limit
+pub const MAX_SERIES_BUCKETS: usize = 2_000;
+
+/// Requests counted by the client error rate, those answered with a 4xx
+pub const CLIENT_ERROR_STATUS_SQL: &str = "CAST(status AS INTEGER) BETWEEN 400 AND 499";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum SeriesMetric {
+    #[default]
+    Count,
+    /// Share of 5xx responses
+    ErrorRate,
+    /// Share of 4xx responses
+    ClientErrorRate,
+    AvgLatency,
+    P50Latency,
+    P90Latency,
+    P95Latency,
+    P99Latency,
+    /// Requests per second
+    Throughput,
+}
+
+impl SeriesMetric {
+    fn percentile(&self) -> Option<u32> {
+        match self {
+            SeriesMetric::P50Latency => Some(50),
+            SeriesMetric::P90Latency => Some(90),
+            SeriesMetric::P95Latency => Some(95),
+            SeriesMetric::P99Latency => Some(99),
+            _ => None,
+        }
+    }
+
+    /// Whether values add up across series, so empty buckets are 0 rather than missing
+    fn is_additive(&self) -> bool {
+        matches!(self, SeriesMetric::Count | SeriesMetric::Throughput)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum SeriesGroup {
+    /// A single series of every request
+    #[default]
+    None,
+    Method,
+    /// `2xx`, `4xx`, `5xx`...
+    StatusClass,
+    /// Endpoint template
+    Endpoint,
+    Service,
+}
+
+impl SeriesGroup {
+    fn sql(&self) -> String {
+        match self {
+            SeriesGroup::None => "'all'".to_string(),
+            SeriesGroup::Method => "method".to_string(),
+            SeriesGroup::StatusClass => "(CAST(status AS INTEGER) / 100) || 'xx'".to_string(),
+            SeriesGroup::Endpoint => ENDPOINT_GROUP_SQL.to_string(),
+            SeriesGroup::Service => "COALESCE(service, 'unknown')".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Series {
+    pub name: String,
+    /// Whether this series gathers the groups outside the top N
+    pub other: bool,
+    /// One value per bucket, `None` for rates and latencies of buckets without requests
+    pub values: Vec<Option<f64>>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SeriesData {
+    pub metric: SeriesMetric,
+    pub group_by: SeriesGroup,
+    /// Start of each bucket, in the requested timezone
+    pub buckets: Vec<DateTime<Tz>>,
+    /// Largest groups first, then the other series if there is one
+    pub series: Vec<Series>,
+}
+
+fn format_date(date: DateTime<Utc>) -> String {
+    date.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+/// Groups with the most requests between `from` and `to`, largest first, and whether there are more
+fn top_groups(
+    group: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    filter: &RequestFilter,
+    top_n: usize,
+    conn: &Connection,
+) -> Result<(Vec<String>, bool)> {
+    let mut params = vec![text(format_date(from)), text(format_date(to))];
+    params.extend_from_slice(filter.params());
+    params.push(SqlValue::Integer(top_n as i64 + 1));
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} AS series FROM requests
+        WHERE date >= ? AND date < ? AND {}
+        GROUP BY series
+        ORDER BY COUNT(*) DESC, series
+        LIMIT ?",
+        group,
+        filter.sql()
+    ))?;
+    let rows = stmt.query_map(params_from_iter(params), |row| row.get::<_, String>(0))?;
+    let mut groups = rows.collect::<Result<Vec<String>, _>>()?;
+    let has_other = groups.len() > top_n;
+    groups.truncate(top_n);
+    Ok((groups, has_other))
+}
+
+/// `metric` of the requests matching `filter` in each bucket, one series per group of `group_by`.
+/// Only the `top_n` groups with the most requests get their own series, the rest are summed up in
+/// an other series.
+pub fn request_series(
+    buckets: &[(DateTime<Tz>, DateTime<Tz>)],
+    metric: SeriesMetric,
+    group_by: SeriesGroup,
+    top_n: usize,
+    filter: &RequestFilter,
+    conn: &Connection,
+) -> Result<SeriesData> {
+    let mut data = SeriesData {
+        metric,
+        group_by,
+        buckets: buckets.iter().map(|(start, _)| *start).collect(),
+        series: vec![],
+    };
+    let (Some((from, _)), Some((_, to))) = (buckets.first(), buckets.last()) else {
+        return Ok(data);
+    };
+    let (from, to) = (from.with_timezone(&Utc), to.with_timezone(&Utc));
+
+    let group = group_by.sql();
+    let (groups, has_other) = top_groups(&group, from, to, filter, top_n, conn)?;
+    if groups.is_empty() {
+        return Ok(data);
+    }
+
+    // Buckets are joined as a table since calendar buckets can't be computed in SQL
+    let mut params = vec![];
+    for (i, (start, end)) in buckets.iter().enumerate() {
+        params.push(SqlValue::Integer(i as i64));
+        params.push(text(format_date(start.with_timezone(&Utc))));
+        params.push(text(format_date(end.with_timezone(&Utc))));
+    }
+    params.extend(groups.iter().map(|g| text(g.clone())));
+    params.extend_from_slice(filter.params());
+    let (ranked, percentile) = match metric.percentile() {
+        Some(p) => (
+            "ROW_NUMBER() OVER (PARTITION BY bucket, series ORDER BY res_time) AS position,
+            COUNT(*) OVER (PARTITION BY bucket, series) AS total",
+            format!("MIN(CASE WHEN position * 100 >= total * {} THEN res_time END)", p),
+        ),
+        None => ("NULL AS position, NULL AS total", "NULL".to_string()),
+    };
+    let mut stmt = conn.prepare(&format!(
+        "
+        WITH buckets(i, bucket_start, bucket_end) AS (VALUES {buckets}),
+        matching AS (
+            SELECT
+                buckets.i AS bucket,
+                CASE WHEN {group} IN ({groups}) THEN {group} END AS series,
+                status,
+                res_time
+            FROM buckets
+            JOIN requests ON requests.date >= buckets.bucket_start AND requests.date < buckets.bucket_end
+            WHERE {filter}
+        ),
+        ranked AS (
+            SELECT *, {ranked} FROM matching
+        )
+        SELECT
+            bucket,
+            series,
+            COUNT(*),
+            COUNT(CASE WHEN {error} THEN 1 END),
+            COUNT(CASE WHEN {client_error} THEN 1 END),
+            AVG(res_time),
+            {percentile}
+        FROM ranked
+        GROUP BY bucket, series;
+        ",
+        buckets = vec!["(?, ?, ?)"; buckets.len()].join(", "),
+        group = group,
+        groups = vec!["?"; groups.len()].join(", "),
+        filter = filter.sql(),
+        ranked = ranked,
+        error = ERROR_STATUS_SQL,
+        client_error = CLIENT_ERROR_STATUS_SQL,
+        percentile = percentile,
+    ))?;
+
+    let position = groups
+        .iter()
+        .enumerate()
+        .map(|(i, g)| (g.clone(), i))
+        .collect::<HashMap<String, usize>>();
+    let empty = if metric.is_additive() { Some(0.) } else { None };
+    data.series = groups
+        .iter()
+        .map(|name| Series {
+            name: name.clone(),
+            other: false,
+            values: vec![empty; buckets.len()],
+        })
+        .collect();
+    if has_other {
+        data.series.push(Series {
+            name: "other".to_string(),
+            other: true,
+            values: vec![empty; buckets.len()],
+        });
+    }
+
+    let mut rows = stmt.query(params_from_iter(params))?;
+    while let Some(row) = rows.next()? {
+        let bucket = row.get::<_, usize>(0)?;
+        // Groups outside the top N are `NULL`
+        let series = match row.get::<_, Option<String>>(1)? {
+            Some(name) => position.get(&name).copied(),
+            None => has_other.then_some(groups.len()),
+        };
+        let Some(series) = series else {
+            continue;
+        };
+        let count = row.get::<_, f64>(2)?;
+        let (start, end) = buckets[bucket];
+        let seconds = end.signed_duration_since(start).num_milliseconds() as f64 / 1000.;
+        let value = match metric {
+            SeriesMetric::Count => count,
+            SeriesMetric::ErrorRate => row.get::<_, f64>(3)? / count,
+            SeriesMetric::ClientErrorRate => row.get::<_, f64>(4)? / count,
+            SeriesMetric::AvgLatency => row.get::<_, f64>(5)?,
+            SeriesMetric::Throughput => count / seconds,
+            _ => row.get::<_, f64>(6)?,
+        };
+        data.series[series].values[bucket] = Some(value);
+    }
+    Ok(data)
+}
@@ -1,48 +1,409 @@
 use super::requests::RequestLog;
-use chrono::{Duration, NaiveDateTime, Utc};
+use anyhow::{anyhow, Result};
+use chrono::{
+    DateTime, Datelike, Duration, LocalResult, Months, NaiveDate, NaiveDateTime, NaiveTime, TimeZone,
+    Utc,
+};
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
+use std::str::FromStr;
 
 pub const RESPONSE_LOG_DATE_FORMAT: &str = "%d-%m-%Y %H:%M:%S%.3f";
+
+/// Time range `(from, to]` stats are computed over
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeWindow {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+}
+
+impl TimeWindow {
+    /// Window from `from` up to `to`, or now when it isn't given
+    pub fn new(from: DateTime<Utc>, to: Option<DateTime<Utc>>) -> TimeWindow {
+        TimeWindow {
+            from,
+            to: to.unwrap_or(Utc::now()),
+        }
+    }
+
+    /// Window of the same length right before this one, that trends are compared against
+    pub fn previous(&self) -> TimeWindow {
+        self.shifted(-self.to.signed_duration_since(self.from))
+    }
+
+    pub fn shifted(&self, by: Duration) -> TimeWindow {
+        TimeWindow {
+            from: self.from + by,
+            to: self.to + by,
+        }
+    }
+
+    pub fn seconds(&self) -> f64 {
+        self.to.signed_duration_since(self.from).num_milliseconds() as f64 / 1000.
+    }
+
+    /// Bounds formatted as stored in the requests table
+    pub fn bounds(&self) -> (String, String) {
+        (
+            self.from.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+            self.to.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+        )
+    }
+}
+
+/// Most buckets a single stats request can produce
+pub const MAX_BUCKETS: usize = 10_000;
+
+/// Length of the buckets stats are grouped in, e.g. `5m`, `1h`, `1d`, `1w` or `1M`.
+/// Days, weeks and months follow the calendar of the requested timezone.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interval {
+    Minutes(u32),
+    Hours(u32),
+    Days(u32),
+    /// Starting on Mondays
+    Weeks(u32),
+    Months(u32),
+}
+
+impl FromStr for Interval {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "hourly" => return Ok(Interval::Hours(1)),
+            "daily" => return Ok(Interval::Days(1)),
+            "weekly" => return Ok(Interval::Weeks(1)),
+            "monthly" => return Ok(Interval::Months(1)),
+            _ => (),
+        }
+        let invalid = || anyhow!("Invalid interval '{}', expected e.g. 5m, 1h, 1d, 1w or 1M", s);
+        let unit_start = s.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+        let count = s[..unit_start].parse::<u32>().map_err(|_| invalid())?;
+        if count == 0 {
+            return Err(invalid());
+        }
+        match &s[unit_start..] {
+            "m" => Ok(Interval::Minutes(count)),
+            "h" => Ok(Interval::Hours(count)),
+            "d" => Ok(Interval::Days(count)),
+            "w" => Ok(Interval::Weeks(count)),
+            "M" => Ok(Interval::Months(count)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Parses an IANA timezone like `Europe/Paris`, UTC when none is given
+pub fn parse_timezone(tz: Option<&str>) -> Result<Tz> {
+    match tz {
+        Some(tz) => Tz::from_str(tz).map_err(|_| anyhow!("Unknown timezone '{}'", tz)),
+        None => Ok(Tz::UTC),
+    }
+}
+
+/// Local time in `tz`, moved past the gap when clocks skip it and to the first occurrence when
+/// clocks go back over it
+fn resolve_local(tz: &Tz, mut local: NaiveDateTime) -> DateTime<Tz> {
+    loop {
+        match tz.from_local_datetime(&local) {
+            LocalResult::Single(date) => return date,
+            LocalResult::Ambiguous(earliest, _) => return earliest,
+            LocalResult::None => local += Duration::minutes(15),
+        }
+    }
+}
+
+fn local_midnight(tz: &Tz, day: NaiveDate) -> DateTime<Tz> {
+    resolve_local(tz, day.and_time(NaiveTime::MIN))
+}
+
+impl Interval {
+    /// Length of sub-day intervals, which are the same in every timezone
+    fn fixed(&self) -> Option<Duration> {
+        match *self {
+            Interval::Minutes(n) => Some(Duration::minutes(n as i64)),
+            Interval::Hours(n) => Some(Duration::hours(n as i64)),
+            _ => None,
+        }
+    }
+
+    /// Start of the bucket containing `date`
+    pub fn floor(&self, date: DateTime<Tz>) -> DateTime<Tz> {
+        let tz = date.timezone();
+        if let Some(step) = self.fixed() {
+            // Align on the wall clock at the current offset, so an hour repeated when clocks go
+            // back gets its own buckets
+            let offset = date.naive_local().signed_duration_since(date.naive_utc());
+            let local = date.timestamp() + offset.num_seconds();
+            let floored = local - local.rem_euclid(step.num_seconds());
+            return DateTime::from_timestamp(floored - offset.num_seconds(), 0)
+                .unwrap_or_default()
+                .with_timezone(&tz);
+        }
+        let day = date.date_naive();
+        match self {
+            Interval::Weeks(_) => local_midnight(
+                &tz,
+                day - Duration::days(day.weekday().num_days_from_monday() as i64),
+            ),
+            Interval::Months(_) => local_midnight(&tz, day.with_day(1).unwrap_or(day)),
+            _ => local_midnight(&tz, day),
+        }
+    }
+
+    /// Start of the bucket after the one starting at `start`
+    pub fn next(&self, start: DateTime<Tz>) -> DateTime<Tz> {
+        let day = start.date_naive();
+        let next = match *self {
+            Interval::Minutes(n) => return start + Duration::minutes(n as i64),
+            Interval::Hours(n) => return start + Duration::hours(n as i64),
+            Interval::Days(n) => day + Duration::days(n as i64),
+            Interval::Weeks(n) => day + Duration::weeks(n as i64),
+            Interval::Months(n) => day + Months::new(n),
+        };
+        local_midnight(&start.timezone(), next)
+    }
+
+    /// Buckets `[start, end)` covering `from` to `to`, aligned on the calendar of `tz`
+    pub fn buckets(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        tz: &Tz,
+    ) -> Result<Vec<(DateTime<Tz>, DateTime<Tz>)>> {
+        let mut buckets = vec![];
+        let mut start = self.floor(from.with_timezone(tz));
+        while start < to {
+            if buckets.len() == MAX_BUCKETS {
+                return Err(anyhow!(
+                    "More than {} buckets, use a longer interval or a shorter range",
+                    MAX_BUCKETS
+                ));
+            }
+            let end = self.next(start);
+            buckets.push((start, end));
+            start = end;
+        }
+        Ok(buckets)
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct FailureToSuccessRate {
+    /// Start of the bucket in the requested timezone
     pub date: String,
+    /// Start of the bucket as RFC 3339 with its UTC offset
+    pub start: String,
     pub failure_rate: f64,
     pub total_requests: usize,
 }
 
+/// Share of 5xx responses in each bucket, `results` being sorted by date
 pub fn get_failure_to_success_stats(
     results: &[RequestLog],
-    mut from: NaiveDateTime,
-    delta: Duration,
-    chart_data: &mut Vec<FailureToSuccessRate>,
-) {
-    while from < Utc::now().naive_local() {
-        let next_time = from + delta;
-        let count = results
-            .iter()
-            .filter(|log| {
-                let log_time = log.date.naive_local();
-                log_time >= from && log_time < next_time && log.status >= 500
-            })
-            .count();
-        let total_requests = results
+    buckets: &[(DateTime<Tz>, DateTime<Tz>)],
+) -> Vec<FailureToSuccessRate> {
+    let mut logs = results.iter().peekable();
+    buckets
+        .iter()
+        .map(|(start, end)| {
+            while logs.next_if(|log| log.date < *start).is_some() {}
+            let (mut count, mut total_requests) = (0, 0);
+            while let Some(log) = logs.next_if(|log| log.date < *end) {
+                total_requests += 1;
+                if log.status >= 500 {
+                    count += 1;
+                }
+            }
+            let average_failures = if total_requests > 0 {
+                count as f64 / total_requests as f64
+            } else {
+                0.0
+            };
+            FailureToSuccessRate {
+                date: start.format(RESPONSE_LOG_DATE_FORMAT).to_string(),
+                start: start.to_rfc3339(),
+                failure_rate: average_failures,
+                total_requests,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(date: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(date).unwrap().with_timezone(&Utc)
+    }
+
+    fn starts(interval: &str, from: &str, to: &str, tz: Tz) -> Vec<String> {
+        Interval::from_str(interval)
+            .unwrap()
+            .buckets(utc(from), utc(to), &tz)
+            .unwrap()
+            .into_iter()
+            .map(|(start, _)| start.to_rfc3339())
+            .collect()
+    }
+
+    #[test]
+    fn parses_intervals() {
+        assert_eq!(Interval::from_str("15m").unwrap(), Interval::Minutes(15));
+        assert_eq!(Interval::from_str("1M").unwrap(), Interval::Months(1));
+        assert_eq!(Interval::from_str("weekly").unwrap(), Interval::Weeks(1));
+        assert!(Interval::from_str("0m").is_err());
+        assert!(Interval::from_str("5y").is_err());
+        assert!(Interval::from_str("m").is_err());
+    }
+
+    #[test]
+    fn hourly_buckets_skip_the_missing_hour_in_spring() {
+        // Clocks in New York go from 02:00 to 03:00 on 2025-03-09
+        let buckets = starts(
+            "1h",
+            "2025-03-09T05:00:00Z",
+            "2025-03-10T04:00:00Z",
+            chrono_tz::America::New_York,
+        );
+        assert_eq!(buckets.len(), 23);
+        assert_eq!(buckets[1], "2025-03-09T01:00:00-05:00");
+        assert_eq!(buckets[2], "2025-03-09T03:00:00-04:00");
+    }
+
+    #[test]
+    fn hourly_buckets_repeat_the_hour_in_autumn() {
+        // Clocks in New York go from 02:00 back to 01:00 on 2025-11-02
+        let buckets = starts(
+            "1h",
+            "2025-11-02T04:00:00Z",
+            "2025-11-03T05:00:00Z",
+            chrono_tz::America::New_York,
+        );
+        assert_eq!(buckets.len(), 25);
+        assert_eq!(buckets[1], "2025-11-02T01:00:00-04:00");
+        assert_eq!(buckets[2], "2025-11-02T01:00:00-05:00");
+        assert_eq!(buckets[3], "2025-11-02T02:00:00-05:00");
+    }
+
+    #[test]
+    fn requests_in_the_repeated_hour_fall_in_their_own_bucket() {
+        let interval = Interval::Minutes(30);
+        let second_pass = utc("2025-11-02T06:40:00Z").with_timezone(&chrono_tz::America::New_York);
+        assert_eq!(
+            interval.floor(second_pass).to_rfc3339(),
+            "2025-11-02T01:30:00-05:00"
+        );
+    }
+
+    #[test]
+    fn daily_buckets_start_at_local_midnight_across_dst() {
+        let tz = chrono_tz::Europe::Paris;
+        let buckets = Interval::Days(1)
+            .buckets(utc("2025-03-29T12:00:00Z"), utc("2025-03-31T12:00:00Z"), &tz)
+            .unwrap();
+        let starts = buckets
             .iter()
-            .filter(|log| {
-                let log_time = log.date.naive_local();
-                log_time >= from && log_time < next_time
-            })
-            .count();
-        let average_failures = if total_requests > 0 {
-            count as f64 / total_requests as f64
-        } else {
-            0.0
+            .map(|(s, _)| s.to_rfc3339())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            starts,
+            [
+                "2025-03-29T00:00:00+01:00",
+                "2025-03-30T00:00:00+01:00",
+                "2025-03-31T00:00:00+02:00"
+            ]
+        );
+        // The day clocks go forward is 23 hours long
+        assert_eq!(buckets[1].1 - buckets[1].0, Duration::hours(23));
+    }
+
+    #[test]
+    fn daily_buckets_start_after_a_skipped_midnight() {
+        // Clocks in Santiago go from 00:00 to 01:00 on 2024-09-08
+        let buckets = starts(
+            "1d",
+            "2024-09-07T12:00:00Z",
+            "2024-09-08T12:00:00Z",
+            chrono_tz::America::Santiago,
+        );
+        assert_eq!(
+            buckets,
+            ["2024-09-07T00:00:00-04:00", "2024-09-08T01:00:00-03:00"]
+        );
+    }
+
+    #[test]
+    fn weekly_buckets_start_on_local_monday() {
+        let buckets = starts(
+            "1w",
+            "2025-01-08T12:00:00Z",
+            "2025-01-14T12:00:00Z",
+            chrono_tz::Asia::Tokyo,
+        );
+        assert_eq!(
+            buckets,
+            ["2025-01-06T00:00:00+09:00", "2025-01-13T00:00:00+09:00"]
+        );
+    }
+
+    #[test]
+    fn monthly_buckets_follow_the_calendar() {
+        let buckets = starts(
+            "1M",
+            "2025-01-15T00:00:00Z",
+            "2025-04-02T00:00:00Z",
+            chrono_tz::UTC,
+        );
+        assert_eq!(
+            buckets,
+            [
+                "2025-01-01T00:00:00+00:00",
+                "2025-02-01T00:00:00+00:00",
+                "2025-03-01T00:00:00+00:00",
+                "2025-04-01T00:00:00+00:00"
+            ]
+        );
+    }
+
+    #[test]
+    fn failure_rates_are_counted_per_bucket() {
+        let log = |date: &str, status: u16| RequestLog {
+            id: None,
+            method: "GET".to_string(),
+            endpoint: "/".to_string(),
+            endpoint_template: None,
+            service: None,
+            trace_id: None,
+            span_id: None,
+            parent_span_id: None,
+            params: String::new(),
+            req_headers: Default::default(),
+            res_headers: Default::default(),
+            date: utc(date),
+            status,
+            req_body: None,
+            res_body: None,
+            res_time: 1,
         };
-        chart_data.push(FailureToSuccessRate {
-            date: from.format(RESPONSE_LOG_DATE_FORMAT).to_string(),
-            failure_rate: average_failures,
-            total_requests,
-        });
-        from = next_time;
+        let logs = [
+            log("2025-01-01T00:01:00Z", 200),
+            log("2025-01-01T00:02:00Z", 500),
+            log("2025-01-01T00:20:00Z", 200),
+        ];
+        let buckets = Interval::Minutes(15)
+            .buckets(
+                utc("2025-01-01T00:00:00Z"),
+                utc("2025-01-01T00:30:00Z"),
+                &chrono_tz::UTC,
+            )
+            .unwrap();
+        let stats = get_failure_to_success_stats(&logs, &buckets);
+        assert_eq!(stats.len(), 2);
+        assert_eq!((stats[0].total_requests, stats[0].failure_rate), (2, 0.5));
+        assert_eq!((stats[1].total_requests, stats[1].failure_rate), (1, 0.0));
     }
 }
@@ -0,0 +1,1119 @@
+//! Scripted synthetic monitors checking flows such as login → create order → fetch order.
+//!
+//! A monitor is an ordered list of HTTP steps run on an interval. Values extracted from a step's
+//! response are templated into later steps with `{{name}}`, and every step can assert on its status,
+//! latency and body. A run stops at the first failing step. Runs are also recorded in `uptime` as
+//! `synthetic:<name>` so availability covers them, and consecutive failing runs form an incident.
+
+use super::{headers::Headers, json_path::JsonPath, replay::ReplayRequest};
+use crate::db::connection::open_db;
+use actix_web::{
+    http::Method,
+    rt::{self, time},
+};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use log::info;
+use regex::Regex;
+use rusqlite::{named_params, params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    env,
+    rc::Rc,
+    str::FromStr,
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+/// Prefix of the `uptime` url runs are recorded under
+pub const SYNTHETIC_UPTIME_PREFIX: &str = "synthetic:";
+pub const DEFAULT_SYNTHETIC_INTERVAL: u64 = 300;
+pub const MIN_SYNTHETIC_INTERVAL: u64 = 10;
+pub const MAX_SYNTHETIC_STEPS: usize = 20;
+/// Seconds to wait for a step's response when no timeout is given
+const DEFAULT_STEP_TIMEOUT: u64 = 10;
+const MAX_STEP_TIMEOUT: u64 = 60;
+/// Days runs are kept for, incidents are kept forever
+const SYNTHETIC_RUN_RETENTION_DAYS: i64 = 30;
+/// How often the scheduler looks for monitors that are due
+const SCHEDULER_TICK: Duration = Duration::from_secs(1);
+/// Only environment variables with this prefix can be templated, so that secrets such as passwords
+/// needn't be stored with the monitor and nothing else of the server's environment can be read
+const ENV_VARIABLE_PREFIX: &str = "SYNTHETIC_";
+
+static PLACEHOLDER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\{\{\s*([^{}]*?)\s*\}\}").unwrap());
+static VARIABLE_NAME: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").unwrap());
+
+fn format_date(date: DateTime<Utc>) -> String {
+    date.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+fn parse_date(date: String) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(&date)?.with_timezone(&Utc))
+}
+
+/// Names of the placeholders in `text`
+fn placeholders(text: &str) -> Vec<String> {
+    PLACEHOLDER
+        .captures_iter(text)
+        .map(|c| c[1].to_string())
+        .collect()
+}
+
+/// Whether a placeholder can be resolved, given the variables defined so far
+fn is_defined(name: &str, defined: &BTreeSet<String>) -> bool {
+    match name {
+        "$uuid" | "$timestamp" => true,
+        _ => match name.strip_prefix("env.") {
+            Some(var) => var.starts_with(ENV_VARIABLE_PREFIX),
+            None => defined.contains(name),
+        },
+    }
+}
+
+/// Value of a placeholder: a variable, `$uuid`, `$timestamp` or `env.SYNTHETIC_*`
+fn resolve(name: &str, variables: &BTreeMap<String, Value>) -> Result<Value> {
+    match name {
+        "$uuid" => Ok(Value::String(Uuid::new_v4().to_string())),
+        "$timestamp" => Ok(Value::from(Utc::now().timestamp_millis())),
+        _ => match name.strip_prefix("env.") {
+            Some(var) if var.starts_with(ENV_VARIABLE_PREFIX) => env::var(var)
+                .map(Value::String)
+                .map_err(|_| anyhow!("Environment variable '{}' is not set", var)),
+            Some(var) => Err(anyhow!(
+                "Only environment variables starting with {} can be used, not '{}'",
+                ENV_VARIABLE_PREFIX,
+                var
+            )),
+            None => variables
+                .get(name)
+                .cloned()
+                .ok_or(anyhow!("Variable '{}' is not defined", name)),
+        },
+    }
+}
+
+fn value_text(value: Value) -> String {
+    match value {
+        Value::String(text) => text,
+        value => value.to_string(),
+    }
+}
+
+/// Replaces the placeholders in `text`
+fn render(text: &str, variables: &BTreeMap<String, Value>) -> Result<String> {
+    let mut rendered = String::new();
+    let mut last = 0;
+    for captures in PLACEHOLDER.captures_iter(text) {
+        let whole = captures.get(0).unwrap();
+        rendered.push_str(&text[last..whole.start()]);
+        rendered.push_str(&value_text(resolve(&captures[1], variables)?));
+        last = whole.end();
+    }
+    rendered.push_str(&text[last..]);
+    Ok(rendered)
+}
+
+/// Replaces the placeholders in the strings of a JSON value. A string that is a single placeholder
+/// takes the variable's value as is, so that numbers and objects keep their type.
+fn render_value(value: &Value, variables: &BTreeMap<String, Value>) -> Result<Value> {
+    match value {
+        Value::String(text) => {
+            let trimmed = text.trim();
+            match PLACEHOLDER.captures(trimmed) {
+                Some(c) if c.get(0).unwrap().as_str() == trimmed => resolve(&c[1], variables),
+                _ => Ok(Value::String(render(text, variables)?)),
+            }
+        }
+        Value::Array(items) => Ok(Value::Array(
+            items
+                .iter()
+                .map(|item| render_value(item, variables))
+                .collect::<Result<_>>()?,
+        )),
+        Value::Object(map) => Ok(Value::Object(
+            map.iter()
+                .map(|(key, value)| Ok((key.clone(), render_value(value, variables)?)))
+                .collect::<Result<_>>()?,
+        )),
+        value => Ok(value.clone()),
+    }
+}
+
+/// Placeholders in the strings of a JSON value
+fn value_placeholders(value: &Value) -> Vec<String> {
+    match value {
+        Value::String(text) => placeholders(text),
+        Value::Array(items) => items.iter().flat_map(value_placeholders).collect(),
+        Value::Object(map) => map.values().flat_map(value_placeholders).collect(),
+        _ => vec![],
+    }
+}
+
+/// Where an extracted variable is read from: `status`, `header:<name>` or a JSON path in the body
+#[derive(Debug, Clone, PartialEq)]
+enum Extractor {
+    Status,
+    Header(String),
+    Body(JsonPath),
+}
+
+impl FromStr for Extractor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s == "status" {
+            return Ok(Extractor::Status);
+        }
+        if let Some(name) = s.strip_prefix("header:") {
+            let name = name.trim().to_lowercase();
+            if name.is_empty() {
+                return Err(anyhow!("Missing header name in '{}'", s));
+            }
+            return Ok(Extractor::Header(name));
+        }
+        Ok(Extractor::Body(JsonPath::from_str(s)?))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BodyAssertion {
+    /// JSON path in the response body
+    pub path: String,
+    /// Expected value, templated when it contains placeholders
+    pub equals: Option<Value>,
+    /// Whether the path should match, true by default when `equals` isn't given
+    pub exists: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct StepAssertions {
+    /// Accepted statuses, any 2xx when not given
+    pub status: Option<Vec<u16>>,
+    /// Slowest accepted response in milliseconds
+    pub max_duration: Option<u64>,
+    /// Text the response body must contain
+    pub contains: Option<String>,
+    #[serde(default)]
+    pub body: Vec<BodyAssertion>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SyntheticStep {
+    pub name: String,
+    /// GET when not given
+    pub method: Option<String>,
+    pub url: String,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Sent as JSON unless it's a string
+    pub body: Option<Value>,
+    /// Variables set from the response, by `status`, `header:<name>` or a JSON path in the body
+    #[serde(default)]
+    pub extract: BTreeMap<String, String>,
+    #[serde(default, rename = "assert")]
+    pub assertions: StepAssertions,
+    /// Seconds to wait for the response
+    pub timeout: Option<u64>,
+}
+
+impl SyntheticStep {
+    fn method(&self) -> Result<Method> {
+        let method = self.method.as_deref().unwrap_or("GET").trim().to_uppercase();
+        Method::from_str(&method).map_err(|_| anyhow!("Invalid method '{}'", method))
+    }
+
+    /// Checks the step, `defined` holding the variables set by the monitor and earlier steps
+    fn validate(&self, defined: &BTreeSet<String>) -> Result<()> {
+        if self.name.trim().is_empty() {
+            return Err(anyhow!("name is required"));
+        }
+        self.method()?;
+        let url = self.url.trim();
+        if !(url.starts_with("http://") || url.starts_with("https://") || url.starts_with("{{")) {
+            return Err(anyhow!("url must start with http:// or https://"));
+        }
+        if self.timeout.is_some_and(|t| t == 0 || t > MAX_STEP_TIMEOUT) {
+            return Err(anyhow!(
+                "timeout must be between 1 and {} seconds",
+                MAX_STEP_TIMEOUT
+            ));
+        }
+
+        let mut used = placeholders(&self.url);
+        for (name, value) in &self.headers {
+            if name.trim().is_empty() {
+                return Err(anyhow!("Header names can't be empty"));
+            }
+            used.extend(placeholders(value));
+        }
+        used.extend(self.body.iter().flat_map(value_placeholders));
+        for assertion in &self.assertions.body {
+            JsonPath::from_str(&assertion.path)?;
+            used.extend(assertion.equals.iter().flat_map(value_placeholders));
+        }
+        if let Some(name) = used.iter().find(|name| !is_defined(name, defined)) {
+            return Err(match name.strip_prefix("env.") {
+                Some(var) => anyhow!(
+                    "Only environment variables starting with {} can be used, not '{}'",
+                    ENV_VARIABLE_PREFIX,
+                    var
+                ),
+                None => anyhow!(
+                    "'{{{{{}}}}}' is not defined by the monitor or an earlier step",
+                    name
+                ),
+            });
+        }
+
+        if let Some(status) = self
+            .assertions
+            .status
+            .iter()
+            .flatten()
+            .find(|s| !(100..=599).contains(*s))
+        {
+            return Err(anyhow!("{} is not a valid status", status));
+        }
+        for (name, source) in &self.extract {
+            if !VARIABLE_NAME.is_match(name) {
+                return Err(anyhow!("'{}' is not a valid variable name", name));
+            }
+            Extractor::from_str(source)?;
+        }
+        Ok(())
+    }
+}
+
+/// A monitor as sent when creating or replacing it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SyntheticMonitorRequest {
+    pub name: String,
+    /// Seconds between runs
+    pub interval: Option<u64>,
+    pub enabled: Option<bool>,
+    /// Variables available to every step
+    #[serde(default)]
+    pub variables: BTreeMap<String, Value>,
+    pub steps: Vec<SyntheticStep>,
+}
+
+impl SyntheticMonitorRequest {
+    pub fn validate(&self) -> Result<()> {
+        if self.name.trim().is_empty() {
+            return Err(anyhow!("name is required"));
+        }
+        if self.interval.is_some_and(|i| i < MIN_SYNTHETIC_INTERVAL) {
+            return Err(anyhow!(
+                "interval must be at least {} seconds",
+                MIN_SYNTHETIC_INTERVAL
+            ));
+        }
+        if self.steps.is_empty() || self.steps.len() > MAX_SYNTHETIC_STEPS {
+            return Err(anyhow!(
+                "A monitor needs between 1 and {} steps",
+                MAX_SYNTHETIC_STEPS
+            ));
+        }
+        if let Some(name) = self.variables.keys().find(|name| !VARIABLE_NAME.is_match(name)) {
+            return Err(anyhow!("'{}' is not a valid variable name", name));
+        }
+        let mut defined = self.variables.keys().cloned().collect::<BTreeSet<String>>();
+        for (i, step) in self.steps.iter().enumerate() {
+            step.validate(&defined)
+                .map_err(|e| anyhow!("Step {} ({}): {}", i, step.name, e))?;
+            defined.extend(step.extract.keys().cloned());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SyntheticMonitor {
+    pub id: i64,
+    pub name: String,
+    pub interval: u64,
+    pub enabled: bool,
+    pub variables: BTreeMap<String, Value>,
+    pub steps: Vec<SyntheticStep>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+const MONITOR_COLUMNS: &str = "id, name, interval, enabled, variables, steps, created_at, updated_at";
+
+impl SyntheticMonitor {
+    fn from_row(row: &Row) -> Result<SyntheticMonitor> {
+        Ok(SyntheticMonitor {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            interval: row.get(2)?,
+            enabled: row.get(3)?,
+            variables: serde_json::from_str(&row.get::<_, String>(4)?)?,
+            steps: serde_json::from_str(&row.get::<_, String>(5)?)?,
+            created_at: parse_date(row.get(6)?)?,
+            updated_at: parse_date(row.get(7)?)?,
+        })
+    }
+
+    /// Url the monitor's runs are recorded under in `uptime`
+    pub fn uptime_url(&self) -> String {
+        format!("{}{}", SYNTHETIC_UPTIME_PREFIX, self.name)
+    }
+}
+
+/// Outcome of one step of a run
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StepResult {
+    pub name: String,
+    pub method: String,
+    /// Url as written in the step, its placeholders unresolved as they often hold secrets
+    pub url: String,
+    pub status: Option<u16>,
+    /// Milliseconds until the response was read
+    pub duration: u64,
+    pub passed: bool,
+    /// Failed assertions and extractions, or why the request couldn't be sent
+    pub failures: Vec<String>,
+    /// Names of the variables set, their values not being stored as they often hold tokens
+    pub extracted: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SyntheticRun {
+    pub id: Option<i64>,
+    pub monitor_id: i64,
+    pub date: DateTime<Utc>,
+    /// `up` when every step passed, `down` otherwise
+    pub status: String,
+    /// Milliseconds for the whole run
+    pub duration: u64,
+    pub failed_step: Option<String>,
+    /// First failure of the failed step
+    pub error: Option<String>,
+    /// Steps up to the failed one
+    pub steps: Vec<StepResult>,
+}
+
+const RUN_COLUMNS: &str = "id, monitor_id, date, status, duration, failed_step, error, steps";
+
+impl SyntheticRun {
+    fn from_row(row: &Row) -> Result<SyntheticRun> {
+        Ok(SyntheticRun {
+            id: row.get(0)?,
+            monitor_id: row.get(1)?,
+            date: parse_date(row.get(2)?)?,
+            status: row.get(3)?,
+            duration: row.get(4)?,
+            failed_step: row.get(5)?,
+            error: row.get(6)?,
+            steps: serde_json::from_str(&row.get::<_, String>(7)?)?,
+        })
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.status == "up"
+    }
+}
+
+/// Checks a response against the step's assertions and extracts its variables
+fn check_response(
+    step: &SyntheticStep,
+    status: u16,
+    duration: u64,
+    headers: &Headers,
+    body: &Value,
+    variables: &mut BTreeMap<String, Value>,
+) -> (Vec<String>, Vec<String>) {
+    let mut failures = vec![];
+    let assertions = &step.assertions;
+    match &assertions.status {
+        Some(statuses) if !statuses.contains(&status) => failures.push(format!(
+            "Expected status {}, got {}",
+            statuses
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<String>>()
+                .join(" or "),
+            status
+        )),
+        None if !(200..300).contains(&status) => {
+            failures.push(format!("Expected a 2xx status, got {}", status))
+        }
+        _ => (),
+    }
+    if let Some(max) = assertions.max_duration.filter(|max| duration > *max) {
+        failures.push(format!("Took {} ms, more than {} ms", duration, max));
+    }
+    if let Some(text) = &assertions.contains {
+        let contained = match body {
+            Value::String(body) => body.contains(text.as_str()),
+            body => body.to_string().contains(text.as_str()),
+        };
+        if !contained {
+            failures.push(format!("Body doesn't contain '{}'", text));
+        }
+    }
+    for assertion in &assertions.body {
+        let Ok(path) = JsonPath::from_str(&assertion.path) else {
+            continue;
+        };
+        let actual = path.first(body);
+        match &assertion.equals {
+            Some(expected) => match render_value(expected, variables) {
+                Ok(rendered) if actual.as_ref() == Some(&rendered) => (),
+                // The expected value is shown as written, since its variables may hold secrets
+                Ok(_) => failures.push(format!(
+                    "Expected {} to be {}, got {}",
+                    assertion.path,
+                    expected,
+                    actual.map_or("nothing".to_string(), |a| a.to_string())
+                )),
+                Err(e) => failures.push(e.to_string()),
+            },
+            None => {
+                let exists = assertion.exists.unwrap_or(true);
+                if actual.is_some() != exists {
+                    failures.push(format!(
+                        "Expected {} to {}",
+                        assertion.path,
+                        if exists { "exist" } else { "be missing" }
+                    ));
+                }
+            }
+        }
+    }
+
+    // Extractions from an unexpected response would only repeat its failures
+    let mut extracted = vec![];
+    if !failures.is_empty() {
+        return (failures, extracted);
+    }
+    for (name, source) in &step.extract {
+        let value = match Extractor::from_str(source) {
+            Ok(Extractor::Status) => Some(Value::from(status)),
+            Ok(Extractor::Header(header)) => headers
+                .pairs()
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(&header))
+                .map(|(_, value)| Value::String(value.clone())),
+            Ok(Extractor::Body(path)) => path.first(body).filter(|v| !v.is_null()),
+            Err(_) => None,
+        };
+        match value {
+            Some(value) => {
+                variables.insert(name.clone(), value);
+                extracted.push(name.clone());
+            }
+            None => failures.push(format!("Nothing to extract for '{}' at {}", name, source)),
+        }
+    }
+    (failures, extracted)
+}
+
+/// Sends one step, updating `variables` with what it extracts
+async fn run_step(step: &SyntheticStep, variables: &mut BTreeMap<String, Value>) -> StepResult {
+    let method = step.method().map(|m| m.to_string()).unwrap_or_default();
+    let mut result = StepResult {
+        name: step.name.clone(),
+        method: method.clone(),
+        url: step.url.clone(),
+        status: None,
+        duration: 0,
+        passed: false,
+        failures: vec![],
+        extracted: vec![],
+    };
+    let request = (|| {
+        let url = render(step.url.trim(), variables)?;
+        let mut headers = step
+            .headers
+            .iter()
+            .map(|(name, value)| Ok((name.trim().to_lowercase(), render(value, variables)?)))
+            .collect::<Result<Vec<(String, String)>>>()?;
+        let body = match &step.body {
+            None | Some(Value::Null) => None,
+            Some(Value::String(text)) => Some(render(text, variables)?),
+            Some(body) => {
+                if !headers.iter().any(|(name, _)| name == "content-type") {
+                    headers.push(("content-type".to_string(), "application/json".to_string()));
+                }
+                Some(render_value(body, variables)?.to_string())
+            }
+        };
+        Ok::<_, anyhow::Error>(ReplayRequest {
+            method: method.clone(),
+            url,
+            headers: Headers::Structured(headers),
+            body,
+            stripped_headers: vec![],
+            warnings: vec![],
+        })
+    })();
+    let request = match request {
+        Ok(request) => request,
+        Err(e) => {
+            result.failures.push(e.to_string());
+            return result;
+        }
+    };
+
+    let timeout = Duration::from_secs(step.timeout.unwrap_or(DEFAULT_STEP_TIMEOUT));
+    let start = Instant::now();
+    match request.send(timeout).await {
+        Ok(response) => {
+            result.duration = start.elapsed().as_millis() as u64;
+            result.status = Some(response.status);
+            let (failures, extracted) = check_response(
+                step,
+                response.status,
+                result.duration,
+                &response.headers,
+                &response.body,
+                variables,
+            );
+            result.failures = failures;
+            result.extracted = extracted;
+        }
+        Err(e) => {
+            result.duration = start.elapsed().as_millis() as u64;
+            result.failures.push(e.to_string());
+        }
+    }
+    result.passed = result.failures.is_empty();
+    result
+}
+
+/// Runs the steps of a monitor in order, stopping at the first failing one
+pub async fn run_monitor(monitor: &SyntheticMonitor) -> SyntheticRun {
+    let date = Utc::now();
+    let start = Instant::now();
+    let mut variables = monitor.variables.clone();
+    let mut steps = vec![];
+    for step in &monitor.steps {
+        let result = run_step(step, &mut variables).await;
+        let passed = result.passed;
+        steps.push(result);
+        if !passed {
+            break;
+        }
+    }
+    let failed = steps.iter().find(|step| !step.passed);
+    SyntheticRun {
+        id: None,
+        monitor_id: monitor.id,
+        date,
+        status: if failed.is_none() { "up" } else { "down" }.to_string(),
+        duration: start.elapsed().as_millis() as u64,
+        failed_step: failed.map(|step| step.name.clone()),
+        error: failed.and_then(|step| step.failures.first().cloned()),
+        steps,
+    }
+}
+
+/// Stores a run, records it as an uptime check and opens, extends or resolves the monitor's incident
+/// within the caller's transaction
+pub fn store_run(monitor: &SyntheticMonitor, run: &mut SyntheticRun, conn: &Connection) -> Result<()> {
+    let date = format_date(run.date);
+    conn.execute(
+        "INSERT INTO synthetic_runs (monitor_id, date, status, duration, failed_step, error, steps)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            run.monitor_id,
+            date,
+            run.status,
+            run.duration,
+            run.failed_step,
+            run.error,
+            serde_json::to_string(&run.steps)?
+        ],
+    )?;
+    run.id = Some(conn.last_insert_rowid());
+    conn.execute(
+        "INSERT INTO uptime (status, timestamp, url) VALUES (?1, ?2, ?3)",
+        params![run.status, Utc::now().to_rfc3339(), monitor.uptime_url()],
+    )?;
+
+    let open = conn
+        .query_row(
+            "SELECT id FROM synthetic_incidents WHERE monitor_id = ?1 AND resolved_at IS NULL",
+            [monitor.id],
+            |row| row.get::<_, i64>(0),
+        )
+        .optional()?;
+    match (run.is_up(), open) {
+        (true, Some(id)) => {
+            conn.execute(
+                "UPDATE synthetic_incidents SET resolved_at = ?2 WHERE id = ?1",
+                params![id, date],
+            )?;
+        }
+        (false, Some(id)) => {
+            conn.execute(
+                "UPDATE synthetic_incidents
+                SET failures = failures + 1, last_failure = ?2, failed_step = ?3, error = ?4
+                WHERE id = ?1",
+                params![id, date, run.failed_step, run.error],
+            )?;
+        }
+        (false, None) => {
+            conn.execute(
+                "INSERT INTO synthetic_incidents (monitor_id, started_at, last_failure, failures, failed_step, error)
+                VALUES (?1, ?2, ?2, 1, ?3, ?4)",
+                params![monitor.id, date, run.failed_step, run.error],
+            )?;
+        }
+        (true, None) => (),
+    }
+
+    let cutoff = format_date(run.date - ChronoDuration::days(SYNTHETIC_RUN_RETENTION_DAYS));
+    conn.execute(
+        "DELETE FROM synthetic_runs WHERE monitor_id = ?1 AND date < ?2",
+        params![monitor.id, cutoff],
+    )?;
+    Ok(())
+}
+
+/// Stores a run in its own transaction, see `store_run`
+pub fn record_run(monitor: &SyntheticMonitor, run: &mut SyntheticRun, conn: &Connection) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    store_run(monitor, run, &tx)?;
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn synthetic_monitors(conn: &Connection) -> Result<Vec<SyntheticMonitor>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM synthetic_monitors ORDER BY name",
+        MONITOR_COLUMNS
+    ))?;
+    let mut rows = stmt.query([])?;
+    let mut monitors = vec![];
+    while let Some(row) = rows.next()? {
+        monitors.push(SyntheticMonitor::from_row(row)?);
+    }
+    Ok(monitors)
+}
+
+pub fn synthetic_monitor(id: i64, conn: &Connection) -> Result<Option<SyntheticMonitor>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM synthetic_monitors WHERE id = ?1",
+        MONITOR_COLUMNS
+    ))?;
+    let mut rows = stmt.query([id])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(SyntheticMonitor::from_row(row)?)),
+        None => Ok(None),
+    }
+}
+
+/// A monitor along with the outcome of its recent runs
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SyntheticMonitorState {
+    #[serde(flatten)]
+    pub monitor: SyntheticMonitor,
+    /// Status of the last run
+    pub status: Option<String>,
+    pub last_run_at: Option<DateTime<Utc>>,
+    /// Percentage of the last day's runs that passed
+    pub availability: Option<f64>,
+    pub open_incident_id: Option<i64>,
+}
+
+pub fn monitor_states(conn: &Connection) -> Result<Vec<SyntheticMonitorState>> {
+    let mut stmt = conn.prepare(
+        "SELECT
+            m.id,
+            (SELECT status FROM synthetic_runs r WHERE r.monitor_id = m.id ORDER BY date DESC, id DESC LIMIT 1),
+            (SELECT MAX(date) FROM synthetic_runs r WHERE r.monitor_id = m.id),
+            (SELECT AVG(status = 'up') * 100 FROM synthetic_runs r WHERE r.monitor_id = m.id AND date >= :since),
+            (SELECT id FROM synthetic_incidents i WHERE i.monitor_id = m.id AND resolved_at IS NULL)
+        FROM synthetic_monitors m",
+    )?;
+    let mut rows = stmt.query(named_params! {
+        ":since": format_date(Utc::now() - ChronoDuration::days(1)),
+    })?;
+    let mut states = HashMap::new();
+    while let Some(row) = rows.next()? {
+        let last_run_at = row.get::<_, Option<String>>(2)?.map(parse_date).transpose()?;
+        states.insert(
+            row.get::<_, i64>(0)?,
+            (row.get(1)?, last_run_at, row.get(3)?, row.get(4)?),
+        );
+    }
+    Ok(synthetic_monitors(conn)?
+        .into_iter()
+        .map(|monitor| {
+            let (status, last_run_at, availability, open_incident_id) =
+                states.remove(&monitor.id).unwrap_or_default();
+            SyntheticMonitorState {
+                monitor,
+                status,
+                last_run_at,
+                availability,
+                open_incident_id,
+            }
+        })
+        .collect())
+}
+
+/// Creates a monitor, failing when the name is taken
+pub fn insert_monitor(request: &SyntheticMonitorRequest, conn: &Connection) -> Result<SyntheticMonitor> {
+    let now = format_date(Utc::now());
+    conn.execute(
+        "INSERT INTO synthetic_monitors (name, interval, enabled, variables, steps, created_at, updated_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)",
+        params![
+            request.name.trim(),
+            request.interval.unwrap_or(DEFAULT_SYNTHETIC_INTERVAL),
+            request.enabled.unwrap_or(true),
+            serde_json::to_string(&request.variables)?,
+            serde_json::to_string(&request.steps)?,
+            now
+        ],
+    )?;
+    synthetic_monitor(conn.last_insert_rowid(), conn)?.ok_or(anyhow!("Monitor was not created"))
+}
+
+/// Replaces a monitor's definition, keeping its runs and incidents. Returns it before and after.
+/// Runs within the caller's transaction, see `audited`.
+pub fn update_monitor(
+    id: i64,
+    request: &SyntheticMonitorRequest,
+    conn: &Connection,
+) -> Result<Option<(SyntheticMonitor, SyntheticMonitor)>> {
+    let Some(before) = synthetic_monitor(id, conn)? else {
+        return Ok(None);
+    };
+    conn.execute(
+        "UPDATE synthetic_monitors
+        SET name = ?2, interval = ?3, enabled = ?4, variables = ?5, steps = ?6, updated_at = ?7
+        WHERE id = ?1",
+        params![
+            id,
+            request.name.trim(),
+            request.interval.unwrap_or(DEFAULT_SYNTHETIC_INTERVAL),
+            request.enabled.unwrap_or(true),
+            serde_json::to_string(&request.variables)?,
+            serde_json::to_string(&request.steps)?,
+            format_date(Utc::now())
+        ],
+    )?;
+    let after = synthetic_monitor(id, conn)?.ok_or(anyhow!("Monitor was not updated"))?;
+    // Keep the availability history under the new name
+    conn.execute(
+        "UPDATE uptime SET url = ?2 WHERE url = ?1",
+        params![before.uptime_url(), after.uptime_url()],
+    )?;
+    Ok(Some((before, after)))
+}
+
+/// Deletes a monitor along with its runs, incidents and uptime checks, returning it. Runs within
+/// the caller's transaction, see `audited`.
+pub fn delete_monitor(id: i64, conn: &Connection) -> Result<Option<SyntheticMonitor>> {
+    let Some(monitor) = synthetic_monitor(id, conn)? else {
+        return Ok(None);
+    };
+    conn.execute("DELETE FROM synthetic_monitors WHERE id = ?1", [id])?;
+    conn.execute("DELETE FROM synthetic_runs WHERE monitor_id = ?1", [id])?;
+    conn.execute("DELETE FROM synthetic_incidents WHERE monitor_id = ?1", [id])?;
+    conn.execute("DELETE FROM uptime WHERE url = ?1", [monitor.uptime_url()])?;
+    Ok(Some(monitor))
+}
+
+/// Most recent runs of a monitor first
+pub fn monitor_runs(id: i64, limit: usize, conn: &Connection) -> Result<Vec<SyntheticRun>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM synthetic_runs WHERE monitor_id = ?1 ORDER BY date DESC, id DESC LIMIT ?2",
+        RUN_COLUMNS
+    ))?;
+    let mut rows = stmt.query(params![id, limit])?;
+    let mut runs = vec![];
+    while let Some(row) = rows.next()? {
+        runs.push(SyntheticRun::from_row(row)?);
+    }
+    Ok(runs)
+}
+
+/// Consecutive failing runs of a monitor, resolved by the next passing run
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SyntheticIncident {
+    pub id: i64,
+    pub monitor_id: i64,
+    pub started_at: DateTime<Utc>,
+    pub resolved_at: Option<DateTime<Utc>>,
+    pub last_failure: DateTime<Utc>,
+    /// Failing runs so far
+    pub failures: usize,
+    /// Step and error of the last failing run
+    pub failed_step: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Most recent incidents of a monitor first
+pub fn monitor_incidents(id: i64, limit: usize, conn: &Connection) -> Result<Vec<SyntheticIncident>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, monitor_id, started_at, resolved_at, last_failure, failures, failed_step, error
+        FROM synthetic_incidents
+        WHERE monitor_id = ?1
+        ORDER BY started_at DESC, id DESC
+        LIMIT ?2",
+    )?;
+    let mut rows = stmt.query(params![id, limit])?;
+    let mut incidents = vec![];
+    while let Some(row) = rows.next()? {
+        incidents.push(SyntheticIncident {
+            id: row.get(0)?,
+            monitor_id: row.get(1)?,
+            started_at: parse_date(row.get(2)?)?,
+            resolved_at: row.get::<_, Option<String>>(3)?.map(parse_date).transpose()?,
+            last_failure: parse_date(row.get(4)?)?,
+            failures: row.get(5)?,
+            failed_step: row.get(6)?,
+            error: row.get(7)?,
+        });
+    }
+    Ok(incidents)
+}
+
+/// Runs the enabled monitors on their intervals. Monitors are reloaded on every tick, so created,
+/// changed and deleted ones are picked up without restarting, and a monitor never runs twice at once.
+/// Database work happens on blocking threads so that a locked database doesn't stall the server.
+pub fn start_synthetic_monitors(db_path: String) {
+    let conn = match open_db(&db_path) {
+        Ok(conn) => Arc::new(Mutex::new(conn)),
+        Err(err) => panic!("Error opening database: {:?}", err),
+    };
+    let running = Rc::new(RefCell::new(HashSet::new()));
+    let mut next_runs: HashMap<i64, Instant> = HashMap::new();
+    let mut interval = time::interval(SCHEDULER_TICK);
+    rt::spawn(async move {
+        loop {
+            interval.tick().await;
+            let conn = conn.clone();
+            let monitors = rt::task::spawn_blocking(move || synthetic_monitors(&conn.lock().unwrap()))
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|monitors| monitors);
+            let monitors = match monitors {
+                Ok(monitors) => monitors,
+                Err(e) => {
+                    info!("Failed to read synthetic monitors: {}", e);
+                    continue;
+                }
+            };
+            let now = Instant::now();
+            next_runs.retain(|id, _| monitors.iter().any(|m| m.id == *id && m.enabled));
+            for monitor in monitors.into_iter().filter(|m| m.enabled) {
+                let due = next_runs.get(&monitor.id).is_none_or(|next| *next <= now);
+                if !due || running.borrow().contains(&monitor.id) {
+                    continue;
+                }
+                next_runs.insert(monitor.id, now + Duration::from_secs(monitor.interval));
+                running.borrow_mut().insert(monitor.id);
+                let running = running.clone();
+                let db_path = db_path.clone();
+                rt::spawn(async move {
+                    let (id, name) = (monitor.id, monitor.name.clone());
+                    let mut run = run_monitor(&monitor).await;
+                    // Runs finish independently, so each stores its result over its own connection
+                    let recorded = rt::task::spawn_blocking(move || {
+                        open_db(&db_path)
+                            .map_err(anyhow::Error::from)
+                            .and_then(|conn| record_run(&monitor, &mut run, &conn))
+                    })
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|recorded| recorded);
+                    if let Err(e) = recorded {
+                        info!("Failed to record run of synthetic monitor {}: {}", name, e);
+                    }
+                    running.borrow_mut().remove(&id);
+                });
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn variables() -> BTreeMap<String, Value> {
+        BTreeMap::from([("token".to_string(), json!("abc")), ("id".to_string(), json!(42))])
+    }
+
+    fn step(value: Value) -> SyntheticStep {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn monitor(steps: Value) -> SyntheticMonitorRequest {
+        serde_json::from_value(json!({ "name": "checkout", "steps": steps })).unwrap()
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        let variables = variables();
+        assert_eq!(
+            render("https://x.test/orders/{{ id }}?t={{token}}", &variables).unwrap(),
+            "https://x.test/orders/42?t=abc"
+        );
+        assert_eq!(render("no placeholders", &variables).unwrap(), "no placeholders");
+        assert_eq!(render("{{$uuid}}", &variables).unwrap().len(), 36);
+        assert!(render("{{missing}}", &variables).is_err());
+        assert!(render("{{env.HOME}}", &variables).is_err());
+    }
+
+    #[test]
+    fn renders_values_keeping_types() {
+        let rendered = render_value(
+            &json!({ "id": "{{id}}", "auth": "Bearer {{token}}", "items": [" {{ id }} ", 1] }),
+            &variables(),
+        )
+        .unwrap();
+        assert_eq!(
+            rendered,
+            json!({ "id": 42, "auth": "Bearer abc", "items": [42, 1] })
+        );
+        assert!(render_value(&json!(["{{missing}}"]), &variables()).is_err());
+    }
+
+    #[test]
+    fn checks_responses() {
+        let step = step(json!({
+            "name": "login",
+            "url": "https://x.test/login",
+            "extract": { "session": "$.session", "code": "status", "type": "header:Content-Type" },
+            "assert": {
+                "status": [200, 201],
+                "maxDuration": 500,
+                "contains": "session",
+                "body": [{ "path": "$.user", "equals": "{{token}}" }, { "path": "$.error", "exists": false }]
+            }
+        }));
+        let headers =
+            Headers::Structured(vec![("content-type".to_string(), "application/json".to_string())]);
+        let body = json!({ "session": "s1", "user": "abc" });
+
+        let mut variables = variables();
+        let (failures, extracted) = check_response(&step, 201, 100, &headers, &body, &mut variables);
+        assert!(failures.is_empty(), "{:?}", failures);
+        assert_eq!(extracted, vec!["code", "session", "type"]);
+        assert_eq!(variables["session"], json!("s1"));
+        assert_eq!(variables["code"], json!(201));
+        assert_eq!(variables["type"], json!("application/json"));
+
+        let body = json!({ "user": "someone", "error": "nope" });
+        let mut variables = BTreeMap::from([("token".to_string(), json!("secret"))]);
+        let (failures, extracted) = check_response(&step, 500, 900, &headers, &body, &mut variables);
+        assert_eq!(
+            failures,
+            vec![
+                "Expected status 200 or 201, got 500",
+                "Took 900 ms, more than 500 ms",
+                "Body doesn't contain 'session'",
+                "Expected $.user to be \"{{token}}\", got \"someone\"",
+                "Expected $.error to be missing",
+            ]
+        );
+        assert!(!failures.iter().any(|f| f.contains("secret")));
+        assert!(extracted.is_empty());
+        assert!(!variables.contains_key("session"));
+    }
+
+    #[test]
+    fn reports_missing_extractions() {
+        let step = step(json!({
+            "name": "fetch",
+            "url": "https://x.test",
+            "extract": { "id": "$.id" }
+        }));
+        let mut variables = BTreeMap::new();
+        let (failures, extracted) = check_response(
+            &step,
+            204,
+            10,
+            &Headers::default(),
+            &json!({ "id": null }),
+            &mut variables,
+        );
+        assert_eq!(failures, vec!["Nothing to extract for 'id' at $.id"]);
+        assert!(extracted.is_empty());
+    }
+
+    #[test]
+    fn validates_monitors() {
+        let valid = monitor(json!([
+            { "name": "login", "method": "post", "url": "https://x.test/login", "extract": { "token": "$.token" } },
+            { "name": "me", "url": "https://x.test/me", "headers": { "authorization": "Bearer {{token}}" } },
+            { "name": "env", "url": "{{env.SYNTHETIC_URL}}/{{$uuid}}" }
+        ]));
+        assert!(valid.validate().is_ok());
+
+        let error = |steps: Value| monitor(steps).validate().unwrap_err().to_string();
+        assert_eq!(
+            error(json!([])),
+            format!("A monitor needs between 1 and {} steps", MAX_SYNTHETIC_STEPS)
+        );
+        assert_eq!(
+            error(json!([{ "name": "a", "url": "ftp://x.test" }])),
+            "Step 0 (a): url must start with http:// or https://"
+        );
+        assert_eq!(
+            error(json!([{ "name": "a", "method": "NOT A METHOD", "url": "https://x.test" }])),
+            "Step 0 (a): Invalid method 'NOT A METHOD'"
+        );
+        assert_eq!(
+            error(json!([
+                { "name": "a", "url": "https://x.test/{{token}}" },
+                { "name": "b", "url": "https://x.test", "extract": { "token": "$.token" } }
+            ])),
+            "Step 0 (a): '{{token}}' is not defined by the monitor or an earlier step"
+        );
+        assert_eq!(
+            error(json!([{ "name": "a", "url": "https://x.test/{{env.HOME}}" }])),
+            "Step 0 (a): Only environment variables starting with SYNTHETIC_ can be used, not 'HOME'"
+        );
+        assert_eq!(
+            error(json!([{ "name": "a", "url": "https://x.test", "timeout": 0 }])),
+            format!(
+                "Step 0 (a): timeout must be between 1 and {} seconds",
+                MAX_STEP_TIMEOUT
+            )
+        );
+        assert_eq!(
+            error(json!([{ "name": "a", "url": "https://x.test", "assert": { "status": [99] } }])),
+            "Step 0 (a): 99 is not a valid status"
+        );
+        assert_eq!(
+            error(json!([{ "name": "a", "url": "https://x.test", "extract": { "1x": "status" } }])),
+            "Step 0 (a): '1x' is not a valid variable name"
+        );
+
+        let mut request = valid.clone();
+        request.interval = Some(MIN_SYNTHETIC_INTERVAL - 1);
+        assert!(request.validate().is_err());
+        let mut request = valid.clone();
+        request.name = " ".to_string();
+        assert!(request.validate().is_err());
+        let mut request = valid;
+        request.variables.insert("bad-name".to_string(), json!(1));
+        assert!(request.validate().is_err());
+    }
+}
@@ -0,0 +1,374 @@
+//! Correlation of request logs by W3C trace context, see https://www.w3.org/TR/trace-context/.
+//!
+//! Requests carrying a `traceparent` header, or logged with explicit ids, are grouped by trace id
+//! and their spans linked through `parent_span_id` into a call tree.
+
+use super::{
+    headers::Headers,
+    requests::{RequestLog, REQUEST_LOG_COLUMNS},
+};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+/// Spans returned for a single trace, the earliest ones being kept
+pub const MAX_TRACE_SPANS: usize = 1000;
+
+/// Lowercase `id` when it's `len` hex digits and not all zeros, which the spec reserves as invalid
+fn hex_id(id: &str, len: usize) -> Option<String> {
+    let id = id.trim().to_lowercase();
+    (id.len() == len && id.chars().all(|c| c.is_ascii_hexdigit()) && id.chars().any(|c| c != '0'))
+        .then_some(id)
+}
+
+pub fn trace_id(id: &str) -> Option<String> {
+    hex_id(id, 32)
+}
+
+pub fn span_id(id: &str) -> Option<String> {
+    hex_id(id, 16)
+}
+
+/// Whether `field` is `len` hex digits, zeros included
+fn is_hex(field: &str, len: usize) -> bool {
+    field.len() == len && field.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Trace id and parent span id of a `traceparent` header, `00-<trace id>-<parent id>-<flags>`,
+/// also found among the header lines of raw headers
+pub fn parse_traceparent(headers: &Headers) -> Option<(String, String)> {
+    let (pairs, _) = headers.parsed_pairs();
+    let (_, value) = pairs.into_iter().find(|(name, _)| name == TRACEPARENT_HEADER)?;
+    let parts = value.trim().split('-').collect::<Vec<&str>>();
+    let version = parts[0].to_lowercase();
+    // Later versions may append fields, version 00 has exactly four and ff is invalid
+    if !is_hex(&version, 2)
+        || version == "ff"
+        || parts.len() < 4
+        || (version == "00" && parts.len() != 4)
+    {
+        return None;
+    }
+    if !is_hex(parts[3], 2) {
+        return None;
+    }
+    Some((trace_id(parts[1])?, span_id(parts[2])?))
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: Option<String>,
+    pub span_id: Option<String>,
+    pub parent_span_id: Option<String>,
+}
+
+impl TraceContext {
+    /// Resolves the ids of a logged request, explicit ones taking precedence over `traceparent`.
+    /// The header's parent id is the caller's span, so requests get a new span id when not given.
+    pub fn resolve(
+        trace: Option<String>,
+        span: Option<String>,
+        parent_span: Option<String>,
+        req_headers: &Headers,
+    ) -> Result<TraceContext> {
+        let explicit = |id: Option<String>, field: &str, parse: fn(&str) -> Option<String>| {
+            id.filter(|id| !id.trim().is_empty())
+                .map(|id| parse(&id).ok_or(anyhow!("'{}' is not a valid {}", id, field)))
+                .transpose()
+        };
+        let trace = explicit(trace, "trace_id", trace_id)?;
+        let span = explicit(span, "span_id", span_id)?;
+        let parent_span = explicit(parent_span, "parent_span_id", span_id)?;
+        let traceparent = parse_traceparent(req_headers);
+
+        let Some(trace) = trace.or_else(|| traceparent.as_ref().map(|(t, _)| t.clone())) else {
+            if span.is_some() || parent_span.is_some() {
+                return Err(anyhow!("span_id and parent_span_id need a trace_id"));
+            }
+            return Ok(TraceContext::default());
+        };
+        let parent_span =
+            parent_span.or_else(|| traceparent.filter(|(t, _)| *t == trace).map(|(_, parent)| parent));
+        Ok(TraceContext {
+            span_id: Some(span.unwrap_or_else(|| Uuid::new_v4().simple().to_string()[..16].into())),
+            parent_span_id: parent_span,
+            trace_id: Some(trace),
+        })
+    }
+}
+
+/// A request in a trace with its position on the trace's timeline
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Span {
+    pub id: Option<i64>,
+    pub method: String,
+    pub endpoint: String,
+    pub endpoint_template: Option<String>,
+    pub service: Option<String>,
+    pub status: u16,
+    pub date: DateTime<Utc>,
+    pub span_id: Option<String>,
+    pub parent_span_id: Option<String>,
+    /// Milliseconds from the start of the trace
+    pub offset: i64,
+    /// Response time in milliseconds
+    pub duration: u32,
+    pub children: Vec<Span>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Trace {
+    pub trace_id: String,
+    pub start: DateTime<Utc>,
+    /// Milliseconds from the first request to the end of the last one
+    pub duration: i64,
+    pub span_count: usize,
+    /// Whether spans past `MAX_TRACE_SPANS` were left out
+    pub truncated: bool,
+    pub services: Vec<String>,
+    /// Spans without a parent in the trace, in start order
+    pub roots: Vec<Span>,
+}
+
+fn span(log: &RequestLog, start: DateTime<Utc>) -> Span {
+    Span {
+        id: log.id,
+        method: log.method.clone(),
+        endpoint: log.endpoint.clone(),
+        endpoint_template: log.endpoint_template.clone(),
+        service: log.service.clone(),
+        status: log.status,
+        date: log.date,
+        span_id: log.span_id.clone(),
+        parent_span_id: log.parent_span_id.clone(),
+        offset: (log.date - start).num_milliseconds(),
+        duration: log.res_time,
+        children: vec![],
+    }
+}
+
+fn build(
+    index: usize,
+    logs: &[RequestLog],
+    children: &[Vec<usize>],
+    visited: &mut HashSet<usize>,
+    start: DateTime<Utc>,
+) -> Span {
+    visited.insert(index);
+    let mut node = span(&logs[index], start);
+    for &child in &children[index] {
+        if !visited.contains(&child) {
+            node.children.push(build(child, logs, children, visited, start));
+        }
+    }
+    node
+}
+
+/// Builds the call tree of `logs`, ordered by date
+pub fn trace_tree(trace_id: &str, logs: &[RequestLog], truncated: bool) -> Option<Trace> {
+    let start = logs.first()?.date;
+    let spans = logs
+        .iter()
+        .enumerate()
+        .filter_map(|(i, log)| Some((log.span_id.as_deref()?, i)))
+        .rev()
+        .collect::<HashMap<&str, usize>>();
+    let parents = logs
+        .iter()
+        .enumerate()
+        .map(|(i, log)| {
+            let parent = spans.get(log.parent_span_id.as_deref()?)?;
+            (*parent != i).then_some(*parent)
+        })
+        .collect::<Vec<Option<usize>>>();
+    let mut children = vec![vec![]; logs.len()];
+    for (i, parent) in parents.iter().enumerate() {
+        if let Some(parent) = parent {
+            children[*parent].push(i);
+        }
+    }
+
+    let mut visited = HashSet::new();
+    let mut roots = vec![];
+    for (i, _) in parents.iter().enumerate().filter(|(_, p)| p.is_none()) {
+        roots.push(build(i, logs, &children, &mut visited, start));
+    }
+    // Spans whose parents form a cycle are shown from the earliest one
+    for i in 0..logs.len() {
+        if !visited.contains(&i) {
+            roots.push(build(i, logs, &children, &mut visited, start));
+        }
+    }
+
+    let mut services = logs
+        .iter()
+        .filter_map(|log| log.service.clone())
+        .collect::<Vec<String>>();
+    services.sort();
+    services.dedup();
+    Some(Trace {
+        trace_id: trace_id.to_string(),
+        start,
+        duration: logs
+            .iter()
+            .map(|log| (log.date - start).num_milliseconds() + log.res_time as i64)
+            .max()
+            .unwrap_or_default(),
+        span_count: logs.len(),
+        truncated,
+        services,
+        roots,
+    })
+}
+
+/// Requests logged with `trace_id`, as a call tree
+pub fn trace(trace_id: &str, conn: &Connection) -> Result<Option<Trace>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM requests WHERE trace_id = ?1 ORDER BY date, id LIMIT ?2",
+        REQUEST_LOG_COLUMNS
+    ))?;
+    let mut rows = stmt.query(params![trace_id, MAX_TRACE_SPANS + 1])?;
+    let mut logs = vec![];
+    while let Some(row) = rows.next()? {
+        logs.push(RequestLog::from_row(row)?);
+    }
+    let truncated = logs.len() > MAX_TRACE_SPANS;
+    logs.truncate(MAX_TRACE_SPANS);
+    Ok(trace_tree(trace_id, &logs, truncated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT: &str = "00f067aa0ba902b7";
+
+    fn traceparent(value: &str) -> Headers {
+        Headers::Structured(vec![(TRACEPARENT_HEADER.to_string(), value.to_string())])
+    }
+
+    fn ids() -> Option<(String, String)> {
+        Some((TRACE.to_string(), PARENT.to_string()))
+    }
+
+    #[test]
+    fn parses_traceparent() {
+        let header = format!("00-{}-{}-01", TRACE, PARENT);
+        assert_eq!(parse_traceparent(&traceparent(&header)), ids());
+        assert_eq!(parse_traceparent(&traceparent(&header.to_uppercase())), ids());
+        let raw = Headers::Raw(format!("GET / HTTP/1.1\nTraceparent: {}", header));
+        assert_eq!(parse_traceparent(&raw), ids());
+        assert_eq!(parse_traceparent(&Headers::default()), None);
+
+        // Version 00 has exactly four fields, later ones may have more
+        assert_eq!(
+            parse_traceparent(&traceparent(&format!("{}-extra", header))),
+            None
+        );
+        assert_eq!(
+            parse_traceparent(&traceparent(&format!("01-{}-{}-01-extra", TRACE, PARENT))),
+            ids()
+        );
+        assert_eq!(parse_traceparent(&traceparent(&format!("00-{}-01", TRACE))), None);
+        assert_eq!(
+            parse_traceparent(&traceparent(&format!("ff-{}-{}-01", TRACE, PARENT))),
+            None
+        );
+        assert_eq!(
+            parse_traceparent(&traceparent(&format!("zz-{}-{}-01", TRACE, PARENT))),
+            None
+        );
+        assert_eq!(
+            parse_traceparent(&traceparent(&format!("00-{}-{}-1", TRACE, PARENT))),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_zero_ids() {
+        let zero_trace = format!("00-{}-{}-01", "0".repeat(32), PARENT);
+        let zero_parent = format!("00-{}-{}-01", TRACE, "0".repeat(16));
+        assert_eq!(parse_traceparent(&traceparent(&zero_trace)), None);
+        assert_eq!(parse_traceparent(&traceparent(&zero_parent)), None);
+        assert_eq!(trace_id(&"0".repeat(32)), None);
+        assert_eq!(span_id(&"0".repeat(16)), None);
+        assert_eq!(span_id(" 00F067AA0BA902B7 "), Some(PARENT.to_string()));
+    }
+
+    #[test]
+    fn resolves_from_traceparent() {
+        let header = traceparent(&format!("00-{}-{}-01", TRACE, PARENT));
+        let context = TraceContext::resolve(None, None, None, &header).unwrap();
+        assert_eq!(context.trace_id.as_deref(), Some(TRACE));
+        assert_eq!(context.parent_span_id.as_deref(), Some(PARENT));
+        // The request is a new span under the caller's
+        let span = context.span_id.unwrap();
+        assert!(span_id(&span).is_some() && span != PARENT, "{}", span);
+
+        assert_eq!(
+            TraceContext::resolve(None, None, None, &Headers::default()).unwrap(),
+            TraceContext::default()
+        );
+        let empty = Some(" ".to_string());
+        assert_eq!(
+            TraceContext::resolve(empty.clone(), empty.clone(), empty, &Headers::default()).unwrap(),
+            TraceContext::default()
+        );
+    }
+
+    #[test]
+    fn prefers_explicit_ids() {
+        let header = traceparent(&format!("00-{}-{}-01", TRACE, PARENT));
+        let other_trace = "a".repeat(32);
+        let context =
+            TraceContext::resolve(Some(other_trace.clone()), Some("B".repeat(16)), None, &header)
+                .unwrap();
+        assert_eq!(
+            context,
+            TraceContext {
+                trace_id: Some(other_trace),
+                span_id: Some("b".repeat(16)),
+                // The header's parent belongs to another trace
+                parent_span_id: None,
+            }
+        );
+
+        let context =
+            TraceContext::resolve(Some(TRACE.to_string()), None, Some("c".repeat(16)), &header).unwrap();
+        assert_eq!(context.parent_span_id, Some("c".repeat(16)));
+        let context = TraceContext::resolve(Some(TRACE.to_uppercase()), None, None, &header).unwrap();
+        assert_eq!(context.parent_span_id.as_deref(), Some(PARENT));
+    }
+
+    #[test]
+    fn rejects_invalid_explicit_ids() {
+        let none = Headers::default();
+        let error = |trace: &str, span: Option<&str>| {
+            TraceContext::resolve(
+                Some(trace.to_string()).filter(|t| !t.is_empty()),
+                span.map(|s| s.to_string()),
+                None,
+                &none,
+            )
+            .unwrap_err()
+            .to_string()
+        };
+        assert_eq!(error("xyz", None), "'xyz' is not a valid trace_id");
+        assert_eq!(
+            error(&"0".repeat(32), None),
+            format!("'{}' is not a valid trace_id", "0".repeat(32))
+        );
+        assert_eq!(error(TRACE, Some("short")), "'short' is not a valid span_id");
+        assert_eq!(
+            error("", Some(PARENT)),
+            "span_id and parent_span_id need a trace_id"
+        );
+    }
+}
@@ -6,6 +6,7 @@ use awc::Client;
 use chrono::Utc;
 use log::info;
 use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::{str::FromStr, time::Duration};
 
@@ -112,15 +113,40 @@ pub fn uptime_percentage(conn: &Connection) -> Result<Vec<Value>> {
     Ok(results)
 }
 
-pub fn delete_uptime_setting_db(conn: &Connection, url: String) -> Result<()> {
-    conn.execute_batch(
-        format!(
-            "BEGIN TRANSACTION; DELETE FROM uptime_settings WHERE url = '{0}'; DELETE FROM uptime WHERE url = '{0}'; COMMIT; ",
-            url
-        )
-        .as_str(),
+/// Latest ping result of a monitored url
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UptimeState {
+    pub url: String,
+    /// `up` or `down`
+    pub status: String,
+    pub checked_at: String,
+}
+
+pub fn uptime_states(conn: &Connection) -> Result<Vec<UptimeState>> {
+    // SQLite takes the other columns from the row holding the MAX
+    let mut stmt = conn.prepare(
+        "
+        SELECT url, status, MAX(timestamp)
+        FROM uptime
+        GROUP BY url
+        ORDER BY url;
+        ",
     )?;
+    let rows = stmt.query_map([], |row| {
+        Ok(UptimeState {
+            url: row.get(0)?,
+            status: row.get(1)?,
+            checked_at: row.get(2)?,
+        })
+    })?;
+    Ok(rows.collect::<Result<Vec<UptimeState>, _>>()?)
+}
 
+/// Deletes a setting and its pings, within the caller's transaction
+pub fn delete_uptime_setting_db(conn: &Connection, url: String) -> Result<()> {
+    conn.execute("DELETE FROM uptime_settings WHERE url = ?1", [&url])?;
+    conn.execute("DELETE FROM uptime WHERE url = ?1", [&url])?;
     Ok(())
 }
 
@@ -198,6 +224,10 @@ pub fn uptime_settings(conn: &Connection) -> Result<Vec<UptimeSetting>> {
     Ok(uptime_settings)
 }
 
+pub fn uptime_setting(conn: &Connection, url: &str) -> Result<Option<UptimeSetting>> {
+    Ok(uptime_settings(conn)?.into_iter().find(|s| s.url == url))
+}
+
 /// Pings the url and logs in the db whether it is up or down
 pub async fn append_uptime(conn: &Connection, url: String) {
     let client = Client::default();
@@ -0,0 +1,72 @@
+use crate::{
+    middleware::auth::User,
+    ops::{
+        anomalies::{active_anomalies, anomalies, AnomalyCondition, AnomalyMetric},
+        pagination::{DEFAULT_PAGE_LIMIT, MAX_PAGE_LIMIT},
+        stats::TimeWindow,
+    },
+    AppState,
+};
+use actix_web::{get, web, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use serde_json::json;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct AnomaliesQuery {
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    index: Option<usize>,
+    limit: Option<usize>,
+    endpoint: Option<String>,
+    method: Option<String>,
+    metric: Option<AnomalyMetric>,
+    min_score: Option<f64>,
+}
+#[get("/anomalies")]
+pub async fn get_anomalies(
+    _: User,
+    query: web::Query<AnomaliesQuery>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    let condition = AnomalyCondition {
+        endpoint: query.endpoint.clone(),
+        method: query.method.clone(),
+        metric: query.metric,
+        min_score: query.min_score,
+    };
+    let window = TimeWindow::new(query.from.unwrap_or(Utc::now() - Duration::days(1)), query.to);
+    match anomalies(
+        &window,
+        &condition,
+        query.index.unwrap_or_default(),
+        Some(query.limit.unwrap_or(DEFAULT_PAGE_LIMIT).min(MAX_PAGE_LIMIT)),
+        &app_state.db_client,
+    ) {
+        Ok((anomalies, total_items)) => HttpResponse::Ok().json(json!({
+            "anomalies": anomalies,
+            "totalItems": total_items,
+        })),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to get anomalies: {:?}", e)})),
+    }
+}
+
+/// Alert condition over the latest checked hour, `firing` while it has matching anomalies
+#[get("/anomalies/active")]
+pub async fn get_active_anomalies(
+    _: User,
+    condition: web::Query<AnomalyCondition>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    match active_anomalies(&condition, &app_state.db_client) {
+        Ok((bucket, anomalies)) => HttpResponse::Ok().json(json!({
+            "firing": !anomalies.is_empty(),
+            "bucket": bucket,
+            "anomalies": anomalies,
+        })),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to get anomalies: {:?}", e)})),
+    }
+}
@@ -0,0 +1,38 @@
+use crate::{middleware::auth::User, ops::audit::get_audit_logs, AppState};
+use actix_web::{get, web, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditLogQuery {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub target: Option<String>,
+    pub index: Option<usize>,
+    pub limit: Option<usize>,
+}
+#[get("/audit-log")]
+pub async fn audit_log(
+    _: User,
+    app_state: web::Data<AppState>,
+    query: web::Query<AuditLogQuery>,
+) -> HttpResponse {
+    match get_audit_logs(
+        query.from,
+        query.to,
+        query.actor.clone(),
+        query.action.clone(),
+        query.target.clone(),
+        query.index.unwrap_or(0),
+        query.limit.unwrap_or(50),
+        &app_state.db_client,
+    ) {
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to read audit log: {:?}", e)})),
+    }
+}
@@ -0,0 +1,205 @@
+use crate::{
+    db::connection::open_db,
+    middleware::auth::User,
+    ops::{
+        audit::{audited, AuditEntry},
+        endpoints::{
+            delete_endpoint_pattern, endpoint_patterns, endpoint_stats, insert_endpoint_pattern,
+            load_templater, retemplate_requests, validate_endpoint_pattern, EndpointPattern,
+            EndpointSort,
+        },
+        filters::RequestFilter,
+        pagination::{DEFAULT_PAGE_LIMIT, MAX_PAGE_LIMIT},
+        query::Query,
+        requests::Order,
+        stats::TimeWindow,
+    },
+    routes::requests::invalid_query,
+    AppState,
+};
+use actix_web::{delete, get, http::StatusCode, post, web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::Deserialize;
+use serde_json::json;
+use std::{str::FromStr, sync::Mutex};
+
+/// Held while logged requests are regrouped, so that concurrent pattern changes can't leave them
+/// grouped by outdated patterns
+static RETEMPLATING: Mutex<()> = Mutex::new(());
+
+/// Recompiles the shared templater after the patterns changed and regroups logged requests.
+/// Returns the number of endpoints moved to another template.
+async fn reload_templater(app_state: &AppState) -> usize {
+    match load_templater(&app_state.db_client) {
+        Ok(templater) => *app_state.templater.write().unwrap() = templater,
+        Err(e) => {
+            info!("Failed to reload endpoint patterns: {}", e);
+            return 0;
+        }
+    }
+    // Rewriting the templates of the whole table happens on a blocking thread with its own
+    // connection, with the patterns read again once no other regrouping runs
+    let db_path = app_state.db_path.clone();
+    let changed = web::block(move || {
+        let _retemplating = RETEMPLATING.lock().unwrap();
+        let conn = open_db(&db_path)?;
+        retemplate_requests(&conn, &load_templater(&conn)?)
+    })
+    .await
+    .map_err(anyhow::Error::from)
+    .and_then(|changed| changed);
+    changed.unwrap_or_else(|e| {
+        info!("Failed to update endpoint templates: {}", e);
+        0
+    })
+}
+
+#[get("/endpoint-patterns")]
+pub async fn get_endpoint_patterns(_: User, app_state: web::Data<AppState>) -> HttpResponse {
+    match endpoint_patterns(&app_state.db_client) {
+        Ok(patterns) => HttpResponse::Ok().json(json!(patterns)),
+        Err(_) => HttpResponse::InternalServerError()
+            .json(json!({"msg": "Failed to retrieve endpoint patterns"})),
+    }
+}
+
+#[post("/endpoint-patterns")]
+pub async fn add_endpoint_pattern(
+    user: User,
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    body: web::Json<EndpointPattern>,
+) -> HttpResponse {
+    if let Err(e) = validate_endpoint_pattern(&body.pattern) {
+        return HttpResponse::BadRequest()
+            .json(json!({"msg": format!("Invalid endpoint pattern: {}", e)}));
+    }
+    let pattern = |id: i64| EndpointPattern {
+        id: Some(id),
+        ..body.0.clone()
+    };
+    let inserted = audited(
+        &app_state.db_client,
+        app_state.audit_retention_days,
+        |conn| insert_endpoint_pattern(conn, &body),
+        |id| {
+            Some(
+                AuditEntry::from_request(&user, &req, "endpoint_pattern.create", Some(id.to_string()))
+                    .after(serde_json::to_value(pattern(*id)).ok()),
+            )
+        },
+    );
+    match inserted {
+        Ok(id) => {
+            let retemplated = reload_templater(&app_state).await;
+            HttpResponse::Ok()
+                .status(StatusCode::OK)
+                .json(json!({"result":"success", "pattern": pattern(id), "retemplated": retemplated}))
+        }
+        Err(e) => {
+            error!("Failed to add endpoint pattern: {}", e);
+            HttpResponse::InternalServerError()
+                .json(json!({"msg": "Failed to add endpoint pattern, does it already exist?"}))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DeleteEndpointPattern {
+    id: i64,
+}
+#[delete("/endpoint-patterns")]
+pub async fn remove_endpoint_pattern(
+    user: User,
+    req: HttpRequest,
+    query: web::Query<DeleteEndpointPattern>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    let deleted = audited(
+        &app_state.db_client,
+        app_state.audit_retention_days,
+        |conn| delete_endpoint_pattern(conn, query.id),
+        |pattern| {
+            let entry = AuditEntry::from_request(
+                &user,
+                &req,
+                "endpoint_pattern.delete",
+                Some(query.id.to_string()),
+            );
+            pattern
+                .as_ref()
+                .map(|pattern| entry.before(serde_json::to_value(pattern).ok()))
+        },
+    );
+    match deleted {
+        Ok(Some(_)) => {
+            let retemplated = reload_templater(&app_state).await;
+            HttpResponse::Ok()
+                .status(StatusCode::OK)
+                .json(json!({"result":"success", "retemplated": retemplated}))
+        }
+        Ok(None) => HttpResponse::NotFound().json(json!({"msg": "Endpoint pattern not found"})),
+        Err(e) => {
+            error!("Failed to delete endpoint pattern: {}", e);
+            HttpResponse::InternalServerError().json(json!({"msg": "Failed to delete endpoint pattern"}))
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct EndpointStatsQuery {
+    from: DateTime<Utc>,
+    to: Option<DateTime<Utc>>,
+    sort: Option<EndpointSort>,
+    order: Option<Order>,
+    limit: Option<usize>,
+    index: Option<usize>,
+    q: Option<String>,
+}
+#[get("/endpoints")]
+pub async fn get_endpoint_stats(
+    _: User,
+    query: web::Query<EndpointStatsQuery>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    let mut filter = RequestFilter::default();
+    match Query::from_str(query.q.as_deref().unwrap_or_default()) {
+        Ok(q) => q.apply(&mut filter),
+        Err(e) => return invalid_query(e),
+    };
+    let window = TimeWindow::new(query.from, query.to);
+    if window.to <= window.from {
+        return HttpResponse::BadRequest().json(json!({"msg": "`to` must be after `from`"}));
+    }
+    let stats = filter.run(&app_state.db_client, || {
+        endpoint_stats(
+            &window,
+            &filter,
+            query.sort.unwrap_or_default(),
+            query.order.unwrap_or(Order::Descending),
+            &app_state.db_client,
+        )
+    });
+    match stats {
+        Ok(stats) => {
+            let total_items = stats.len();
+            let endpoints = stats
+                .into_iter()
+                .skip(query.index.unwrap_or_default())
+                .take(query.limit.unwrap_or(DEFAULT_PAGE_LIMIT).min(MAX_PAGE_LIMIT))
+                .collect::<Vec<_>>();
+            let previous = window.previous();
+            HttpResponse::Ok().json(json!({
+                "endpoints": endpoints,
+                "totalItems": total_items,
+                "from": window.from,
+                "to": window.to,
+                "previousFrom": previous.from,
+            }))
+        }
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to get endpoint stats: {:?}", e)})),
+    }
+}
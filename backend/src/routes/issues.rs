@@ -0,0 +1,125 @@
+use crate::{
+    middleware::auth::User,
+    ops::{
+        audit::{audited, AuditEntry},
+        issues::{
+            get_issues, issue_by_id, issue_requests, issue_trend, set_issue_state, IssueSort, IssueState,
+        },
+        pagination::{DEFAULT_PAGE_LIMIT, MAX_PAGE_LIMIT},
+    },
+    AppState,
+};
+use actix_web::{get, patch, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// Requests listed with an issue
+const ISSUE_REQUESTS_LIMIT: usize = 20;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct IssuesQuery {
+    pub state: Option<IssueState>,
+    /// `4xx` or `5xx`
+    pub status: Option<String>,
+    pub regressed: Option<bool>,
+    pub sort: Option<IssueSort>,
+    pub index: Option<usize>,
+    pub limit: Option<usize>,
+}
+#[get("/issues")]
+pub async fn get_issue_list(
+    _: User,
+    app_state: web::Data<AppState>,
+    query: web::Query<IssuesQuery>,
+) -> HttpResponse {
+    let status_class = match query.status.as_deref().map(|s| s.to_lowercase()) {
+        None => None,
+        Some(class) => match class.strip_suffix("xx").and_then(|d| d.parse::<u16>().ok()) {
+            Some(digit @ (4 | 5)) => Some(digit),
+            _ => {
+                return HttpResponse::BadRequest()
+                    .json(json!({"msg": format!("Status must be 4xx or 5xx, not '{}'", class)}))
+            }
+        },
+    };
+    match get_issues(
+        query.state,
+        status_class,
+        query.regressed,
+        query.sort.unwrap_or_default(),
+        query.index.unwrap_or_default(),
+        query.limit.unwrap_or(DEFAULT_PAGE_LIMIT).min(MAX_PAGE_LIMIT),
+        &app_state.db_client,
+    ) {
+        Ok(issues) => HttpResponse::Ok().json(issues),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to get issues: {:?}", e)})),
+    }
+}
+
+/// An issue with its hourly occurrences over the last week and its latest requests
+#[get("/issues/{id}")]
+pub async fn get_issue(_: User, app_state: web::Data<AppState>, path: web::Path<i64>) -> HttpResponse {
+    let id = path.into_inner();
+    let conn = &app_state.db_client;
+    let issue = match issue_by_id(id, conn) {
+        Ok(Some(issue)) => issue,
+        Ok(None) => return HttpResponse::NotFound().json(json!({"msg": "Issue not found"})),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .json(json!({"msg": format!("Failed to get issue: {:?}", e)}))
+        }
+    };
+    let related = issue_trend(id, conn)
+        .and_then(|trend| Ok((trend, issue_requests(id, ISSUE_REQUESTS_LIMIT, conn)?)));
+    match related {
+        Ok((trend, requests)) => HttpResponse::Ok().json(json!({
+            "issue": issue,
+            "hourly": trend,
+            "requests": requests,
+        })),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to get issue occurrences: {:?}", e)})),
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IssueStateChange {
+    pub state: IssueState,
+}
+/// Resolves, ignores or reopens an issue
+#[patch("/issues/{id}")]
+pub async fn update_issue(
+    user: User,
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    path: web::Path<i64>,
+    body: web::Json<IssueStateChange>,
+) -> HttpResponse {
+    let id = path.into_inner();
+    let updated = audited(
+        &app_state.db_client,
+        app_state.audit_retention_days,
+        |conn| set_issue_state(id, body.state, conn),
+        |change| {
+            let (before, after) = change.as_ref()?;
+            Some(
+                AuditEntry::from_request(
+                    &user,
+                    &req,
+                    &format!("issue.{}", body.state),
+                    Some(id.to_string()),
+                )
+                .before(serde_json::to_value(before).ok())
+                .after(serde_json::to_value(after).ok()),
+            )
+        },
+    );
+    match updated {
+        Ok(Some((_, after))) => HttpResponse::Ok().json(json!({"result": "success", "issue": after})),
+        Ok(None) => HttpResponse::NotFound().json(json!({"msg": "Issue not found"})),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to update issue: {:?}", e)})),
+    }
+}
@@ -0,0 +1,161 @@
+use crate::{
+    middleware::auth::User,
+    ops::{
+        logs::{
+            correlated_logs, get_paginated_logs, insert_logs, AttributeFilter, LogEntries, LogFilter,
+            LogLevel, MAX_APPENDED_LOGS,
+        },
+        pagination::{CountMode, Page, MAX_PAGE_LIMIT},
+        requests::request_by_id,
+    },
+    AppState,
+};
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::str::FromStr;
+
+/// Appends a log line or an array of them
+#[post("/append-logs")]
+pub async fn append_logs(
+    _: User,
+    app_state: web::Data<AppState>,
+    body: web::Json<LogEntries>,
+) -> HttpResponse {
+    let mut entries = body.into_inner().0;
+    if entries.len() > MAX_APPENDED_LOGS {
+        return HttpResponse::BadRequest().json(json!({
+            "msg": format!("At most {} log lines can be sent at once", MAX_APPENDED_LOGS)
+        }));
+    }
+    let mut redacted = 0;
+    for (i, entry) in entries.iter_mut().enumerate() {
+        if let Err(e) = entry.validate() {
+            return HttpResponse::BadRequest().json(json!({"msg": format!("Log line {}: {}", i, e)}));
+        }
+        // Strip secrets and PII before anything is written to the db
+        redacted += app_state.redactor.read().unwrap().redact_log_entry(entry);
+    }
+
+    match insert_logs(&entries, app_state.log_retention_days, &app_state.db_client) {
+        Ok(ids) => {
+            HttpResponse::Ok().json(json!({"result": "success", "ids": ids, "redacted": redacted}))
+        }
+        Err(e) => {
+            info!("Failed to append logs: {}", e);
+            HttpResponse::InternalServerError().json(json!({"msg": "Failed to append logs"}))
+        }
+    }
+}
+
+/// Comma separated levels, e.g. `warn,error`
+fn levels(levels: Option<&str>) -> Result<Vec<LogLevel>, String> {
+    levels
+        .unwrap_or_default()
+        .split(',')
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| LogLevel::from_str(l.trim()).map_err(|_| format!("Unknown log level '{}'", l)))
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LogsQuery {
+    pub from: DateTime<Utc>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+    /// Offset for clients that don't use cursors
+    pub index: Option<usize>,
+    pub cursor: Option<String>,
+    pub count: Option<CountMode>,
+    pub search: Option<String>,
+    pub level: Option<String>,
+    pub min_level: Option<String>,
+    pub service: Option<String>,
+    pub trace_id: Option<String>,
+    pub correlation_id: Option<String>,
+}
+#[get("/logs")]
+pub async fn get_logs(
+    _: User,
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    query: web::Query<LogsQuery>,
+) -> HttpResponse {
+    let query = query.into_inner();
+    // `attr` can be repeated, so it's read from the raw query string
+    let attributes = web::Query::<Vec<(String, String)>>::from_query(req.query_string())
+        .map_err(|e| e.to_string())
+        .and_then(|pairs| {
+            pairs
+                .iter()
+                .filter(|(key, _)| key == "attr")
+                .map(|(_, value)| AttributeFilter::from_str(value).map_err(|e| e.to_string()))
+                .collect::<Result<Vec<AttributeFilter>, String>>()
+        });
+    let filters = attributes.and_then(|attributes| {
+        Ok(LogFilter {
+            search: query.search,
+            levels: levels(query.level.as_deref())?,
+            min_level: query
+                .min_level
+                .as_deref()
+                .filter(|l| !l.trim().is_empty())
+                .map(|l| LogLevel::from_str(l.trim()).map_err(|_| format!("Unknown log level '{}'", l)))
+                .transpose()?,
+            service: query.service,
+            trace_id: query.trace_id,
+            correlation_id: query.correlation_id,
+            attributes,
+        })
+    });
+    let filter = match filters {
+        Ok(filter) => filter,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({"msg": format!("Invalid filter: {}", e)}))
+        }
+    };
+    let page = match Page::new(query.cursor.as_deref(), query.index, query.limit, query.count) {
+        Ok(page) => page,
+        Err(e) => return HttpResponse::BadRequest().json(json!({"msg": e.to_string()})),
+    };
+    match get_paginated_logs(query.from, query.to, &page, &filter, &app_state.db_client) {
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to read logs: {:?}", e)})),
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestLogsQuery {
+    /// Seconds around the request to include logs of its service from
+    pub window: Option<i64>,
+    pub limit: Option<usize>,
+}
+/// Log lines correlated with a request by trace id, correlation header or time
+#[get("/requests/{id:\\d+}/logs")]
+pub async fn request_logs(
+    _: User,
+    app_state: web::Data<AppState>,
+    path: web::Path<i64>,
+    query: web::Query<RequestLogsQuery>,
+) -> HttpResponse {
+    let conn = &app_state.db_client;
+    let log = match request_by_id(path.into_inner(), conn) {
+        Ok(Some(log)) => log,
+        Ok(None) => return HttpResponse::NotFound().json(json!({"msg": "Request not found"})),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .json(json!({"msg": format!("Failed to read request: {:?}", e)}))
+        }
+    };
+    let limit = query.limit.unwrap_or(MAX_PAGE_LIMIT).min(MAX_PAGE_LIMIT);
+    match correlated_logs(&log, query.window, limit, conn) {
+        Ok(logs) => HttpResponse::Ok().json(json!({ "logs": logs })),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to read logs: {:?}", e)})),
+    }
+}
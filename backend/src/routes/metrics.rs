@@ -0,0 +1,173 @@
+use crate::{
+    middleware::auth::User,
+    ops::{
+        custom_metrics::{
+            insert_metric_samples, metric_kind, metric_series, validate_label_name, LabelMatcher,
+            MetricAggregation, MetricSample, MetricSampleRequest, MAX_INGESTED_SAMPLES,
+        },
+        metrics::{render_metrics, ServerMetrics},
+        remote_write::decode_write_request,
+        series::{DEFAULT_SERIES_TOP_N, MAX_SERIES_BUCKETS, MAX_SERIES_TOP_N},
+        stats::{parse_timezone, Interval},
+    },
+    AppState,
+};
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::str::FromStr;
+
+/// Metrics in the Prometheus text exposition format
+#[get("/metrics")]
+pub async fn get_metrics(_: User, app_state: web::Data<AppState>) -> HttpResponse {
+    let server = ServerMetrics {
+        queue_depth: app_state.hub.queued(),
+        live_subscribers: app_state.hub.subscribers(),
+    };
+    let metrics = app_state.metrics.lock().unwrap().clone();
+    match render_metrics(&metrics, server, &app_state.db_client) {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4; charset=utf-8")
+            .body(body),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to collect metrics: {}", e)})),
+    }
+}
+
+fn store_samples(samples: Vec<MetricSample>, app_state: &AppState) -> HttpResponse {
+    if samples.len() > MAX_INGESTED_SAMPLES {
+        return HttpResponse::BadRequest().json(json!({
+            "msg": format!("At most {} samples can be sent at once", MAX_INGESTED_SAMPLES)
+        }));
+    }
+    match insert_metric_samples(samples, app_state.metric_retention_days, &app_state.db_client) {
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(e) => {
+            info!("Failed to store metric samples: {}", e);
+            HttpResponse::InternalServerError()
+                .json(json!({"msg": format!("Failed to store metric samples: {}", e)}))
+        }
+    }
+}
+
+/// Stores custom metric samples sent as a JSON array
+#[post("/metrics/ingest")]
+pub async fn ingest_metrics(
+    _: User,
+    app_state: web::Data<AppState>,
+    samples: web::Json<Vec<MetricSampleRequest>>,
+) -> HttpResponse {
+    let samples = samples
+        .into_inner()
+        .into_iter()
+        .map(MetricSample::try_from)
+        .collect::<Result<Vec<MetricSample>, _>>();
+    match samples {
+        Ok(samples) => store_samples(samples, &app_state),
+        Err(e) => HttpResponse::BadRequest().json(json!({"msg": e.to_string()})),
+    }
+}
+
+/// Stores custom metric samples sent with Prometheus remote-write
+#[post("/metrics/write")]
+pub async fn write_metrics(_: User, app_state: web::Data<AppState>, body: web::Bytes) -> HttpResponse {
+    match decode_write_request(&body) {
+        Ok(samples) => store_samples(samples, &app_state),
+        Err(e) => HttpResponse::BadRequest().json(json!({"msg": e.to_string()})),
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricQuery {
+    pub name: String,
+    pub from: DateTime<Utc>,
+    pub to: Option<DateTime<Utc>>,
+    /// Depends on the metric's type when not given
+    pub aggregation: Option<MetricAggregation>,
+    /// Label whose values get their own series
+    pub group_by: Option<String>,
+    /// e.g. `5m`, `1h`, `1d`, `1w` or `1M`, hourly by default
+    pub interval: Option<String>,
+    pub tz: Option<String>,
+    pub top_n: Option<usize>,
+}
+
+/// Time series of a custom metric, narrowed down with repeatable `label=name:value` parameters
+#[get("/metrics/query")]
+pub async fn query_metrics(
+    _: User,
+    req: HttpRequest,
+    query: web::Query<MetricQuery>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    let matchers = web::Query::<Vec<(String, String)>>::from_query(req.query_string())
+        .map_err(|e| anyhow::anyhow!(e.to_string()))
+        .and_then(|pairs| {
+            pairs
+                .iter()
+                .filter(|(key, _)| key == "label")
+                .map(|(_, value)| LabelMatcher::from_str(value))
+                .collect::<anyhow::Result<Vec<LabelMatcher>>>()
+        });
+    let matchers = match matchers {
+        Ok(matchers) => matchers,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({"msg": format!("Invalid filter: {}", e)}))
+        }
+    };
+    if let Some(Err(e)) = query.group_by.as_deref().map(validate_label_name) {
+        return HttpResponse::BadRequest().json(json!({"msg": format!("Invalid groupBy: {}", e)}));
+    }
+    let interval = match Interval::from_str(query.interval.as_deref().unwrap_or("1h")) {
+        Ok(interval) => interval,
+        Err(e) => return HttpResponse::BadRequest().json(json!({"msg": e.to_string()})),
+    };
+    let tz = match parse_timezone(query.tz.as_deref()) {
+        Ok(tz) => tz,
+        Err(e) => return HttpResponse::BadRequest().json(json!({"msg": e.to_string()})),
+    };
+    let buckets = match interval.buckets(query.from, query.to.unwrap_or(Utc::now()), &tz) {
+        Ok(buckets) if buckets.len() > MAX_SERIES_BUCKETS => {
+            return HttpResponse::BadRequest().json(json!({
+                "msg": format!("More than {} buckets, use a longer interval or a shorter range", MAX_SERIES_BUCKETS)
+            }))
+        }
+        Ok(buckets) => buckets,
+        Err(e) => return HttpResponse::BadRequest().json(json!({"msg": e.to_string()})),
+    };
+    let kind = match metric_kind(&query.name, &app_state.db_client) {
+        Ok(Some(kind)) => kind,
+        Ok(None) => return HttpResponse::NotFound().json(json!({"msg": "Metric not found"})),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .json(json!({"msg": format!("Failed to get metric: {:?}", e)}))
+        }
+    };
+    let aggregation = query.aggregation.unwrap_or(MetricAggregation::default_for(kind));
+    if !aggregation.supports(kind) {
+        return HttpResponse::BadRequest().json(json!({
+            "msg": format!("{:?} can't be used on a {:?}", aggregation, kind).to_lowercase()
+        }));
+    }
+
+    match metric_series(
+        &query.name,
+        kind,
+        &matchers,
+        query.group_by.as_deref(),
+        aggregation,
+        query
+            .top_n
+            .unwrap_or(DEFAULT_SERIES_TOP_N)
+            .clamp(1, MAX_SERIES_TOP_N),
+        &buckets,
+        &app_state.db_client,
+    ) {
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to get metric series: {:?}", e)})),
+    }
+}
@@ -1,3 +1,13 @@
+pub mod anomalies;
+pub mod audit;
 pub mod auth;
+pub mod endpoints;
+pub mod issues;
+pub mod logs;
+pub mod metrics;
+pub mod redaction;
+pub mod replay;
 pub mod requests;
+pub mod synthetic;
+pub mod traces;
 pub mod uptime;
@@ -0,0 +1,114 @@
+use crate::{
+    middleware::auth::User,
+    ops::{
+        audit::{audited, AuditEntry},
+        redaction::{
+            delete_redaction_rule, insert_redaction_rule, load_redactor, redaction_rules,
+            validate_redaction_rule, RedactionRule,
+        },
+    },
+    AppState,
+};
+use actix_web::{delete, get, http::StatusCode, post, web, HttpRequest, HttpResponse};
+use log::{error, info};
+use serde::Deserialize;
+use serde_json::json;
+
+/// Recompiles the shared redactor after the rules changed
+fn reload_redactor(app_state: &AppState) {
+    match load_redactor(&app_state.db_client) {
+        Ok(redactor) => *app_state.redactor.write().unwrap() = redactor,
+        Err(e) => info!("Failed to reload redaction rules: {}", e),
+    }
+}
+
+#[get("/redaction-rules")]
+pub async fn get_redaction_rules(_: User, app_state: web::Data<AppState>) -> HttpResponse {
+    match redaction_rules(&app_state.db_client) {
+        Ok(rules) => HttpResponse::Ok().json(json!(rules)),
+        Err(_) => HttpResponse::InternalServerError()
+            .json(json!({"msg": "Failed to retrieve redaction rules"})),
+    }
+}
+
+#[post("/redaction-rules")]
+pub async fn add_redaction_rule(
+    user: User,
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    body: web::Json<RedactionRule>,
+) -> HttpResponse {
+    if let Err(e) = validate_redaction_rule(&body) {
+        return HttpResponse::BadRequest()
+            .json(json!({"msg": format!("Invalid redaction rule: {}", e)}));
+    }
+    let rule = |id: i64| RedactionRule {
+        id: Some(id),
+        ..body.0.clone()
+    };
+    let inserted = audited(
+        &app_state.db_client,
+        app_state.audit_retention_days,
+        |conn| insert_redaction_rule(conn, &body),
+        |id| {
+            Some(
+                AuditEntry::from_request(&user, &req, "redaction_rule.create", Some(id.to_string()))
+                    .after(serde_json::to_value(rule(*id)).ok()),
+            )
+        },
+    );
+    match inserted {
+        Ok(id) => {
+            reload_redactor(&app_state);
+            HttpResponse::Ok()
+                .status(StatusCode::OK)
+                .json(json!({"result":"success", "rule": rule(id)}))
+        }
+        Err(e) => {
+            error!("Failed to add redaction rule: {}", e);
+            HttpResponse::InternalServerError()
+                .json(json!({"msg": "Failed to add redaction rule, does it already exist?"}))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DeleteRedactionRule {
+    id: i64,
+}
+#[delete("/redaction-rules")]
+pub async fn remove_redaction_rule(
+    user: User,
+    req: HttpRequest,
+    query: web::Query<DeleteRedactionRule>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    let deleted = audited(
+        &app_state.db_client,
+        app_state.audit_retention_days,
+        |conn| delete_redaction_rule(conn, query.id),
+        |rule| {
+            let entry = AuditEntry::from_request(
+                &user,
+                &req,
+                "redaction_rule.delete",
+                Some(query.id.to_string()),
+            );
+            rule.as_ref()
+                .map(|rule| entry.before(serde_json::to_value(rule).ok()))
+        },
+    );
+    match deleted {
+        Ok(Some(_)) => {
+            reload_redactor(&app_state);
+            HttpResponse::Ok()
+                .status(StatusCode::OK)
+                .json(json!({"result":"success"}))
+        }
+        Ok(None) => HttpResponse::NotFound().json(json!({"msg": "Redaction rule not found"})),
+        Err(e) => {
+            error!("Failed to delete redaction rule: {}", e);
+            HttpResponse::InternalServerError().json(json!({"msg": "Failed to delete redaction rule"}))
+        }
+    }
+}
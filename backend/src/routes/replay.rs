@@ -0,0 +1,90 @@
+use crate::{
+    middleware::auth::User,
+    ops::{
+        audit::{record_audit, AuditEntry},
+        replay::{
+            replay_target, ReplayDiff, ReplayOptions, ReplayRequest, ReplayResponse,
+            DEFAULT_REPLAY_TIMEOUT, MAX_REPLAY_TIMEOUT,
+        },
+        requests::request_by_id,
+    },
+    AppState,
+};
+use actix_web::{
+    post,
+    web::{self, Bytes},
+    HttpRequest, HttpResponse,
+};
+use log::error;
+use serde_json::json;
+use std::time::Duration;
+
+/// Re-sends a logged request to REPLAY_TARGET_URL and compares the response with the logged one.
+/// Takes optional `ReplayOptions` as a JSON body.
+#[post("/requests/{id:\\d+}/replay")]
+pub async fn replay_request(
+    user: User,
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    path: web::Path<i64>,
+    body: Bytes,
+) -> HttpResponse {
+    let Some(target) = replay_target() else {
+        return HttpResponse::BadRequest()
+            .json(json!({"msg": "Replay is disabled, set REPLAY_TARGET_URL to enable it"}));
+    };
+    let options = match body.is_empty() {
+        true => ReplayOptions::default(),
+        false => match serde_json::from_slice::<ReplayOptions>(&body) {
+            Ok(options) => options,
+            Err(e) => {
+                return HttpResponse::BadRequest()
+                    .json(json!({"msg": format!("Invalid replay options: {}", e)}))
+            }
+        },
+    };
+    let id = path.into_inner();
+    let log = match request_by_id(id, &app_state.db_client) {
+        Ok(Some(log)) => log,
+        Ok(None) => return HttpResponse::NotFound().json(json!({"msg": "Request not found"})),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .json(json!({"msg": format!("Failed to read request: {:?}", e)}))
+        }
+    };
+    let replay = match ReplayRequest::new(&log, &options, &target) {
+        Ok(replay) => replay,
+        Err(e) => return HttpResponse::BadRequest().json(json!({"msg": e.to_string()})),
+    };
+
+    // The replay is audited before it's sent, and not sent when that fails
+    let entry = AuditEntry::from_request(&user, &req, "request.replay", Some(id.to_string()))
+        .after(Some(json!({"method": replay.method, "url": replay.url})));
+    if let Err(e) = record_audit(&app_state.db_client, &entry, app_state.audit_retention_days) {
+        error!("Failed to record audit log: {}", e);
+        return HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to record audit log: {:?}", e)}));
+    }
+
+    let timeout = options
+        .timeout
+        .unwrap_or(DEFAULT_REPLAY_TIMEOUT)
+        .clamp(1, MAX_REPLAY_TIMEOUT);
+    let result = replay.send(Duration::from_secs(timeout)).await;
+
+    match result {
+        Ok(replayed) => {
+            let original = ReplayResponse::original(&log);
+            HttpResponse::Ok().json(json!({
+                "request": replay,
+                "diff": ReplayDiff::new(&original, &replayed),
+                "original": original,
+                "replayed": replayed,
+            }))
+        }
+        Err(e) => HttpResponse::BadGateway().json(json!({
+            "msg": e.to_string(),
+            "request": replay,
+        })),
+    }
+}
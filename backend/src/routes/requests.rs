@@ -1,11 +1,31 @@
 use crate::{
+    db::connection::open_db,
     middleware::auth::User,
     ops::{
+        body_filters::{BodyFilter, MAX_BODY_FILTERS},
+        comparison::{
+            compare_windows, ComparisonBaseline, MoverMetric, DEFAULT_MOVERS_LIMIT, MAX_MOVERS_LIMIT,
+        },
+        endpoints::endpoint_host,
+        export::{export_requests, ChannelWriter, ExportFormat},
+        filters::{text, RequestFilter},
+        headers::{HeaderDirection, HeaderFilter},
+        issues::record_issue,
+        live::{LiveChannels, LiveFilter, LiveTail, ServerMessage, Subscription},
+        pagination::{CountMode, Page},
+        query::{Query, QueryError},
         requests::{
-            get_paginated_requests, get_requests_from, requests_by_status, requests_search,
-            requests_search_suggestions, service_level_indicators, Order, RequestLog, RequestLogRequest,
+            correlated_requests, get_paginated_requests, get_requests_from, insert_request_log,
+            neighbour_requests, request_by_id, request_filter, requests_after, requests_by_status,
+            requests_search, requests_search_suggestions, requests_since_id, service_level_indicators,
+            Order, RequestLog, RequestLogRequest,
+        },
+        series::{
+            request_series, SeriesGroup, SeriesMetric, DEFAULT_SERIES_TOP_N, MAX_SERIES_BUCKETS,
+            MAX_SERIES_TOP_N,
         },
-        stats::get_failure_to_success_stats,
+        stats::{get_failure_to_success_stats, parse_timezone, Interval, TimeWindow},
+        traces::TraceContext,
         websocket::{authenticate_connection, close_session},
     },
     AppState,
@@ -15,19 +35,24 @@ use actix_web::{
     http::StatusCode,
     post,
     rt::{self, pin, time},
-    web, HttpRequest, HttpResponse,
+    web::{self, Bytes},
+    HttpRequest, HttpResponse,
 };
-use actix_ws::Message;
-use chrono::{DateTime, Datelike, Timelike, Utc};
+use actix_ws::{Message, ProtocolError};
+use chrono::{DateTime, Utc};
 use futures_util::{
     future::{self, Either},
-    StreamExt,
+    stream, StreamExt,
 };
 use log::info;
-use rusqlite::params;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::time::{Duration, Instant};
+use std::{
+    io,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
 
 #[get("/")]
 pub async fn index() -> HttpResponse {
@@ -42,34 +67,59 @@ pub async fn append_request(
     app_state: web::Data<AppState>,
     query: web::Json<RequestLogRequest>,
 ) -> HttpResponse {
-    let datetime = query.date.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
-    let stmt = "INSERT INTO requests
-        (date, endpoint, params, method, req_headers, res_headers, status, req_body, res_body, res_time)
-        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)";
-
-    let req_body = query.req_body.clone().unwrap_or_default();
-    let res_body = query.res_body.clone().unwrap_or_default();
+    let query = query.into_inner();
+    let trace = match TraceContext::resolve(
+        query.trace_id,
+        query.span_id,
+        query.parent_span_id,
+        &query.req_headers,
+    ) {
+        Ok(trace) => trace,
+        Err(e) => return HttpResponse::BadRequest().json(json!({"msg": e.to_string()})),
+    };
     let endpoint = query.url.split('?').next().unwrap_or_default();
     let params = query.url.split('?').nth(1).unwrap_or_default();
 
-    match app_state.db_client.execute(
-        stmt,
-        params![
-            datetime,
-            endpoint,
-            params,
-            query.method,
-            query.req_headers,
-            query.res_headers,
-            query.status,
-            req_body.to_string(),
-            res_body.to_string(),
-            query.res_time
-        ],
-    ) {
-        Ok(_) => HttpResponse::Ok()
-            .status(StatusCode::OK)
-            .json(json!({"result":"success"})),
+    let mut log = RequestLog {
+        method: query.method,
+        endpoint: endpoint.to_string(),
+        params: params.to_string(),
+        req_headers: query.req_headers,
+        res_headers: query.res_headers,
+        date: query.date,
+        status: query.status,
+        req_body: Some(query.req_body.unwrap_or_default()),
+        res_body: Some(query.res_body.unwrap_or_default()),
+        res_time: query.res_time,
+        id: None,
+        endpoint_template: None,
+        // Hosts are case insensitive, `service:` filters match them in lowercase
+        service: query
+            .service
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_lowercase())
+            .or_else(|| endpoint_host(endpoint)),
+        trace_id: trace.trace_id,
+        span_id: trace.span_id,
+        parent_span_id: trace.parent_span_id,
+    };
+
+    // Strip secrets and PII before anything is written to the db
+    let redacted = app_state.redactor.read().unwrap().redact_log(&mut log);
+    log.endpoint_template = Some(app_state.templater.read().unwrap().template(&log.endpoint));
+
+    match insert_request_log(&app_state.db_client, &log) {
+        Ok(id) => {
+            log.id = Some(id);
+            if let Err(e) = record_issue(&log, &app_state.db_client) {
+                info!("Failed to group request into an issue: {}", e);
+            }
+            app_state.metrics.lock().unwrap().record(&log);
+            app_state.hub.publish(log);
+            HttpResponse::Ok()
+                .status(StatusCode::OK)
+                .json(json!({"result":"success", "id": id, "redacted": redacted}))
+        }
         Err(e) => {
             info!("Failed to append request log: {}", e);
             HttpResponse::InternalServerError().json(json!({"msg": format!("Failed to append")}))
@@ -77,6 +127,39 @@ pub async fn append_request(
     }
 }
 
+/// Collects the repeatable `reqHeader`, `resHeader` and `body` filters from the query string
+fn repeated_filters(req: &HttpRequest) -> Result<(Vec<HeaderFilter>, Vec<BodyFilter>), String> {
+    let pairs = web::Query::<Vec<(String, String)>>::from_query(req.query_string())
+        .map_err(|e| e.to_string())?;
+    let mut header_filters = vec![];
+    let mut body_filters = vec![];
+    for (key, value) in pairs.iter() {
+        match key.as_str() {
+            "reqHeader" => header_filters
+                .push(HeaderFilter::parse(HeaderDirection::Req, value).map_err(|e| e.to_string())?),
+            "resHeader" => header_filters
+                .push(HeaderFilter::parse(HeaderDirection::Res, value).map_err(|e| e.to_string())?),
+            "body" => body_filters.push(BodyFilter::from_str(value).map_err(|e| e.to_string())?),
+            _ => continue,
+        }
+    }
+    if body_filters.len() > MAX_BODY_FILTERS {
+        return Err(format!(
+            "At most {} body filters can be used at once",
+            MAX_BODY_FILTERS
+        ));
+    }
+    Ok((header_filters, body_filters))
+}
+
+/// Response for an invalid `q` filter query, pointing at where parsing failed
+pub(crate) fn invalid_query(e: QueryError) -> HttpResponse {
+    HttpResponse::BadRequest().json(json!({
+        "msg": format!("Invalid query: {}", e),
+        "position": e.position,
+    }))
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
 pub enum TimeRange {
@@ -89,43 +172,298 @@ pub enum TimeRange {
 pub struct LogTimeRange {
     pub from: DateTime<Utc>,
     pub to: Option<DateTime<Utc>>,
-    pub limit: usize,
-    pub index: usize,
+    pub limit: Option<usize>,
+    /// Offset for clients that don't use cursors
+    pub index: Option<usize>,
+    pub cursor: Option<String>,
+    pub count: Option<CountMode>,
     pub search: Option<String>,
     pub method: Option<String>,
     pub status: Option<String>,
     pub res_time_l_t: Option<String>,
     pub res_time_g_t: Option<String>,
+    pub q: Option<String>,
 }
 #[get("/requests")]
 pub async fn requests(
     _: User,
+    req: HttpRequest,
     app_state: web::Data<AppState>,
     query: web::Query<LogTimeRange>,
 ) -> HttpResponse {
-    // Get the current time
-    match get_paginated_requests(
-        query.from,
-        query.to,
-        query.index,
-        query.limit,
+    let (header_filters, body_filters) = match repeated_filters(&req) {
+        Ok(filters) => filters,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({"msg": format!("Invalid filter: {}", e)}))
+        }
+    };
+    let q = match Query::from_str(query.q.as_deref().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(e) => return invalid_query(e),
+    };
+    let filter = request_filter(
         query.search.clone(),
         query.method.clone(),
         query.status.clone(),
         query.res_time_l_t.clone(),
         query.res_time_g_t.clone(),
-        &app_state.db_client,
-    ) {
+        &header_filters,
+        &body_filters,
+        &q,
+    );
+    let page = match Page::new(query.cursor.as_deref(), query.index, query.limit, query.count) {
+        Ok(page) => page,
+        Err(e) => return HttpResponse::BadRequest().json(json!({"msg": e.to_string()})),
+    };
+    match get_paginated_requests(query.from, query.to, &page, &filter, &app_state.db_client) {
         Ok(result) => HttpResponse::Ok().json(result),
         Err(e) => HttpResponse::InternalServerError()
             .json(json!({"msg": format!("Failed to read log file: {:?}", e)})),
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, Copy)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportQuery {
+    pub format: Option<ExportFormat>,
+}
+/// Streams the requests matching the same filters as `/requests` as CSV (default), NDJSON or Parquet
+#[get("/requests/export")]
+pub async fn export(
+    _: User,
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    query: web::Query<LogTimeRange>,
+    export_query: web::Query<ExportQuery>,
+) -> HttpResponse {
+    /// Chunks buffered between the exporting thread and the response
+    const EXPORT_CHANNEL_CAPACITY: usize = 16;
+
+    let (header_filters, body_filters) = match repeated_filters(&req) {
+        Ok(filters) => filters,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({"msg": format!("Invalid filter: {}", e)}))
+        }
+    };
+    let q = match Query::from_str(query.q.as_deref().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(e) => return invalid_query(e),
+    };
+    let filter = request_filter(
+        query.search.clone(),
+        query.method.clone(),
+        query.status.clone(),
+        query.res_time_l_t.clone(),
+        query.res_time_g_t.clone(),
+        &header_filters,
+        &body_filters,
+        &q,
+    );
+    let format = export_query.format.unwrap_or(ExportFormat::Csv);
+    let (from, to, limit) = (query.from, query.to, query.limit);
+
+    // Rows are read on a blocking thread with its own connection and streamed as they are written
+    let (sender, receiver) = mpsc::channel(EXPORT_CHANNEL_CAPACITY);
+    let db_path = app_state.db_path.clone();
+    rt::task::spawn_blocking(move || {
+        let mut writer = ChannelWriter::new(sender);
+        let exported = open_db(&db_path).map_err(anyhow::Error::from).and_then(|conn| {
+            export_requests(Some(from), to, &filter, limit, format, &mut writer, &conn)
+        });
+        if let Err(e) = exported {
+            info!("Failed to export requests: {}", e);
+            writer.fail(io::Error::other(e.to_string()));
+        }
+    });
+    let body = stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    });
+
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"requests.{}\"", format),
+        ))
+        .streaming(body)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamQuery {
+    pub search: Option<String>,
+    pub method: Option<String>,
+    pub status: Option<String>,
+    pub res_time_l_t: Option<String>,
+    pub res_time_g_t: Option<String>,
+    pub q: Option<String>,
+}
+
+/// Formats a Server-Sent Event
+fn sse_event(event: &str, id: Option<i64>, data: &impl Serialize) -> String {
+    let id = id.map(|id| format!("id: {}\n", id)).unwrap_or_default();
+    format!(
+        "{}event: {}\ndata: {}\n\n",
+        id,
+        event,
+        serde_json::to_string(data).unwrap_or_default()
+    )
+}
+
+/// Live feed of new requests matching the same filters as `/requests`, as Server-Sent Events for
+/// clients that can't use `/requests-ws`. Each request is sent with its id as event id, so clients
+/// reconnecting with `Last-Event-ID` first get the requests stored since.
+#[get("/requests/stream")]
+pub async fn stream_requests(
+    _: User,
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    query: web::Query<StreamQuery>,
+) -> HttpResponse {
+    /// How long the stream can stay silent before a comment is sent to keep proxies from closing it
+    const STREAM_KEEP_ALIVE: Duration = Duration::from_secs(15);
+    /// Stored requests read at a time when resuming from `Last-Event-ID`
+    const RESUME_PAGE_SIZE: usize = 1000;
+    /// Chunks buffered between the feed and the response
+    const STREAM_CHANNEL_CAPACITY: usize = 16;
+
+    let (header_filters, body_filters) = match repeated_filters(&req) {
+        Ok(filters) => filters,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({"msg": format!("Invalid filter: {}", e)}))
+        }
+    };
+    let q = match Query::from_str(query.q.as_deref().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(e) => return invalid_query(e),
+    };
+    let filter = request_filter(
+        query.search.clone(),
+        query.method.clone(),
+        query.status.clone(),
+        query.res_time_l_t.clone(),
+        query.res_time_g_t.clone(),
+        &header_filters,
+        &body_filters,
+        &q,
+    );
+    let last_event_id = match req.headers().get("Last-Event-ID") {
+        Some(value) => match value.to_str().ok().and_then(|id| id.trim().parse::<i64>().ok()) {
+            Some(id) => Some(id),
+            None => {
+                return HttpResponse::BadRequest()
+                    .json(json!({"msg": "Invalid Last-Event-ID, expected a request id"}))
+            }
+        },
+        None => None,
+    };
+
+    // Subscribed before reading the missed requests so nothing stored in between is lost
+    let mut tail = LiveTail::new(&app_state.hub, Subscription::matching(filter.clone()));
+    let missed = match last_event_id {
+        Some(id) => match requests_since_id(id, RESUME_PAGE_SIZE, &filter, &app_state.db_client) {
+            Ok(missed) => Some(missed),
+            Err(e) => {
+                return HttpResponse::InternalServerError()
+                    .json(json!({"msg": format!("Failed to read missed requests: {:?}", e)}))
+            }
+        },
+        None => None,
+    };
+
+    let (sender, receiver) = mpsc::channel::<Result<Bytes, io::Error>>(STREAM_CHANNEL_CAPACITY);
+    rt::spawn(async move {
+        let mut last_id = last_event_id.unwrap_or_default();
+        let mut events = String::new();
+        // Missed requests are sent a page at a time until caught up with the live feed
+        let mut missed = missed;
+        while let Some(page) = missed.take() {
+            for log in &page {
+                events.push_str(&sse_event("request", log.id, log));
+                last_id = log.id.unwrap_or(last_id);
+            }
+            if page.len() < RESUME_PAGE_SIZE {
+                break;
+            }
+            if sender
+                .send(Ok(Bytes::from(std::mem::take(&mut events))))
+                .await
+                .is_err()
+            {
+                return;
+            }
+            match requests_since_id(last_id, RESUME_PAGE_SIZE, &filter, &app_state.db_client) {
+                Ok(page) => missed = Some(page),
+                Err(e) => {
+                    let msg = format!("Failed to read missed requests after {}: {}", last_id, e);
+                    events.push_str(&sse_event("error", None, &json!({ "msg": msg })));
+                }
+            }
+        }
+        let mut last_sent = Instant::now() - STREAM_KEEP_ALIVE;
+        loop {
+            // Requests are sent as soon as they are published, a comment after a silent while
+            let published = {
+                let published = tail.wait();
+                let keep_alive = time::sleep(STREAM_KEEP_ALIVE.saturating_sub(last_sent.elapsed()));
+                pin!(published);
+                pin!(keep_alive);
+                matches!(future::select(published, keep_alive).await, Either::Left(_))
+            };
+            if !published && events.is_empty() {
+                events.push_str(": keep-alive\n\n");
+            }
+            for message in tail.poll(&app_state.db_client) {
+                match message {
+                    // Requests already sent when resuming can be published again
+                    ServerMessage::Requests { requests: logs } => {
+                        for log in logs {
+                            if let Some(id) = log.id.filter(|id| *id > last_id) {
+                                events.push_str(&sse_event("request", log.id, &log));
+                                last_id = id;
+                            }
+                        }
+                    }
+                    ServerMessage::Dropped { count } => {
+                        events.push_str(&sse_event("dropped", None, &json!({ "count": count })))
+                    }
+                    ServerMessage::Error { msg } => {
+                        events.push_str(&sse_event("error", None, &json!({ "msg": msg })))
+                    }
+                    _ => {}
+                }
+            }
+            if events.is_empty() {
+                continue;
+            }
+            // Fails once the client is gone
+            if sender
+                .send(Ok(Bytes::from(std::mem::take(&mut events))))
+                .await
+                .is_err()
+            {
+                break;
+            }
+            last_sent = Instant::now();
+        }
+    });
+    let body = stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(body)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct FromDate {
     pub from: DateTime<Utc>,
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+    pub q: Option<String>,
 }
 #[get("/requests-from")]
 pub async fn requests_from(
@@ -133,23 +471,110 @@ pub async fn requests_from(
     app_state: web::Data<AppState>,
     query: web::Query<FromDate>,
 ) -> HttpResponse {
-    match get_requests_from(
-        query.0.from.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
-        Order::Ascending,
-        &app_state.db_client,
-    ) {
+    let mut filter = RequestFilter::default();
+    match Query::from_str(query.q.as_deref().unwrap_or_default()) {
+        Ok(q) => q.apply(&mut filter),
+        Err(e) => return invalid_query(e),
+    };
+    // Pages are opt-in, existing clients expect every request as a bare array
+    if query.limit.is_none() && query.cursor.is_none() {
+        return match get_requests_from(
+            query.from.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+            Order::Ascending,
+            &filter,
+            &app_state.db_client,
+        ) {
+            Ok(result) => HttpResponse::Ok().json(result),
+            Err(e) => HttpResponse::InternalServerError()
+                .json(json!({"msg": format!("Failed to read requests: {:?}", e)})),
+        };
+    }
+    let page = match Page::new(query.cursor.as_deref(), None, query.limit, Some(CountMode::None)) {
+        Ok(page) => page,
+        Err(e) => return HttpResponse::BadRequest().json(json!({"msg": e.to_string()})),
+    };
+    match requests_after(query.from, &page, &filter, &app_state.db_client) {
         Ok(result) => HttpResponse::Ok().json(result),
         Err(e) => HttpResponse::InternalServerError()
-            .json(json!({"msg": format!("Failed to read log file: {:?}", e)})),
+            .json(json!({"msg": format!("Failed to read requests: {:?}", e)})),
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestDetailQuery {
+    /// Requests to the same endpoint to list before and after, 5 by default
+    pub neighbours: Option<usize>,
+}
+#[get("/requests/{id:\\d+}")]
+pub async fn request_detail(
+    _: User,
+    app_state: web::Data<AppState>,
+    path: web::Path<i64>,
+    query: web::Query<RequestDetailQuery>,
+) -> HttpResponse {
+    /// Most neighbours listed on each side
+    const MAX_NEIGHBOURS: usize = 50;
+    let id = path.into_inner();
+    let limit = query.neighbours.unwrap_or(5).min(MAX_NEIGHBOURS);
+    let conn = &app_state.db_client;
+
+    let log = match request_by_id(id, conn) {
+        Ok(Some(log)) => log,
+        Ok(None) => return HttpResponse::NotFound().json(json!({"msg": "Request not found"})),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .json(json!({"msg": format!("Failed to read request: {:?}", e)}))
+        }
+    };
+    let related = neighbour_requests(&log, limit, conn)
+        .and_then(|neighbours| Ok((neighbours, correlated_requests(id, MAX_NEIGHBOURS, conn)?)));
+    match related {
+        Ok(((previous, next), correlation)) => HttpResponse::Ok().json(json!({
+            "request": log,
+            "neighbours": {
+                "previous": previous,
+                "next": next,
+            },
+            "links": {
+                "self": format!("/requests/{}", id),
+                "logs": format!("/requests/{}/logs", id),
+                "correlation": correlation,
+            },
+        })),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to read related requests: {:?}", e)})),
+    }
+}
+
+/// What woke a live tail connection up
+enum SocketEvent {
+    Client(Option<Result<Message, ProtocolError>>),
+    Heartbeat,
+    Published,
+}
+
 #[get("/requests-ws")]
 async fn get_requests_socket(
     req: HttpRequest,
     app_state: web::Data<AppState>,
     stream: web::Payload,
 ) -> HttpResponse {
+    // The tail starts with the `q` filter query, if any, until the client subscribes
+    let q = web::Query::<Vec<(String, String)>>::from_query(req.query_string())
+        .ok()
+        .and_then(|pairs| pairs.iter().find(|(key, _)| key == "q").map(|(_, q)| q.clone()));
+    if let Err(e) = Query::from_str(q.as_deref().unwrap_or_default()) {
+        return invalid_query(e);
+    }
+    let subscription = match Subscription::new(LiveFilter {
+        q,
+        ..Default::default()
+    }) {
+        Ok(subscription) => subscription,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "msg": e.to_string() })),
+    };
+
     let (res, mut session, mut stream) = match actix_ws::handle(&req, stream) {
         Ok(res) => res,
         Err(err) => {
@@ -170,27 +595,47 @@ async fn get_requests_socket(
         let mut last_heartbeat = Instant::now();
         let mut is_authenticated = false;
         let mut auth_attempt = 0;
-        let connected_at = Utc::now();
         let mut interval = time::interval(HEARTBEAT_INTERVAL);
-        let mut previous_last: Option<RequestLog> = None;
+        // Subscribed right away so nothing stored during authentication is missed
+        let mut channels = LiveChannels::new(&app_state.hub, subscription);
 
         loop {
-            let tick = interval.tick();
-            pin!(tick);
-            // waits for either `stream` to receive a message from the client or the heartbeat
-            // interval timer to tick, yielding the value of whichever one is ready first
-            match future::select(stream.next(), tick).await {
+            // waits for either `stream` to receive a message from the client, the heartbeat
+            // interval timer to tick or a request to be published, yielding whichever is ready first
+            let event = {
+                let tick = interval.tick();
+                // requests are only pushed once authenticated
+                let published = match is_authenticated {
+                    true => Either::Left(channels.published()),
+                    false => Either::Right(future::pending()),
+                };
+                pin!(tick);
+                pin!(published);
+                match future::select(stream.next(), future::select(tick, published)).await {
+                    Either::Left((msg, _)) => SocketEvent::Client(msg),
+                    Either::Right((Either::Left(_), _)) => SocketEvent::Heartbeat,
+                    Either::Right((Either::Right(_), _)) => SocketEvent::Published,
+                }
+            };
+            match event {
                 // received message from WebSocket client
-                Either::Left((Some(Ok(msg)), _)) => {
+                SocketEvent::Client(Some(Ok(msg))) => {
                     match msg {
                         Message::Text(text) => {
                             // authentication
                             if !is_authenticated {
                                 auth_attempt += 1;
                                 is_authenticated = authenticate_connection(text.clone());
-                                if auth_attempt >= MAX_AUTH_ATTEMPTS {
+                                if !is_authenticated && auth_attempt >= MAX_AUTH_ATTEMPTS {
                                     close_session(&session).await;
                                 }
+                            } else {
+                                let reply = channels.handle(&text);
+                                let _ = session.text(serde_json::to_string(&reply).unwrap()).await;
+                                // requests buffered while paused are sent on resume
+                                for message in channels.poll_requests(&app_state.db_client) {
+                                    let _ = session.text(serde_json::to_string(&message).unwrap()).await;
+                                }
                             }
                         }
                         Message::Binary(_) => {
@@ -214,39 +659,32 @@ async fn get_requests_socket(
                 }
 
                 // client WebSocket stream error
-                Either::Left((Some(Err(_err)), _)) => {
+                SocketEvent::Client(Some(Err(_err))) => {
                     session.close(None).await.unwrap();
                     break None;
                 }
                 // client WebSocket stream ended
-                Either::Left((None, _)) => {
+                SocketEvent::Client(None) => {
                     session.close(None).await.unwrap();
                     break None;
                 }
+                // pushed as soon as published, along with any queued since
+                SocketEvent::Published => {
+                    for message in channels.poll_requests(&app_state.db_client) {
+                        let _ = session.text(serde_json::to_string(&message).unwrap()).await;
+                    }
+                }
                 // heartbeat interval ticked
-                Either::Right((_inst, _)) => {
+                SocketEvent::Heartbeat => {
                     // if no heartbeat ping/pong received recently, close the connection
                     if !is_authenticated
                         && Instant::now().duration_since(last_heartbeat) > CLIENT_TIMEOUT
                     {
                         session.close(None).await.unwrap();
                         break None;
-                    } else if let Ok(result) = get_requests_from(
-                        connected_at.format("%Y-%m-%dT%H:%M:%S.%3f").to_string(),
-                        Order::Ascending,
-                        &app_state.db_client,
-                    ) {
-                        if !result.is_empty()
-                            && (previous_last.is_none()
-                                || previous_last
-                                    .clone()
-                                    .is_some_and(|sp| sp.ne(result.last().unwrap())))
-                        {
-                            previous_last = Some(result.last().unwrap().clone());
-                            session
-                                .text(serde_json::to_string(&result).unwrap())
-                                .await
-                                .unwrap_or_default();
+                    } else if is_authenticated {
+                        for message in channels.poll(&app_state.db_client) {
+                            let _ = session.text(serde_json::to_string(&message).unwrap()).await;
                         }
                     }
                     // send heartbeat ping
@@ -271,12 +709,18 @@ pub enum Granularity {
 pub enum StatType {
     Status,
 }
-#[derive(Serialize, Deserialize, Clone, Debug, Copy)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Stats {
     pub stat_type: StatType,
     pub from: DateTime<Utc>,
-    pub granularity: Granularity,
+    pub to: Option<DateTime<Utc>>,
+    /// Kept for older clients, `interval` takes precedence
+    pub granularity: Option<Granularity>,
+    /// e.g. `5m`, `1h`, `1d`, `1w` or `1M`
+    pub interval: Option<String>,
+    /// IANA timezone buckets are aligned to, UTC by default
+    pub tz: Option<String>,
 }
 
 const DB_LOG_DATE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.3fZ";
@@ -287,33 +731,46 @@ async fn get_status_stats(
 
     query: web::Query<Stats>,
 ) -> HttpResponse {
+    let interval = match (&query.interval, query.granularity) {
+        (Some(interval), _) => Interval::from_str(interval),
+        (None, Some(Granularity::Daily)) => Ok(Interval::Days(1)),
+        (None, Some(Granularity::Weekly)) => Ok(Interval::Weeks(1)),
+        (None, Some(Granularity::Monthly)) => Ok(Interval::Months(1)),
+        (None, _) => Ok(Interval::Hours(1)),
+    };
+    let interval = match interval {
+        Ok(interval) => interval,
+        Err(e) => return HttpResponse::BadRequest().json(json!({"msg": e.to_string()})),
+    };
+    let tz = match parse_timezone(query.tz.as_deref()) {
+        Ok(tz) => tz,
+        Err(e) => return HttpResponse::BadRequest().json(json!({"msg": e.to_string()})),
+    };
+    let to = query.to.unwrap_or(Utc::now());
+    let buckets = match interval.buckets(query.from, to, &tz) {
+        Ok(buckets) => buckets,
+        Err(e) => return HttpResponse::BadRequest().json(json!({"msg": e.to_string()})),
+    };
+
+    // Requests from the start of the first bucket, which can be before `from`
+    let start = buckets
+        .first()
+        .map_or(query.from, |(start, _)| start.with_timezone(&Utc));
+    let mut filter = RequestFilter::default();
+    filter.push("date < ?", vec![text(to.format(DB_LOG_DATE_FORMAT).to_string())]);
     let results_from = get_requests_from(
-        query.from.format(DB_LOG_DATE_FORMAT).to_string(),
+        (start - chrono::Duration::milliseconds(1))
+            .format(DB_LOG_DATE_FORMAT)
+            .to_string(),
         Order::Ascending,
+        &filter,
         &app_state.db_client,
     );
-    let mut chart_data = vec![];
-    let delta = match query.granularity {
-        Granularity::Hourly => chrono::Duration::hours(1),
-        Granularity::Daily => chrono::Duration::days(1),
-        Granularity::Weekly => chrono::Duration::weeks(1),
-        Granularity::Monthly => chrono::Duration::weeks(4),
-    };
-    let mut from = query.from.naive_local();
-
-    match query.granularity {
-        Granularity::Hourly => from = from.with_minute(0).unwrap(),
-        Granularity::Daily => from = from.with_hour(0).unwrap(),
-        Granularity::Weekly => {
-            from = from.with_day0(0).unwrap()
-                - chrono::Duration::days(from.weekday().num_days_from_monday() as i64)
-        }
-        Granularity::Monthly => from = from.with_day0(0).unwrap(),
+    match results_from {
+        Ok(results) => HttpResponse::Ok().json(get_failure_to_success_stats(&results, &buckets)),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to get request stats: {:?}", e)})),
     }
-
-    get_failure_to_success_stats(&results_from.unwrap(), from, delta, &mut chart_data);
-
-    HttpResponse::Ok().json(chart_data)
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Copy)]
@@ -368,25 +825,47 @@ async fn get_requests_search_suggestions(
 pub struct PaginatedSearch {
     keyword: String,
     method: Option<String>,
-    index: usize,
-    limit: usize,
+    index: Option<usize>,
+    limit: Option<usize>,
+    cursor: Option<String>,
+    count: Option<CountMode>,
     status: Option<String>,
     res_time_l_t: Option<String>,
-    res_time_h_t: Option<String>,
+    res_time_g_t: Option<String>,
+    q: Option<String>,
 }
 #[get("/requests/search")]
 async fn get_requests_search(
     _: User,
+    req: HttpRequest,
     app_state: web::Data<AppState>,
     query: web::Query<PaginatedSearch>,
 ) -> HttpResponse {
-    let suggestions = requests_search(
-        query.keyword.clone(),
+    let (header_filters, body_filters) = match repeated_filters(&req) {
+        Ok(filters) => filters,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({"msg": format!("Invalid filter: {}", e)}))
+        }
+    };
+    let q = match Query::from_str(query.q.as_deref().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(e) => return invalid_query(e),
+    };
+    let filter = request_filter(
+        None,
         query.method.clone(),
-        query.index,
-        query.limit,
-        &app_state.db_client,
+        query.status.clone(),
+        query.res_time_l_t.clone(),
+        query.res_time_g_t.clone(),
+        &header_filters,
+        &body_filters,
+        &q,
     );
+    let page = match Page::new(query.cursor.as_deref(), query.index, query.limit, query.count) {
+        Ok(page) => page,
+        Err(e) => return HttpResponse::BadRequest().json(json!({"msg": e.to_string()})),
+    };
+    let suggestions = requests_search(&query.keyword, &filter, &page, &app_state.db_client);
     match suggestions {
         Ok(result) => HttpResponse::Ok().json(result),
         Err(e) => HttpResponse::InternalServerError()
@@ -412,3 +891,120 @@ async fn get_service_level_indicators(
             .json(json!({"msg": format!("Failed to get service level indicators: {:?}", e)})),
     }
 }
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ComparisonQuery {
+    pub from: DateTime<Utc>,
+    pub to: Option<DateTime<Utc>>,
+    pub baseline: Option<ComparisonBaseline>,
+    pub baseline_from: Option<DateTime<Utc>>,
+    pub baseline_to: Option<DateTime<Utc>>,
+    pub movers_by: Option<MoverMetric>,
+    pub movers_limit: Option<usize>,
+    pub q: Option<String>,
+}
+#[get("/requests/compare")]
+async fn compare(
+    _: User,
+    query: web::Query<ComparisonQuery>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    let mut filter = RequestFilter::default();
+    match Query::from_str(query.q.as_deref().unwrap_or_default()) {
+        Ok(q) => q.apply(&mut filter),
+        Err(e) => return invalid_query(e),
+    };
+    let current = TimeWindow::new(query.from, query.to);
+    if current.to <= current.from {
+        return HttpResponse::BadRequest().json(json!({"msg": "`to` must be after `from`"}));
+    }
+    let baseline =
+        match query
+            .baseline
+            .unwrap_or_default()
+            .window(&current, query.baseline_from, query.baseline_to)
+        {
+            Ok(baseline) => baseline,
+            Err(e) => return HttpResponse::BadRequest().json(json!({"msg": e.to_string()})),
+        };
+    let comparison = filter.run(&app_state.db_client, || {
+        compare_windows(
+            &current,
+            &baseline,
+            &filter,
+            query.movers_by.unwrap_or_default(),
+            query
+                .movers_limit
+                .unwrap_or(DEFAULT_MOVERS_LIMIT)
+                .min(MAX_MOVERS_LIMIT),
+            &app_state.db_client,
+        )
+    });
+    match comparison {
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to compare time ranges: {:?}", e)})),
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SeriesQuery {
+    pub from: DateTime<Utc>,
+    pub to: Option<DateTime<Utc>>,
+    pub metric: Option<SeriesMetric>,
+    pub group_by: Option<SeriesGroup>,
+    /// e.g. `5m`, `1h`, `1d`, `1w` or `1M`, hourly by default
+    pub interval: Option<String>,
+    pub tz: Option<String>,
+    pub top_n: Option<usize>,
+    pub q: Option<String>,
+}
+#[get("/requests/series")]
+async fn series(
+    _: User,
+    query: web::Query<SeriesQuery>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    let mut filter = RequestFilter::default();
+    match Query::from_str(query.q.as_deref().unwrap_or_default()) {
+        Ok(q) => q.apply(&mut filter),
+        Err(e) => return invalid_query(e),
+    };
+    let interval = match Interval::from_str(query.interval.as_deref().unwrap_or("1h")) {
+        Ok(interval) => interval,
+        Err(e) => return HttpResponse::BadRequest().json(json!({"msg": e.to_string()})),
+    };
+    let tz = match parse_timezone(query.tz.as_deref()) {
+        Ok(tz) => tz,
+        Err(e) => return HttpResponse::BadRequest().json(json!({"msg": e.to_string()})),
+    };
+    let buckets = match interval.buckets(query.from, query.to.unwrap_or(Utc::now()), &tz) {
+        Ok(buckets) if buckets.len() > MAX_SERIES_BUCKETS => {
+            return HttpResponse::BadRequest().json(json!({
+                "msg": format!("More than {} buckets, use a longer interval or a shorter range", MAX_SERIES_BUCKETS)
+            }))
+        }
+        Ok(buckets) => buckets,
+        Err(e) => return HttpResponse::BadRequest().json(json!({"msg": e.to_string()})),
+    };
+    let series = filter.run(&app_state.db_client, || {
+        request_series(
+            &buckets,
+            query.metric.unwrap_or_default(),
+            query.group_by.unwrap_or_default(),
+            query
+                .top_n
+                .unwrap_or(DEFAULT_SERIES_TOP_N)
+                .clamp(1, MAX_SERIES_TOP_N),
+            &filter,
+            &app_state.db_client,
+        )
+    });
+    match series {
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to get request series: {:?}", e)})),
+    }
+}
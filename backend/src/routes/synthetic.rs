@@ -0,0 +1,260 @@
+use crate::{
+    middleware::auth::User,
+    ops::{
+        audit::{audited, AuditEntry},
+        pagination::{DEFAULT_PAGE_LIMIT, MAX_PAGE_LIMIT},
+        synthetic::{
+            delete_monitor, insert_monitor, monitor_incidents, monitor_runs, monitor_states,
+            run_monitor, store_run, synthetic_monitor, update_monitor, SyntheticMonitorRequest,
+        },
+    },
+    AppState,
+};
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse};
+use rusqlite::ErrorCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// Runs and incidents listed with a monitor
+const MONITOR_HISTORY_LIMIT: usize = 20;
+
+/// Whether a write failed because the monitor's name is taken
+fn is_name_taken(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<rusqlite::Error>()
+        .and_then(|e| e.sqlite_error_code())
+        .is_some_and(|code| code == ErrorCode::ConstraintViolation)
+}
+
+#[get("/synthetic-monitors")]
+pub async fn get_synthetic_monitors(_: User, app_state: web::Data<AppState>) -> HttpResponse {
+    match monitor_states(&app_state.db_client) {
+        Ok(monitors) => HttpResponse::Ok().json(json!({ "monitors": monitors })),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to read synthetic monitors: {:?}", e)})),
+    }
+}
+
+#[post("/synthetic-monitors")]
+pub async fn add_synthetic_monitor(
+    user: User,
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    body: web::Json<SyntheticMonitorRequest>,
+) -> HttpResponse {
+    if let Err(e) = body.validate() {
+        return HttpResponse::BadRequest().json(json!({"msg": format!("Invalid monitor: {}", e)}));
+    }
+    let inserted = audited(
+        &app_state.db_client,
+        app_state.audit_retention_days,
+        |conn| insert_monitor(&body, conn),
+        |monitor| {
+            Some(
+                AuditEntry::from_request(
+                    &user,
+                    &req,
+                    "synthetic_monitor.create",
+                    Some(monitor.id.to_string()),
+                )
+                .after(serde_json::to_value(monitor).ok()),
+            )
+        },
+    );
+    match inserted {
+        Ok(monitor) => HttpResponse::Ok().json(json!({"result": "success", "monitor": monitor})),
+        Err(e) if is_name_taken(&e) => HttpResponse::Conflict()
+            .json(json!({"msg": format!("A monitor named '{}' already exists", body.name.trim())})),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to add synthetic monitor: {:?}", e)})),
+    }
+}
+
+/// A monitor with its latest runs and incidents
+#[get("/synthetic-monitors/{id}")]
+pub async fn get_synthetic_monitor(
+    _: User,
+    app_state: web::Data<AppState>,
+    path: web::Path<i64>,
+) -> HttpResponse {
+    let id = path.into_inner();
+    let conn = &app_state.db_client;
+    let monitor = match monitor_states(conn) {
+        Ok(states) => states.into_iter().find(|state| state.monitor.id == id),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .json(json!({"msg": format!("Failed to get synthetic monitor: {:?}", e)}))
+        }
+    };
+    let Some(monitor) = monitor else {
+        return HttpResponse::NotFound().json(json!({"msg": "Synthetic monitor not found"}));
+    };
+    let history = monitor_runs(id, MONITOR_HISTORY_LIMIT, conn)
+        .and_then(|runs| Ok((runs, monitor_incidents(id, MONITOR_HISTORY_LIMIT, conn)?)));
+    match history {
+        Ok((runs, incidents)) => HttpResponse::Ok().json(json!({
+            "monitor": monitor,
+            "runs": runs,
+            "incidents": incidents,
+        })),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to get synthetic monitor runs: {:?}", e)})),
+    }
+}
+
+/// Replaces a monitor's definition, keeping its runs and incidents
+#[put("/synthetic-monitors/{id}")]
+pub async fn update_synthetic_monitor(
+    user: User,
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    path: web::Path<i64>,
+    body: web::Json<SyntheticMonitorRequest>,
+) -> HttpResponse {
+    if let Err(e) = body.validate() {
+        return HttpResponse::BadRequest().json(json!({"msg": format!("Invalid monitor: {}", e)}));
+    }
+    let id = path.into_inner();
+    let updated = audited(
+        &app_state.db_client,
+        app_state.audit_retention_days,
+        |conn| update_monitor(id, &body, conn),
+        |change| {
+            let (before, after) = change.as_ref()?;
+            Some(
+                AuditEntry::from_request(&user, &req, "synthetic_monitor.update", Some(id.to_string()))
+                    .before(serde_json::to_value(before).ok())
+                    .after(serde_json::to_value(after).ok()),
+            )
+        },
+    );
+    match updated {
+        Ok(Some((_, after))) => HttpResponse::Ok().json(json!({"result": "success", "monitor": after})),
+        Ok(None) => HttpResponse::NotFound().json(json!({"msg": "Synthetic monitor not found"})),
+        Err(e) if is_name_taken(&e) => HttpResponse::Conflict()
+            .json(json!({"msg": format!("A monitor named '{}' already exists", body.name.trim())})),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to update synthetic monitor: {:?}", e)})),
+    }
+}
+
+#[delete("/synthetic-monitors/{id}")]
+pub async fn remove_synthetic_monitor(
+    user: User,
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    path: web::Path<i64>,
+) -> HttpResponse {
+    let id = path.into_inner();
+    let deleted = audited(
+        &app_state.db_client,
+        app_state.audit_retention_days,
+        |conn| delete_monitor(id, conn),
+        |monitor| {
+            let monitor = monitor.as_ref()?;
+            Some(
+                AuditEntry::from_request(&user, &req, "synthetic_monitor.delete", Some(id.to_string()))
+                    .before(serde_json::to_value(monitor).ok()),
+            )
+        },
+    );
+    match deleted {
+        Ok(Some(_)) => HttpResponse::Ok().json(json!({"result": "success"})),
+        Ok(None) => HttpResponse::NotFound().json(json!({"msg": "Synthetic monitor not found"})),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to delete synthetic monitor: {:?}", e)})),
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MonitorHistoryQuery {
+    pub limit: Option<usize>,
+}
+#[get("/synthetic-monitors/{id}/runs")]
+pub async fn get_synthetic_runs(
+    _: User,
+    app_state: web::Data<AppState>,
+    path: web::Path<i64>,
+    query: web::Query<MonitorHistoryQuery>,
+) -> HttpResponse {
+    let id = path.into_inner();
+    let conn = &app_state.db_client;
+    match synthetic_monitor(id, conn) {
+        Ok(Some(_)) => (),
+        Ok(None) => return HttpResponse::NotFound().json(json!({"msg": "Synthetic monitor not found"})),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .json(json!({"msg": format!("Failed to get synthetic monitor: {:?}", e)}))
+        }
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_LIMIT).min(MAX_PAGE_LIMIT);
+    match monitor_runs(id, limit, conn) {
+        Ok(runs) => HttpResponse::Ok().json(json!({ "runs": runs })),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to read synthetic monitor runs: {:?}", e)})),
+    }
+}
+
+#[get("/synthetic-monitors/{id}/incidents")]
+pub async fn get_synthetic_incidents(
+    _: User,
+    app_state: web::Data<AppState>,
+    path: web::Path<i64>,
+    query: web::Query<MonitorHistoryQuery>,
+) -> HttpResponse {
+    let id = path.into_inner();
+    let conn = &app_state.db_client;
+    match synthetic_monitor(id, conn) {
+        Ok(Some(_)) => (),
+        Ok(None) => return HttpResponse::NotFound().json(json!({"msg": "Synthetic monitor not found"})),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .json(json!({"msg": format!("Failed to get synthetic monitor: {:?}", e)}))
+        }
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_LIMIT).min(MAX_PAGE_LIMIT);
+    match monitor_incidents(id, limit, conn) {
+        Ok(incidents) => HttpResponse::Ok().json(json!({ "incidents": incidents })),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to read synthetic monitor incidents: {:?}", e)})),
+    }
+}
+
+/// Runs a monitor right away, even when disabled, and records the run like scheduled ones
+#[post("/synthetic-monitors/{id}/run")]
+pub async fn run_synthetic_monitor(
+    user: User,
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    path: web::Path<i64>,
+) -> HttpResponse {
+    let id = path.into_inner();
+    let monitor = match synthetic_monitor(id, &app_state.db_client) {
+        Ok(Some(monitor)) => monitor,
+        Ok(None) => return HttpResponse::NotFound().json(json!({"msg": "Synthetic monitor not found"})),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .json(json!({"msg": format!("Failed to get synthetic monitor: {:?}", e)}))
+        }
+    };
+    let mut run = run_monitor(&monitor).await;
+    let recorded = audited(
+        &app_state.db_client,
+        app_state.audit_retention_days,
+        |conn| {
+            store_run(&monitor, &mut run, conn)?;
+            Ok((run.id, run.status.clone()))
+        },
+        |(run_id, status)| {
+            Some(
+                AuditEntry::from_request(&user, &req, "synthetic_monitor.run", Some(id.to_string()))
+                    .after(Some(json!({"runId": run_id, "status": status}))),
+            )
+        },
+    );
+    match recorded {
+        Ok(_) => HttpResponse::Ok().json(json!({ "run": run })),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to record synthetic monitor run: {:?}", e)})),
+    }
+}
@@ -0,0 +1,26 @@
+use crate::{
+    middleware::auth::User,
+    ops::traces::{trace, trace_id},
+    AppState,
+};
+use actix_web::{get, web, HttpResponse};
+use serde_json::json;
+
+/// Requests of a trace as a call tree, with each span's offset and duration for a waterfall
+#[get("/traces/{trace_id}")]
+pub async fn get_trace(
+    _: User,
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let Some(id) = trace_id(&path.into_inner()) else {
+        return HttpResponse::BadRequest()
+            .json(json!({"msg": "Trace id must be 32 hex digits, not all zeros"}));
+    };
+    match trace(&id, &app_state.db_client) {
+        Ok(Some(trace)) => HttpResponse::Ok().json(trace),
+        Ok(None) => HttpResponse::NotFound().json(json!({"msg": "Trace not found"})),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({"msg": format!("Failed to get trace: {:?}", e)})),
+    }
+}
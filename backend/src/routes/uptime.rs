@@ -1,8 +1,11 @@
 use crate::{
     middleware::auth::User,
-    ops::uptime::{
-        delete_uptime_setting_db, uptime_percentage, uptime_percentage_per_hour, uptime_settings,
-        UptimePingMessage,
+    ops::{
+        audit::{audited, AuditEntry},
+        uptime::{
+            delete_uptime_setting_db, uptime_percentage, uptime_percentage_per_hour, uptime_setting,
+            uptime_settings, UptimePingMessage,
+        },
     },
     AppState,
 };
@@ -11,8 +14,9 @@ use actix_web::{
     http::StatusCode,
     post,
     web::{self, Query},
-    HttpResponse,
+    HttpRequest, HttpResponse,
 };
+use log::error;
 use rusqlite::params;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
@@ -60,7 +64,8 @@ pub async fn get_uptime_settings(_: User, app_state: web::Data<AppState>) -> Htt
 
 #[post("/uptime-settings")]
 pub async fn setup_uptime_ping(
-    _: User,
+    user: User,
+    req: HttpRequest,
     app_state: web::Data<AppState>,
     body: web::Json<UptimeSetting>,
 ) -> HttpResponse {
@@ -69,20 +74,31 @@ pub async fn setup_uptime_ping(
     let uptime_actor = app_state.uptime_actor.clone();
     let interval = body.interval.unwrap_or(60);
 
-    let stmt = "
-          INSERT INTO uptime_settings (url, interval, method, enabled, name)
-          VALUES (?1, ?2, ?3, ?4, ?5)
-        ";
-    match app_state.db_client.execute(
-        stmt,
-        params![
-            body.url,
-            interval.to_string(),
-            body.method.clone().unwrap_or(Method::GET).to_string(),
-            body.enabled.unwrap_or(true),
-            body.name
-        ],
-    ) {
+    let inserted = audited(
+        &app_state.db_client,
+        app_state.audit_retention_days,
+        |conn| {
+            conn.execute(
+                "INSERT INTO uptime_settings (url, interval, method, enabled, name)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    body.url,
+                    interval.to_string(),
+                    body.method.clone().unwrap_or(Method::GET).to_string(),
+                    body.enabled.unwrap_or(true),
+                    body.name
+                ],
+            )?;
+            Ok(())
+        },
+        |_| {
+            Some(
+                AuditEntry::from_request(&user, &req, "uptime_setting.create", Some(body.url.clone()))
+                    .after(serde_json::to_value(&body.0).ok()),
+            )
+        },
+    );
+    match inserted {
         Ok(_) => {
             let _ = uptime_actor
                 .send(UptimePingMessage {
@@ -97,8 +113,11 @@ pub async fn setup_uptime_ping(
                 .status(StatusCode::OK)
                 .json(json!({"result":"success"}))
         }
-        Err(_) => HttpResponse::InternalServerError()
-            .json(json!({"msg": format!("Failed to setup uptime ping, is the url already setup?")})),
+        Err(e) => {
+            error!("Failed to setup uptime ping: {}", e);
+            HttpResponse::InternalServerError()
+                .json(json!({"msg": format!("Failed to setup uptime ping, is the url already setup?")}))
+        }
     }
 }
 
@@ -108,13 +127,29 @@ struct DeleteUptimeSettings {
 }
 #[delete("/uptime-settings")]
 pub async fn delete_uptime_setting(
-    _: User,
+    user: User,
+    req: HttpRequest,
     query: web::Query<DeleteUptimeSettings>,
     app_state: web::Data<AppState>,
 ) -> HttpResponse {
     let db_path = app_state.db_path.clone();
     let uptime_actor = app_state.uptime_actor.clone();
-    match delete_uptime_setting_db(&app_state.db_client, query.url.clone()) {
+    let deleted = audited(
+        &app_state.db_client,
+        app_state.audit_retention_days,
+        |conn| {
+            let before = uptime_setting(conn, &query.url)?;
+            delete_uptime_setting_db(conn, query.url.clone())?;
+            Ok(before)
+        },
+        |before| {
+            Some(
+                AuditEntry::from_request(&user, &req, "uptime_setting.delete", Some(query.url.clone()))
+                    .before(before.as_ref().and_then(|b| serde_json::to_value(b).ok())),
+            )
+        },
+    );
+    match deleted {
         Ok(_) => {
             let _ = uptime_actor
                 .send(UptimePingMessage {
@@ -129,8 +164,11 @@ pub async fn delete_uptime_setting(
                 .status(StatusCode::OK)
                 .json(json!({"result":"success"}))
         }
-        Err(_) => HttpResponse::InternalServerError()
-            .json(json!({"msg": format!("Failed to delete uptime settings")})),
+        Err(e) => {
+            error!("Failed to delete uptime settings: {}", e);
+            HttpResponse::InternalServerError()
+                .json(json!({"msg": format!("Failed to delete uptime settings")}))
+        }
     }
 }
 
@@ -1,7 +1,13 @@
 import { Skeleton } from "@/components/shared/skeleton";
 import { Popover, PopoverBackdrop, PopoverButton, PopoverPanel } from "@headlessui/react";
 import { isEmpty } from "lodash";
-import { formatResponseTime, getStatusColor, parsePathName, PrettyPrintJson } from "../../util";
+import {
+  formatResponseTime,
+  getStatusColor,
+  headerLines,
+  parsePathName,
+  PrettyPrintJson,
+} from "../../util";
 
 export interface RequestListItemProps {
   setSelectedIndex: (index: number | null) => void;
@@ -72,8 +78,7 @@ export function RequestListItem({
         </div>
         <p className="pt-3">Request headers:</p>
         <div className="h-fit flex-1 mt-3 p-3 border-[0.5px] border-admin-text-light bg-admin-bg-dark rounded-md">
-          {log.req_headers
-            .split("\n")
+          {headerLines(log.req_headers)
             .sort()
             .map((headers, i) => (
               <div key={i + log.date} className={`flex flex-row text-sm`}>
@@ -103,8 +108,7 @@ export function RequestListItem({
         )}
         <p className="pt-3">Response headers:</p>
         <div className="h-fit flex-1 mt-2 p-3 border-[0.5px] border-admin-text-light bg-admin-bg-dark rounded-md">
-          {log.res_headers
-            .split("\n")
+          {headerLines(log.res_headers)
             .sort()
             .map((headers, i) => (
               <div key={i + log.date} className={`flex flex-row text-sm`}>
@@ -3,6 +3,9 @@ import { useEffect, useState } from "react";
 import useWebSocket, { ReadyState } from "react-use-websocket";
 import { getApiBase, isDev } from "../util";
 
+/** Most streamed requests kept in memory, the oldest being dropped first */
+const MAX_STREAM_LOGS = 500;
+
 export const useLogsSocket = (enabled: boolean) => {
   const session = useSession();
   const [streamLogs, setStreamLogs] = useState<Request[]>([]);
@@ -16,8 +19,12 @@ export const useLogsSocket = (enabled: boolean) => {
         sendMessage(session?.session?.token ?? "");
       },
       onMessage: message => {
-        if (message?.data) {
-          setStreamLogs(JSON.parse(message.data));
+        if (!message?.data) return;
+        const data = JSON.parse(message.data);
+        if (data.type === "requests") {
+          setStreamLogs(logs => [...logs, ...data.requests].slice(-MAX_STREAM_LOGS));
+        } else if (isDev && data.type !== "subscribed") {
+          console.log(data);
         }
       },
       shouldReconnect: res => {
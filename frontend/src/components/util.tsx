@@ -29,6 +29,15 @@ export function getStatusColor(status: number) {
   }
 }
 
+export function headerLines(headers: RequestHeaders) {
+  if (typeof headers === "string") {
+    return headers.split("\n");
+  }
+  return Object.entries(headers).flatMap(([name, value]) =>
+    (Array.isArray(value) ? value : [value]).map(v => `${name}: ${v}`),
+  );
+}
+
 export const PrettyPrintJson = ({ data }: { data: unknown }) => {
   // (destructured) data could be a prop for example
   return (
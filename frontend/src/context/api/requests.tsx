@@ -4,7 +4,8 @@ import { UserDataInterface } from "../session";
 export async function getStatsRequest(from: string, token: string) {
   try {
     const response = await fetch(
-      `${getApiBase()}/requests-stats?statType=status&from=${from}&granularity=hourly`,
+      `${getApiBase()}/requests-stats?statType=status&from=${from}&interval=1h` +
+        `&tz=${encodeURIComponent(Intl.DateTimeFormat().resolvedOptions().timeZone)}`,
       {
         headers: {
           "Content-Type": "application/json",
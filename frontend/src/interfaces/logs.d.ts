@@ -1,9 +1,17 @@
+type RequestHeaders = string | Record<string, string | string[]>;
+
 interface Request {
+  id: number;
   date: string;
   method: string;
-  req_headers: string;
-  res_headers: string;
+  req_headers: RequestHeaders;
+  res_headers: RequestHeaders;
   endpoint: string;
+  endpoint_template?: string;
+  service?: string;
+  trace_id?: string;
+  span_id?: string;
+  parent_span_id?: string;
   params: string;
   status: number;
   req_body: string;